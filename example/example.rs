@@ -27,24 +27,29 @@ fn main() {
 
     let nick = format!("rustirclib{}", rand::task_rng().gen_range(100u, 1000u));
     opts.nick = nick.as_slice();
-    match irc::conn::connect(opts, (), |c,e,_| handler(c,e)) {
-        Ok(()) => println!("Exiting..."),
+    match irc::conn::connect(opts, (), |c,e,_| { handler(c,e); irc::conn::Continue }) {
+        Ok(summary) => println!("Exiting after {}: {}", summary.duration, summary.reason),
         Err(err) => println!("Connection error: {}", err)
     }
 }
 
 fn handler(conn: &mut Conn, event: Event) {
     match event {
+        irc::conn::Connecting => println!("Connecting"),
         irc::conn::Connected => println!("Connected"),
-        irc::conn::Disconnected => println!("Disconnected"),
+        irc::conn::Registering => println!("Registering"),
+        irc::conn::Registered(reg) => {
+            println!("Registered as {}", str::from_utf8_lossy(reg.nick.as_slice()));
+        }
+        irc::conn::Disconnected(reason) => println!("Disconnected: {}", reason),
         irc::conn::LineReceived(line) => {
             match line {
                 Line{command: IRCCode(1), ..} => {
                     println!("Logged in");
                     // we've logged in
-                    conn.join(bytes!("##rustirclib"), [])
+                    conn.join(bytes!("##rustirclib"), []).unwrap()
                 }
-                Line{command: IRCCmd(cmd), args, prefix: prefix } => match cmd.as_slice() {
+                Line{command: IRCCmd(cmd), args, prefix: prefix, .. } => match cmd.as_slice() {
                     "JOIN" if prefix.is_some() => {
                         let prefix = prefix.unwrap();
                         if prefix.nick() != conn.me().nick() {
@@ -52,7 +57,7 @@ fn handler(conn: &mut Conn, event: Event) {
                         }
                         if args.is_empty() {
                             let line = Line{command: IRCCmd("JOIN".into_maybe_owned()),
-                                            args: args, prefix: Some(prefix)};
+                                            args: args, prefix: Some(prefix), tags: Vec::new()};
                             println!("ERROR: Invalid JOIN message received: {}", line_desc(&line));
                             return;
                         }
@@ -71,7 +76,7 @@ fn handler(conn: &mut Conn, event: Event) {
                             }
                             _ => {
                                 print!("ERROR: Unexpected {} line: ", cmd);
-                                let line = Line{command: IRCCmd(cmd), args: args, prefix: prefix};
+                                let line = Line{command: IRCCmd(cmd), args: args, prefix: prefix, tags: Vec::new()};
                                 println!("{}", line_desc(&line));
                                 return;
                             }
@@ -84,14 +89,14 @@ fn handler(conn: &mut Conn, event: Event) {
                     }
                     _ => ()
                 },
-                Line{command: IRCAction(dst), args, prefix } => {
+                Line{command: IRCAction(dst), args, prefix, .. } => {
                     let (src, msg) = match prefix {
                         Some(_) if args.len() == 1 => {
                             let msg = args.move_iter().next().unwrap();
                             (prefix.as_ref().unwrap().nick(), msg)
                         }
                         _ => {
-                            let line = Line{command: IRCAction(dst), args: args, prefix: prefix};
+                            let line = Line{command: IRCAction(dst), args: args, prefix: prefix, tags: Vec::new()};
                             println!("ERROR: Unexpected ACTION line: {}", line_desc(&line));
                             return;
                         }
@@ -104,6 +109,7 @@ fn handler(conn: &mut Conn, event: Event) {
                 _ => ()
             }
         }
+        _ => ()
     }
 }
 
@@ -0,0 +1,205 @@
+//! A minimal terminal client, built with the `cli` feature
+//!
+//! `run()` connects with the given options, prints what happens to stdout and
+//! reads commands from stdin:
+//!
+//! * `/join <channel> [key]`
+//! * `/part <channel> [message]`
+//! * `/msg <target> <text>`
+//! * `/quote <raw line>`
+//! * `/quit [message]`
+//!
+//! The end of the input quits too, so a script can be piped in as a smoke
+//! test, e.g. `printf '/join #test\n/msg #test hi\n' | ircbot`.
+
+use std::io;
+use std::io::BufferedReader;
+use std::task::spawn;
+
+use conn;
+use conn::{Conn, CmdState, Options, Event, Continue, Line, IRCCmd, IRCCode, IRCAction};
+use conn::{Connecting, Connected, Registered, NickTruncated, ErrorReply, JoinFailed};
+use conn::{Invited, LineReceived, Disconnected};
+
+/// A command typed on stdin
+#[deriving(Clone, PartialEq, Eq, Show)]
+enum Input {
+    Join(Vec<u8>, Vec<u8>),
+    Part(Vec<u8>, Vec<u8>),
+    Msg(Vec<u8>, Vec<u8>),
+    Quote(Vec<u8>),
+    Quit(Vec<u8>)
+}
+
+/// Connects and runs the client until the connection has terminated, and
+/// returns what `conn::connect()` returned. `opts.commands` is replaced
+/// with the commands read from stdin.
+///
+/// The task reading stdin stops at the next line or the end of the input
+/// after the connection has terminated.
+pub fn run(mut opts: Options) -> conn::Result {
+    let (tx, rx) = channel();
+    opts.commands = Some(rx);
+    spawn(proc() {
+        let mut stdin = BufferedReader::new(io::stdin());
+        for line in stdin.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break
+            };
+            let input = match parse_input(line.as_slice().trim_right_chars(['\r', '\n'].as_slice())) {
+                Ok(None) => continue,
+                Ok(Some(input)) => input,
+                Err(usage) => {
+                    let _ = writeln!(io::stderr(), "usage: {}", usage);
+                    continue;
+                }
+            };
+            let quit = match input { Quit(_) => true, _ => false };
+            if tx.send_opt(proc(conn: &mut Conn, _: &mut (), _: CmdState) run_input(conn, input)).is_err() || quit {
+                return;
+            }
+        }
+        let _ = tx.send_opt(proc(conn: &mut Conn, _: &mut (), _: CmdState) conn.quit([]));
+    });
+    conn::connect(opts, (), |_, event, _| {
+        match format_event(&event) {
+            None => (),
+            Some(text) => println!("{}", text)
+        }
+        Continue
+    })
+}
+
+/// Parses a line of input. Returns Ok(None) for an empty line, or the usage
+/// of the command if the arguments are missing.
+fn parse_input(line: &str) -> Result<Option<Input>, &'static str> {
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.starts_with("/") {
+        return Err("/join, /part, /msg, /quote or /quit");
+    }
+    let (cmd, rest) = split_word(line.slice_from(1));
+    let (first, text) = split_word(rest);
+    let (first, text) = (first.as_bytes().to_vec(), text.as_bytes().to_vec());
+    match cmd {
+        "join" if !first.is_empty() => Ok(Some(Join(first, text))),
+        "join" => Err("/join <channel> [key]"),
+        "part" if !first.is_empty() => Ok(Some(Part(first, text))),
+        "part" => Err("/part <channel> [message]"),
+        "msg" if !first.is_empty() && !text.is_empty() => Ok(Some(Msg(first, text))),
+        "msg" => Err("/msg <target> <text>"),
+        "quote" if !rest.is_empty() => Ok(Some(Quote(rest.as_bytes().to_vec()))),
+        "quote" => Err("/quote <raw line>"),
+        "quit" => Ok(Some(Quit(rest.as_bytes().to_vec()))),
+        _ => Err("/join, /part, /msg, /quote or /quit")
+    }
+}
+
+/// Splits off the first word of `s`, returning it and the rest
+fn split_word<'a>(s: &'a str) -> (&'a str, &'a str) {
+    let s = s.trim_left();
+    match s.find(' ') {
+        None => (s, ""),
+        Some(idx) => (s.slice_to(idx), s.slice_from(idx + 1).trim_left())
+    }
+}
+
+fn run_input(conn: &mut Conn, input: Input) {
+    match input {
+        Join(chan, key) => match conn.join(chan.as_slice(), key.as_slice()) {
+            Ok(()) => (),
+            Err(e) => println!("! Can't join {}: {}", String::from_utf8_lossy(chan.as_slice()), e)
+        },
+        Part(chan, msg) => conn.part(chan.as_slice(), msg.as_slice()),
+        Msg(target, text) => conn.privmsg(target.as_slice(), text.as_slice()),
+        Quote(raw) => conn.send_raw(raw.as_slice()),
+        Quit(msg) => conn.quit(msg.as_slice())
+    }
+}
+
+fn lossy(v: &[u8]) -> String {
+    String::from_utf8_lossy(v).into_string()
+}
+
+/// Returns the text printed for an event, if any
+fn format_event(event: &Event) -> Option<String> {
+    Some(match *event {
+        Connecting => "* Connecting".to_string(),
+        Connected => "* Connected".to_string(),
+        Registered(ref reg) => format!("* Registered as {}", lossy(reg.nick.as_slice())),
+        NickTruncated(_, ref nick) => format!("* Nick shortened to {}", lossy(nick.as_slice())),
+        ErrorReply(ref err) => format!("! {} {}", err.code, lossy(err.message.as_slice())),
+        JoinFailed(ref chan, ref err) => {
+            format!("! Couldn't join {}: {}", lossy(chan.as_slice()), lossy(err.message.as_slice()))
+        }
+        Invited(ref by, ref chan) => {
+            let by = by.as_ref().map_or("someone".to_string(), |u| lossy(u.nick()));
+            format!("* {} invited you to {}", by, lossy(chan.as_slice()))
+        }
+        LineReceived(ref line) => return format_line(line),
+        Disconnected(ref reason) => format!("* Disconnected: {}", reason),
+        _ => return None
+    })
+}
+
+/// Returns the text printed for a received line. Numerics other than errors
+/// are printed without the leading nick, and other lines are printed raw.
+fn format_line(line: &Line) -> Option<String> {
+    let nick = line.prefix.as_ref().map_or(String::new(), |u| lossy(u.nick()));
+    let arg = |i: uint| line.args.as_slice().get(i).map_or(String::new(), |a| lossy(a.as_slice()));
+    Some(match line.command {
+        IRCCmd(ref cmd) => match cmd.as_slice() {
+            "PRIVMSG" => format!("[{}] <{}> {}", arg(0), nick, arg(1)),
+            "NOTICE" => format!("[{}] -{}- {}", arg(0), nick, arg(1)),
+            "JOIN" => format!("[{}] --> {} joined", arg(0), nick),
+            "PART" => format!("[{}] <-- {} left ({})", arg(0), nick, arg(1)),
+            "KICK" => format!("[{}] <-- {} was kicked by {} ({})", arg(0), arg(1), nick, arg(2)),
+            "QUIT" => format!("<-- {} quit ({})", nick, arg(0)),
+            "NICK" => format!("* {} is now {}", nick, arg(0)),
+            "TOPIC" => format!("[{}] * {} set the topic: {}", arg(0), nick, arg(1)),
+            "PING" | "PONG" => return None,
+            _ => lossy(line.to_raw().as_slice())
+        },
+        IRCAction(ref dst) => format!("[{}] * {} {}", lossy(dst.as_slice()), nick, arg(0)),
+        // errors have their own events
+        IRCCode(code) if code >= 400 && code < 600 => return None,
+        IRCCode(_) if line.args.len() > 1 => {
+            let words = line.args.slice_from(1).iter().map(|a| lossy(a.as_slice())).collect::<Vec<_>>();
+            words.connect(" ")
+        }
+        _ => lossy(line.to_raw().as_slice())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_input, format_line, Join, Msg, Quit, Quote};
+    use conn::Line;
+
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(parse_input("/join #rust"), Ok(Some(Join(b"#rust".to_vec(), Vec::new()))));
+        assert_eq!(parse_input("/join #secret  hunter2"), Ok(Some(Join(b"#secret".to_vec(), b"hunter2".to_vec()))));
+        assert_eq!(parse_input("/msg nick hello there"), Ok(Some(Msg(b"nick".to_vec(), b"hello there".to_vec()))));
+        assert_eq!(parse_input("/quote MODE me +i"), Ok(Some(Quote(b"MODE me +i".to_vec()))));
+        assert_eq!(parse_input("/quit"), Ok(Some(Quit(Vec::new()))));
+        assert_eq!(parse_input(""), Ok(None));
+        assert!(parse_input("/msg nick").is_err());
+        assert!(parse_input("/join").is_err());
+        assert!(parse_input("/frobnicate").is_err());
+        assert!(parse_input("hello").is_err());
+    }
+
+    #[test]
+    fn test_format_line() {
+        let f = |raw: &[u8]| format_line(&Line::parse(raw).unwrap());
+        assert_eq!(f(b":nick!u@h PRIVMSG #rust :hi all"), Some("[#rust] <nick> hi all".to_string()));
+        assert_eq!(f(b":nick!u@h PRIVMSG #rust :\x01ACTION waves\x01"), Some("[#rust] * nick waves".to_string()));
+        assert_eq!(f(b":nick!u@h QUIT :bye"), Some("<-- nick quit (bye)".to_string()));
+        assert_eq!(f(b":server 372 me :- the motd"), Some("- the motd".to_string()));
+        assert_eq!(f(b":server 401 me nobody :No such nick"), None);
+        assert_eq!(f(b"PING :server"), None);
+    }
+}
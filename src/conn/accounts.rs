@@ -0,0 +1,224 @@
+//! Finding out which services account a nick is logged in to
+
+use std::collections::HashMap;
+
+use conn::{Conn, Line, IRCCmd, IRCCode};
+
+/// Callback for the result of `account_of()`. It's given the account, or
+/// None if the nick isn't logged in (or doesn't exist).
+pub type AccountCallback = proc(&mut Conn, Option<Vec<u8>>);
+
+/// The token for our WHOX queries, to tell their replies from other ones
+static WHOX_TOKEN: &'static [u8] = b"152";
+
+/// Known accounts and lookups in progress, by casemapped nick
+struct Accounts {
+    known: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    pending: Vec<Lookup>
+}
+
+struct Lookup {
+    /// The casemapped nick
+    nick: Vec<u8>,
+    /// The nick as it was asked for
+    name: Vec<u8>,
+    account: Option<Vec<u8>>,
+    callbacks: Vec<AccountCallback>
+}
+
+impl<'a> Conn<'a> {
+    /// Finds out which services account `nick` is logged in to, and calls
+    /// `cb` with it.
+    ///
+    /// Accounts are remembered from account tags and ACCOUNT lines (if the
+    /// account-tag and account-notify capabilities are enabled), from
+    /// extended-join, and from earlier lookups. If the account isn't known, it's
+    /// looked up with a WHOX query if the server supports it, or with WHOIS
+    /// otherwise. Remembered accounts are forgotten when the nick quits or
+    /// changes nick, and when we reconnect. Lookups still in progress when
+    /// the connection is lost are given None.
+    pub fn account_of(&mut self, nick: &[u8], cb: AccountCallback) {
+        let key = self.isupport.casemapping().lower(nick);
+        match known(self, nick) {
+            Some(account) => {
+                cb(self, account);
+                return;
+            }
+            None => ()
+        }
+        match self.accounts().pending.iter_mut().find(|l| l.nick == key) {
+            Some(lookup) => {
+                lookup.callbacks.push(cb);
+                return;
+            }
+            None => ()
+        }
+        self.accounts().pending.push(Lookup { nick: key, name: nick.to_vec(), account: None, callbacks: vec![cb] });
+        if self.quirks.whox {
+            let mut fields = b"%tna,".to_vec();
+            fields.push_all(WHOX_TOKEN);
+            self.send_command(IRCCmd("WHO".into_maybe_owned()), [nick, fields.as_slice()], false);
+        } else {
+            self.send_command(IRCCmd("WHOIS".into_maybe_owned()), [nick], false);
+        }
+    }
+
+    fn accounts<'b>(&'b mut self) -> &'b mut Accounts {
+        self.extensions.get_or_insert_with(|| Accounts { known: HashMap::new(), pending: Vec::new() })
+    }
+}
+
+/// Returns the account `nick` is known to be logged in to, without looking
+/// it up: Some(None) if it's known not to be logged in, and None if it isn't
+/// known. See `Conn::account_of()`.
+pub fn known(conn: &mut Conn, nick: &[u8]) -> Option<Option<Vec<u8>>> {
+    let key = conn.isupport.casemapping().lower(nick);
+    match conn.accounts().known.get(&key) {
+        Some(account) => return Some(account.clone()),
+        None => ()
+    }
+    conn.state.user(nick).and_then(|u| u.account().map(|a| a.map(|a| a.to_vec())))
+}
+
+/// Forgets the accounts known on the last connection, where nicks may have
+/// belonged to someone else, and gives the lookups in progress None
+pub fn reset(conn: &mut Conn) {
+    let pending = match conn.extensions.remove::<Accounts>() {
+        None => return,
+        Some(accounts) => accounts.pending
+    };
+    for lookup in pending.into_iter() {
+        for cb in lookup.callbacks.into_iter() {
+            cb(conn, None);
+        }
+    }
+}
+
+/// Keys the lookups in progress by the new casemapping. The known accounts
+/// are forgotten, and looked up again when they're needed.
+pub fn rekey(conn: &mut Conn) {
+    let casemapping = conn.isupport.casemapping();
+    let accounts = conn.accounts();
+    accounts.known.clear();
+    for lookup in accounts.pending.iter_mut() {
+        lookup.nick = casemapping.lower(lookup.name.as_slice());
+    }
+}
+
+/// Keeps the known accounts up to date from a line
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    let nick = match line.prefix {
+        None => return,
+        Some(ref user) => conn.isupport.casemapping().lower(user.nick())
+    };
+    match line.command {
+        IRCCmd(ref s) if "QUIT" == s.as_slice() || "NICK" == s.as_slice() => {
+            conn.accounts().known.remove(&nick);
+        }
+        IRCCmd(ref s) if "ACCOUNT" == s.as_slice() && !line.args.is_empty() => {
+            let account = line.args[0].clone();
+            let account = if account.as_slice() == b"*" { None } else { Some(account) };
+            conn.accounts().known.insert(nick, account);
+        }
+        _ => match line.tag("account") {
+            None => (),
+            Some(account) => {
+                conn.accounts().known.insert(nick, Some(account.as_bytes().to_vec()));
+            }
+        }
+    }
+}
+
+/// Records the account found for a lookup in progress
+fn found(conn: &mut Conn, nick: &[u8], account: Option<Vec<u8>>) {
+    let nick = conn.isupport.casemapping().lower(nick);
+    match conn.accounts().pending.iter_mut().find(|l| l.nick == nick) {
+        None => (),
+        Some(lookup) => lookup.account = account
+    }
+}
+
+/// Finishes the lookup for `nick`, if there is one. Returns `false` if there isn't.
+fn finish(conn: &mut Conn, nick: &[u8]) -> bool {
+    let nick = conn.isupport.casemapping().lower(nick);
+    let lookup = {
+        let accounts = conn.accounts();
+        match accounts.pending.iter().position(|l| l.nick == nick) {
+            None => return false,
+            Some(idx) => accounts.pending.remove(idx).unwrap()
+        }
+    };
+    let Lookup { nick, account, callbacks, .. } = lookup;
+    conn.accounts().known.insert(nick, account.clone());
+    for cb in callbacks.into_iter() {
+        cb(conn, account.clone());
+    }
+    true
+}
+
+// 330
+pub fn RPL_WHOISACCOUNT(conn: &mut Conn, line: &Line) {
+    // :server 330 me nick account :is logged in as
+    if line.args.len() > 2 {
+        found(conn, line.args[1].as_slice(), Some(line.args[2].clone()));
+    }
+}
+
+// 318
+pub fn RPL_ENDOFWHOIS(conn: &mut Conn, line: &Line) {
+    // :server 318 me nick :End of /WHOIS list.
+    if line.args.len() > 1 {
+        finish(conn, line.args[1].as_slice());
+    }
+}
+
+// 354
+pub fn RPL_WHOSPCRPL(conn: &mut Conn, line: &Line) {
+    // :server 354 me 152 nick account
+    match line.args.as_slice() {
+        [_, ref token, ref nick, ref account] if token.as_slice() == WHOX_TOKEN => {
+            let account = if account.as_slice() == b"0" { None } else { Some(account.clone()) };
+            found(conn, nick.as_slice(), account);
+        }
+        _ => ()
+    }
+}
+
+/// Handles an RPL_ENDOFWHO (315). Returns `true` if it ended one of our
+/// WHOX lookups, and shouldn't be reported.
+pub fn RPL_ENDOFWHO(conn: &mut Conn, line: &Line) -> bool {
+    match line.command {
+        IRCCode(315) if line.args.len() > 1 => finish(conn, line.args[1].as_slice()),
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use conn::{Manual, Options, Event, Continue};
+
+    #[test]
+    fn forgotten_on_reconnect() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let r = results.clone();
+        conn.conn().account_of(b"alice", proc(_, account) { r.lock().push(account) });
+        let r = results.clone();
+        conn.conn().account_of(b"bob", proc(_, account) { r.lock().push(account) });
+        assert_eq!(conn.step([b":irc.example.net 330 ircnick alice alice :is logged in as",
+                              b":irc.example.net 318 ircnick alice :End of /WHOIS list."], Duration::zero()),
+                   vec![b"WHOIS alice".to_vec(), b"WHOIS bob".to_vec()]);
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        // bob's lookup never finished
+        assert_eq!(*results.lock(), vec![Some(b"alice".to_vec()), None]);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        // alice may be someone else now
+        conn.conn().account_of(b"alice", proc(_, _) {});
+        assert_eq!(conn.step([], Duration::zero()), vec![b"WHOIS alice".to_vec()]);
+    }
+}
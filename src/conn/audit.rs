@@ -0,0 +1,74 @@
+//! Inspecting outgoing lines before they're sent, see `Options.send_hook`
+
+use conn::Conn;
+
+/// Sees every line right before it's sent to the server, and can change or
+/// drop it. See `Options.send_hook`.
+///
+/// This includes the lines the connection sends on its own, like PONGs,
+/// CTCP replies and the registration commands. Lines held back until we're
+/// registered are seen when they're finally sent.
+pub trait SendHook {
+    /// Returns the line to send instead of `line`, which is `line` itself to
+    /// let it through unchanged, or None to drop it. The line doesn't include
+    /// the terminating \r\n.
+    fn before_send(&mut self, line: Vec<u8>) -> Option<Vec<u8>>;
+}
+
+/// Runs the send hook on `line`. Returns the line to send, if any.
+///
+/// A rewritten line is cut off at the first CR or LF, so a hook can't sneak
+/// in another command.
+pub fn check(conn: &mut Conn, line: Vec<u8>) -> Option<Vec<u8>> {
+    let mut line = match conn.send_hook {
+        None => return Some(line),
+        Some(ref mut hook) => match hook.before_send(line) {
+            None => return None,
+            Some(line) => line
+        }
+    };
+    match line.iter().position(|&b| b == '\r' as u8 || b == '\n' as u8) {
+        None => (),
+        Some(idx) => line.truncate(idx)
+    }
+    if line.is_empty() { None } else { Some(line) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use conn::{Manual, Options, Event};
+    use super::SendHook;
+
+    #[test]
+    fn send_hook() {
+        struct Audit(Arc<Mutex<Vec<Vec<u8>>>>);
+        impl SendHook for Audit {
+            fn before_send(&mut self, line: Vec<u8>) -> Option<Vec<u8>> {
+                let Audit(ref seen) = *self;
+                seen.lock().push(line.clone());
+                if line.as_slice().starts_with(b"PRIVMSG #secret ") {
+                    None
+                } else if line.as_slice() == b"PRIVMSG #rust :hi" {
+                    Some(b"PRIVMSG #rust :hello\r\nQUIT".to_vec())
+                } else {
+                    Some(line)
+                }
+            }
+        }
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.send_hook = Some(box Audit(seen.clone()) as Box<SendHook + Send>);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        seen.lock().clear();
+        conn.conn().privmsg(b"#secret", b"password");
+        conn.conn().privmsg(b"#rust", b"hi");
+        let out = conn.step([b"PING :irc.example.net"], Duration::zero());
+        assert_eq!(out, vec![b"PRIVMSG #rust :hello".to_vec(), b"PONG irc.example.net".to_vec()]);
+        // the hook sees the automatic PONG too
+        assert_eq!(*seen.lock(), vec![b"PRIVMSG #secret :password".to_vec(), b"PRIVMSG #rust :hi".to_vec(),
+                                      b"PONG irc.example.net".to_vec()]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Logging in while registering, see `Options.authenticator`
+
+use std::mem;
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
+
+use conn::{Conn, Line, IRCCmd, AuthFailed};
+use conn::caps;
+
+/// The most base64 that one AUTHENTICATE line carries. Longer payloads are
+/// split, and one that fills the last line exactly is followed by `+`.
+static SASL_CHUNK: uint = 400;
+
+/// A way of logging in, run while the connection registers. See
+/// `Options.authenticator`.
+///
+/// `start()` is called right before NICK and USER are sent, `saw_line()` for
+/// each line received until the server has welcomed us, and `registered()`
+/// once it has. If the authenticator has a SASL mechanism and the server
+/// offers SASL, registration waits for the SASL exchange, which is driven by
+/// `sasl_response()`.
+///
+/// All methods have defaults that do nothing, so an authenticator only
+/// implements the ones it needs.
+pub trait Authenticator {
+    /// Sends what has to come before NICK and USER, e.g. PASS
+    fn start(&mut self, _conn: &mut Conn) {}
+
+    /// Returns the SASL mechanism to log in with, e.g. "PLAIN", if any
+    fn sasl_mechanism(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the response to a SASL challenge, already base64-decoded. The
+    /// first challenge of most mechanisms is empty. None aborts the exchange.
+    fn sasl_response(&mut self, _challenge: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Sees a line received before the server has welcomed us, e.g. a
+    /// bouncer asking for a password
+    fn saw_line(&mut self, _conn: &mut Conn, _line: &Line) {}
+
+    /// Called once the server has welcomed us (001), e.g. to identify to NickServ
+    fn registered(&mut self, _conn: &mut Conn) {}
+}
+
+/// Sends a server password with PASS. Bouncers use it to log in, e.g. ZNC
+/// with `user/network:password`.
+pub struct ServerPassword(pub Vec<u8>);
+
+impl Authenticator for ServerPassword {
+    fn start(&mut self, conn: &mut Conn) {
+        let ServerPassword(ref password) = *self;
+        conn.send_command(IRCCmd("PASS".into_maybe_owned()), [password.as_slice()], false);
+    }
+}
+
+/// Logs in to a services account with SASL PLAIN
+pub struct SaslPlain {
+    /// The account name
+    pub account: Vec<u8>,
+    /// The account password
+    pub password: Vec<u8>
+}
+
+impl Authenticator for SaslPlain {
+    fn sasl_mechanism(&self) -> Option<String> {
+        Some("PLAIN".to_string())
+    }
+
+    fn sasl_response(&mut self, _challenge: &[u8]) -> Option<Vec<u8>> {
+        // authorization identity, authentication identity, password
+        let mut response = self.account.clone();
+        response.push(0);
+        response.push_all(self.account.as_slice());
+        response.push(0);
+        response.push_all(self.password.as_slice());
+        Some(response)
+    }
+}
+
+/// Logs in with SASL EXTERNAL, i.e. with the TLS client certificate that
+/// the transport presents
+pub struct SaslExternal;
+
+impl Authenticator for SaslExternal {
+    fn sasl_mechanism(&self) -> Option<String> {
+        Some("EXTERNAL".to_string())
+    }
+
+    fn sasl_response(&mut self, _challenge: &[u8]) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+}
+
+/// Identifies to NickServ with `IDENTIFY <account> <password>` once
+/// registered, for networks without SASL
+pub struct NickServIdentify {
+    /// The account name
+    pub account: Vec<u8>,
+    /// The account password
+    pub password: Vec<u8>
+}
+
+impl Authenticator for NickServIdentify {
+    fn registered(&mut self, conn: &mut Conn) {
+        let mut msg = b"IDENTIFY ".to_vec();
+        msg.push_all(self.account.as_slice());
+        msg.push(' ' as u8);
+        msg.push_all(self.password.as_slice());
+        conn.services_msg(b"NickServ", msg.as_slice());
+    }
+}
+
+/// A SASL exchange in progress
+struct Sasl {
+    /// The base64 challenge received so far
+    challenge: Vec<u8>
+}
+
+/// Runs `Authenticator::start()`. Returns `true` if the authenticator wants
+/// to log in with SASL, so the "sasl" capability has to be asked for.
+pub fn start(conn: &mut Conn) -> bool {
+    conn.extensions.remove::<Sasl>();
+    let mut auth = match conn.authenticator.take() {
+        None => return false,
+        Some(auth) => auth
+    };
+    auth.start(conn);
+    let sasl = auth.sasl_mechanism().is_some();
+    conn.authenticator = Some(auth);
+    sasl
+}
+
+/// Starts the SASL exchange once the capabilities have been negotiated, which
+/// keeps registration on hold until it's over. Returns `false` if there's no
+/// SASL to do, because the authenticator has no mechanism or the server
+/// didn't enable SASL.
+pub fn start_sasl(conn: &mut Conn) -> bool {
+    if !caps::negotiating(conn) {
+        return false;
+    }
+    if conn.extensions.get::<Sasl>().is_some() {
+        return true;
+    }
+    let mechanism = match conn.authenticator.as_ref().and_then(|a| a.sasl_mechanism()) {
+        Some(ref mechanism) if conn.has_cap("sasl") => mechanism.clone(),
+        _ => return false
+    };
+    conn.extensions.insert(Sasl { challenge: Vec::new() });
+    conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()), [mechanism.as_bytes()], false);
+    true
+}
+
+/// Runs `Authenticator::saw_line()` for a line received while registering
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    let mut auth = match conn.authenticator.take() {
+        None => return,
+        Some(auth) => auth
+    };
+    auth.saw_line(conn, line);
+    conn.authenticator = Some(auth);
+}
+
+/// Runs `Authenticator::registered()`
+pub fn registered(conn: &mut Conn) {
+    let mut auth = match conn.authenticator.take() {
+        None => return,
+        Some(auth) => auth
+    };
+    auth.registered(conn);
+    conn.authenticator = Some(auth);
+}
+
+pub fn AUTHENTICATE(conn: &mut Conn, line: &Line) {
+    // AUTHENTICATE <part of the base64 challenge>, or + if it's empty
+    let part = match line.args.as_slice().head() {
+        None => return,
+        Some(part) => part.as_slice()
+    };
+    let challenge = match conn.extensions.get_mut::<Sasl>() {
+        None => return,
+        Some(sasl) => {
+            if part != b"+" {
+                sasl.challenge.push_all(part);
+            }
+            if part.len() == SASL_CHUNK {
+                // more is coming
+                return;
+            }
+            mem::replace(&mut sasl.challenge, Vec::new())
+        }
+    };
+    let challenge = match challenge.as_slice().from_base64() {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            info!("Couldn't decode the SASL challenge: {}", e);
+            conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()), [b"*"], false);
+            return;
+        }
+    };
+    let response = match conn.authenticator.as_mut() {
+        None => None,
+        Some(auth) => auth.sasl_response(challenge.as_slice())
+    };
+    match response {
+        // the server answers with ERR_SASLABORTED (906)
+        None => conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()), [b"*"], false),
+        Some(response) => {
+            let response = response.as_slice().to_base64(STANDARD);
+            let response = response.as_bytes();
+            for part in response.chunks(SASL_CHUNK) {
+                conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()), [part], false);
+            }
+            if response.len() % SASL_CHUNK == 0 {
+                conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()), [b"+"], false);
+            }
+        }
+    }
+}
+
+// 903
+pub fn RPL_SASLSUCCESS(conn: &mut Conn, _line: &Line) {
+    if conn.extensions.remove::<Sasl>().is_some() {
+        caps::finish(conn);
+    }
+}
+
+/// Handles the SASL errors (902 and 904-907). Registration goes on without
+/// an account.
+pub fn sasl_failed(conn: &mut Conn, line: &Line) {
+    if conn.extensions.remove::<Sasl>().is_none() {
+        return;
+    }
+    // :server 904 me :SASL authentication failed
+    let msg = line.args.as_slice().last().map_or(Vec::new(), |m| m.clone());
+    info!("SASL login failed: {}", String::from_utf8_lossy(msg.as_slice()));
+    conn.emit(AuthFailed(msg));
+    caps::finish(conn);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Authenticator, SaslPlain};
+
+    #[test]
+    fn sasl_plain() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.authenticator = Some(box SaslPlain {
+            account: b"alice".to_vec(),
+            password: b"hunter2".to_vec()
+        } as Box<Authenticator + Send>);
+        let mut conn = Manual::new(opts, Vec::new());
+        let out = conn.step([], Duration::zero());
+        assert_eq!(out[0].as_slice(), b"CAP LS 302");
+        let ls = b":irc.example.net CAP * LS :multi-prefix sasl";
+        assert_eq!(conn.step([ls], Duration::zero()), vec![b"CAP REQ :sasl".to_vec()]);
+        assert_eq!(conn.step([b":irc.example.net CAP * ACK :sasl"], Duration::zero()),
+                   vec![b"AUTHENTICATE PLAIN".to_vec()]);
+        assert_eq!(conn.step([b"AUTHENTICATE +"], Duration::zero()),
+                   vec![b"AUTHENTICATE YWxpY2UAYWxpY2UAaHVudGVyMg==".to_vec()]);
+        let success = b":irc.example.net 903 ircnick :SASL authentication successful";
+        assert_eq!(conn.step([success], Duration::zero()), vec![b"CAP END".to_vec()]);
+    }
+}
@@ -0,0 +1,255 @@
+//! Automatic op and voice for users on a per-channel access list
+
+use std::time::Duration;
+
+use conn::{Conn, Line};
+use modes::ModeChange;
+
+/// Who an access list or ignore list entry applies to
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum AccessMatch {
+    /// Users whose `nick!user@host` matches a glob, e.g. `*!*@trusted.host`
+    ByMask(Vec<u8>),
+    /// Users logged in to a services account
+    ByAccount(Vec<u8>)
+}
+
+/// What a user on an access list is given when they join
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum AccessLevel {
+    /// Channel operator status (+o)
+    AutoOp,
+    /// Voice (+v)
+    AutoVoice
+}
+
+/// An entry on a channel's access list
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct AccessEntry {
+    /// Who the entry applies to
+    pub matches: AccessMatch,
+    /// What they're given
+    pub level: AccessLevel
+}
+
+impl AccessEntry {
+    /// Returns an entry for users matching the hostmask glob `mask`
+    pub fn mask(mask: &[u8], level: AccessLevel) -> AccessEntry {
+        AccessEntry { matches: ByMask(mask.to_vec()), level: level }
+    }
+
+    /// Returns an entry for users logged in to `account`
+    pub fn account(account: &[u8], level: AccessLevel) -> AccessEntry {
+        AccessEntry { matches: ByAccount(account.to_vec()), level: level }
+    }
+}
+
+/// How long mode changes are collected before they're sent together, in milliseconds
+static BATCH_DELAY_MS: i64 = 1000;
+
+struct AccessLists {
+    /// The access lists, by casemapped channel name
+    lists: Vec<(Vec<u8>, Vec<AccessEntry>)>,
+    /// Mode changes waiting to be sent: channel, mode and nick
+    pending: Vec<(Vec<u8>, char, Vec<u8>)>,
+    flush_scheduled: bool
+}
+
+impl<'a> Conn<'a> {
+    /// Sets the access list of `chan`. Users who join and match an entry
+    /// are opped or voiced, if we're an operator there. Pass an empty list
+    /// to turn this off for the channel.
+    ///
+    /// Entries for accounts use the account from extended-join if it's
+    /// available, and look it up with `account_of()` otherwise. Mode changes
+    /// are collected for a second and sent in as few MODE lines as possible,
+    /// so that a netsplit rejoin doesn't flood the server.
+    pub fn set_access_list(&mut self, chan: &[u8], entries: Vec<AccessEntry>) {
+        let chan = self.isupport.casemapping().lower(chan);
+        let lists = self.access_lists();
+        lists.lists.retain(|&(ref c, _)| *c != chan);
+        if !entries.is_empty() {
+            lists.lists.push((chan, entries));
+        }
+    }
+
+    /// Returns the access list of `chan`. See `set_access_list()`.
+    pub fn access_list(&self, chan: &[u8]) -> Vec<AccessEntry> {
+        let chan = self.isupport.casemapping().lower(chan);
+        self.extensions.get::<AccessLists>().and_then(|lists| {
+            lists.lists.iter().find(|&&(ref c, _)| *c == chan).map(|&(_, ref e)| e.clone())
+        }).unwrap_or(Vec::new())
+    }
+
+    fn access_lists<'b>(&'b mut self) -> &'b mut AccessLists {
+        self.extensions.get_or_insert_with(|| {
+            AccessLists { lists: Vec::new(), pending: Vec::new(), flush_scheduled: false }
+        })
+    }
+}
+
+/// Drops the mode changes that were waiting to be sent on the last
+/// connection. The access lists are kept.
+pub fn reset(conn: &mut Conn) {
+    match conn.extensions.get_mut::<AccessLists>() {
+        None => (),
+        Some(lists) => {
+            lists.pending.clear();
+            lists.flush_scheduled = false;
+        }
+    }
+}
+
+/// Checks someone else's JOIN against the channel's access list
+pub fn JOIN(conn: &mut Conn, line: &Line) {
+    let (user, chan) = match (line.prefix.as_ref(), line.args.as_slice().head()) {
+        (Some(user), Some(chan)) => (user.clone(), chan.clone()),
+        _ => return
+    };
+    let entries = conn.access_list(chan.as_slice());
+    if entries.is_empty() || !conn.state.is_op(chan.as_slice(), conn.user.nick()) {
+        return;
+    }
+    let account = conn.state.user(user.nick()).and_then(|u| u.account()).map(|a| a.map(|a| a.to_vec()));
+    let casemapping = conn.isupport.casemapping();
+    let levels = entries.iter().filter(|e| match e.matches {
+        ByMask(ref mask) => casemapping.glob_match(mask.as_slice(), user.raw()),
+        ByAccount(ref name) => match account {
+            Some(Some(ref a)) => casemapping.eq_ignore_case(a.as_slice(), name.as_slice()),
+            _ => false
+        }
+    }).map(|e| e.level.clone()).collect::<Vec<_>>();
+
+    let has_account_entries = entries.iter().any(|e| match e.matches { ByAccount(..) => true, _ => false });
+    if levels.contains(&AutoOp) || account.is_some() || !has_account_entries {
+        grant(conn, chan.as_slice(), user.nick(), levels.as_slice());
+        return;
+    }
+    // the account might give a higher level than the mask matches
+    let nick = user.nick().to_vec();
+    conn.account_of(nick.as_slice(), proc(conn, account) {
+        let casemapping = conn.isupport.casemapping();
+        let mut levels = levels;
+        for entry in conn.access_list(chan.as_slice()).into_iter() {
+            let matched = match (&entry.matches, account.as_ref()) {
+                (&ByAccount(ref name), Some(a)) => {
+                    casemapping.eq_ignore_case(a.as_slice(), name.as_slice())
+                }
+                _ => false
+            };
+            if matched {
+                levels.push(entry.level);
+            }
+        }
+        grant(conn, chan.as_slice(), nick.as_slice(), levels.as_slice());
+    });
+}
+
+/// Queues +o or +v for `nick`, whichever is the highest of `levels`
+fn grant(conn: &mut Conn, chan: &[u8], nick: &[u8], levels: &[AccessLevel]) {
+    let mode = if levels.contains(&AutoOp) {
+        'o'
+    } else if levels.contains(&AutoVoice) {
+        'v'
+    } else {
+        return
+    };
+    let schedule = {
+        let lists = conn.access_lists();
+        lists.pending.push((chan.to_vec(), mode, nick.to_vec()));
+        !lists.flush_scheduled
+    };
+    if schedule {
+        conn.access_lists().flush_scheduled = true;
+        conn.schedule(Duration::milliseconds(BATCH_DELAY_MS), proc(conn) flush(conn));
+    }
+}
+
+/// Sends the queued mode changes, skipping users who left or already have the mode
+fn flush(conn: &mut Conn) {
+    let pending = {
+        let lists = conn.access_lists();
+        lists.flush_scheduled = false;
+        ::std::mem::replace(&mut lists.pending, Vec::new())
+    };
+    let mut chans: Vec<Vec<u8>> = Vec::new();
+    for &(ref chan, _, _) in pending.iter() {
+        let casemapping = conn.isupport.casemapping();
+        if !chans.iter().any(|c| casemapping.eq_ignore_case(c.as_slice(), chan.as_slice())) {
+            chans.push(chan.clone());
+        }
+    }
+    for chan in chans.iter() {
+        let chan = chan.as_slice();
+        if !conn.state.is_op(chan, conn.user.nick()) {
+            continue;
+        }
+        let changes = pending.iter().filter(|&&(ref c, mode, ref nick)| {
+            conn.isupport.casemapping().eq_ignore_case(c.as_slice(), chan) &&
+                conn.state.member(chan, nick.as_slice()).is_some() &&
+                !conn.state.has_rank(chan, nick.as_slice(), mode)
+        }).map(|&(_, mode, ref nick)| ModeChange::set(mode, Some(nick.as_slice()))).collect::<Vec<_>>();
+        if !changes.is_empty() {
+            conn.set_modes(chan, changes.as_slice());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue};
+    use super::{AccessEntry, AutoOp, AutoVoice};
+
+    #[test]
+    fn access_list() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":ircnick!u@h JOIN #rust",
+                   b":ChanServ!s@services. MODE #rust +o ircnick"], Duration::zero());
+        conn.conn().set_access_list(b"#rust", vec![AccessEntry::mask(b"*!*@friend.example", AutoVoice),
+                                                   AccessEntry::mask(b"*!*@op.example", AutoOp),
+                                                   AccessEntry::account(b"alice", AutoOp)]);
+        assert_eq!(conn.conn().access_list(b"#RUST").len(), 3);
+        // bob's account might give him more than voice, so it's looked up
+        assert_eq!(conn.step([b":bob!b@friend.example JOIN #rust",
+                              b":carol!c@op.example JOIN #rust",
+                              b":dave!d@h JOIN #rust alice :Dave",
+                              b":mallory!m@h JOIN #rust * :Mallory",
+                              b":erin!e@op.example JOIN #rust",
+                              b":frank!f@op.example JOIN #rust"], Duration::zero()),
+                   vec![b"WHOIS bob".to_vec()]);
+        // the changes are sent together, without the users who left or already have the mode
+        assert_eq!(conn.step([b":irc.example.net 318 ircnick bob :End of /WHOIS list.",
+                              b":ChanServ!s@services. MODE #rust +o erin",
+                              b":frank!f@op.example PART #rust"], Duration::seconds(1)),
+                   vec![b"MODE #rust +oov carol dave bob".to_vec()]);
+
+        // nothing is sent where we aren't an operator
+        conn.step([b":ChanServ!s@services. MODE #rust -o ircnick",
+                   b":grace!g@op.example JOIN #rust"], Duration::zero());
+        assert!(conn.step([], Duration::seconds(1)).is_empty());
+
+        conn.conn().set_access_list(b"#Rust", Vec::new());
+        assert!(conn.conn().access_list(b"#rust").is_empty());
+    }
+
+    #[test]
+    fn auto_op_after_reconnect() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let joined = [b":irc.example.net 001 ircnick :Welcome",
+                      b":ircnick!u@h JOIN #rust",
+                      b":ChanServ!s@services. MODE #rust +o ircnick"];
+        conn.step(joined, Duration::zero());
+        conn.conn().set_access_list(b"#rust", vec![AccessEntry::mask(b"*!*@friend.example", AutoOp)]);
+        // the connection is lost before the mode change is sent
+        assert!(conn.step([b":alice!a@friend.example JOIN #rust"], Duration::zero()).is_empty());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step(joined, Duration::zero());
+        conn.step([b":bob!b@friend.example JOIN #rust"], Duration::zero());
+        assert_eq!(conn.step([], Duration::seconds(1)), vec![b"MODE #rust +o bob".to_vec()]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Timed bans, lifted automatically when they expire
+
+use std::cmp::max;
+use std::time::Duration;
+
+use conn::{Conn, Line, IRCCmd, IRCCode, AccessMatch};
+use conn::persist;
+use modes::ModeChange;
+
+/// A ban set with `ban_for()` that hasn't been lifted yet
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct TimedBan {
+    /// The channel the ban is in
+    pub chan: Vec<u8>,
+    /// The ban mask
+    pub mask: Vec<u8>,
+    /// When the ban should be lifted, in seconds since the epoch
+    pub expires: i64
+}
+
+/// The timed bans that are still in place
+struct TimedBans(Vec<TimedBan>);
+
+impl<'a> Conn<'a> {
+    /// Bans `mask` from `chan` for `duration`, and lifts the ban with -b once
+    /// it's up.
+    ///
+    /// Pending unbans are saved to `Options.store` (if there is one), so they
+    /// survive restarts and reconnects. A ban that expires while we're not in
+    /// the channel, or not an operator there, is lifted once we are.
+    pub fn ban_for(&mut self, chan: &[u8], mask: &[u8], duration: Duration) {
+        self.set_modes(chan, [ModeChange::set('b', Some(mask))]);
+        let secs = max(duration.num_seconds(), 0);
+        self.add_timed_ban(TimedBan {
+            chan: chan.to_vec(),
+            mask: mask.to_vec(),
+            expires: self.epoch_secs() + secs
+        });
+        let chan = chan.to_vec();
+        self.schedule(Duration::seconds(secs), proc(conn) lift_expired(conn, chan.as_slice()));
+    }
+
+    /// Bans the users `entry` matches from `chan` for `duration`, like
+    /// `ban_for()` with the mask from `access_ban_mask()`. Returns `false`
+    /// without sending anything if the server can't ban an account.
+    pub fn ban_access_for(&mut self, chan: &[u8], entry: &AccessMatch, duration: Duration) -> bool {
+        match self.access_ban_mask(entry) {
+            None => false,
+            Some(mask) => {
+                self.ban_for(chan, mask.as_slice(), duration);
+                true
+            }
+        }
+    }
+
+    /// Returns the timed bans that haven't been lifted yet
+    pub fn timed_bans(&self) -> Vec<TimedBan> {
+        self.extensions.get::<TimedBans>().map_or(Vec::new(), |&TimedBans(ref bans)| bans.clone())
+    }
+
+    /// Forgets the timed ban on `mask` in `chan` without lifting it.
+    /// Returns `false` if there isn't one.
+    pub fn cancel_timed_ban(&mut self, chan: &[u8], mask: &[u8]) -> bool {
+        let casemapping = self.isupport.casemapping();
+        let found = match self.extensions.get_mut::<TimedBans>() {
+            None => false,
+            Some(&TimedBans(ref mut bans)) => {
+                let len = bans.len();
+                bans.retain(|b| !(casemapping.eq_ignore_case(b.chan.as_slice(), chan) &&
+                                  b.mask.as_slice() == mask));
+                bans.len() != len
+            }
+        };
+        if found {
+            persist::timed_bans_changed(self);
+        }
+        found
+    }
+
+    fn add_timed_ban(&mut self, ban: TimedBan) {
+        let casemapping = self.isupport.casemapping();
+        {
+            let &TimedBans(ref mut bans) = self.extensions.get_or_insert_with(|| TimedBans(Vec::new()));
+            bans.retain(|b| !(casemapping.eq_ignore_case(b.chan.as_slice(), ban.chan.as_slice()) &&
+                              b.mask == ban.mask));
+            bans.push(ban);
+        }
+        persist::timed_bans_changed(self);
+    }
+}
+
+/// Restores timed bans loaded from the store
+pub fn restore(conn: &mut Conn, bans: Vec<TimedBan>) {
+    conn.extensions.insert(TimedBans(bans));
+}
+
+/// Lifts expired bans in channels where we just became able to. Once the
+/// NAMES list of a channel arrives, the bans there that haven't expired yet
+/// are scheduled, since scheduled jobs don't survive a reconnect.
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    let chan = match line.command {
+        // :me MODE #chan +o me
+        IRCCmd(ref s) if "MODE" == s.as_slice() && !line.args.is_empty() => line.args[0].clone(),
+        // :server 366 me #chan :End of /NAMES list.
+        IRCCode(366) if line.args.len() > 1 => line.args[1].clone(),
+        _ => return
+    };
+    if conn.extensions.get::<TimedBans>().map_or(true, |&TimedBans(ref bans)| bans.is_empty()) {
+        return;
+    }
+    lift_expired(conn, chan.as_slice());
+    match line.command {
+        IRCCode(366) => {
+            let now = conn.epoch_secs();
+            for ban in conn.timed_bans().into_iter() {
+                if conn.isupport.casemapping().eq_ignore_case(ban.chan.as_slice(), chan.as_slice()) {
+                    let chan = chan.clone();
+                    conn.schedule(Duration::seconds(ban.expires - now),
+                                  proc(conn) lift_expired(conn, chan.as_slice()));
+                }
+            }
+        }
+        _ => ()
+    }
+}
+
+/// Sends -b for the expired bans in `chan`, if we're an operator there
+fn lift_expired(conn: &mut Conn, chan: &[u8]) {
+    if !conn.state.is_op(chan, conn.user.nick()) {
+        return;
+    }
+    let now = conn.epoch_secs();
+    let casemapping = conn.isupport.casemapping();
+    let expired = match conn.extensions.get_mut::<TimedBans>() {
+        None => return,
+        Some(&TimedBans(ref mut bans)) => {
+            let (expired, rest) = bans.clone().partition(|b| {
+                b.expires <= now && casemapping.eq_ignore_case(b.chan.as_slice(), chan)
+            });
+            *bans = rest;
+            expired
+        }
+    };
+    if expired.is_empty() {
+        return;
+    }
+    let changes = expired.iter().map(|b| ModeChange::unset('b', Some(b.mask.as_slice())))
+                                .collect::<Vec<_>>();
+    conn.set_modes(chan, changes.as_slice());
+    persist::timed_bans_changed(conn);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event};
+
+    #[test]
+    fn ban_for() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":ircnick!u@h JOIN #rust",
+                   b":ChanServ!s@services. MODE #rust +o ircnick"], Duration::zero());
+        conn.conn().ban_for(b"#rust", b"*!*@spam.example", Duration::minutes(1));
+        assert_eq!(conn.step([], Duration::seconds(59)), vec![b"MODE #rust +b *!*@spam.example".to_vec()]);
+        assert_eq!(conn.conn().timed_bans().len(), 1);
+        assert_eq!(conn.step([], Duration::seconds(1)), vec![b"MODE #rust -b *!*@spam.example".to_vec()]);
+        assert!(conn.conn().timed_bans().is_empty());
+
+        // a cancelled ban stays
+        conn.conn().ban_for(b"#rust", b"*!*@flood.example", Duration::minutes(1));
+        conn.step([], Duration::zero());
+        assert!(conn.conn().cancel_timed_ban(b"#Rust", b"*!*@flood.example"));
+        assert!(!conn.conn().cancel_timed_ban(b"#rust", b"*!*@flood.example"));
+        assert!(conn.step([], Duration::minutes(1)).is_empty());
+    }
+
+    #[test]
+    fn ban_for_expires_while_not_op() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":ircnick!u@h JOIN #rust",
+                   b":ChanServ!s@services. MODE #rust +o ircnick"], Duration::zero());
+        conn.conn().ban_for(b"#rust", b"*!*@spam.example", Duration::seconds(30));
+        conn.step([b":ChanServ!s@services. MODE #rust -o ircnick"], Duration::minutes(1));
+        assert_eq!(conn.conn().timed_bans().len(), 1);
+        // lifted as soon as we're an operator again
+        assert_eq!(conn.step([b":ChanServ!s@services. MODE #rust +o ircnick"], Duration::zero()),
+                   vec![b"MODE #rust -b *!*@spam.example".to_vec()]);
+        assert!(conn.conn().timed_bans().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+//! IRCv3 capability negotiation (CAP)
+
+use conn::{Conn, Line, IRCCmd, IRCCode};
+use conn::auth;
+
+/// The state of capability negotiation on a connection
+struct Caps {
+    /// The capabilities we want, from `Options.caps`
+    wanted: Vec<String>,
+    /// The capabilities the server offers, gathered from the CAP LS replies
+    offered: Vec<String>,
+    /// The capabilities the server acknowledged
+    enabled: Vec<String>,
+    /// Whether we're waiting for the server, which holds back registration
+    /// until we send CAP END
+    negotiating: bool
+}
+
+impl<'a> Conn<'a> {
+    /// Returns the capabilities that the server enabled for us, out of the
+    /// ones asked for with `Options.caps`
+    pub fn caps(&self) -> Vec<String> {
+        self.extensions.get::<Caps>().map_or(Vec::new(), |c| c.enabled.clone())
+    }
+
+    /// Returns `true` if the server enabled the capability `cap`
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.extensions.get::<Caps>().map_or(false, |c| c.enabled.iter().any(|e| e.as_slice() == cap))
+    }
+}
+
+/// Starts negotiating `wanted`, right before NICK and USER are sent
+pub fn start(conn: &mut Conn, wanted: &[&str]) {
+    conn.extensions.insert(Caps {
+        wanted: wanted.iter().map(|c| c.to_string()).collect(),
+        offered: Vec::new(),
+        enabled: Vec::new(),
+        negotiating: !wanted.is_empty()
+    });
+    if !wanted.is_empty() {
+        conn.send_command(IRCCmd("CAP".into_maybe_owned()), [b"LS", b"302"], false);
+    }
+}
+
+pub fn CAP(conn: &mut Conn, line: &Line) {
+    // :server CAP <nick> <subcommand> [*] :<caps>
+    if line.args.len() < 3 {
+        return;
+    }
+    let sub = line.args[1].as_slice();
+    let more = line.args.len() > 3 && line.args[2].as_slice() == b"*";
+    let list = line.args[line.args.len()-1].as_slice();
+    let names = list.split(|&b| b == ' ' as u8).filter(|c| !c.is_empty()).map(|c| {
+        // drop the value of `name=value` in 302 replies
+        let name = c.splitn(1, |&b| b == '=' as u8).next().unwrap();
+        String::from_utf8_lossy(name).into_string()
+    }).collect::<Vec<_>>();
+    let request: Vec<String> = match conn.extensions.get_mut::<Caps>() {
+        None => return,
+        Some(caps) => {
+            if sub == b"LS" {
+                caps.offered.extend(names.into_iter());
+                if more || !caps.negotiating {
+                    return;
+                }
+                caps.wanted.iter().filter(|w| caps.offered.contains(*w)).map(|w| w.clone()).collect()
+            } else if sub == b"ACK" {
+                for name in names.into_iter() {
+                    if name.as_slice().starts_with("-") {
+                        let name = name.as_slice().slice_from(1);
+                        caps.enabled.retain(|e| e.as_slice() != name);
+                    } else if !caps.enabled.contains(&name) {
+                        caps.enabled.push(name);
+                    }
+                }
+                Vec::new()
+            } else if sub == b"NAK" {
+                Vec::new()
+            } else if sub == b"DEL" {
+                caps.enabled.retain(|e| !names.contains(e));
+                return;
+            } else {
+                return;
+            }
+        }
+    };
+    if !request.is_empty() {
+        let request = request.connect(" ");
+        conn.send_command(IRCCmd("CAP".into_maybe_owned()), [b"REQ", request.as_bytes()], true);
+    } else if !auth::start_sasl(conn) {
+        // nothing we want is offered, or the server answered our request,
+        // and there's no SASL login to wait for
+        finish(conn);
+    }
+}
+
+/// Handles ERR_UNKNOWNCOMMAND (421) and ERR_NOTREGISTERED (451) during
+/// negotiation. Old servers that don't know CAP answer CAP LS with one of
+/// these, and register us as usual once they have NICK and USER.
+pub fn rejected(conn: &mut Conn, line: &Line) {
+    if !negotiating(conn) {
+        return;
+    }
+    // :server 421 * CAP :Unknown command, and 451 with or without the command
+    let about_cap = match line.command {
+        IRCCode(421) => line.args.len() > 1 && line.args[1].as_slice() == b"CAP",
+        IRCCode(451) => line.args.len() < 3 || line.args[1].as_slice() == b"CAP",
+        _ => false
+    };
+    if about_cap {
+        info!("Server doesn't support CAP, registering without capabilities");
+        conn.extensions.get_mut::<Caps>().map(|c| c.negotiating = false);
+    }
+}
+
+/// Returns `true` while registration is held back by capability negotiation
+pub fn negotiating(conn: &Conn) -> bool {
+    conn.extensions.get::<Caps>().map_or(false, |c| c.negotiating)
+}
+
+/// Ends negotiation, which lets the server finish registering us
+pub fn finish(conn: &mut Conn) {
+    let negotiating = match conn.extensions.get_mut::<Caps>() {
+        None => false,
+        Some(caps) => {
+            let negotiating = caps.negotiating;
+            caps.negotiating = false;
+            negotiating
+        }
+    };
+    if negotiating {
+        conn.send_command(IRCCmd("CAP".into_maybe_owned()), [b"END"], false);
+    }
+}
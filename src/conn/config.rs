@@ -0,0 +1,106 @@
+//! Changing settings of a running connection, see `Conn::reconfigure()`
+
+use std::time::Duration;
+
+use conn::{Conn, AccessMatch, CtcpLimits, InvitePolicy, ReplyPolicy, RejoinPolicy};
+use conn::ignore;
+use isupport::CaseMapping;
+
+/// The settings that can be changed without reconnecting. They mean the
+/// same as the `Options` fields of the same name.
+///
+/// Get the current ones with `Conn::config()`, change what's needed and
+/// apply them with `Conn::reconfigure()`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Config {
+    /// See `Options.ctcp_limits`
+    pub ctcp_limits: Option<CtcpLimits>,
+    /// See `Options.services_interval`
+    pub services_interval: Duration,
+    /// See `Options.auto_join_invites`
+    pub auto_join_invites: Option<InvitePolicy>,
+    /// See `Options.reply_policy`
+    pub reply_policy: ReplyPolicy,
+    /// See `Options.rejoin_on_kick`
+    pub rejoin_on_kick: Option<RejoinPolicy>,
+    /// See `Options.join_on_connect`
+    pub join_on_connect: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The ignore list, see `Conn::ignore()`
+    pub ignore: Vec<AccessMatch>
+}
+
+impl<'a> Conn<'a> {
+    /// Returns the settings that `reconfigure()` can change, as they are now
+    pub fn config(&self) -> Config {
+        Config {
+            ctcp_limits: self.ctcp_limits.clone(),
+            services_interval: self.services_interval,
+            auto_join_invites: self.invite_policy.clone(),
+            reply_policy: self.reply_policy.clone(),
+            rejoin_on_kick: self.rejoin_policy.clone(),
+            join_on_connect: self.join_on_connect.clone(),
+            ignore: self.ignore_list()
+        }
+    }
+
+    /// Applies changed settings without reconnecting.
+    ///
+    /// If the channels in `join_on_connect` have already been joined on this
+    /// connection, the ones that were added are joined and the ones that were
+    /// removed are parted. The others are left alone, even if their key changed.
+    pub fn reconfigure(&mut self, config: Config) {
+        if self.autojoined {
+            let casemapping = self.isupport.casemapping();
+            let parted = self.join_on_connect.iter().filter(|&&(ref chan, _)| {
+                !listed(casemapping, config.join_on_connect.as_slice(), chan.as_slice())
+            }).map(|&(ref chan, _)| chan.clone()).collect::<Vec<_>>();
+            let joined = config.join_on_connect.iter().filter(|&&(ref chan, _)| {
+                !listed(casemapping, self.join_on_connect.as_slice(), chan.as_slice())
+            }).map(|&(ref chan, ref key)| (chan.as_slice(), key.as_slice())).collect::<Vec<_>>();
+            for chan in parted.iter() {
+                if self.state.channel(chan.as_slice()).is_some() {
+                    self.part(chan.as_slice(), []);
+                }
+            }
+            if !joined.is_empty() {
+                self.join_many(joined.as_slice());
+            }
+        }
+        self.join_on_connect = config.join_on_connect;
+        self.ctcp_limits = config.ctcp_limits;
+        self.services_interval = config.services_interval;
+        self.invite_policy = config.auto_join_invites;
+        self.reply_policy = config.reply_policy;
+        self.rejoin_policy = config.rejoin_on_kick;
+        ignore::replace(self, config.ignore);
+    }
+}
+
+/// Returns `true` if `chan` is one of the channels in `list`
+fn listed(casemapping: CaseMapping, list: &[(Vec<u8>, Vec<u8>)], chan: &[u8]) -> bool {
+    list.iter().any(|&(ref c, _)| casemapping.eq_ignore_case(c.as_slice(), chan))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, ByMask};
+
+    #[test]
+    fn reconfigure() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.join_on_connect = vec![(b"#a".to_vec(), Vec::new()), (b"#b".to_vec(), Vec::new())];
+        let mut conn = Manual::new(opts, Vec::new());
+        let out = conn.step([b":irc.example.net 001 ircnick :Welcome",
+                             b":irc.example.net 376 ircnick :End of /MOTD command."], Duration::zero());
+        assert_eq!(out.last().unwrap().as_slice(), b"JOIN #a,#b");
+        conn.step([b":ircnick!u@h JOIN #a", b":ircnick!u@h JOIN #b"], Duration::zero());
+        let mut config = conn.conn().config();
+        config.join_on_connect = vec![(b"#B".to_vec(), Vec::new()), (b"#c".to_vec(), b"key".to_vec())];
+        config.ignore = vec![ByMask(b"*!*@spam.example".to_vec())];
+        config.ctcp_limits = None;
+        conn.conn().reconfigure(config.clone());
+        assert_eq!(conn.step([], Duration::zero()), vec![b"PART #a".to_vec(), b"JOIN #c key".to_vec()]);
+        assert_eq!(conn.conn().config(), config);
+    }
+}
@@ -0,0 +1,262 @@
+//! Built-in CTCP handling: PING, sent with `ctcp_ping()` and answered
+//! automatically, and DCC requests
+
+use std::collections::RingBuf;
+use std::time::Duration;
+
+use conn::{Conn, Line, DccRequest, CtcpDropped};
+use dcc;
+use User;
+
+/// Callback for the reply to a CTCP PING. It's given the round-trip time.
+pub type PingCallback = proc(&mut Conn, Duration);
+
+/// How long to wait for a PING reply before forgetting about it, in nanoseconds
+static PING_EXPIRY_NS: u64 = 300 * 1_000_000_000;
+
+/// CTCP PINGs that we're waiting on replies to
+struct PendingPings(Vec<Ping>);
+
+struct Ping {
+    /// The casemapped nick that was pinged
+    nick: Vec<u8>,
+    token: Vec<u8>,
+    sent: u64,
+    cb: PingCallback
+}
+
+/// How many CTCP queries are answered automatically, see `Options.ctcp_limits`.
+///
+/// Flooding a client with CTCP queries is an old way to get it disconnected
+/// for flooding the server with the replies. Queries over either budget are
+/// dropped unanswered.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct CtcpLimits {
+    /// The most queries answered per sender within `window`. Senders are
+    /// told apart by host, so clones that change nicks share a budget.
+    pub per_source: uint,
+    /// The most queries answered in total within `window`
+    pub global: uint,
+    /// The time that the budgets are counted over
+    pub window: Duration
+}
+
+impl CtcpLimits {
+    /// Returns limits that answer `per_source` queries per sender and
+    /// `global` queries in total within `window`
+    pub fn new(per_source: uint, global: uint, window: Duration) -> CtcpLimits {
+        CtcpLimits {
+            per_source: per_source,
+            global: global,
+            window: window
+        }
+    }
+}
+
+/// When the recent automatic replies were sent, oldest first
+struct ReplyLog {
+    global: RingBuf<u64>,
+    /// By host, or casemapped nick for senders without one
+    sources: Vec<(Vec<u8>, RingBuf<u64>)>
+}
+
+impl<'a> Conn<'a> {
+    /// Sends a CTCP PING to `nick`, and calls `cb` with the round-trip time
+    /// when the reply arrives.
+    ///
+    /// The reply is matched on the nick and the token sent with the PING.
+    /// If there's no reply within 5 minutes, `cb` is dropped without being called.
+    pub fn ctcp_ping(&mut self, nick: &[u8], cb: PingCallback) {
+        let now = self.now();
+        let token = now.to_string().into_bytes();
+        self.ctcp(nick, b"PING", token.as_slice());
+        let nick = self.isupport.casemapping().lower(nick);
+        let &PendingPings(ref mut pending) = self.extensions.get_or_insert_with(|| {
+            PendingPings(Vec::new())
+        });
+        pending.retain(|p| now - p.sent < PING_EXPIRY_NS);
+        pending.push(Ping { nick: nick, token: token, sent: now, cb: cb });
+    }
+}
+
+/// Returns `true` if the automatic reply to `user`'s CTCP query fits in
+/// `Options.ctcp_limits`, and counts it. Otherwise a CtcpDropped event is
+/// sent for the query.
+pub fn may_reply(conn: &mut Conn, user: &User, command: &[u8]) -> bool {
+    let limits = match conn.ctcp_limits {
+        None => return true,
+        Some(ref limits) => limits.clone()
+    };
+    let now = conn.now();
+    let window = limits.window.num_milliseconds() as u64 * 1_000_000;
+    let source = match user.host() {
+        Some(host) => host.to_vec(),
+        None => conn.isupport.casemapping().lower(user.nick())
+    };
+    let allowed = {
+        let log = conn.extensions.get_or_insert_with(|| {
+            ReplyLog { global: RingBuf::new(), sources: Vec::new() }
+        });
+        expire(&mut log.global, now, window);
+        for &(_, ref mut times) in log.sources.iter_mut() {
+            expire(times, now, window);
+        }
+        log.sources.retain(|&(_, ref times)| !times.is_empty());
+        let idx = match log.sources.iter().position(|&(ref s, _)| *s == source) {
+            Some(idx) => idx,
+            None => {
+                log.sources.push((source, RingBuf::new()));
+                log.sources.len() - 1
+            }
+        };
+        let (_, ref mut sent) = *log.sources.get_mut(idx);
+        if log.global.len() >= limits.global || sent.len() >= limits.per_source {
+            false
+        } else {
+            log.global.push_back(now);
+            sent.push_back(now);
+            true
+        }
+    };
+    if !allowed {
+        debug!("[DEBUG] Not answering CTCP {} from {}, too many queries",
+               String::from_utf8_lossy(command), String::from_utf8_lossy(user.raw()));
+        conn.emit(CtcpDropped(user.clone(), command.to_vec()));
+    }
+    allowed
+}
+
+/// Forgets the times that are at least `window` old
+fn expire(times: &mut RingBuf<u64>, now: u64, window: u64) {
+    while times.front().map_or(false, |&t| now - t >= window) {
+        times.pop_front();
+    }
+}
+
+/// Answers a CTCP PING by echoing its parameters back
+pub fn PING(conn: &mut Conn, line: &Line) {
+    let (user, ctcp) = match (line.prefix.as_ref(), line.ctcp()) {
+        (Some(user), Some(ctcp)) => (user.clone(), ctcp),
+        _ => return
+    };
+    if may_reply(conn, &user, b"PING") {
+        conn.ctcp_reply(user.nick(), b"PING", ctcp.params.as_slice());
+    }
+}
+
+/// Parses a DCC request and sends a DccRequest event for it.
+/// Requests we can't parse are ignored.
+pub fn DCC(conn: &mut Conn, line: &Line) {
+    let (user, ctcp) = match (line.prefix.as_ref(), line.ctcp()) {
+        (Some(user), Some(ctcp)) => (user.clone(), ctcp),
+        _ => return
+    };
+    match dcc::parse(ctcp.params.as_slice()) {
+        None => debug!("[DEBUG] Ignoring malformed DCC request: {}",
+                       String::from_utf8_lossy(ctcp.params.as_slice())),
+        Some(req) => conn.emit(DccRequest(user, req))
+    }
+}
+
+/// Matches a CTCP PING reply to the `ctcp_ping()` that it answers
+pub fn PING_reply(conn: &mut Conn, line: &Line) {
+    let (nick, ctcp) = match (line.prefix.as_ref(), line.ctcp()) {
+        (Some(user), Some(ctcp)) => (conn.isupport.casemapping().lower(user.nick()), ctcp),
+        _ => return
+    };
+    let ping = match conn.extensions.get_mut::<PendingPings>() {
+        None => return,
+        Some(&PendingPings(ref mut pending)) => {
+            match pending.iter().position(|p| p.nick == nick && p.token == ctcp.params) {
+                None => return,
+                Some(idx) => pending.remove(idx).unwrap()
+            }
+        }
+    };
+    let Ping { sent, cb, .. } = ping;
+    let elapsed = conn.now() - sent;
+    cb(conn, Duration::microseconds((elapsed / 1000) as i64));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use conn::{Manual, Options, Conn, Event, Control, Continue, Handled};
+    use conn::{CtcpLimits, CtcpDropped, CtcpQuery};
+
+    #[test]
+    fn ctcp_flood() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.ctcp_limits = Some(CtcpLimits::new(2, 3, Duration::seconds(10)));
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let ping = |n: &str| format!(":{}!u@{}.example.com PRIVMSG ircnick :\x01PING 1\x01", n, n).into_bytes();
+        let (a, b) = (ping("a"), ping("b"));
+        let out = conn.step([a.as_slice(), a.as_slice(), a.as_slice(), b.as_slice(), b.as_slice()],
+                            Duration::seconds(10));
+        assert_eq!(out, vec![b"NOTICE a :\x01PING 1\x01".to_vec(), b"NOTICE a :\x01PING 1\x01".to_vec(),
+                             b"NOTICE b :\x01PING 1\x01".to_vec()]);
+        let dropped = conn.events().iter().filter(|e| match **e { CtcpDropped(..) => true, _ => false }).count();
+        assert_eq!(dropped, 2);
+        // the budgets are back after the window
+        let out = conn.step([b.as_slice()], Duration::zero());
+        assert_eq!(out, vec![b"NOTICE b :\x01PING 1\x01".to_vec()]);
+    }
+
+    #[test]
+    fn ctcp_ping() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::seconds(5));
+        let rtts = Arc::new(Mutex::new(Vec::new()));
+        let r = rtts.clone();
+        conn.conn().ctcp_ping(b"Bob", proc(_, rtt) { r.lock().push(rtt) });
+        // the token is the time it was sent
+        assert_eq!(conn.step([], Duration::milliseconds(1500)),
+                   vec![b"PRIVMSG Bob :\x01PING 5000000000\x01".to_vec()]);
+        // replies from someone else or with another token don't count
+        conn.step([b":carol!c@h NOTICE ircnick :\x01PING 5000000000\x01",
+                   b":bob!b@h NOTICE ircnick :\x01PING 123\x01"], Duration::zero());
+        assert!(rtts.lock().is_empty());
+        conn.step([b":bob!b@h NOTICE ircnick :\x01PING 5000000000\x01"], Duration::zero());
+        assert_eq!(*rtts.lock(), vec![Duration::milliseconds(1500)]);
+        // and only the first reply does
+        conn.step([b":bob!b@h NOTICE ircnick :\x01PING 5000000000\x01"], Duration::zero());
+        assert_eq!(rtts.lock().len(), 1);
+
+        // unanswered pings are forgotten after 5 minutes
+        let r = rtts.clone();
+        conn.conn().ctcp_ping(b"dave", proc(_, rtt) { r.lock().push(rtt) });
+        conn.step([], Duration::minutes(5));
+        conn.conn().ctcp_ping(b"erin", proc(_, _) {});
+        conn.step([b":dave!d@h NOTICE ircnick :\x01PING 6500000000\x01"], Duration::zero());
+        assert_eq!(rtts.lock().len(), 1);
+    }
+
+    #[test]
+    fn ctcp_query_claimed() {
+        let opts: Options<Vec<Vec<u8>>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        fn claim(conn: &mut Conn, event: Event, queries: &mut Vec<Vec<u8>>) -> Control {
+            match event {
+                CtcpQuery(user, ctcp) => {
+                    queries.push(ctcp.command.clone());
+                    if user.nick() == b"alice" {
+                        conn.ctcp_reply(user.nick(), ctcp.command.as_slice(), b"pong");
+                        return Handled;
+                    }
+                }
+                _ => ()
+            }
+            Continue
+        }
+        conn.tick([b":irc.example.net 001 ircnick :Welcome"], Duration::zero(), |c, e, p| claim(c, e, p));
+        let out = conn.tick([b":alice!a@host PRIVMSG ircnick :\x01PING 1\x01",
+                             b":bob!b@host PRIVMSG ircnick :\x01PING 1\x01",
+                             b":bob!b@host PRIVMSG #rust :\x01ACTION waves\x01"],
+                            Duration::zero(), |c, e, p| claim(c, e, p));
+        assert_eq!(out, vec![b"NOTICE alice :\x01PING pong\x01".to_vec(), b"NOTICE bob :\x01PING 1\x01".to_vec()]);
+        assert_eq!(*conn.payload(), vec![b"PING".to_vec(), b"PING".to_vec()]);
+    }
+}
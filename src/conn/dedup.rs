@@ -0,0 +1,123 @@
+//! Suppressing duplicate messages, e.g. from overlapping bouncer playback
+
+use std::cmp::max;
+use std::collections::{HashSet, RingBuf};
+use std::time::Duration;
+
+use conn::{Conn, Line, IRCCmd, IRCAction, IRCCTCP, IRCCTCPReply};
+
+/// The messages seen within the window, oldest first
+struct Seen {
+    /// When each message was received, and its key
+    times: RingBuf<(u64, Vec<u8>)>,
+    keys: HashSet<Vec<u8>>
+}
+
+impl Seen {
+    fn new() -> Seen {
+        Seen { times: RingBuf::new(), keys: HashSet::new() }
+    }
+
+    /// Records `key` as seen at `now`, forgetting keys older than `window`
+    /// nanoseconds. Returns `true` if the key was already seen within the window.
+    fn check(&mut self, key: Vec<u8>, now: u64, window: u64) -> bool {
+        loop {
+            match self.times.front() {
+                Some(&(t, _)) if now - t > window => (),
+                _ => break
+            }
+            let (_, old) = self.times.pop_front().unwrap();
+            self.keys.remove(&old);
+        }
+        if self.keys.contains(&key) {
+            return true;
+        }
+        self.keys.insert(key.clone());
+        self.times.push_back((now, key));
+        false
+    }
+}
+
+/// Returns `true` if `line` is a message that was already received within
+/// `window`, going by its msgid tag, or its sender, target, text and
+/// server-time tag if it has none.
+/// Lines other than PRIVMSGs, NOTICEs, ACTIONs and CTCPs are never duplicates,
+/// and neither are messages without either tag.
+pub fn is_duplicate(conn: &mut Conn, window: Duration, line: &Line) -> bool {
+    let key = match key(line) {
+        None => return false,
+        Some(key) => key
+    };
+    let window = max(window.num_milliseconds(), 0) as u64 * 1_000_000;
+    let now = conn.now();
+    let seen = conn.extensions.get_or_insert_with(|| Seen::new());
+    seen.check(key, now, window)
+}
+
+/// Returns the key that identifies a message, or None if the line isn't one
+/// or can't be told apart from the same text sent again
+fn key(line: &Line) -> Option<Vec<u8>> {
+    match line.command {
+        IRCCmd(ref cmd) if "PRIVMSG" == cmd.as_slice() || "NOTICE" == cmd.as_slice() => (),
+        IRCAction(..) | IRCCTCP(..) | IRCCTCPReply(..) => (),
+        _ => return None
+    }
+    match line.msgid() {
+        Some(msgid) => {
+            let mut key = b"msgid=".to_vec();
+            key.push_all(msgid.as_bytes());
+            return Some(key);
+        }
+        None => ()
+    }
+    let time = match line.tag("time") {
+        None => return None,
+        Some(time) => time
+    };
+    let untagged = Line {
+        tags: Vec::new(),
+        prefix: line.prefix.clone(),
+        command: line.command.clone(),
+        args: line.args.clone()
+    };
+    let mut key = time.as_bytes().to_vec();
+    key.push(' ' as u8);
+    key.push_all(untagged.to_raw().as_slice());
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Seen, key};
+    use conn::Line;
+
+    #[test]
+    fn test_seen() {
+        let mut seen = Seen::new();
+        assert!(!seen.check(b"a".to_vec(), 0, 10));
+        assert!(!seen.check(b"b".to_vec(), 5, 10));
+        assert!(seen.check(b"a".to_vec(), 10, 10));
+        // a was first seen at 0, so it's forgotten by now
+        assert!(!seen.check(b"a".to_vec(), 11, 10));
+        assert!(seen.check(b"b".to_vec(), 11, 10));
+    }
+
+    #[test]
+    fn test_key() {
+        let k = |raw: &[u8]| key(&Line::parse(raw).unwrap());
+        let a = k(b"@time=2014-06-01T12:00:00.000Z :nick!u@h PRIVMSG #chan :hi");
+        assert!(a.is_some());
+        assert_eq!(a, k(b"@time=2014-06-01T12:00:00.000Z;account=nick :nick!u@h PRIVMSG #chan :hi"));
+        assert!(a != k(b"@time=2014-06-01T12:00:01.000Z :nick!u@h PRIVMSG #chan :hi"));
+        assert!(a != k(b"@time=2014-06-01T12:00:00.000Z :nick!u@h PRIVMSG #other :hi"));
+        assert_eq!(k(b":nick!u@h JOIN #chan"), None);
+        // saying the same thing twice isn't a duplicate without a tag to tell
+        assert_eq!(k(b":nick!u@h PRIVMSG #chan :hi"), None);
+        assert!(k(b"@msgid=x :nick!u@h PRIVMSG #chan :hi").is_some());
+        // the msgid is all that counts when there is one
+        let b = k(b"@msgid=x;time=2014-06-01T12:00:00.000Z :nick!u@h PRIVMSG #chan :hi");
+        assert!(a != b);
+        assert_eq!(b, k(b"@time=2014-06-01T12:00:00.100Z;msgid=x :nick!u@h PRIVMSG #chan :hi"));
+        assert!(b != k(b"@msgid=y;time=2014-06-01T12:00:00.000Z :nick!u@h PRIVMSG #chan :hi"));
+    }
+}
@@ -0,0 +1,89 @@
+//! Typed per-connection storage
+
+use std::any::{Any, AnyRefExt, AnyMutExt};
+use std::boxed::BoxAny;
+use std::collections::HashMap;
+use std::intrinsics::TypeId;
+
+/// A map that holds at most one value of any given type.
+///
+/// Every Conn carries one of these. It lets handlers, commands and add-ons
+/// keep their own state on the connection without Conn needing a field for
+/// each of them. Use a private newtype as the key if you need to store a
+/// common type like `Vec<u8>` without colliding with someone else.
+pub struct Extensions {
+    map: HashMap<TypeId, Box<Any>>
+}
+
+impl Extensions {
+    /// Returns a new, empty Extensions map
+    pub fn new() -> Extensions {
+        Extensions { map: HashMap::new() }
+    }
+
+    /// Stores a value, returning the previous value of the same type, if any.
+    pub fn insert<T: 'static>(&mut self, val: T) -> Option<T> {
+        self.map.insert(TypeId::of::<T>(), box val as Box<Any>).map(|old| {
+            *old.downcast::<T>().ok().unwrap()
+        })
+    }
+
+    /// Returns a reference to the stored value of type T, if any.
+    pub fn get<'a, T: 'static>(&'a self) -> Option<&'a T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|v| v.downcast_ref::<T>())
+    }
+
+    /// Returns a mutable reference to the stored value of type T, if any.
+    pub fn get_mut<'a, T: 'static>(&'a mut self) -> Option<&'a mut T> {
+        self.map.get_mut(&TypeId::of::<T>()).and_then(|v| v.downcast_mut::<T>())
+    }
+
+    /// Returns a mutable reference to the stored value of type T,
+    /// inserting the result of `f` first if there is none.
+    pub fn get_or_insert_with<'a, T: 'static>(&'a mut self, f: || -> T) -> &'a mut T {
+        if !self.contains::<T>() {
+            self.insert(f());
+        }
+        self.get_mut::<T>().unwrap()
+    }
+
+    /// Removes and returns the stored value of type T, if any.
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map.remove(&TypeId::of::<T>()).map(|v| *v.downcast::<T>().ok().unwrap())
+    }
+
+    /// Returns `true` if a value of type T is stored.
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Extensions;
+
+    struct Counter(uint);
+
+    #[test]
+    fn test_extensions() {
+        let mut ext = Extensions::new();
+        assert!(ext.get::<Counter>().is_none());
+        assert!(ext.insert(Counter(1)).is_none());
+        ext.insert(vec![1u8, 2, 3]);
+
+        match ext.get_mut::<Counter>() {
+            Some(&Counter(ref mut n)) => *n += 1,
+            None => panic!("missing Counter")
+        }
+        assert_eq!(ext.get::<Counter>().map(|&Counter(n)| n), Some(2));
+        assert_eq!(ext.get::<Vec<u8>>(), Some(&vec![1u8, 2, 3]));
+
+        {
+            let &Counter(ref mut n) = ext.get_or_insert_with(|| Counter(10));
+            *n += 1;
+        }
+        assert_eq!(ext.remove::<Counter>().map(|Counter(n)| n), Some(3));
+        assert!(!ext.contains::<Counter>());
+        assert!(ext.contains::<Vec<u8>>());
+    }
+}
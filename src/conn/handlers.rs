@@ -1,36 +1,242 @@
 //! Built-in IRC message handlers
 
-use conn::{IRCCode, IRCCmd, Conn, Line};
+use std::collections::HashSet;
+
+use conn::{IRCCode, IRCCmd, IRCCTCP, IRCCTCPReply, Conn, Line, JoinFailed, WhoReplies};
+use conn::NickTruncated;
+use conn::{accounts, autoop, bans, caps, ctcp, list, memo, notify, oper, persist, rejoin, resync, seen};
+use conn::{auth, reactions, readmarker, serverinfo, services, topicguard, typing};
+use errors::IrcError;
+use who::WhoReply;
+use User;
+
+/// Handles a line received from the server. `ctcp_claimed` is set if the
+/// line is a CTCP query that the callback answered itself, which skips the
+/// built-in CTCP handling.
+pub fn handle_line(conn: &mut Conn, line: &Line, ctcp_claimed: bool) {
+    match line.command {
+        IRCCode(004) => normal::RPL_MYINFO(conn, line),
+        IRCCode(005) => normal::RPL_ISUPPORT(conn, line),
+        IRCCmd(ref s) if "ERROR" == s.as_slice() => normal::ERROR(conn, line),
+        IRCCmd(ref s) if "KILL" == s.as_slice() => normal::KILL(conn, line),
+        IRCCode(400...599) => normal::error_reply(conn, line),
+        IRCCmd(ref s) if "FAIL" == s.as_slice() || "WARN" == s.as_slice() ||
+                         "NOTE" == s.as_slice() => normal::standard_reply(conn, line),
+        _ => ()
+    }
+    // the state needs to see NICK changes before we update our own nick
+    conn.state.handle_line(conn.user.nick(), line);
 
-pub fn handle_line(conn: &mut Conn, line: &Line) {
     if !conn.logged_in {
+        auth::saw_line(conn, line);
         match line.command {
             IRCCode(001) => handshake::RPL_WELCOME(conn, line),
             IRCCode(433) => handshake::ERR_NICKNAMEINUSE(conn, line),
             IRCCode(432) => handshake::ERR_ERRONEUSNICKNAME(conn, line),
             IRCCode(436) => handshake::ERR_NICKCOLLISION(conn, line),
             IRCCode(437) => handshake::ERR_UNAVAILRESOURCE(conn, line),
+            IRCCode(010) => normal::RPL_BOUNCE(conn, line),
+            IRCCode(396) => normal::RPL_HOSTHIDDEN(conn, line),
+            IRCCode(421) | IRCCode(451) => caps::rejected(conn, line),
             IRCCmd(ref s) if "PING" == s.as_slice() => normal::PING(conn, line),
+            IRCCode(902) | IRCCode(904...907) => auth::sasl_failed(conn, line),
+            IRCCode(903) => auth::RPL_SASLSUCCESS(conn, line),
+            IRCCmd(ref s) if "CAP" == s.as_slice() => caps::CAP(conn, line),
+            IRCCmd(ref s) if "AUTHENTICATE" == s.as_slice() => auth::AUTHENTICATE(conn, line),
             _ => ()
         }
     } else {
+        memo::saw_line(conn, line);
+        accounts::saw_line(conn, line);
+        services::saw_line(conn, line);
+        bans::saw_line(conn, line);
+        seen::saw_line(conn, line);
+        resync::saw_line(conn, line);
+        topicguard::saw_line(conn, line);
         match line.command {
+            IRCCode(010) => normal::RPL_BOUNCE(conn, line),
+            IRCCode(216) => oper::RPL_STATSKLINE(conn, line),
+            IRCCode(219) => oper::RPL_ENDOFSTATS(conn, line),
+            IRCCode(221) => normal::RPL_UMODEIS(conn, line),
+            IRCCode(251...255) => serverinfo::lusers_reply(conn, line),
+            IRCCode(256...259) => serverinfo::admin_reply(conn, line),
+            IRCCode(263) => list::RPL_TRYAGAIN(conn, line),
+            IRCCode(265) | IRCCode(266) => serverinfo::lusers_reply(conn, line),
+            IRCCode(301) => memo::RPL_AWAY(conn, line),
+            IRCCode(302) => normal::RPL_USERHOST(conn, line),
+            IRCCode(303) => notify::RPL_ISON(conn, line),
+            IRCCode(311) => normal::RPL_WHOISUSER(conn, line),
+            IRCCode(315) => {
+                if !accounts::RPL_ENDOFWHO(conn, line) {
+                    who_end(conn, line);
+                }
+            }
+            IRCCode(318) => accounts::RPL_ENDOFWHOIS(conn, line),
+            IRCCode(322) => list::RPL_LIST(conn, line),
+            IRCCode(323) => list::RPL_LISTEND(conn, line),
+            IRCCode(330) => accounts::RPL_WHOISACCOUNT(conn, line),
+            IRCCode(351) => serverinfo::RPL_VERSION(conn, line),
+            IRCCode(352) => who_reply(conn, line),
+            IRCCode(354) => accounts::RPL_WHOSPCRPL(conn, line),
+            IRCCode(371) => serverinfo::RPL_INFO(conn, line),
+            IRCCode(374) => serverinfo::RPL_ENDOFINFO(conn, line),
+            IRCCode(376) | IRCCode(422) => normal::end_of_motd(conn),
+            IRCCode(391) => serverinfo::RPL_TIME(conn, line),
+            IRCCode(396) => normal::RPL_HOSTHIDDEN(conn, line),
+            IRCCode(402) | IRCCode(423) => serverinfo::query_failed(conn, line),
+            IRCCode(470) => normal::ERR_LINKCHANNEL(conn, line),
+            IRCCode(491) => oper::ERR_NOOPERHOST(conn, line),
+            IRCCode(740) => oper::RPL_RSACHALLENGE2(conn, line),
+            IRCCode(741) => oper::RPL_ENDOFRSACHALLENGE2(conn, line),
             IRCCmd(ref s) if "PING" == s.as_slice() => normal::PING(conn, line),
             IRCCmd(ref s) if "NICK" == s.as_slice() => normal::NICK(conn, line),
+            IRCCmd(ref s) if "CAP" == s.as_slice() => caps::CAP(conn, line),
+            IRCCmd(ref s) if "JOIN" == s.as_slice() => normal::JOIN(conn, line),
+            IRCCmd(ref s) if "KICK" == s.as_slice() => rejoin::KICK(conn, line),
+            IRCCmd(ref s) if "INVITE" == s.as_slice() => normal::INVITE(conn, line),
+            IRCCmd(ref s) if "MODE" == s.as_slice() => normal::MODE(conn, line),
+            IRCCmd(ref s) if "NOTICE" == s.as_slice() => normal::NOTICE(conn, line),
+            IRCCmd(ref s) if "WALLOPS" == s.as_slice() => oper::WALLOPS(conn, line),
+            IRCCmd(ref s) if "GLOBOPS" == s.as_slice() => oper::GLOBOPS(conn, line),
+            IRCCmd(ref s) if "TAGMSG" == s.as_slice() => {
+                typing::TAGMSG(conn, line);
+                reactions::TAGMSG(conn, line);
+            }
+            IRCCmd(ref s) if "MARKREAD" == s.as_slice() => readmarker::MARKREAD(conn, line),
+            IRCCTCP(..) if ctcp_claimed => (),
+            IRCCTCP(ref cmd, _) if b"PING" == cmd.as_slice() => ctcp::PING(conn, line),
+            IRCCTCP(ref cmd, _) if b"DCC" == cmd.as_slice() => ctcp::DCC(conn, line),
+            IRCCTCPReply(ref cmd, _) if b"PING" == cmd.as_slice() => ctcp::PING_reply(conn, line),
             _ => ()
         }
     }
 }
 
+/// WHO replies received so far for the query in progress
+struct PendingWho(Vec<WhoReply>);
+
+// 352
+fn who_reply(conn: &mut Conn, line: &Line) {
+    let prefix = conn.isupport.prefix().iter().map(|&(_, s)| s).collect::<Vec<_>>();
+    match WhoReply::parse(line, prefix.as_slice()) {
+        None => (),
+        Some(reply) => {
+            let &PendingWho(ref mut replies) = conn.extensions.get_or_insert_with(|| {
+                PendingWho(Vec::new())
+            });
+            replies.push(reply);
+        }
+    }
+}
+
+// 315
+fn who_end(conn: &mut Conn, line: &Line) {
+    // :server 315 me mask :End of /WHO list.
+    if line.args.len() < 2 {
+        return;
+    }
+    let replies = match conn.extensions.remove::<PendingWho>() {
+        None => Vec::new(),
+        Some(PendingWho(replies)) => replies
+    };
+    conn.emit(WhoReplies(line.args[1].clone(), replies));
+}
+
+/// Channels we've sent a JOIN for and haven't seen the result of yet,
+/// by casemapped name.
+struct PendingJoins(HashSet<Vec<u8>>);
+
+/// Records a JOIN we sent, so a failure to join can be reported with a
+/// JoinFailed event. `chans` may be a comma-separated list.
+pub fn join_sent(conn: &mut Conn, chans: &[u8]) {
+    let casemapping = conn.isupport.casemapping();
+    let &PendingJoins(ref mut pending) = conn.extensions.get_or_insert_with(|| {
+        PendingJoins(HashSet::new())
+    });
+    for chan in chans.split(|&b| b == ',' as u8).filter(|c| !c.is_empty()) {
+        pending.insert(casemapping.lower(chan));
+    }
+}
+
+/// Forgets the JOINs sent on the last connection, whose results won't come
+pub fn reset(conn: &mut Conn) {
+    conn.extensions.remove::<PendingJoins>();
+}
+
+/// Removes a channel from the pending JOINs, returning `true` if it was there.
+fn join_done(conn: &mut Conn, chan: &[u8]) -> bool {
+    let key = conn.isupport.casemapping().lower(chan);
+    match conn.extensions.get_mut::<PendingJoins>() {
+        None => false,
+        Some(&PendingJoins(ref mut pending)) => pending.remove(&key)
+    }
+}
+
+/// Replaces the username and hostname of our own User, keeping the nick.
+fn set_userhost(conn: &mut Conn, user: Option<&[u8]>, host: Option<&[u8]>) {
+    let user = user.or(conn.user.user()).map(|v| v.to_vec());
+    let host = host.or(conn.user.host()).map(|v| v.to_vec());
+    conn.user = User::new(conn.user.nick(), user.as_ref().map(|v| v.as_slice()),
+                          host.as_ref().map(|v| v.as_slice()));
+}
+
+/// Sends JoinFailed if `err` is about a channel we tried to join
+fn join_error(conn: &mut Conn, err: &IrcError) {
+    match err.code {
+        // ERR_NOSUCHCHANNEL, ERR_TOOMANYCHANNELS, ERR_UNAVAILRESOURCE,
+        // ERR_CHANNELISFULL, ERR_INVITEONLYCHAN, ERR_BANNEDFROMCHAN,
+        // ERR_BADCHANNELKEY, ERR_NEEDREGGEDNICK
+        403 | 405 | 437 | 471 | 473 | 474 | 475 | 477 => (),
+        _ => return
+    }
+    match err.target {
+        Some(ref chan) if join_done(conn, chan.as_slice()) => {
+            if err.code == 475 {
+                persist::forget_key(conn, chan.as_slice());
+            }
+            conn.emit(JoinFailed(chan.clone(), err.clone()));
+            rejoin::join_failed(conn, chan.as_slice(), err);
+        }
+        _ => ()
+    }
+}
+
 mod handshake {
     use conn::{Conn, Line};
+    use User;
 
     // 001
     pub fn RPL_WELCOME(conn: &mut Conn, line: &Line) {
         conn.logged_in = true;
+        // :irc.example.net 001 me :Welcome
+        match line.prefix {
+            Some(ref server) if server.user().is_none() && server.host().is_none() => {
+                conn.server_name = Some(server.raw().to_vec());
+            }
+            _ => ()
+        }
         if !line.args.is_empty() {
-            conn.user = conn.user.with_nick(line.args[0].as_slice());
+            let nick = line.args[0].as_slice();
+            // some servers cut nicks that are too long down to NICKLEN
+            let requested = conn.user.nick().to_vec();
+            let casemapping = conn.isupport.casemapping();
+            if nick.len() < requested.len() &&
+               casemapping.eq_ignore_case(nick, requested.slice_to(nick.len())) {
+                conn.emit(NickTruncated(requested.clone(), nick.to_vec()));
+            }
+            conn.user = conn.user.with_nick(nick);
         }
+        // Many servers end the welcome text with our full nick!user@host
+        if line.args.len() > 1 {
+            let text = line.args[line.args.len()-1].as_slice();
+            let mask = text.split(|&b| b == ' ' as u8).last().unwrap_or(text);
+            let mask = User::parse(mask);
+            if mask.nick() == conn.user.nick() && mask.host().is_some() {
+                super::set_userhost(conn, mask.user(), mask.host());
+                return;
+            }
+        }
+        conn.refresh_userhost();
     }
 
     // 433
@@ -40,8 +246,11 @@ mod handshake {
             if nick == conn.user.nick() {
               let mut new_nick = nick.to_vec();
               new_nick.push_all(['_' as u8]);
-                conn.set_nick(new_nick.as_slice());
-                return;
+                // past NICKLEN, replace the last character instead
+                let fits = conn.isupport.get_uint("NICKLEN").map_or(true, |max| new_nick.len() <= max);
+                if fits && conn.set_nick(new_nick.as_slice()).is_ok() {
+                    return;
+                }
             }
         }
         // nick was truncated? Fall back to generic _-replacement behavior
@@ -79,20 +288,38 @@ mod handshake {
                 break;
             }
         }
-        if modified {
-            conn.set_nick(nick.as_slice());
-        } else {
+        if !modified || conn.set_nick(nick.as_slice()).is_err() {
             conn.quit([]);
         }
     }
 }
 
 mod normal {
-    use conn::{IRCCmd, Conn, Line};
+    use conn::{IRCCmd, Conn, Line, ManualPong, PingReceived, Redirected, Invited};
+    use conn::{DisconnectError, DisconnectKilled, ErrorReply, ServerNotice, HostChanged};
+    use conn::{JoinForwarded, StandardReplyReceived, ISupportChanged};
+    use conn::{autoop, joinflood, rejoin};
+    use errors::{IrcError, StandardReply};
+    use modes;
+    use quirks::Quirks;
+    use snotice::Snotice;
+    use std::str;
+    use super::set_userhost;
 
     pub fn PING(conn: &mut Conn, line: &Line) {
-      let hack = line.args.iter().map(|v| v.as_slice()).collect::<Vec<_>>();
-        conn.send_command(IRCCmd("PONG".into_maybe_owned()), hack.as_slice(), false);
+        if conn.ping_mode == ManualPong {
+            let token = line.args.last().map_or(Vec::new(), |v| v.clone());
+            conn.emit(PingReceived(token));
+            return;
+        }
+        // this is also how the cookie PINGs that some servers send before 001
+        // are answered. Those cookies can be anything, so a token that isn't
+        // a single word goes in a trailing parameter.
+        let hack = line.args.iter().map(|v| v.as_slice()).collect::<Vec<_>>();
+        let colon = hack.last().map_or(false, |t| {
+            t.is_empty() || t.starts_with(b":") || t.contains(&(' ' as u8))
+        });
+        conn.send_command(IRCCmd("PONG".into_maybe_owned()), hack.as_slice(), colon);
     }
 
     pub fn NICK(conn: &mut Conn, line: &Line) {
@@ -109,4 +336,260 @@ mod normal {
             None => ()
         }
     }
+
+    pub fn ERROR(conn: &mut Conn, line: &Line) {
+        // the server is about to close the connection. If we were killed or we quit,
+        // that's the more useful explanation.
+        let msg = line.args.last().map_or(Vec::new(), |v| v.clone());
+        conn.server_error = Some(msg.clone());
+        if conn.disconnect.is_none() && !conn.quitting {
+            conn.disconnect = Some(DisconnectError(msg));
+        }
+    }
+
+    pub fn KILL(conn: &mut Conn, line: &Line) {
+        // :killer KILL me :reason
+        if line.args.is_empty() ||
+           !conn.isupport.casemapping().eq_ignore_case(line.args[0].as_slice(), conn.user.nick()) {
+            return;
+        }
+        let reason = if line.args.len() > 1 { line.args[1].clone() } else { Vec::new() };
+        conn.disconnect = Some(DisconnectKilled(line.prefix.clone(), reason));
+    }
+
+    // 4xx, 5xx
+    pub fn error_reply(conn: &mut Conn, line: &Line) {
+        match IrcError::from_line(line) {
+            None => (),
+            Some(err) => {
+                super::join_error(conn, &err);
+                conn.last_error = Some(err.clone());
+                conn.emit(ErrorReply(err))
+            }
+        }
+    }
+
+    // FAIL, WARN, NOTE
+    pub fn standard_reply(conn: &mut Conn, line: &Line) {
+        match StandardReply::from_line(line) {
+            None => (),
+            Some(reply) => conn.emit(StandardReplyReceived(reply))
+        }
+    }
+
+    // 005
+    pub fn RPL_ISUPPORT(conn: &mut Conn, line: &Line) {
+        let casemapping = conn.isupport.casemapping();
+        let changed = conn.isupport.add_tokens(line.args.as_slice());
+        conn.state.set_isupport(&conn.isupport);
+        if conn.isupport.casemapping() != casemapping {
+            conn.rekey();
+        }
+        conn.quirks = Quirks::detect(conn.quirks.version.as_ref().map(|v| v.as_slice()), &conn.isupport);
+        // the first 005s come before the end of the MOTD
+        if conn.autojoined && !changed.is_empty() {
+            conn.emit(ISupportChanged(changed));
+        }
+    }
+
+    pub fn RPL_MYINFO(conn: &mut Conn, line: &Line) {
+        // :server 004 me servername version usermodes chanmodes
+        if line.args.len() > 1 {
+            conn.server_name = Some(line.args[1].clone());
+        }
+        if line.args.len() > 2 {
+            conn.quirks = Quirks::detect(Some(line.args[2].as_slice()), &conn.isupport);
+        }
+    }
+
+    pub fn JOIN(conn: &mut Conn, line: &Line) {
+        match line.prefix {
+            Some(ref user) if conn.isupport.casemapping().eq_ignore_case(user.nick(), conn.user.nick()) => {
+                if !line.args.is_empty() {
+                    super::join_done(conn, line.args[0].as_slice());
+                    rejoin::joined(conn, line.args[0].as_slice());
+                }
+                // our own JOIN echo carries the hostmask the server sees for us
+                if user.host().is_some() {
+                    set_userhost(conn, user.user(), user.host());
+                }
+            }
+            Some(_) => {
+                joinflood::JOIN(conn, line);
+                autoop::JOIN(conn, line);
+            }
+            None => ()
+        }
+    }
+
+    pub fn INVITE(conn: &mut Conn, line: &Line) {
+        // :inviter INVITE me #chan
+        match line.args.as_slice() {
+            [ref nick, ref chan] if conn.isupport.casemapping().eq_ignore_case(nick.as_slice(), conn.user.nick()) => {
+                let accept = match conn.invite_policy {
+                    None => false,
+                    Some(ref policy) => {
+                        let casemapping = conn.isupport.casemapping();
+                        let inviter = line.prefix.as_ref().map_or([].as_slice(), |u| u.nick());
+                        (policy.inviters.is_empty() || policy.inviters.iter().any(|n| {
+                            casemapping.eq_ignore_case(n.as_slice(), inviter)
+                        })) && (policy.channels.is_empty() || policy.channels.iter().any(|c| {
+                            casemapping.eq_ignore_case(c.as_slice(), chan.as_slice())
+                        }))
+                    }
+                };
+                conn.emit(Invited(line.prefix.clone(), chan.clone()));
+                if accept {
+                    match conn.join(chan.as_slice(), []) {
+                        Ok(()) => (),
+                        Err(e) => {
+                            info!("Not accepting invite to {}: {}", String::from_utf8_lossy(chan.as_slice()), e)
+                        }
+                    }
+                }
+            }
+            _ => ()
+        }
+    }
+
+    pub fn MODE(conn: &mut Conn, line: &Line) {
+        // :me MODE me :+ix
+        if line.args.len() < 2 ||
+           !conn.isupport.casemapping().eq_ignore_case(line.args[0].as_slice(), conn.user.nick()) {
+            return;
+        }
+        modes::apply_user_modes(&mut conn.user_modes, line.args[1].as_slice());
+    }
+
+    // 221
+    pub fn RPL_UMODEIS(conn: &mut Conn, line: &Line) {
+        // :server 221 me +ix
+        if line.args.len() < 2 {
+            return;
+        }
+        conn.user_modes.clear();
+        modes::apply_user_modes(&mut conn.user_modes, line.args[1].as_slice());
+    }
+
+    // server notices
+    pub fn NOTICE(conn: &mut Conn, line: &Line) {
+        // :irc.server.net NOTICE me :*** Notice -- Client connecting: ...
+        let from_server = line.source(&conn.state).map_or(true, |p| p.user().is_none());
+        match line.args.as_slice() {
+            [_, ref text] if from_server && text.as_slice().starts_with(b"***") => {
+                let notice = Snotice::parse(text.as_slice(), conn.quirks.snotice_patterns);
+                conn.emit(ServerNotice(notice));
+            }
+            _ => ()
+        }
+    }
+
+    // 376, 422
+    pub fn end_of_motd(conn: &mut Conn) {
+        if conn.autojoined {
+            return;
+        }
+        conn.autojoined = true;
+        let chans = conn.join_on_connect.clone();
+        let chans = chans.iter().map(|&(ref chan, ref key)| (chan.as_slice(), key.as_slice()))
+                         .collect::<Vec<_>>();
+        if !chans.is_empty() {
+            conn.join_many(chans.as_slice());
+        }
+    }
+
+    // 470
+    pub fn ERR_LINKCHANNEL(conn: &mut Conn, line: &Line) {
+        // :server 470 me #old #new :Forwarding to another channel
+        if line.args.len() < 3 || !super::join_done(conn, line.args[1].as_slice()) {
+            return;
+        }
+        let (from, to) = (line.args[1].clone(), line.args[2].clone());
+        // the key was for the old channel
+        let casemapping = conn.isupport.casemapping();
+        for &(ref mut chan, ref mut key) in conn.join_on_connect.iter_mut() {
+            if casemapping.eq_ignore_case(chan.as_slice(), from.as_slice()) {
+                *chan = to.clone();
+                key.clear();
+            }
+        }
+        conn.emit(JoinForwarded(from, to));
+    }
+
+    // 010
+    pub fn RPL_BOUNCE(conn: &mut Conn, line: &Line) {
+        // :server 010 me host port :info
+        if line.args.len() < 3 {
+            return;
+        }
+        let host = match str::from_utf8(line.args[1].as_slice()) {
+            Some(host) if !host.is_empty() => host.to_string(),
+            _ => return
+        };
+        let port = match str::from_utf8(line.args[2].as_slice()).and_then(|p| from_str(p)) {
+            Some(port) => port,
+            None => return
+        };
+        if conn.follow_redirects {
+            conn.redirect = Some((host.clone(), port));
+            conn.quit([]);
+        }
+        conn.emit(Redirected(host, port));
+    }
+
+    // 302
+    pub fn RPL_USERHOST(conn: &mut Conn, line: &Line) {
+        // :server 302 me :nick1*=+user@host nick2=-user@host
+        if line.args.len() < 2 {
+            return;
+        }
+        for reply in line.args[1].as_slice().split(|&b| b == ' ' as u8) {
+            let eq = match reply.position_elem(&('=' as u8)) {
+                None => continue,
+                Some(idx) => idx
+            };
+            let mut nick = reply.slice_to(eq);
+            if nick.ends_with(b"*") {
+                // the nick is an IRC operator
+                nick = nick.slice_to(nick.len()-1);
+            }
+            if !conn.isupport.casemapping().eq_ignore_case(nick, conn.user.nick()) {
+                continue;
+            }
+            let mut mask = reply.slice_from(eq+1);
+            if mask.starts_with(b"+") || mask.starts_with(b"-") {
+                // away status
+                mask = mask.slice_from(1);
+            }
+            match mask.position_elem(&('@' as u8)) {
+                None => (),
+                Some(at) => set_userhost(conn, Some(mask.slice_to(at)), Some(mask.slice_from(at+1)))
+            }
+        }
+    }
+
+    // 311
+    pub fn RPL_WHOISUSER(conn: &mut Conn, line: &Line) {
+        // :server 311 me nick user host * :realname
+        if line.args.len() < 4 ||
+           !conn.isupport.casemapping().eq_ignore_case(line.args[1].as_slice(), conn.user.nick()) {
+            return;
+        }
+        set_userhost(conn, Some(line.args[2].as_slice()), Some(line.args[3].as_slice()));
+    }
+
+    // 396
+    pub fn RPL_HOSTHIDDEN(conn: &mut Conn, line: &Line) {
+        // :server 396 me [user@]host :is now your displayed host
+        if line.args.len() < 2 {
+            return;
+        }
+        let mask = line.args[1].as_slice();
+        match mask.position_elem(&('@' as u8)) {
+            None => set_userhost(conn, None, Some(mask)),
+            Some(at) => set_userhost(conn, Some(mask.slice_to(at)), Some(mask.slice_from(at+1)))
+        }
+        let host = conn.user.host().unwrap_or(mask).to_vec();
+        conn.emit(HostChanged(host));
+    }
 }
@@ -0,0 +1,98 @@
+//! A minimal ident (RFC 1413) responder, see `Options.identd`
+
+use std::from_str::from_str;
+use std::io::{Acceptor, BufferedReader, IoResult, Listener};
+use std::io::net::tcp::{TcpAcceptor, TcpListener};
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use conn::Spawner;
+
+/// How long a client has to send its query, in milliseconds
+static QUERY_TIMEOUT_MS: u64 = 10_000;
+
+/// A running responder. It stops when this is dropped.
+pub struct Identd {
+    acceptor: TcpAcceptor,
+    /// The local and remote port of the connection we answer for
+    ports: Arc<Mutex<Option<(u16, u16)>>>
+}
+
+impl Identd {
+    /// Starts answering ident queries on `port` with `user`, on a task
+    /// started by `spawner`
+    pub fn start(port: u16, user: &str, spawner: &mut Spawner) -> IoResult<Identd> {
+        let listener = try!(TcpListener::bind("0.0.0.0", port));
+        let mut acceptor = try!(listener.listen());
+        let ports = Arc::new(Mutex::new(None));
+        let identd = Identd { acceptor: acceptor.clone(), ports: ports.clone() };
+        let user = user.to_string();
+        spawner.spawn("libirc identd", proc() {
+            // this fails once close_accept() has been called
+            for stream in acceptor.incoming() {
+                let mut stream = match stream {
+                    Err(_) => break,
+                    Ok(stream) => stream
+                };
+                stream.set_timeout(Some(QUERY_TIMEOUT_MS));
+                let query = match BufferedReader::new(stream.clone()).read_until('\n' as u8) {
+                    Err(_) => continue,
+                    Ok(query) => query
+                };
+                let ports = *ports.lock();
+                let _ = stream.write(reply(query.as_slice(), ports, user.as_slice()).as_slice());
+            }
+        });
+        Ok(identd)
+    }
+
+    /// Sets the ports of the connection to the IRC server, once it's open.
+    /// Queries about other connections are answered with NO-USER.
+    pub fn set_ports(&self, local: u16, remote: u16) {
+        *self.ports.lock() = Some((local, remote));
+    }
+}
+
+impl Drop for Identd {
+    fn drop(&mut self) {
+        let _ = self.acceptor.close_accept();
+    }
+}
+
+/// Returns the reply to an ident query, e.g. `6191, 23` asks who owns the
+/// connection from our port 6191 to the querying host's port 23. `ports` is
+/// our local port and the server's port.
+fn reply(query: &[u8], ports: Option<(u16, u16)>, user: &str) -> Vec<u8> {
+    let query = str::from_utf8(query).unwrap_or("");
+    let asked = {
+        let mut parts = query.split(',').map(|p| from_str::<u16>(p.trim()));
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Some(local)), Some(Some(remote)), None) => Some((local, remote)),
+            _ => None
+        }
+    };
+    match asked {
+        None => b"0, 0 : ERROR : INVALID-PORT\r\n".to_vec(),
+        Some((local, remote)) if ports == Some((local, remote)) => {
+            format!("{}, {} : USERID : UNIX : {}\r\n", local, remote, user).into_bytes()
+        }
+        Some((local, remote)) => format!("{}, {} : ERROR : NO-USER\r\n", local, remote).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reply;
+
+    #[test]
+    fn test_reply() {
+        let ports = Some((6191, 6667));
+        assert_eq!(reply(b"6191 , 6667\r\n", ports, "ircuser"),
+                   b"6191, 6667 : USERID : UNIX : ircuser\r\n".to_vec());
+        assert_eq!(reply(b"6191,6667\n", ports, "ircuser"),
+                   b"6191, 6667 : USERID : UNIX : ircuser\r\n".to_vec());
+        assert_eq!(reply(b"6192, 6667\r\n", ports, "ircuser"), b"6192, 6667 : ERROR : NO-USER\r\n".to_vec());
+        assert_eq!(reply(b"6191, 6667\r\n", None, "ircuser"), b"6191, 6667 : ERROR : NO-USER\r\n".to_vec());
+        assert_eq!(reply(b"hello\r\n", ports, "ircuser"), b"0, 0 : ERROR : INVALID-PORT\r\n".to_vec());
+    }
+}
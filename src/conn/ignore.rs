@@ -0,0 +1,121 @@
+//! Ignoring users by hostmask or services account
+
+use conn::{Conn, Line, IRCCmd, IRCCode, AccessMatch, ByMask, ByAccount};
+use conn::accounts;
+
+/// The ignore list, see `Conn::ignore()`
+struct IgnoreList(Vec<AccessMatch>);
+
+impl<'a> Conn<'a> {
+    /// Ignores the users that `entry` matches: their messages, notices,
+    /// CTCPs and INVITEs aren't handled, answered or sent to the callback.
+    ///
+    /// Entries for accounts keep matching when the user changes host or
+    /// nick. They use the account from the line's account tag or the one
+    /// already known from account-notify, extended-join or an earlier
+    /// `account_of()`. Senders aren't looked up, so a sender whose account
+    /// isn't known isn't ignored by account.
+    pub fn ignore(&mut self, entry: AccessMatch) {
+        let &IgnoreList(ref mut list) = self.extensions.get_or_insert_with(|| IgnoreList(Vec::new()));
+        if !list.contains(&entry) {
+            list.push(entry);
+        }
+    }
+
+    /// Removes `entry` from the ignore list. Returns `false` if it wasn't on it.
+    pub fn unignore(&mut self, entry: &AccessMatch) -> bool {
+        match self.extensions.get_mut::<IgnoreList>() {
+            None => false,
+            Some(&IgnoreList(ref mut list)) => {
+                let len = list.len();
+                list.retain(|e| e != entry);
+                list.len() != len
+            }
+        }
+    }
+
+    /// Returns the ignore list. See `ignore()`.
+    pub fn ignore_list(&self) -> Vec<AccessMatch> {
+        self.extensions.get::<IgnoreList>().map_or(Vec::new(), |&IgnoreList(ref list)| list.clone())
+    }
+}
+
+/// Replaces the ignore list, see `Conn::reconfigure()`
+pub fn replace(conn: &mut Conn, list: Vec<AccessMatch>) {
+    if list.is_empty() {
+        conn.extensions.remove::<IgnoreList>();
+    } else {
+        conn.extensions.insert(IgnoreList(list));
+    }
+}
+
+/// Returns `true` if `line` is a message, notice, CTCP or INVITE from a user
+/// on the ignore list
+pub fn is_ignored(conn: &mut Conn, line: &Line) -> bool {
+    let list = match conn.extensions.get::<IgnoreList>() {
+        Some(&IgnoreList(ref list)) if !list.is_empty() => list.clone(),
+        _ => return false
+    };
+    let user = match line.prefix {
+        Some(ref user) if !user.is_server(&conn.state) => user,
+        _ => return false
+    };
+    match line.command {
+        IRCCmd(ref cmd) if cmd.as_slice() != "PRIVMSG" && cmd.as_slice() != "NOTICE" &&
+                           cmd.as_slice() != "INVITE" => return false,
+        IRCCode(_) => return false,
+        _ => ()
+    }
+    let casemapping = conn.isupport.casemapping();
+    if list.iter().any(|e| match *e {
+        ByMask(ref mask) => casemapping.glob_match(mask.as_slice(), user.raw()),
+        ByAccount(_) => false
+    }) {
+        return true;
+    }
+    if !list.iter().any(|e| match *e { ByAccount(..) => true, _ => false }) {
+        return false;
+    }
+    // looking up every sender would flood the server with WHOIS
+    let account = match line.tag("account") {
+        Some(account) => Some(account.as_bytes().to_vec()),
+        None => accounts::known(conn, user.nick()).and_then(|a| a)
+    };
+    match account {
+        None => false,
+        Some(account) => list.iter().any(|e| match *e {
+            ByAccount(ref name) => casemapping.eq_ignore_case(name.as_slice(), account.as_slice()),
+            ByMask(_) => false
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, LineReceived, ByMask, ByAccount};
+
+    #[test]
+    fn ignore_list() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.conn().ignore(ByMask(b"*!*@spam.example".to_vec()));
+        conn.conn().ignore(ByAccount(b"troll".to_vec()));
+        conn.events();
+        let out = conn.step([b":spammer!u@spam.example PRIVMSG #rust :buy now",
+                             b"@account=troll :new_nick!u@other.host PRIVMSG #rust :hi",
+                             b":troll2!u@h ACCOUNT troll",
+                             b":troll2!u@h PRIVMSG ircnick :\x01PING 1\x01",
+                             b":bob!u@h PRIVMSG #rust :hello"], Duration::zero());
+        // bob's account isn't known, and isn't looked up
+        assert!(out.is_empty());
+        let senders = conn.events().into_iter().filter_map(|e| match e {
+            LineReceived(line) => Some(line.prefix.map_or(Vec::new(), |u| u.nick().to_vec())),
+            _ => None
+        }).collect::<Vec<Vec<u8>>>();
+        assert_eq!(senders, vec![b"troll2".to_vec(), b"bob".to_vec()]);
+        assert!(conn.conn().unignore(&ByMask(b"*!*@spam.example".to_vec())));
+        assert_eq!(conn.conn().ignore_list(), vec![ByAccount(b"troll".to_vec())]);
+    }
+}
@@ -0,0 +1,236 @@
+//! Detecting join floods, for channel protection
+
+use std::cmp::max;
+use std::collections::RingBuf;
+use std::time::Duration;
+
+use conn::{Conn, Line, JoinFlood};
+use modes::ModeChange;
+use User;
+
+/// When a channel counts as being join flooded, and what to do about it
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct FloodPolicy {
+    /// The number of joins that make a flood
+    pub joins: uint,
+    /// The time that the joins have to happen in
+    pub window: Duration,
+    /// Only joins from users whose `nick!user@host` matches this glob are
+    /// counted. None counts everyone.
+    pub mask: Option<Vec<u8>>,
+    /// Channel modes to set when a flood is detected, e.g. `['r']` to only
+    /// let registered users in or `['i']` for invite-only. Only set if we're
+    /// an operator in the channel.
+    pub lock_modes: Vec<char>,
+    /// How long the lock modes stay set
+    pub lock_for: Duration
+}
+
+impl FloodPolicy {
+    /// Returns a policy that detects `joins` joins within `window` from
+    /// anyone, and only sends JoinFlood events
+    pub fn new(joins: uint, window: Duration) -> FloodPolicy {
+        FloodPolicy {
+            joins: joins,
+            window: window,
+            mask: None,
+            lock_modes: Vec::new(),
+            lock_for: Duration::minutes(5)
+        }
+    }
+}
+
+/// The policy and recent joins of a channel
+struct Watched {
+    /// The casemapped channel name
+    chan: Vec<u8>,
+    policy: FloodPolicy,
+    /// When each recent join happened, and who joined, oldest first
+    joins: RingBuf<(u64, User)>,
+    /// Whether the lock modes are set
+    locked: bool
+}
+
+struct FloodWatch(Vec<Watched>);
+
+impl<'a> Conn<'a> {
+    /// Watches `chan` for join floods, or stops watching it if `policy` is None.
+    ///
+    /// Users who join and part repeatedly count once for every join. When
+    /// a flood is detected, a JoinFlood event is sent, and the policy's lock
+    /// modes are set and later removed again.
+    pub fn set_flood_policy(&mut self, chan: &[u8], policy: Option<FloodPolicy>) {
+        let chan = self.isupport.casemapping().lower(chan);
+        let &FloodWatch(ref mut watched) = self.extensions.get_or_insert_with(|| FloodWatch(Vec::new()));
+        watched.retain(|w| w.chan != chan);
+        match policy {
+            None => (),
+            Some(policy) => {
+                watched.push(Watched { chan: chan, policy: policy, joins: RingBuf::new(), locked: false })
+            }
+        }
+    }
+}
+
+/// Forgets the joins and locks of the last connection. The job that would
+/// have removed the lock modes is gone, and so is our view of the channel.
+/// The policies are kept.
+pub fn reset(conn: &mut Conn) {
+    match conn.extensions.get_mut::<FloodWatch>() {
+        None => (),
+        Some(&FloodWatch(ref mut watched)) => {
+            for w in watched.iter_mut() {
+                w.joins.clear();
+                w.locked = false;
+            }
+        }
+    }
+}
+
+/// Records someone else's JOIN, and acts on the channel's policy if it's a flood
+pub fn JOIN(conn: &mut Conn, line: &Line) {
+    let (user, chan) = match (line.prefix.as_ref(), line.args.as_slice().head()) {
+        (Some(user), Some(chan)) => (user.clone(), chan.clone()),
+        _ => return
+    };
+    let casemapping = conn.isupport.casemapping();
+    let key = casemapping.lower(chan.as_slice());
+    let now = conn.now();
+    let (flooders, policy) = match conn.extensions.get_mut::<FloodWatch>() {
+        None => return,
+        Some(&FloodWatch(ref mut watched)) => {
+            let w = match watched.iter_mut().find(|w| w.chan == key) {
+                None => return,
+                Some(w) => w
+            };
+            match w.policy.mask {
+                Some(ref mask) if !casemapping.glob_match(mask.as_slice(), user.raw()) => return,
+                _ => ()
+            }
+            let window = max(w.policy.window.num_milliseconds(), 0) as u64 * 1_000_000;
+            loop {
+                match w.joins.front() {
+                    Some(&(t, _)) if now - t > window => (),
+                    _ => break
+                }
+                w.joins.pop_front();
+            }
+            w.joins.push_back((now, user));
+            if w.joins.len() < w.policy.joins {
+                return;
+            }
+            let flooders = w.joins.iter().map(|&(_, ref u)| u.clone()).collect::<Vec<_>>();
+            w.joins.clear();
+            let lock = !w.locked && !w.policy.lock_modes.is_empty();
+            if lock {
+                w.locked = true;
+            }
+            (flooders, if lock { Some(w.policy.clone()) } else { None })
+        }
+    };
+    conn.emit(JoinFlood(chan.clone(), flooders));
+    match policy {
+        Some(ref policy) if conn.state.is_op(chan.as_slice(), conn.user.nick()) => {
+            set_lock(conn, chan.as_slice(), policy.lock_modes.as_slice(), true);
+            let modes = policy.lock_modes.clone();
+            conn.schedule(policy.lock_for, proc(conn) {
+                set_lock(conn, chan.as_slice(), modes.as_slice(), false);
+                unlocked(conn, chan.as_slice());
+            });
+        }
+        // not an operator: let the next flood try again
+        Some(_) => unlocked(conn, chan.as_slice()),
+        None => ()
+    }
+}
+
+fn set_lock(conn: &mut Conn, chan: &[u8], modes: &[char], set: bool) {
+    let changes = modes.iter().map(|&m| {
+        if set { ModeChange::set(m, None) } else { ModeChange::unset(m, None) }
+    }).collect::<Vec<_>>();
+    conn.set_modes(chan, changes.as_slice());
+}
+
+fn unlocked(conn: &mut Conn, chan: &[u8]) {
+    let key = conn.isupport.casemapping().lower(chan);
+    match conn.extensions.get_mut::<FloodWatch>() {
+        None => (),
+        Some(&FloodWatch(ref mut watched)) => {
+            for w in watched.iter_mut().filter(|w| w.chan == key) {
+                w.locked = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue, JoinFlood};
+    use super::FloodPolicy;
+
+    fn floods(events: Vec<Event>) -> Vec<(Vec<u8>, Vec<Vec<u8>>)> {
+        events.into_iter().filter_map(|e| match e {
+            JoinFlood(chan, users) => Some((chan, users.iter().map(|u| u.nick().to_vec()).collect())),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn thresholds() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":ircnick!u@h JOIN #rust",
+                   b":ChanServ!s@services. MODE #rust +o ircnick"], Duration::zero());
+        let mut policy = FloodPolicy::new(3, Duration::seconds(10));
+        policy.mask = Some(b"*!*@*.example".to_vec());
+        policy.lock_modes = vec!['r', 'i'];
+        policy.lock_for = Duration::minutes(1);
+        conn.conn().set_flood_policy(b"#Rust", Some(policy));
+        conn.events();
+
+        // joins further apart than the window, or not matching the mask, don't count
+        conn.step([b":a!a@a.example JOIN #rust"], Duration::seconds(6));
+        conn.step([b":b!b@b.example JOIN #rust", b":c!c@other.host JOIN #rust"], Duration::seconds(5));
+        assert!(conn.step([b":d!d@d.example JOIN #rust"], Duration::zero()).is_empty());
+        assert!(floods(conn.events()).is_empty());
+        assert_eq!(conn.step([b":e!e@e.example JOIN #rust"], Duration::zero()), vec![b"MODE #rust +ri".to_vec()]);
+        assert_eq!(floods(conn.events()),
+                   vec![(b"#rust".to_vec(), vec![b"b".to_vec(), b"d".to_vec(), b"e".to_vec()])]);
+
+        // a flood while the channel is locked is only reported
+        let flood = [b":f!f@f.example JOIN #rust", b":g!g@g.example JOIN #rust", b":h!h@h.example JOIN #rust"];
+        assert!(conn.step(flood, Duration::zero()).is_empty());
+        assert_eq!(floods(conn.events()).len(), 1);
+        assert_eq!(conn.step([], Duration::minutes(1)), vec![b"MODE #rust -ri".to_vec()]);
+
+        // without operator status too, and the next flood locks once we have it
+        conn.step([b":ChanServ!s@services. MODE #rust -o ircnick"], Duration::zero());
+        conn.events();
+        assert!(conn.step(flood, Duration::zero()).is_empty());
+        assert_eq!(floods(conn.events()).len(), 1);
+        conn.step([b":ChanServ!s@services. MODE #rust +o ircnick"], Duration::zero());
+        assert_eq!(conn.step(flood, Duration::zero()), vec![b"MODE #rust +ri".to_vec()]);
+    }
+
+    #[test]
+    fn lock_after_reconnect() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let joined = [b":irc.example.net 001 ircnick :Welcome",
+                      b":ircnick!u@h JOIN #rust",
+                      b":ChanServ!s@services. MODE #rust +o ircnick"];
+        conn.step(joined, Duration::zero());
+        let mut policy = FloodPolicy::new(2, Duration::seconds(10));
+        policy.lock_modes = vec!['i'];
+        conn.conn().set_flood_policy(b"#rust", Some(policy));
+        let flood = [b":a!a@h JOIN #rust", b":b!b@h JOIN #rust"];
+        assert_eq!(conn.step(flood, Duration::zero()), vec![b"MODE #rust +i".to_vec()]);
+        // the connection is lost while the channel is locked
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step(joined, Duration::zero());
+        assert_eq!(conn.step(flood, Duration::zero()), vec![b"MODE #rust +i".to_vec()]);
+    }
+}
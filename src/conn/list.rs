@@ -0,0 +1,200 @@
+//! A cache of the network's channel list (LIST)
+
+use std::cmp::max;
+use std::from_str::from_str;
+use std::mem;
+use std::str;
+use std::time::Duration;
+
+use conn::{Conn, Line, IRCCmd, ChannelListUpdated};
+
+/// The least time between two LISTs sent by `Conn::refresh_channel_list()`,
+/// in seconds. Servers throttle LIST, and answer too frequent ones with
+/// RPL_TRYAGAIN (263).
+static MIN_REFRESH_INTERVAL_S: u64 = 60;
+
+/// A channel from a LIST reply
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ListEntry {
+    /// The channel name
+    pub channel: Vec<u8>,
+    /// The number of users in the channel
+    pub users: uint,
+    /// The channel topic, empty if there isn't one. Some servers put the
+    /// channel modes in front of it, e.g. `[+nt] the topic`.
+    pub topic: Vec<u8>
+}
+
+struct ChannelList {
+    entries: Vec<ListEntry>,
+    /// When the last complete list arrived
+    updated: Option<u64>,
+    /// When the last LIST was sent
+    requested: Option<u64>,
+    /// The entries of the LIST in progress, if there is one
+    pending: Option<Vec<ListEntry>>
+}
+
+impl<'a> Conn<'a> {
+    /// Asks the server for the channel list, unless the cached one is less
+    /// than `max_age` old. The reply replaces the cache once it's complete,
+    /// and a ChannelListUpdated event is sent.
+    ///
+    /// Returns `false` if no LIST was sent: because the cache is fresh
+    /// enough, a LIST is in progress, one was sent less than a minute ago, or
+    /// the server doesn't advertise SAFELIST. Without SAFELIST, the whole
+    /// list is sent at once, which can overflow our send queue on the server
+    /// and get us disconnected.
+    pub fn refresh_channel_list(&mut self, max_age: Duration) -> bool {
+        if !self.isupport.contains("SAFELIST") {
+            return false;
+        }
+        let now = self.now();
+        let max_age = max(max_age.num_milliseconds(), 0) as u64 * 1_000_000;
+        {
+            let list = self.extensions.get_or_insert_with(|| ChannelList {
+                entries: Vec::new(),
+                updated: None,
+                requested: None,
+                pending: None
+            });
+            let fresh = list.updated.map_or(false, |t| now - t < max_age);
+            let throttled = list.requested.map_or(false, |t| now - t < MIN_REFRESH_INTERVAL_S * 1_000_000_000);
+            if fresh || throttled || list.pending.is_some() {
+                return false;
+            }
+            list.requested = Some(now);
+            list.pending = Some(Vec::new());
+        }
+        self.send_command(IRCCmd("LIST".into_maybe_owned()), [], false);
+        true
+    }
+
+    /// Returns the cached channel list, empty if there isn't one yet
+    pub fn channel_list<'b>(&'b self) -> &'b [ListEntry] {
+        self.extensions.get::<ChannelList>().map_or([].as_slice(), |l| l.entries.as_slice())
+    }
+
+    /// Returns how old the cached channel list is, or None if there isn't one
+    pub fn channel_list_age(&self) -> Option<Duration> {
+        let now = self.now();
+        self.extensions.get::<ChannelList>().and_then(|l| l.updated).map(|t| {
+            Duration::milliseconds(((now - t) / 1_000_000) as i64)
+        })
+    }
+
+    /// Returns the cached channels whose name or topic contains `text`,
+    /// ignoring case, with the biggest channels first
+    pub fn search_channel_list(&self, text: &[u8]) -> Vec<ListEntry> {
+        let casemapping = self.isupport.casemapping();
+        let text = casemapping.lower(text);
+        let mut found = self.channel_list().iter().filter(|e| {
+            contains(casemapping.lower(e.channel.as_slice()).as_slice(), text.as_slice()) ||
+                contains(casemapping.lower(e.topic.as_slice()).as_slice(), text.as_slice())
+        }).map(|e| e.clone()).collect::<Vec<_>>();
+        found.sort_by(|a, b| b.users.cmp(&a.users));
+        found
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.len() <= haystack.len() &&
+        range(0, haystack.len() - needle.len() + 1).any(|i| haystack.slice_from(i).starts_with(needle))
+}
+
+/// Forgets the LIST in progress on the last connection, which won't be
+/// answered. The cached list is kept.
+pub fn reset(conn: &mut Conn) {
+    match conn.extensions.get_mut::<ChannelList>() {
+        None => (),
+        Some(list) => list.pending = None
+    }
+}
+
+/// Returns the LIST in progress, unless it was sent by someone other than
+/// `refresh_channel_list()`
+fn pending<'a>(conn: &'a mut Conn) -> Option<&'a mut Vec<ListEntry>> {
+    conn.extensions.get_mut::<ChannelList>().and_then(|l| l.pending.as_mut())
+}
+
+pub fn RPL_LIST(conn: &mut Conn, line: &Line) {
+    // :server 322 me #chan 42 :the topic
+    if line.args.len() < 3 {
+        return;
+    }
+    let users = str::from_utf8(line.args[2].as_slice()).and_then(|n| from_str::<uint>(n)).unwrap_or(0);
+    let entry = ListEntry {
+        channel: line.args[1].clone(),
+        users: users,
+        topic: if line.args.len() > 3 { line.args[3].clone() } else { Vec::new() }
+    };
+    match pending(conn) {
+        None => (),
+        Some(entries) => entries.push(entry)
+    }
+}
+
+pub fn RPL_LISTEND(conn: &mut Conn, _line: &Line) {
+    let now = conn.now();
+    let count = match conn.extensions.get_mut::<ChannelList>() {
+        None => return,
+        Some(list) => match mem::replace(&mut list.pending, None) {
+            None => return,
+            Some(entries) => {
+                list.entries = entries;
+                list.updated = Some(now);
+                list.entries.len()
+            }
+        }
+    };
+    conn.emit(ChannelListUpdated(count));
+}
+
+pub fn RPL_TRYAGAIN(conn: &mut Conn, line: &Line) {
+    // :server 263 me LIST :Server load is temporarily too heavy. Please wait a while and try again.
+    if line.args.len() < 2 || line.args[1].as_slice() != b"LIST" {
+        return;
+    }
+    // keep the old list, and let the next refresh try again
+    match conn.extensions.get_mut::<ChannelList>() {
+        None => (),
+        Some(list) => list.pending = None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue};
+    use super::{contains, ListEntry};
+
+    #[test]
+    fn refresh_after_reconnect() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let welcome = [b":irc.example.net 001 ircnick :Welcome",
+                       b":irc.example.net 005 ircnick SAFELIST :are supported by this server"];
+        conn.step(welcome, Duration::zero());
+        assert!(conn.conn().refresh_channel_list(Duration::minutes(5)));
+        // the connection is lost in the middle of the list
+        conn.step([b":irc.example.net 321 ircnick Channel :Users  Name",
+                   b":irc.example.net 322 ircnick #rust 42 :Rust"], Duration::zero());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step(welcome, Duration::minutes(1));
+        assert!(conn.conn().refresh_channel_list(Duration::minutes(5)));
+        assert_eq!(conn.step([b":irc.example.net 322 ircnick #rust 42 :Rust",
+                              b":irc.example.net 323 ircnick :End of /LIST"], Duration::zero()),
+                   vec![b"LIST".to_vec()]);
+        assert_eq!(conn.conn().channel_list(),
+                   [ListEntry { channel: b"#rust".to_vec(), users: 42, topic: b"Rust".to_vec() }].as_slice());
+    }
+
+    #[test]
+    fn test_contains() {
+        assert!(contains(b"#rust-lang", b"rust"));
+        assert!(contains(b"rust", b"rust"));
+        assert!(contains(b"anything", b""));
+        assert!(!contains(b"#rus", b"rust"));
+    }
+}
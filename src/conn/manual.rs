@@ -0,0 +1,156 @@
+//! Driving a connection by hand, without sockets, tasks or timers
+
+use std::comm::{channel, Receiver};
+use std::cmp::max;
+use std::mem;
+use std::time::Duration;
+
+use conn::{Conn, Options, Event, Control, Continue, DisconnectSummary};
+use conn::{Connecting, Connected, Registering, Disconnected, ReconnectScheduled};
+use conn::{DisconnectClosed, DisconnectQuit};
+use conn::seen;
+use conn::persist;
+
+/// A connection whose input, output and clock are driven by the caller,
+/// which makes it possible to test handlers, scheduled jobs and timeouts
+/// deterministically.
+///
+/// Nothing is sent anywhere: `tick()` takes the lines "received" from the
+/// server and returns the lines the connection wrote. The connection's clock
+/// starts at zero and only moves when `tick()` advances it, so the ping
+/// timeout, `Conn::schedule()` and everything else that waits for some time
+/// happen exactly when the elapsed time says they should.
+///
+/// The callback gets the same events as with `connect()`, but reconnecting
+/// and redirects are up to the caller: a Reconnect from the callback only
+/// sends a QUIT, and after `server_closed()` nothing happens until
+/// `reconnect()` is called.
+///
+/// With a `Vec<Event>` payload, `step()` ticks with a callback that keeps
+/// every event, and `events()` takes them, which is what most tests need.
+pub struct Manual<'a, Payload> {
+    conn: Conn<'a>,
+    opts: Options<'a, Payload>,
+    payload: Payload,
+    output: Receiver<Vec<u8>>
+}
+
+impl<'a, Payload> Manual<'a, Payload> {
+    /// Returns a connection that has just connected and sent its registration
+    /// commands. The Connecting, Connected and Registering events are sent to
+    /// the callback of the first `tick()`.
+    ///
+    /// The transport, resolver, proxy header and identd options aren't used.
+    pub fn new(mut opts: Options<'a, Payload>, payload: Payload) -> Manual<'a, Payload> {
+        let mut conn = Conn::new(&mut opts);
+        conn.set_clock(0);
+        if opts.track_seen {
+            seen::enable(&mut conn);
+        }
+        persist::load(&mut conn);
+        let (write_tx, output) = channel();
+        conn.emit(Connecting);
+        conn.emit(Connected);
+        conn.begin(&opts, write_tx);
+        conn.emit(Registering);
+        Manual {
+            conn: conn,
+            opts: opts,
+            payload: payload,
+            output: output
+        }
+    }
+
+    /// Handles `input` as lines received from the server, without their
+    /// CRLF, then moves the clock forward by `elapsed` and runs the
+    /// timeouts and scheduled jobs that are due. Commands waiting in
+    /// `Options.commands` are run first.
+    ///
+    /// Returns the lines written to the server meanwhile, without their CRLF.
+    pub fn tick(&mut self, input: &[&[u8]], elapsed: Duration,
+                mut cb: |&mut Conn, Event, &mut Payload| -> Control) -> Vec<Vec<u8>> {
+        self.conn.flush_events(&mut self.payload, &mut cb);
+        self.conn.drain_commands(&self.opts, &mut self.payload, &mut cb);
+        for line in input.iter() {
+            if !self.conn.is_connected() {
+                break;
+            }
+            self.conn.line_received(line.to_vec(), &self.opts, &mut self.payload, &mut cb);
+        }
+        let elapsed = max(elapsed.num_milliseconds(), 0) as u64 * 1_000_000;
+        let now = self.conn.now() + elapsed;
+        self.conn.set_clock(now);
+        if self.conn.is_connected() {
+            self.conn.on_tick(&mut self.payload, &mut cb);
+        }
+        let mut output = Vec::new();
+        loop {
+            match self.output.try_recv() {
+                Ok(line) => output.push(line),
+                Err(_) => break
+            }
+        }
+        output
+    }
+
+    /// Handles the server closing the connection: sends the Disconnected
+    /// event and returns the summary of the connection
+    pub fn server_closed(&mut self, mut cb: |&mut Conn, Event, &mut Payload| -> Control)
+                         -> DisconnectSummary {
+        self.conn.write_tx = None;
+        if self.conn.disconnect.is_none() {
+            self.conn.disconnect = Some(if self.conn.quitting { DisconnectQuit } else { DisconnectClosed });
+        }
+        self.conn.flush_events(&mut self.payload, &mut cb);
+        let summary = self.conn.summary();
+        self.conn.call(Disconnected(summary.reason.clone()), &mut self.payload, &mut cb);
+        self.conn.drain_commands(&self.opts, &mut self.payload, &mut cb);
+        summary
+    }
+
+    /// Connects again after `server_closed()`, as `connect()` does when the
+    /// callback asks to reconnect: the state of the last connection is reset,
+    /// the registration commands are sent, and the ReconnectScheduled,
+    /// Connecting, Connected and Registering events are sent to the callback
+    /// of the next `tick()`.
+    /// The clock keeps going.
+    pub fn reconnect(&mut self) {
+        self.conn.reset(&self.opts);
+        let (write_tx, output) = channel();
+        self.output = output;
+        let host = self.conn.host.as_slice().to_string();
+        self.conn.emit(ReconnectScheduled(host, self.conn.port, None));
+        self.conn.emit(Connecting);
+        self.conn.emit(Connected);
+        self.conn.begin(&self.opts, write_tx);
+        self.conn.emit(Registering);
+    }
+
+    /// Returns the connection, e.g. to send commands or inspect its state
+    pub fn conn(&mut self) -> &mut Conn<'a> {
+        &mut self.conn
+    }
+
+    /// Returns the payload given to the callback
+    pub fn payload(&mut self) -> &mut Payload {
+        &mut self.payload
+    }
+}
+
+impl<'a> Manual<'a, Vec<Event>> {
+    /// Like `tick()`, with a callback that keeps the events for `events()`
+    pub fn step(&mut self, input: &[&[u8]], elapsed: Duration) -> Vec<Vec<u8>> {
+        self.tick(input, elapsed, |c, e, p| record(c, e, p))
+    }
+
+    /// Returns the events recorded since the last call
+    pub fn events(&mut self) -> Vec<Event> {
+        mem::replace(&mut self.payload, Vec::new())
+    }
+}
+
+/// The callback of `step()`
+fn record(_: &mut Conn, event: Event, events: &mut Vec<Event>) -> Control {
+    events.push(event);
+    Continue
+}
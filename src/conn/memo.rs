@@ -0,0 +1,208 @@
+//! Holding back messages for users that are away or offline
+
+use std::collections::HashSet;
+
+use conn::{Conn, Line, IRCCmd, IRCAction, IRCCTCP, IRCCTCPReply, MessagesDelivered};
+
+/// What we know about absent users, and the messages waiting for them
+struct Memos {
+    /// Casemapped nicks that we've been told are away
+    away: HashSet<Vec<u8>>,
+    /// Nicks with the messages waiting for them, in the order they were queued
+    queued: Vec<(Vec<u8>, Vec<Vec<u8>>)>
+}
+
+impl<'a> Conn<'a> {
+    /// Sends a PRIVMSG to `nick`, or holds it back if `nick` is known to be
+    /// away or offline. Returns `true` if the message was sent right away.
+    ///
+    /// A nick counts as offline if it's on the watch list and the last poll
+    /// found it offline (see `watch()`), and as away if the server told us so
+    /// with a 301 reply. Held messages are sent once the nick comes online, or
+    /// once we see it say something, join a channel or change its nick. A
+    /// MessagesDelivered event is sent when that happens.
+    pub fn privmsg_or_hold(&mut self, nick: &[u8], msg: &[u8]) -> bool {
+        if !self.is_absent(nick) {
+            self.privmsg(nick, msg);
+            return true;
+        }
+        let casemapping = self.isupport.casemapping();
+        let memos = self.memos();
+        match memos.queued.iter().position(|&(ref n, _)| casemapping.eq_ignore_case(n.as_slice(), nick)) {
+            Some(idx) => {
+                let (_, ref mut msgs) = *memos.queued.get_mut(idx);
+                msgs.push(msg.to_vec());
+            }
+            None => memos.queued.push((nick.to_vec(), vec![msg.to_vec()]))
+        }
+        false
+    }
+
+    /// Returns the number of messages being held for `nick`
+    pub fn held_messages(&self, nick: &[u8]) -> uint {
+        let casemapping = self.isupport.casemapping();
+        match self.extensions.get::<Memos>() {
+            None => 0,
+            Some(memos) => {
+                memos.queued.iter().find(|&&(ref n, _)| casemapping.eq_ignore_case(n.as_slice(), nick))
+                                   .map_or(0, |&(_, ref msgs)| msgs.len())
+            }
+        }
+    }
+
+    /// Returns `true` if `nick` is known to be away or offline
+    fn is_absent(&self, nick: &[u8]) -> bool {
+        let casemapping = self.isupport.casemapping();
+        let away = self.extensions.get::<Memos>().map_or(false, |m| {
+            m.away.contains(&casemapping.lower(nick))
+        });
+        away || self.watched().iter().any(|&(ref n, status)| {
+            status == Some(false) && casemapping.eq_ignore_case(n.as_slice(), nick)
+        })
+    }
+
+    fn memos<'b>(&'b mut self) -> &'b mut Memos {
+        self.extensions.get_or_insert_with(|| {
+            Memos { away: HashSet::new(), queued: Vec::new() }
+        })
+    }
+}
+
+/// Forgets who was away on the last connection. The held messages are kept.
+pub fn reset(conn: &mut Conn) {
+    match conn.extensions.get_mut::<Memos>() {
+        None => (),
+        Some(memos) => memos.away.clear()
+    }
+}
+
+/// Marks `nick` as present and sends any messages held for it
+pub fn returned(conn: &mut Conn, nick: &[u8]) {
+    let casemapping = conn.isupport.casemapping();
+    let held = match conn.extensions.get_mut::<Memos>() {
+        None => return,
+        Some(memos) => {
+            memos.away.remove(&casemapping.lower(nick));
+            match memos.queued.iter().position(|&(ref n, _)| casemapping.eq_ignore_case(n.as_slice(), nick)) {
+                None => return,
+                Some(idx) => memos.queued.remove(idx).unwrap()
+            }
+        }
+    };
+    let (_, msgs) = held;
+    for msg in msgs.iter() {
+        conn.privmsg(nick, msg.as_slice());
+    }
+    conn.emit(MessagesDelivered(nick.to_vec(), msgs.len()));
+}
+
+/// Treats a user talking, joining or changing nick as a sign that they're back
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    if !conn.extensions.contains::<Memos>() {
+        return;
+    }
+    let nick = match line.prefix {
+        None => return,
+        Some(ref user) => user.nick()
+    };
+    match line.command {
+        IRCCmd(ref s) if "NICK" == s.as_slice() && !line.args.is_empty() => {
+            // the held messages follow the nick change
+            let casemapping = conn.isupport.casemapping();
+            let new = line.args[0].as_slice();
+            for entry in conn.memos().queued.iter_mut() {
+                let (ref mut held, _) = *entry;
+                if casemapping.eq_ignore_case(held.as_slice(), nick) {
+                    *held = new.to_vec();
+                }
+            }
+            returned(conn, new);
+        }
+        IRCCmd(ref s) if "PRIVMSG" == s.as_slice() || "NOTICE" == s.as_slice() ||
+                         "JOIN" == s.as_slice() => returned(conn, nick),
+        IRCAction(_) | IRCCTCP(_, _) | IRCCTCPReply(_, _) => returned(conn, nick),
+        _ => ()
+    }
+}
+
+// 301
+pub fn RPL_AWAY(conn: &mut Conn, line: &Line) {
+    // :server 301 me nick :away message
+    if line.args.len() < 2 {
+        return;
+    }
+    let nick = conn.isupport.casemapping().lower(line.args[1].as_slice());
+    conn.memos().away.insert(nick);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue, MessagesDelivered};
+
+    fn delivered(events: Vec<Event>) -> Vec<(Vec<u8>, uint)> {
+        events.into_iter().filter_map(|e| match e {
+            MessagesDelivered(nick, count) => Some((nick, count)),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn released_when_back() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 301 ircnick bob :gone fishing",
+                   b":irc.example.net 301 ircnick dave :lunch"], Duration::zero());
+        assert!(!conn.conn().privmsg_or_hold(b"bob", b"hi"));
+        assert!(!conn.conn().privmsg_or_hold(b"BOB", b"are you there?"));
+        assert!(!conn.conn().privmsg_or_hold(b"dave", b"ping"));
+        assert!(conn.conn().privmsg_or_hold(b"carol", b"hello"));
+        assert_eq!(conn.step([], Duration::zero()), vec![b"PRIVMSG carol hello".to_vec()]);
+        assert_eq!(conn.conn().held_messages(b"Bob"), 2);
+        conn.events();
+
+        // talking is a sign of being back
+        let out = conn.step([b":Bob!b@h PRIVMSG #rust :back"], Duration::zero());
+        assert_eq!(out, vec![b"PRIVMSG Bob hi".to_vec(), b"PRIVMSG Bob :are you there?".to_vec()]);
+        assert_eq!(delivered(conn.events()), vec![(b"Bob".to_vec(), 2)]);
+        assert!(conn.conn().privmsg_or_hold(b"bob", b"welcome back"));
+        conn.step([], Duration::zero());
+
+        // the messages follow a nick change
+        let out = conn.step([b":dave!d@h NICK dave_"], Duration::zero());
+        assert_eq!(out, vec![b"PRIVMSG dave_ ping".to_vec()]);
+        assert_eq!(delivered(conn.events()), vec![(b"dave_".to_vec(), 1)]);
+        assert_eq!(conn.conn().held_messages(b"dave"), 0);
+    }
+
+    #[test]
+    fn released_when_online() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.conn().watch(b"erin");
+        conn.conn().poll_ison(Some(Duration::seconds(60)));
+        assert_eq!(conn.step([b":irc.example.net 303 ircnick :"], Duration::zero()),
+                   vec![b"ISON erin".to_vec()]);
+        assert!(!conn.conn().privmsg_or_hold(b"erin", b"see you"));
+        assert_eq!(conn.step([], Duration::seconds(60)), vec![b"ISON erin".to_vec()]);
+        let out = conn.step([b":irc.example.net 303 ircnick :erin"], Duration::zero());
+        assert_eq!(out, vec![b"PRIVMSG erin :see you".to_vec()]);
+        assert_eq!(delivered(conn.events()), vec![(b"erin".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn away_forgotten_on_reconnect() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 301 ircnick bob :gone fishing"], Duration::zero());
+        assert!(!conn.conn().privmsg_or_hold(b"bob", b"hi"));
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        assert!(conn.conn().privmsg_or_hold(b"bob", b"still there?"));
+        assert_eq!(conn.conn().held_messages(b"bob"), 1);
+    }
+}
@@ -1,19 +1,107 @@
 //! Management of IRC server connection
 
+use std::any::{Any, AnyRefExt};
 use std::from_str::from_str;
 use std::str::from_utf8;
 use std::fmt;
 use std::io;
-use std::io::{IoError, IoResult, TcpStream};
-use std::io::BufferedStream;
-use std::{char,str,uint};
+use std::io::{IoError, IoResult, TcpStream, Timer};
+use std::{char,mem,str,uint};
 use std::str::MaybeOwned;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::comm;
-use std::task::TaskBuilder;
-use User;
+use std::rt::unwind;
+use std::time::Duration;
+use time;
+use {User, Prefix, ServerPrefix};
+use dcc::Dcc;
+use snotice::Snotice;
+use who::WhoReply;
+use dns;
+use dns::{Resolver, DnsResolver};
+use errors::{IrcError, StandardReply};
+use isupport::ISupport;
+use quirks::Quirks;
+use modes;
+use modes::ModeChange;
+use state::State;
+use store::StateStore;
+use validate;
+use validate::InvalidName;
+use self::identd::Identd;
+
+pub use self::extensions::Extensions;
+pub use self::notify::IsonCallback;
+pub use self::ctcp::{PingCallback, CtcpLimits};
+pub use self::accounts::AccountCallback;
+pub use self::bans::TimedBan;
+pub use self::joinflood::FloodPolicy;
+pub use self::seen::{LastSeen, SeenAction, SeenJoin, SeenPart, SeenQuit, SeenMessage, SeenEmote};
+pub use self::seen::SeenNickChange;
+pub use self::noise::{Noise, ConnectNotices, ConnectedBanners, GlobalNotices};
+pub use self::oper::{Kline, KlineCallback, ChallengeSolver};
+pub use self::autoop::{AccessEntry, AccessMatch, ByMask, ByAccount, AccessLevel, AutoOp, AutoVoice};
+pub use self::pool::{Handler, Reply, connect_pooled};
+pub use self::transport::{Transport, Connection, LineReader, LineWriter};
+pub use self::transport::{TcpTransport, WebSocketTransport};
+pub use self::transport::{TlsOptions, TlsVersion, Tls10, Tls11, Tls12};
+pub use self::transport::{TcpLineReader, TcpLineWriter};
+#[cfg(feature = "zlib")]
+pub use self::zlib::{ZlibTransport, ZlibReader, ZlibWriter, TlsConnector, TlsStream};
+pub use self::spawner::{Spawner, TaskSpawner};
+pub use self::manual::Manual;
+pub use self::auth::{Authenticator, ServerPassword, SaslPlain, SaslExternal, NickServIdentify};
+pub use self::audit::SendHook;
+pub use self::config::Config;
+pub use self::proxy::{ProxyHeader, ProxyVersion, ProxyV1, ProxyV2};
+pub use self::resync::ChannelResync;
+pub use self::list::ListEntry;
+pub use self::rejoin::RejoinPolicy;
+pub use self::services::{ChanServRequest, ChanServOp, ChanServUnban, ChanServInvite, ChanServTopic};
+pub use self::routing::ChannelHandler;
+pub use self::typing::{TypingState, TypingActive, TypingPaused, TypingDone};
+pub use self::serverinfo::{ServerVersion, ServerTime, AdminInfo, NetworkStats};
+pub use self::serverinfo::{VersionCallback, TimeCallback, AdminCallback, InfoCallback, LusersCallback};
 
 mod handlers;
+mod extensions;
+mod notify;
+mod moderation;
+mod memo;
+mod persist;
+mod pool;
+mod transport;
+#[cfg(feature = "zlib")]
+mod zlib;
+mod spawner;
+mod manual;
+mod dedup;
+mod ctcp;
+mod accounts;
+mod bans;
+mod autoop;
+mod joinflood;
+mod seen;
+mod noise;
+mod oper;
+mod services;
+mod caps;
+mod auth;
+mod audit;
+mod config;
+mod topicguard;
+mod identd;
+mod proxy;
+mod resync;
+mod netsplit;
+mod list;
+mod rejoin;
+mod ignore;
+mod serverinfo;
+mod routing;
+mod typing;
+mod readmarker;
+mod reactions;
 
 /// Conn represenets a connection to a single IRC server
 ///
@@ -22,12 +110,85 @@ mod handlers;
 /// extra data for your handler to use. It is completely ignored by this
 /// library otherwise.
 pub struct Conn<'a> {
-    host: &'a str,
+    host: MaybeOwned<'a>,
+    port: u16,
+    stream: Option<TcpStream>,
     write_tx: Option<Sender<Vec<u8>>>,
     logged_in: bool,
     user: User,
+    isupport: ISupport,
+    quirks: Quirks,
+    /// The name the server gave itself when welcoming us, see `server_name()`
+    server_name: Option<Vec<u8>>,
+    state: State,
+    extensions: Extensions,
+    events: Vec<Event>,
+    scheduled: Vec<Scheduled>,
+    join_delay: Duration,
+    ping_mode: PingMode,
+    follow_redirects: bool,
+    redirect: Option<(String, u16)>,
+    ping_timeout: Option<Duration>,
+    last_read: u64,
+    ping_sent: bool,
+    quitting: bool,
+    /// When to give up on the server closing the connection after `quit_and_wait()`
+    quit_deadline: Option<u64>,
+    disconnect: Option<DisconnectReason>,
+    invite_policy: Option<InvitePolicy>,
+    reply_policy: ReplyPolicy,
+    rejoin_policy: Option<RejoinPolicy>,
+    services_interval: Duration,
+    ctcp_limits: Option<CtcpLimits>,
+    store: Option<Box<StateStore + Send + 'a>>,
+    transport: Box<Transport + Send + 'a>,
+    tls: Option<TlsOptions>,
+    /// The host, port and TLS session of the last connection, for resuming it
+    tls_session: Option<(String, u16, Vec<u8>)>,
+    spawner: Box<Spawner + Send + 'a>,
+    authenticator: Option<Box<Authenticator + Send + 'a>>,
+    send_hook: Option<Box<SendHook + Send + 'a>>,
+    /// The time in nanoseconds when driven by `Manual`, instead of the system clock
+    clock: Option<u64>,
+    tap: Option<Sender<RawLine>>,
+    hold_until_registered: bool,
+    /// Lines sent before registration, see `Options.hold_until_registered`
+    held_lines: Vec<Vec<u8>>,
+    join_on_connect: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Whether the MOTD has ended and join_on_connect been joined on this connection
+    autojoined: bool,
+    user_modes: Vec<char>,
+    /// The message of the callback's panic, once it has panicked
+    panicked: Option<String>,
+    /// When the connection was opened, for DisconnectSummary
+    connected_at: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// The last error numeric on this connection
+    last_error: Option<IrcError>,
+    /// The message of the ERROR the server sent on this connection
+    server_error: Option<Vec<u8>>,
+    /// The ident responder, while a connection is being registered
+    identd: Option<Identd>,
+}
+
+/// How long the QUIT sent after the callback panics has to get to the server,
+/// in seconds
+static QUIT_WAIT_S: i64 = 5;
+
+/// How many lines are held back until registration, see
+/// `Options.hold_until_registered`. Lines past that are dropped.
+static MAX_HELD_LINES: uint = 100;
+
+/// A job waiting to be run by the event loop
+struct Scheduled {
+    at: u64,
+    job: proc(&mut Conn)
 }
 
+/// How often the event loop checks for scheduled jobs, in milliseconds
+static TICK_MS: i64 = 100;
+
 /// Options used with Conn for connecting to the server.
 ///
 /// Payload is the type of the payload carried along by the connection.
@@ -41,18 +202,249 @@ pub struct Options<'a, Payload=()> {
     pub nick: &'a str,
     /// The username to use
     pub user: &'a str,
-    /// The real name to use
+    /// The real name to use. It can contain spaces and start with a colon;
+    /// line breaks and NULs are dropped, and an empty one is replaced with
+    /// `user`, since servers reject a USER without one.
     pub real: &'a str,
+    /// The user modes asked for in the USER command. Defaults to invisible.
+    pub user_modes: RegistrationModes,
     /// A Port to send procs to.
     /// The Port will be closed when connect() returns.
     /// Any proc sent to this port will be executed on the connection's task,
-    /// with a handle to the connection.
+    /// with a handle to the connection, in the order they were sent.
     ///
-    /// When the connection shuts down, all already-scheduled procs will read from the
-    /// channel, the channel closed, and then the procs will execute. Any procs added
-    /// to the channel after the channel is drained, but before it's closed, will be
-    /// discarded.
+    /// Each proc is told with its CmdState argument whether the connection is
+    /// still active. Procs that run after it was lost get CmdDisconnected with
+    /// the reason, and anything they send is dropped. Once the connection has
+    /// terminated, the procs waiting
+    /// in the channel are run before the Disconnected event, and any sent after
+    /// that are run before connect() returns. When a redirect is about to be
+    /// followed, the waiting procs run on the new connection instead.
     pub commands: Option<Receiver<Cmd<Payload>>>,
+    /// The delay between the JOIN lines sent by `Conn::join_many()`
+    pub join_delay: Duration,
+    /// How PINGs from the server are answered
+    pub handle_ping: PingMode,
+    /// Whether to reconnect to the server given in an RPL_BOUNCE (010) redirect
+    pub follow_redirects: bool,
+    /// How long the connection may be silent before it's considered dead.
+    /// After half this time without hearing from the server, we send it a PING.
+    /// Pass None to disable the check.
+    pub ping_timeout: Option<Duration>,
+    /// Which INVITEs to accept by joining the channel. None (the default) means
+    /// that invites are only reported with an Invited event.
+    pub auto_join_invites: Option<InvitePolicy>,
+    /// How `Conn::reply()` answers messages, and whether it answers NOTICEs.
+    /// Defaults to SkipNotices.
+    pub reply_policy: ReplyPolicy,
+    /// Whether to rejoin channels we're kicked from, and how to back off if
+    /// the server won't let us back in. Defaults to None, which doesn't
+    /// rejoin.
+    pub rejoin_on_kick: Option<RejoinPolicy>,
+    /// The time between two messages to services, see `Conn::services_msg()`.
+    /// Defaults to 2 seconds.
+    pub services_interval: Duration,
+    /// Where to save state that should survive restarts, like the watch list
+    /// and channel keys. The state is loaded from it by `connect()`.
+    pub store: Option<Box<StateStore + Send + 'a>>,
+    /// The commands and numerics that LineReceived events are sent for, e.g.
+    /// `&["PRIVMSG", "INVITE", "353"]`. Other lines are still handled internally,
+    /// and typed events like JoinFailed are sent regardless.
+    /// None (the default) means all lines.
+    pub subscribe: Option<&'a [&'a str]>,
+    /// A channel that gets a copy of every line sent and received, including
+    /// lines that couldn't be parsed. Meant for debugging tools.
+    pub tap: Option<Sender<RawLine>>,
+    /// Sees every line right before it's sent, including the ones the
+    /// connection sends on its own, and can rewrite or drop it. Lines it
+    /// drops aren't sent to the tap either. Defaults to None.
+    pub send_hook: Option<Box<SendHook + Send + 'a>>,
+    /// How to connect to the server. None (the default) means a plain TCP
+    /// connection, see `TcpTransport`. With the "zlib" feature,
+    /// `ZlibTransport` compresses the link for bouncers that support it,
+    /// after the TLS handshake if there is one.
+    pub transport: Option<Box<Transport + Send + 'a>>,
+    /// The TLS settings given to the transport, which has to support TLS.
+    /// If they say to, the session of the last connection is resumed when
+    /// reconnecting to the same server. Defaults to None, for no TLS.
+    pub tls: Option<TlsOptions>,
+    /// How to run the tasks that read and write lines, and the ident
+    /// responder, as well as the task started by `connect_async()` and the
+    /// workers of `connect_pooled()`. None (the default) means `TaskSpawner`.
+    pub spawner: Option<Box<Spawner + Send + 'a>>,
+    /// The SRV service to look up for the host, e.g. "_ircs._tcp" or "_irc._tcp".
+    /// If set, `host` can be just the network's domain: the servers from its SRV
+    /// records are tried in order, falling back to `host` and `port` themselves.
+    /// Defaults to None, which skips the lookup.
+    pub srv_service: Option<&'a str>,
+    /// How to look up SRV records. None (the default) means `DnsResolver`.
+    pub resolver: Option<Box<Resolver + Send + 'a>>,
+    /// If set, a message received again within this long, with the same sender,
+    /// target, text and server-time tag, isn't sent to the callback. Messages
+    /// with a `msgid` tag are told apart by it instead. Messages with neither
+    /// tag are never dropped, since someone may well say the same thing twice.
+    /// This hides the overlap between a bouncer's playback and live traffic,
+    /// which needs the "server-time" or "message-tags" capability.
+    /// Defaults to None, which sends every message.
+    pub dedup_window: Option<Duration>,
+    /// Whether lines other than the ones used to register (NICK, USER, PASS,
+    /// WEBIRC, CAP, AUTHENTICATE, PING, PONG and QUIT) are held back until the server
+    /// has welcomed us, instead of being sent right away and rejected with
+    /// ERR_NOTREGISTERED (451). At most 100 lines are held; the ones after
+    /// that are dropped. Held lines are forgotten if the connection is lost
+    /// before registration. Defaults to `true`.
+    pub hold_until_registered: bool,
+    /// Channels to join once connected, as (channel, key) pairs. Use an empty
+    /// key for channels that don't have one.
+    ///
+    /// They're joined with `Conn::join_many()` once the server has sent the
+    /// MOTD (or said there isn't one), on every connection.
+    pub join_on_connect: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Whether to remember when each nick was last seen, for `Conn::last_seen()`.
+    /// Only what's seen in channels is remembered, not private messages to
+    /// us, and only for the 10000 nicks seen most recently. The sightings are
+    /// saved to `store`, if there is one. Defaults to `false`.
+    pub track_seen: bool,
+    /// Routine notices that aren't sent to the callback as LineReceived
+    /// events, e.g. `Noise::all()`. Defaults to none.
+    pub ignore_noise: Vec<Noise>,
+    /// Whether to drop the messages, notices and CTCPs sent from our own nick,
+    /// like the echoes of echo-message and a bouncer playing back what we
+    /// said, before anything handles them. No LineReceived events are sent
+    /// for them and they aren't answered, which keeps a bot from replying to
+    /// itself. Defaults to `false`.
+    pub ignore_own_messages: bool,
+    /// IRCv3 capabilities to ask the server for, e.g. `vec!["away-notify",
+    /// "extended-join"]`. The ones the server offers are requested before
+    /// registering, see `Conn::caps()`. Servers that don't support CAP
+    /// register us without any. Defaults to none, which skips negotiation.
+    pub caps: Vec<&'a str>,
+    /// How to log in while registering, e.g. `SaslPlain` or `ServerPassword`.
+    /// An authenticator with a SASL mechanism adds "sasl" to the capabilities
+    /// asked for; if the server doesn't offer it, registration goes ahead
+    /// without logging in. Defaults to None.
+    pub authenticator: Option<Box<Authenticator + Send + 'a>>,
+    /// The port to answer ident (RFC 1413) queries on, usually 113, with
+    /// `user` as the username. Some networks delay or refuse clients without
+    /// ident. The responder only runs from connecting until the server has
+    /// registered us, and answers NO-USER for other connections. Listening on
+    /// ports below 1024 needs privileges; if it can't listen, connecting goes
+    /// ahead without it. Defaults to None.
+    pub identd: Option<u16>,
+    /// For web gateways: sends a WEBIRC command before registering, so that
+    /// the server shows the real client's host and IP instead of ours. The
+    /// server has to be configured to trust the gateway. Defaults to None.
+    pub webirc: Option<WebircConfig>,
+    /// A PROXY protocol header to send as soon as the connection is open, for
+    /// servers behind a load balancer that expects one. This only works with
+    /// transports that don't send anything themselves while connecting, like
+    /// `TcpTransport`. Defaults to None.
+    pub proxy_header: Option<ProxyHeader>,
+    /// Whether to turn off Nagle's algorithm on the socket (TCP_NODELAY), so
+    /// that short lines are sent right away. Defaults to `false`.
+    pub tcp_nodelay: bool,
+    /// If set, TCP keepalive probes are sent after the socket has been idle
+    /// this long, which keeps NAT mappings alive on quiet connections. This
+    /// is separate from `ping_timeout`. Defaults to None.
+    pub tcp_keepalive: Option<Duration>,
+    /// If set, the QUITs of users lost in a netsplit aren't sent to the
+    /// callback one by one. Once no more have arrived for this long, they're
+    /// sent as a single Netsplit event. When the users come back, their JOINs
+    /// are grouped into a Netjoin event the same way, if they come back with
+    /// the same `nick!user@host` within 15 minutes. Defaults to None.
+    pub netsplit_window: Option<Duration>,
+    /// How many CTCP queries are answered automatically, to keep a CTCP flood
+    /// from making us flood the server with replies. Queries over the limits
+    /// are dropped with a CtcpDropped event. None answers every query.
+    /// Defaults to 3 per sender and 10 in total per 30 seconds.
+    pub ctcp_limits: Option<CtcpLimits>,
+}
+
+/// A line sent or received, as seen by `Options.tap`.
+/// The lines don't include the terminating \r\n.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum RawLine {
+    /// A line received from the server
+    Inbound(Vec<u8>),
+    /// A line sent to the server
+    Outbound(Vec<u8>)
+}
+
+/// How PINGs from the server are handled
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum PingMode {
+    /// PINGs are answered with a PONG automatically. This is the default.
+    AutoPong,
+    /// PINGs are not answered. Instead a PingReceived event is sent, and it's up
+    /// to the callback to answer with `Conn::pong()`, e.g. after forwarding the
+    /// PING to a downstream client.
+    ManualPong
+}
+
+/// How `Conn::reply()` answers messages. See `Options.reply_policy`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ReplyPolicy {
+    /// PRIVMSGs are answered with a PRIVMSG, and NOTICEs aren't answered at
+    /// all, as RFC 1459 asks of automatic replies so that two bots can't get
+    /// into a loop. This is the default.
+    SkipNotices,
+    /// Everything is answered with a NOTICE
+    ReplyWithNotice,
+    /// Everything is answered with a PRIVMSG, including NOTICEs
+    ReplyWithPrivmsg
+}
+
+/// Which INVITEs are accepted automatically. See `Options.auto_join_invites`.
+///
+/// An invite is accepted if it comes from one of `inviters` and is for one of
+/// `channels`. An empty list allows anything. Inviters are matched by nick only,
+/// so on networks without nick registration anybody can use an allowed nick.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct InvitePolicy {
+    /// The nicks whose invites are accepted
+    pub inviters: Vec<Vec<u8>>,
+    /// The channels that we accept invites to
+    pub channels: Vec<Vec<u8>>
+}
+
+impl InvitePolicy {
+    /// Returns a policy that accepts every invite
+    pub fn any() -> InvitePolicy {
+        InvitePolicy { inviters: Vec::new(), channels: Vec::new() }
+    }
+}
+
+/// The user modes that can be asked for when registering, as the mode
+/// bitmask of the USER command (RFC 2812). Servers that use the older RFC
+/// 1459 form ignore it. See `Options.user_modes`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct RegistrationModes {
+    /// Whether to be invisible (+i), i.e. hidden from WHO and NAMES for
+    /// users who don't share a channel with us
+    pub invisible: bool,
+    /// Whether to receive WALLOPS (+w)
+    pub wallops: bool
+}
+
+impl RegistrationModes {
+    /// Returns the bitmask sent in the USER command
+    pub fn mask(&self) -> uint {
+        (if self.wallops { 4 } else { 0 }) | (if self.invisible { 8 } else { 0 })
+    }
+}
+
+/// What a web gateway tells the server about the client it connects for.
+/// See `Options.webirc`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct WebircConfig {
+    /// The password configured for the gateway on the server
+    pub password: String,
+    /// The name of the gateway, e.g. "kiwiirc"
+    pub gateway: String,
+    /// The client's hostname. Use the IP address if it has no reverse DNS.
+    pub hostname: String,
+    /// The client's IP address
+    pub ip: String
 }
 
 impl<'a, Payload> Options<'a, Payload> {
@@ -65,24 +457,291 @@ impl<'a, Payload> Options<'a, Payload> {
             nick: "ircnick",
             user: "ircuser",
             real: "rust-irclib user",
-            commands: None
+            user_modes: RegistrationModes { invisible: true, wallops: false },
+            commands: None,
+            join_delay: Duration::seconds(1),
+            handle_ping: AutoPong,
+            follow_redirects: false,
+            ping_timeout: Some(Duration::seconds(240)),
+            auto_join_invites: None,
+            reply_policy: SkipNotices,
+            rejoin_on_kick: None,
+            services_interval: Duration::seconds(2),
+            store: None,
+            subscribe: None,
+            tap: None,
+            transport: None,
+            tls: None,
+            spawner: None,
+            srv_service: None,
+            resolver: None,
+            dedup_window: None,
+            hold_until_registered: true,
+            join_on_connect: Vec::new(),
+            track_seen: false,
+            ignore_noise: Vec::new(),
+            ignore_own_messages: false,
+            caps: Vec::new(),
+            authenticator: None,
+            send_hook: None,
+            identd: None,
+            webirc: None,
+            proxy_header: None,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            netsplit_window: None,
+            ctcp_limits: Some(CtcpLimits::new(3, 10, Duration::seconds(30)))
         }
     }
 }
 
 /// Typedef for commands that can be sent to the commands Port
-pub type Cmd<Payload=()> = proc(&mut Conn, &mut Payload) : Send;
+pub type Cmd<Payload=()> = proc(&mut Conn, &mut Payload, CmdState) : Send;
+
+/// Whether the connection a command runs on is active, see `Options.commands`
+#[deriving(Clone, Show)]
+pub enum CmdState {
+    /// The connection is active, and what the command sends is sent
+    CmdConnected,
+    /// The connection was lost for the given reason before the command ran.
+    /// Nothing the command sends goes anywhere.
+    CmdDisconnected(DisconnectReason)
+}
 
 /// Events that can be handled in the callback
+///
+/// The lifecycle events are sent in the order Connecting, Connected, Registering,
+/// Registered, Disconnected. If the connection attempt fails, connect() returns an
+/// error after Connecting and no further events are sent. When the connection is
+/// re-established, because the callback asked to reconnect or a server redirect is
+/// being followed, Disconnected is followed by ReconnectScheduled and then
+/// Connecting for the new connection.
+///
+/// Events that are caused by a received line are sent before the LineReceived
+/// event for that line.
+///
+/// Events own all their data and are Send, so they can be handed off to other
+/// tasks for processing. See `connect_async()`.
+#[deriving(Clone)]
 pub enum Event {
-    /// The connection was established
+    /// A TCP connection to the server is being opened
+    Connecting,
+    /// The TCP connection was established
     Connected,
+    /// The registration commands (NICK/USER) have been sent and we're waiting
+    /// for the server to accept them
+    Registering,
+    /// The server accepted our registration (001 was received).
+    /// This is sent right before the LineReceived event for the 001.
+    Registered(Registration),
+    /// Our nick was shortened to fit the server's NICKLEN. The values are the
+    /// nick that was asked for and the one used instead. This is sent when
+    /// `Conn::set_nick()` truncates a nick, and right after Registered when
+    /// the server truncated the nick we registered with.
+    NickTruncated(Vec<u8>, Vec<u8>),
     /// A line was received from the server.
     /// This event is not sent until the user has successfully logged in.
     /// The first received line should be 001
     LineReceived(Line),
-    /// The connection has terminated
-    Disconnected
+    /// A PING was received while using ManualPong. The value is the ping token,
+    /// which should be sent back with `Conn::pong()`.
+    /// Unlike LineReceived, this can be sent before registration is complete.
+    PingReceived(Vec<u8>),
+    /// The server told us to use another server instead (RPL_BOUNCE, 010).
+    /// The values are the host and port. If `Options.follow_redirects` is set,
+    /// the connection is closed and re-established with that server.
+    Redirected(String, u16),
+    /// The server replied with an error numeric (4xx or 5xx).
+    /// Errors during registration that are handled internally, like the nick
+    /// already being in use, are sent too.
+    ErrorReply(IrcError),
+    /// The server sent an IRCv3 standard reply: a FAIL, WARN or NOTE. Like
+    /// ErrorReply, this can be sent before registration is complete.
+    StandardReplyReceived(StandardReply),
+    /// We couldn't join a channel we sent a JOIN for. The values are the channel
+    /// and the error that the server replied with. The ErrorReply for the error is
+    /// sent as well.
+    JoinFailed(Vec<u8>, IrcError),
+    /// We'll try to rejoin a channel we were kicked from, with
+    /// `Options.rejoin_on_kick` set. The values are the channel and how long
+    /// until the JOIN is sent. This is sent after the KICK, and again with a
+    /// longer delay after each JoinFailed for the channel.
+    RejoinScheduled(Vec<u8>, Duration),
+    /// We gave up on rejoining a channel we were kicked from, because the
+    /// server refused too many JOINs. The values are the channel and the
+    /// last error.
+    RejoinGaveUp(Vec<u8>, IrcError),
+    /// A request made with one of the ChanServ helpers, like
+    /// `Conn::chanserv_op()`, went through. The values are the channel and
+    /// the request.
+    ChanServDone(Vec<u8>, ChanServRequest),
+    /// ChanServ refused a request made with one of the ChanServ helpers. The
+    /// values are the channel, the request and ChanServ's message.
+    ChanServFailed(Vec<u8>, ChanServRequest, Vec<u8>),
+    /// Logging in with SASL failed or was aborted, see `Options.authenticator`.
+    /// The value is the server's message. Registration goes on without an
+    /// account.
+    AuthFailed(Vec<u8>),
+    /// A channel we sent a JOIN for forwarded us to another channel
+    /// (ERR_LINKCHANNEL, 470). The values are the channel and the one we're
+    /// joined to instead. If the channel is in `Options.join_on_connect`, the
+    /// other one is joined on later connections.
+    JoinForwarded(Vec<u8>, Vec<u8>),
+    /// Somebody invited us to a channel. The values are the inviter, if known,
+    /// and the channel. If the invite matches `Options.auto_join_invites`, the
+    /// channel is joined after this event.
+    Invited(Option<User>, Vec<u8>),
+    /// A nick on the watch list came online. See `Conn::watch()`.
+    UserOnline(Vec<u8>),
+    /// A nick on the watch list went offline. See `Conn::watch()`.
+    UserOffline(Vec<u8>),
+    /// Messages held back by `Conn::privmsg_or_hold()` were sent, because the
+    /// nick came back. The values are the nick and the number of messages.
+    MessagesDelivered(Vec<u8>, uint),
+    /// Someone sent us a DCC request. The values are the sender and the request.
+    DccRequest(User, Dcc),
+    /// A CTCP query wasn't answered because it went over
+    /// `Options.ctcp_limits`. The values are the sender and the CTCP command.
+    CtcpDropped(User, Vec<u8>),
+    /// The replies to a WHO query have all arrived. The values are the mask
+    /// that was queried and the matching users.
+    WhoReplies(Vec<u8>, Vec<WhoReply>),
+    /// A channel watched with `Conn::set_flood_policy()` is being join flooded.
+    /// The values are the channel and the users whose joins made up the flood.
+    JoinFlood(Vec<u8>, Vec<User>),
+    /// The server sent us a server notice (a NOTICE starting with `***`),
+    /// such as the client connection notices sent to IRC operators.
+    /// The notice is parsed with the patterns in `Conn::quirks()`.
+    ServerNotice(Snotice),
+    /// A WALLOPS was sent to users with user mode +w. The values are the
+    /// sender (a user or a server), if known, and the message.
+    Wallops(Option<Prefix>, Vec<u8>),
+    /// A GLOBOPS was sent to the IRC operators, on servers that deliver them
+    /// as GLOBOPS lines. The values are the sender, if known, and the message.
+    Globops(Option<Prefix>, Vec<u8>),
+    /// The server changed our displayed host, e.g. when a vhost was turned
+    /// on or a cloak was applied (RPL_HOSTHIDDEN, 396). The value is the new host.
+    HostChanged(Vec<u8>),
+    /// A channel repaired with `Conn::resync()` has been updated from the
+    /// server's replies. The values are the channel and what was out of sync.
+    ChannelResynced(Vec<u8>, ChannelResync),
+    /// Users quit because two servers lost their link, with
+    /// `Options.netsplit_window` set. The values are the two servers and the
+    /// users. Their QUITs aren't sent as LineReceived events.
+    Netsplit(Vec<u8>, Vec<u8>, Vec<User>),
+    /// Users lost in a netsplit came back, with `Options.netsplit_window` set.
+    /// The values are the two servers of the split and the users. Their JOINs
+    /// aren't sent as LineReceived events.
+    Netjoin(Vec<u8>, Vec<u8>, Vec<User>),
+    /// The channel list asked for with `Conn::refresh_channel_list()` has
+    /// arrived. The value is the number of channels in it.
+    ChannelListUpdated(uint),
+    /// Someone started or stopped typing a message to a channel or to us, as
+    /// told by the `+typing` tag, which needs the "message-tags" capability.
+    /// The values are the user, the target and the state.
+    Typing(User, Vec<u8>, TypingState),
+    /// The server sent the read marker of a channel or nick, because we set
+    /// or asked for it, joined the channel, or another client of the same
+    /// account moved it. The values are the target and the time it was read
+    /// up to, or None if it has never been marked read.
+    /// See `Conn::set_read_marker()`.
+    ReadMarkerChanged(Vec<u8>, Option<String>),
+    /// Someone reacted to a message, as told by the `+draft/react` tag, which
+    /// needs the "message-tags" capability. The values are the user, the
+    /// target of the message, its ID and the reaction. See `Conn::react()`.
+    Reacted(User, Vec<u8>, String, String),
+    /// Someone sent a CTCP query (other than ACTION) to us or to a channel.
+    /// The values are the sender and the query. This is sent before the
+    /// built-in handling of PING and DCC, and before the LineReceived event.
+    /// Returning Handled skips the built-in handling, e.g. to answer PINGs or
+    /// take DCC requests differently.
+    CtcpQuery(User, Ctcp),
+    /// The server changed the features it supports with another 005 line,
+    /// e.g. after a rehash. The values are the tokens that were added or
+    /// changed, with their new value, and the ones that were removed, with
+    /// None. The 005 lines sent while connecting don't cause this event.
+    /// The accessors of `Conn::isupport()` and the channel state already use
+    /// the new values.
+    ISupportChanged(Vec<(String, Option<Vec<u8>>)>),
+    /// Someone changed the topic of a channel guarded with
+    /// `Conn::guard_topic()`, and we set it back. The values are the channel
+    /// and the topic that was replaced.
+    TopicRestored(Vec<u8>, Vec<u8>),
+    /// The connection has terminated, for the given reason
+    Disconnected(DisconnectReason),
+    /// We're going to connect again, because the callback returned Reconnect
+    /// (e.g. after a ping timeout) or an RPL_BOUNCE redirect is being
+    /// followed. The values are the host and port to connect to, and how long
+    /// until then, which is None when it happens right away. Connecting is
+    /// sent next.
+    ReconnectScheduled(String, u16, Option<Duration>)
+}
+
+/// What the callback wants the connection to do after handling an event
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Control {
+    /// Carry on as usual
+    Continue,
+    /// The callback dealt with the event itself. For a CtcpQuery, this keeps
+    /// the query from being answered automatically. For other events, it's
+    /// the same as Continue.
+    Handled,
+    /// Quit with the given message, as with `Conn::quit()`.
+    /// Pass an empty message to use the default.
+    Quit(Vec<u8>),
+    /// Quit, and connect to the same server again once disconnected.
+    /// When returned for the Disconnected event, this reconnects even if the
+    /// connection was lost because of an error.
+    Reconnect
+}
+
+/// Why a connection was terminated
+#[deriving(Clone, Show)]
+pub enum DisconnectReason {
+    /// We sent a QUIT
+    DisconnectQuit,
+    /// We were killed by an IRC operator or by services.
+    /// The values are the killer, if known, and the reason.
+    DisconnectKilled(Option<User>, Vec<u8>),
+    /// The server sent an ERROR, usually because of a ban. The value is the error message.
+    DisconnectError(Vec<u8>),
+    /// The server stopped responding. See `Options.ping_timeout`.
+    DisconnectPingTimeout,
+    /// The socket returned an error
+    DisconnectIoError(IoError),
+    /// The server closed the connection without telling us why
+    DisconnectClosed
+}
+
+/// How the last connection went, as returned by connect() once it has terminated
+#[deriving(Clone, Show)]
+pub struct DisconnectSummary {
+    /// Why the connection was terminated
+    pub reason: DisconnectReason,
+    /// How long the connection was open
+    pub duration: Duration,
+    /// The number of bytes sent, counting two bytes per line for the terminator
+    pub bytes_sent: u64,
+    /// The number of bytes received, counted the same way
+    pub bytes_received: u64,
+    /// The last error numeric (4xx or 5xx) the server sent, if any
+    pub last_error: Option<IrcError>,
+    /// The message of the ERROR the server sent before closing the
+    /// connection, if any. This is kept even when `reason` is something else,
+    /// e.g. because we quit.
+    pub server_error: Option<Vec<u8>>
+}
+
+/// Details of a successful registration, carried by the Registered event
+#[deriving(Clone)]
+pub struct Registration {
+    /// The nickname the server assigned us. This can differ from the nickname
+    /// that was requested, e.g. on servers that truncate long nicks.
+    pub nick: Vec<u8>,
+    /// The server that welcomed us, if the 001 had a prefix
+    pub server: Option<User>,
+    /// The text of the welcome message
+    pub welcome: Vec<u8>,
 }
 
 /// Errors that can be returned from connect()
@@ -90,78 +749,411 @@ pub enum Error {
     /// Error connecting to server
     ErrConnect(IoError),
     /// I/O error raised while connection is active
-    ErrIO(IoError)
+    ErrIO(IoError),
+    /// The callback panicked, with the given message. The connection was
+    /// closed after sending a QUIT.
+    ErrCallbackPanicked(String)
 }
 
 impl fmt::Show for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ErrConnect(ref err) => { write!(f, "connect error: {}", *err) }
-            ErrIO(ref err) => err.fmt(f)
+            ErrIO(ref err) => err.fmt(f),
+            ErrCallbackPanicked(ref msg) => write!(f, "callback panicked: {}", *msg)
         }
     }
 }
 
 /// Typedef for connection results
-pub type Result = ::std::result::Result<(),Error>;
+pub type Result = ::std::result::Result<DisconnectSummary,Error>;
 
 pub static DefaultPort: u16 = 6667;
 
 /// Connects to the remote server. This method will not return until the connection
-/// is terminated. Returns Ok(()) after connection termination if the connection was
-/// established successfully, or Err(_) if the connection could not be established in the
+/// is terminated. Returns Ok with a summary of the last connection after connection
+/// termination if the connection was established successfully, or Err(_) if the connection could not be established in the
 /// first place, or if an error is thrown while the connection is active.
 ///
+/// The callback returns a Control after each event, which lets it quit or reconnect
+/// without going through the commands channel.
+///
 /// This method spawns some I/O-blocked tasks, so it is recommended that it be called
 /// from a libgreen task.
 ///
+/// If the callback panics, the connection sends a QUIT, closes the socket and
+/// stops its I/O tasks, and this returns ErrCallbackPanicked. The callback
+/// isn't called again after that, not even for Disconnected.
+///
 /// Note: If your Conn has no payload, you should pass () as the payload parameter.
-pub fn connect<Payload>(opts: Options<Payload>, mut payload: Payload,
-                        cb: |&mut Conn, Event, &mut Payload|) -> Result {
-    let stream = match TcpStream::connect((opts.host, opts.port)) {
-        Err(e) => return Err(ErrConnect(e)),
-        Ok(stream) => stream
-    };
+pub fn connect<Payload>(mut opts: Options<Payload>, mut payload: Payload,
+                        cb: |&mut Conn, Event, &mut Payload| -> Control) -> Result {
+    let mut conn = Conn::new(&mut opts);
+    if opts.track_seen {
+        seen::enable(&mut conn);
+    }
+    persist::load(&mut conn);
 
-    let mut conn = Conn{
-        host: opts.host,
-        write_tx: None,
-        logged_in: false,
-        user: User::new(opts.nick.as_bytes(), Some(opts.user.as_bytes()), None),
+    // servers found with SRV records, tried before the configured host
+    let mut candidates = match opts.srv_service {
+        None => Vec::new(),
+        Some(service) => {
+            let mut resolver = opts.resolver.take().unwrap_or_else(|| {
+                box DnsResolver::new() as Box<Resolver + Send>
+            });
+            let name = format!("{}.{}", service, opts.host);
+            match resolver.resolve_srv(name.as_slice()) {
+                Ok(records) => dns::order_records(records),
+                Err(e) => {
+                    info!("SRV lookup for {} failed: {}", name, e);
+                    Vec::new()
+                }
+            }
+        }
     };
 
-    cb(&mut conn, Connected, &mut payload);
+    loop {
+        // the event loop isn't running yet, so the callback's Control is ignored
+        // for Connecting and Connected
+        conn.call(Connecting, &mut payload, &mut cb);
+        try!(conn.check_panicked());
+
+        conn.identd = match opts.identd {
+            None => None,
+            Some(port) => match Identd::start(port, opts.user, &mut *conn.spawner) {
+                Ok(identd) => Some(identd),
+                Err(e) => {
+                    info!("Couldn't start the ident responder on port {}: {}", port, e);
+                    None
+                }
+            }
+        };
+        let candidates = mem::replace(&mut candidates, Vec::new());
+        let mut connection = match conn.open(candidates) {
+            Err(e) => return Err(ErrConnect(e)),
+            Ok(connection) => connection
+        };
+        set_socket_options(&mut connection.socket, &opts);
+        match opts.proxy_header {
+            None => (),
+            Some(ref header) => match proxy::send_header(&mut connection.socket, header) {
+                Err(e) => return Err(ErrConnect(e)),
+                Ok(()) => ()
+            }
+        }
+        match (conn.identd.as_ref(), connection.socket.socket_name()) {
+            (Some(identd), Ok(addr)) => identd.set_ports(addr.port, conn.port),
+            _ => ()
+        }
+
+        conn.call(Connected, &mut payload, &mut cb);
+        // dropping the connection closes it, as the I/O tasks haven't started
+        try!(conn.check_panicked());
 
-    let res = conn.run(stream, opts, &mut payload, |c,e,p| cb(c,e,p));
+        let res = conn.run(connection, &opts, &mut payload, |c,e,p| cb(c,e,p));
+        try!(conn.check_panicked());
+        let summary = conn.summary();
 
-    cb(&mut conn, Disconnected, &mut payload);
+        let reason = summary.reason.clone();
+        let reconnect = conn.call(Disconnected(reason), &mut payload, &mut cb) == Some(Reconnect);
+        try!(conn.check_panicked());
 
-    match res {
-        Err(e) => Err(ErrIO(e)),
-        Ok(()) => Ok(())
+        let next = match conn.redirect.take() {
+            Some(next) if res.is_ok() => Some(next),
+            _ if reconnect => Some((conn.host.as_slice().to_string(), conn.port)),
+            _ => None
+        };
+        match next {
+            Some((host, port)) => {
+                conn.reset(&opts);
+                conn.host = host.clone().into_maybe_owned();
+                conn.port = port;
+                // like Connecting, the callback's Control is ignored
+                conn.call(ReconnectScheduled(host, port, None), &mut payload, &mut cb);
+                try!(conn.check_panicked());
+            }
+            None => {
+                // run what was sent in response to Disconnected
+                conn.drain_commands(&opts, &mut payload, &mut cb);
+                return match res {
+                    Err(e) => Err(ErrIO(e)),
+                    Ok(()) => Ok(summary)
+                };
+            }
+        }
+    }
+}
+
+/// The socket options set by `set_socket_options()`
+trait SocketOptions {
+    fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()>;
+    fn set_keepalive(&mut self, delay_in_seconds: Option<uint>) -> IoResult<()>;
+}
+
+impl SocketOptions for TcpStream {
+    // the inherent methods are the ones called here
+    fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()> {
+        self.set_nodelay(nodelay)
+    }
+
+    fn set_keepalive(&mut self, delay_in_seconds: Option<uint>) -> IoResult<()> {
+        self.set_keepalive(delay_in_seconds)
+    }
+}
+
+/// Applies `Options.tcp_nodelay` and `Options.tcp_keepalive`. Failures are
+/// only logged, since the connection works without them.
+fn set_socket_options<S: SocketOptions, Payload>(socket: &mut S, opts: &Options<Payload>) {
+    if opts.tcp_nodelay {
+        match socket.set_nodelay(true) {
+            Ok(()) => (),
+            Err(e) => info!("Couldn't set TCP_NODELAY: {}", e)
+        }
+    }
+    match opts.tcp_keepalive {
+        None => (),
+        Some(idle) => match socket.set_keepalive(Some(max(idle.num_seconds(), 1) as uint)) {
+            Ok(()) => (),
+            Err(e) => info!("Couldn't turn on TCP keepalive: {}", e)
+        }
+    }
+}
+
+/// A connection running on its own task, as returned by `connect_async()`
+pub struct Handle<Payload=()> {
+    /// The events of the connection. This is closed once the connection
+    /// has terminated.
+    pub events: Receiver<Event>,
+    /// Sends commands to be run on the connection's task, like the
+    /// `Options.commands` channel.
+    pub commands: Sender<Cmd<Payload>>,
+    result: Receiver<Result>
+}
+
+impl<Payload: Send> Handle<Payload> {
+    /// Waits for the connection to terminate and returns what connect() returned
+    pub fn wait(self) -> Result {
+        match self.result.recv_opt() {
+            Ok(res) => res,
+            // the connection task failed
+            Err(()) => Err(ErrIO(IoError {
+                kind: io::OtherIoError,
+                desc: "connection task failed",
+                detail: None
+            }))
+        }
+    }
+
+    /// Returns what connect() returned, or None if the connection is still active
+    pub fn try_result(&self) -> Option<Result> {
+        self.result.try_recv().ok()
+    }
+}
+
+/// Connects to the remote server on a new task and returns right away.
+///
+/// This is an alternative to `connect()` for programs with their own main loop.
+/// Events are delivered over `Handle.events` instead of to a callback, and
+/// anything that needs the Conn is done by sending a command with
+/// `Handle.commands`. Any `opts.commands` channel is replaced by that one.
+pub fn connect_async<Payload: Send>(mut opts: Options<'static, Payload>,
+                                    payload: Payload) -> Handle<Payload> {
+    let (cmd_tx, cmd_rx) = channel();
+    opts.commands = Some(cmd_rx);
+    let (event_tx, event_rx) = channel();
+    let (result_tx, result_rx) = channel();
+    let mut spawner = opts.spawner.take().unwrap_or_else(|| box TaskSpawner as Box<Spawner + Send>);
+    let (spawner_tx, spawner_rx) = channel();
+    spawner.spawn("libirc connection", proc() {
+        // the connection starts its own tasks with the same spawner
+        let mut opts = opts;
+        opts.spawner = Some(spawner_rx.recv());
+        let res = connect(opts, payload, |_, event, _| {
+            // nobody is listening if the Handle was dropped
+            let _ = event_tx.send_opt(event);
+            Continue
+        });
+        let _ = result_tx.send_opt(res);
+    });
+    spawner_tx.send(spawner);
+    Handle {
+        events: event_rx,
+        commands: cmd_tx,
+        result: result_rx
     }
 }
 
 impl<'a> Conn<'a> {
-    fn run<Payload>(&mut self, stream: TcpStream, opts: Options<Payload>, payload: &mut Payload,
-                    cb: |&mut Conn, Event, &mut Payload|) -> IoResult<()> {
+    /// Returns a Conn that isn't connected yet, taking the transport, store
+    /// and spawner out of `opts`
+    fn new<Payload>(opts: &mut Options<'a, Payload>) -> Conn<'a> {
+        Conn {
+            host: opts.host.into_maybe_owned(),
+            port: opts.port,
+            stream: None,
+            write_tx: None,
+            logged_in: false,
+            user: User::new(opts.nick.as_bytes(), Some(opts.user.as_bytes()), None),
+            isupport: ISupport::new(),
+            quirks: Quirks::new(),
+            state: State::new(),
+            server_name: None,
+            extensions: Extensions::new(),
+            events: Vec::new(),
+            scheduled: Vec::new(),
+            join_delay: opts.join_delay,
+            ping_mode: opts.handle_ping,
+            follow_redirects: opts.follow_redirects,
+            redirect: None,
+            ping_timeout: opts.ping_timeout,
+            last_read: 0,
+            ping_sent: false,
+            quitting: false,
+            quit_deadline: None,
+            disconnect: None,
+            invite_policy: opts.auto_join_invites.clone(),
+            reply_policy: opts.reply_policy,
+            rejoin_policy: opts.rejoin_on_kick.clone(),
+            services_interval: opts.services_interval,
+            ctcp_limits: opts.ctcp_limits.clone(),
+            store: opts.store.take(),
+            tap: opts.tap.clone(),
+            hold_until_registered: opts.hold_until_registered,
+            held_lines: Vec::new(),
+            join_on_connect: opts.join_on_connect.clone(),
+            autojoined: false,
+            user_modes: Vec::new(),
+            panicked: None,
+            connected_at: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            last_error: None,
+            server_error: None,
+            identd: None,
+            transport: opts.transport.take().unwrap_or_else(|| box TcpTransport as Box<Transport + Send>),
+            tls: opts.tls.clone(),
+            tls_session: None,
+            spawner: opts.spawner.take().unwrap_or_else(|| box TaskSpawner as Box<Spawner + Send>),
+            authenticator: opts.authenticator.take(),
+            send_hook: opts.send_hook.take(),
+            clock: None,
+        }
+    }
+
+    /// Returns the current time in nanoseconds, which is the manual clock
+    /// for a Conn driven by `Manual`
+    fn now(&self) -> u64 {
+        match self.clock {
+            None => time::precise_time_ns(),
+            Some(now) => now
+        }
+    }
+
+    /// Returns the time in seconds since the epoch. A Conn driven by `Manual`
+    /// was created at the epoch as far as this is concerned, so that it follows
+    /// the manual clock.
+    fn epoch_secs(&self) -> i64 {
+        match self.clock {
+            None => time::get_time().sec,
+            Some(now) => (now / 1_000_000_000) as i64
+        }
+    }
+
+    /// Sets the manual clock, in nanoseconds
+    fn set_clock(&mut self, now: u64) {
+        self.clock = Some(now);
+        self.state.set_clock(now);
+    }
+
+    /// Casemaps the names that the extensions' tables are keyed by again,
+    /// after the server changed its CASEMAPPING, like `State` does for
+    /// channels and users. Known accounts and the typing notifications sent
+    /// are only caches, so they're forgotten instead.
+    fn rekey(&mut self) {
+        accounts::rekey(self);
+        rejoin::rekey(self);
+        seen::rekey(self);
+        topicguard::rekey(self);
+        typing::rekey(self);
+    }
+
+    /// Resets the state of the connection and sends the registration
+    /// commands, once lines can be written with `write_tx`
+    fn begin<Payload>(&mut self, opts: &Options<Payload>, write_tx: Sender<Vec<u8>>) {
+        self.write_tx = Some(write_tx);
+        self.last_read = self.now();
+        self.ping_sent = false;
+        self.quitting = false;
+        self.quit_deadline = None;
+        self.autojoined = false;
+        self.disconnect = None;
+        self.connected_at = self.now();
+        self.bytes_sent = 0;
+        self.bytes_received = 0;
+        self.last_error = None;
+        self.server_error = None;
+        self.user_modes.clear();
+        self.server_name = None;
+        self.held_lines.clear();
+        accounts::reset(self);
+        autoop::reset(self);
+        handlers::reset(self);
+        joinflood::reset(self);
+        list::reset(self);
+        memo::reset(self);
+        netsplit::reset(self);
+        oper::reset(self);
+        rejoin::reset(self);
+        readmarker::reset(self);
+        seen::reset(self);
+        serverinfo::reset(self);
+        services::reset(self);
+        topicguard::reset(self);
+
+        // WEBIRC has to come first
+        match opts.webirc {
+            None => (),
+            Some(ref webirc) => {
+                self.send_command(IRCCmd("WEBIRC".into_maybe_owned()),
+                                  [webirc.password.as_bytes(), webirc.gateway.as_bytes(),
+                                   webirc.hostname.as_bytes(), webirc.ip.as_bytes()], false);
+            }
+        }
+        // PASS comes before NICK and USER too
+        let sasl = auth::start(self);
+        let mut wanted = opts.caps.clone();
+        if sasl && !wanted.contains(&"sasl") {
+            wanted.push("sasl");
+        }
+        caps::start(self, wanted.as_slice());
+        self.send_command(IRCCmd("NICK".into_maybe_owned()), [opts.nick.as_bytes()], false);
+        let mask = opts.user_modes.mask().to_string();
+        self.send_command(IRCCmd("USER".into_maybe_owned()), [opts.user.as_bytes(), mask.as_bytes(), b"*",
+                          realname(opts.real, opts.user).as_slice()], true);
+    }
+
+    fn run<Payload>(&mut self, connection: Connection, opts: &Options<Payload>, payload: &mut Payload,
+                    mut cb: |&mut Conn, Event, &mut Payload| -> Control) -> IoResult<()> {
         // spawn I/O tasks
         let (write_tx, write_rx) = channel();
-        self.write_tx = Some(write_tx);
         let (read_tx, read_rx) = channel();
         let (err_tx, err_rx) = channel();
+        // closed when the writer task exits
+        let (writer_done_tx, writer_done_rx) = channel::<()>();
 
+        let Connection { reader, writer, socket, .. } = connection;
+        self.stream = Some(socket);
         {
-            let stream = stream.clone();
             let err_tx = err_tx.clone();
-            TaskBuilder::new().named("libirc writer").spawn(proc() {
-                let mut stream = stream;
+            self.spawner.spawn("libirc writer", proc() {
+                let _done = writer_done_tx;
+                let mut writer = writer;
                 loop {
                     let line = match write_rx.recv_opt() {
                         Err(_) => break,
                         Ok(v) => v
                     };
-                    match stream.write(line.as_slice()).and_then(|_| stream.flush()) {
+                    match writer.write_line(line.as_slice()) {
                         Ok(_) => (),
                         Err(e) => {
                             if e.kind != io::EndOfFile {
@@ -174,10 +1166,10 @@ impl<'a> Conn<'a> {
             });
         }
         {
-            TaskBuilder::new().named("libirc reader").spawn(proc() {
-                let mut stream = BufferedStream::new(stream);
+            self.spawner.spawn("libirc reader", proc() {
+                let mut reader = reader;
                 loop {
-                    let mut line = match stream.read_until('\n' as u8) {
+                    let line = match reader.read_line() {
                         Ok(v) => v,
                         Err(e) => {
                             if e.kind != io::EndOfFile {
@@ -186,35 +1178,32 @@ impl<'a> Conn<'a> {
                             break;
                         }
                     };
-                    if !chomp_owned(&mut line) {
-                        // no line terminator? Must have hit EOF
+                    if read_tx.send_opt(line).is_err() {
                         break;
                     }
-                    if line.len() > 0 {
-                        if read_tx.send_opt(line).is_err() {
-                            break;
-                        }
-                    }
                 }
             })
         }
 
         // send handshake commands
-        self.send_command(IRCCmd("NICK".into_maybe_owned()), [opts.nick.as_bytes()], false);
-        self.send_command(IRCCmd("USER".into_maybe_owned()), [opts.user.as_bytes(), b"8 *",
-                          opts.real.as_bytes()], true);
+        self.begin(opts, write_tx);
+        self.dispatch(Registering, payload, &mut cb);
 
 
         // run event loop
         // need to do some shenanigans with scoping to make borrowck happy
         let mut result = Ok(());
-        let procs = {
+        let mut timer = try!(Timer::new());
+        let tick = timer.periodic(Duration::milliseconds(TICK_MS));
+        {
             let select = comm::Select::new();
             let mut read_handle = select.handle(&read_rx);
             unsafe { read_handle.add() }
             let mut err_handle = select.handle(&err_rx);
             unsafe { err_handle.add() }
-            let commands = opts.commands;
+            let mut tick_handle = select.handle(&tick);
+            unsafe { tick_handle.add() }
+            let commands = &opts.commands;
             let mut cmd_handle = commands.as_ref().map(|p| select.handle(p));
             if cmd_handle.is_some() {
                 unsafe { cmd_handle.as_mut().unwrap().add(); }
@@ -231,6 +1220,9 @@ impl<'a> Conn<'a> {
                         break;
                     }
                 }
+                if tick.try_recv().is_ok() {
+                    self.on_tick(payload, &mut cb);
+                }
                 if commands.is_some() {
                     match commands.as_ref().unwrap().try_recv() {
                         Err(comm::Empty) => (),
@@ -238,9 +1230,7 @@ impl<'a> Conn<'a> {
                             unsafe { cmd_handle.as_mut().unwrap().remove(); }
                             cmd_handle = None;
                         }
-                        Ok(cmd) => {
-                            cmd(self, payload);
-                        }
+                        Ok(cmd) => self.run_command(cmd, payload, &mut cb)
                     }
                 }
                 let line = match read_rx.try_recv() {
@@ -248,22 +1238,7 @@ impl<'a> Conn<'a> {
                     Err(comm::Disconnected) => break,
                     Ok(line) => line
                 };
-                let line = match Line::parse(line.as_slice()) {
-                    None => {
-                        let line = line.as_slice();
-                        info!("[DEBUG] Found non-parseable line: {}", String::from_utf8_lossy(line));
-                        continue;
-                    }
-                    Some(line) => line
-                };
-                if log_enabled!(::log::DEBUG) {
-                    let line = line.to_raw();
-                    debug!("[DEBUG] Received line: {}", String::from_utf8_lossy(line.as_slice()));
-                }
-                handlers::handle_line(self, &line);
-                if self.logged_in {
-                    cb(self, LineReceived(line), payload);
-                }
+                self.line_received(line, opts, payload, &mut cb);
             }
             if result.is_ok() {
                 // check the err_handle one more time
@@ -274,48 +1249,405 @@ impl<'a> Conn<'a> {
                     _ => ()
                 }
             }
+        }
+        // ensure our write handle is closed out, in case we stopped due to read shutting down
+        self.write_tx = None;
+        self.identd = None;
+        self.stream = None;
+        match self.quit_deadline {
+            None => (),
+            Some(deadline) => {
+                // give the writer until the deadline to get everything out
+                let now = self.now();
+                let remaining = if deadline > now { (deadline - now) / 1_000_000 } else { 0 };
+                let timeout = timer.oneshot(Duration::milliseconds(remaining as i64));
+                select! {
+                    _ = writer_done_rx.recv_opt() => (),
+                    () = timeout.recv() => info!("Gave up waiting for the QUIT to be sent")
+                }
+            }
+        }
+        match result {
+            Err(ref e) => self.disconnect = Some(DisconnectIoError(e.clone())),
+            Ok(_) if self.quitting && self.disconnect.is_none() => {
+                self.disconnect = Some(DisconnectQuit)
+            }
+            Ok(_) if self.disconnect.is_none() => self.disconnect = Some(DisconnectClosed),
+            Ok(_) => ()
+        }
+        // commands that were waiting run now, unless we're about to follow a
+        // redirect, in which case they'll run on the new connection
+        if self.redirect.is_none() {
+            self.drain_commands(opts, payload, &mut cb);
+        }
+        self.flush_events(payload, &mut cb);
 
-            // drain the commands
-            match commands {
-                None => None,
-                Some(ref port) => {
-                    let mut procs = Vec::new();
-                    loop {
-                        match port.try_recv() {
-                            Err(_) => break,
-                            Ok(cmd) => procs.push(cmd)
-                        }
-                    }
-                    Some(procs)
+        // return the result
+        result
+    }
+
+    /// Handles a line received from the server
+    fn line_received<Payload>(&mut self, line: Vec<u8>, opts: &Options<Payload>, payload: &mut Payload,
+                              cb: &mut |&mut Conn, Event, &mut Payload| -> Control) {
+        tap(&mut self.tap, Inbound(line.clone()));
+        self.bytes_received += line.len() as u64 + 2;
+        self.last_read = self.now();
+        self.ping_sent = false;
+        let line = match Line::parse(line.as_slice()) {
+            None => {
+                let line = line.as_slice();
+                info!("[DEBUG] Found non-parseable line: {}", String::from_utf8_lossy(line));
+                return;
+            }
+            Some(line) => line
+        };
+        if log_enabled!(::log::DEBUG) {
+            let line = line.to_raw();
+            debug!("[DEBUG] Received line: {}", String::from_utf8_lossy(line.as_slice()));
+        }
+        if opts.ignore_own_messages && self.logged_in && is_own_message(self, &line) {
+            debug!("[DEBUG] Dropping our own message");
+            return;
+        }
+        if self.logged_in && ignore::is_ignored(self, &line) {
+            debug!("[DEBUG] Dropping a line from an ignored user");
+            return;
+        }
+        let was_logged_in = self.logged_in;
+        let ctcp_claimed = self.logged_in && self.offer_ctcp(&line, payload, cb);
+        handlers::handle_line(self, &line, ctcp_claimed);
+        if self.logged_in && !was_logged_in {
+            // the server has done its ident lookup by now
+            self.identd = None;
+            for line in mem::replace(&mut self.held_lines, Vec::new()).into_iter() {
+                self.write_line(line);
+            }
+            auth::registered(self);
+            let reg = Registration {
+                nick: self.user.nick().to_vec(),
+                server: line.prefix.clone(),
+                welcome: if line.args.len() > 1 {
+                    line.args[line.args.len()-1].clone()
+                } else {
+                    Vec::new()
                 }
+            };
+            self.dispatch(Registered(reg), payload, cb);
+        }
+        let in_netsplit = self.logged_in &&
+            opts.netsplit_window.map_or(false, |w| netsplit::saw_line(self, w, &line));
+        self.flush_events(payload, cb);
+        let wanted = self.logged_in && !in_netsplit &&
+            !opts.dedup_window.map_or(false, |w| dedup::is_duplicate(self, w, &line)) &&
+            !noise::is_noise(self, opts.ignore_noise.as_slice(), &line);
+        if wanted {
+            routing::dispatch(self, &line);
+            self.flush_events(payload, cb);
+        }
+        if wanted && line.command.is_one_of(opts.subscribe) {
+            self.dispatch(LineReceived(line), payload, cb);
+        }
+    }
+
+    /// Sends a CtcpQuery event if `line` is a CTCP query. Returns `true` if
+    /// the callback returned Handled for it.
+    fn offer_ctcp<Payload>(&mut self, line: &Line, payload: &mut Payload,
+                           cb: &mut |&mut Conn, Event, &mut Payload| -> Control) -> bool {
+        let user = match line.prefix {
+            Some(ref user) if !user.is_server(&self.state) => user.clone(),
+            _ => return false
+        };
+        let ctcp = match line.command {
+            IRCCTCP(..) => line.ctcp().unwrap(),
+            _ => return false
+        };
+        self.dispatch(CtcpQuery(user, ctcp), payload, cb) == Some(Handled)
+    }
+
+    /// Does what's due on every tick of the event loop: pinging the server,
+    /// giving up on a QUIT and running scheduled jobs
+    fn on_tick<Payload>(&mut self, payload: &mut Payload,
+                        cb: &mut |&mut Conn, Event, &mut Payload| -> Control) {
+        self.check_ping_timeout();
+        self.check_quit_deadline();
+        self.run_scheduled();
+        self.flush_events(payload, cb);
+    }
+
+    /// Returns the summary of the connection that just terminated
+    fn summary(&self) -> DisconnectSummary {
+        let elapsed = self.now() - self.connected_at;
+        DisconnectSummary {
+            reason: self.disconnect.clone().unwrap_or(DisconnectClosed),
+            duration: Duration::milliseconds((elapsed / 1_000_000) as i64),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            last_error: self.last_error.clone(),
+            server_error: self.server_error.clone()
+        }
+    }
+
+    /// Runs the commands waiting in `Options.commands`, and any that arrive
+    /// while they run, after the connection has been lost
+    fn drain_commands<Payload>(&mut self, opts: &Options<Payload>, payload: &mut Payload,
+                               cb: &mut |&mut Conn, Event, &mut Payload| -> Control) {
+        let port = match opts.commands {
+            None => return,
+            Some(ref port) => port
+        };
+        loop {
+            match port.try_recv() {
+                Err(_) => break,
+                Ok(cmd) => self.run_command(cmd, payload, cb)
             }
+        }
+    }
+
+    /// Runs a command from `Options.commands`, telling it whether we're connected
+    fn run_command<Payload>(&mut self, cmd: Cmd<Payload>, payload: &mut Payload,
+                            cb: &mut |&mut Conn, Event, &mut Payload| -> Control) {
+        let state = if self.is_connected() {
+            CmdConnected
+        } else {
+            CmdDisconnected(self.disconnect.clone().unwrap_or(DisconnectClosed))
         };
-        // at this point the commands port is out of scope and therefore closed
-        // ensure our write handle is closed out, in case we stopped due to read shutting down,
-        // and then run any buffered procs
+        cmd(self, payload, state);
+        self.flush_events(payload, cb);
+    }
+
+    /// Connects to the first of `candidates` that works, or to our own host
+    /// and port if none of them do. The host and port are updated to the
+    /// server that we connected to.
+    fn open(&mut self, candidates: Vec<dns::SrvRecord>) -> IoResult<Connection> {
+        for record in candidates.into_iter() {
+            match self.connect_to(record.target.as_slice(), record.port) {
+                Ok(connection) => {
+                    self.host = record.target.into_maybe_owned();
+                    self.port = record.port;
+                    return Ok(connection);
+                }
+                Err(e) => info!("Couldn't connect to {}:{}: {}", record.target, record.port, e)
+            }
+        }
+        let host = self.host.as_slice().to_string();
+        let port = self.port;
+        self.connect_to(host.as_slice(), port)
+    }
+
+    /// Connects with the transport, resuming the TLS session of the last
+    /// connection if it was to the same server and `Options.tls` says to
+    fn connect_to(&mut self, host: &str, port: u16) -> IoResult<Connection> {
+        let mut tls = self.tls.clone();
+        match (tls.as_mut(), self.tls_session.as_ref()) {
+            (Some(tls), Some(&(ref h, p, ref session))) if tls.resume_sessions &&
+                                                            h.as_slice() == host && p == port => {
+                tls.session = Some(session.clone());
+            }
+            _ => ()
+        }
+        let connection = try!(self.transport.connect(host, port, tls.as_ref()));
+        self.tls_session = connection.tls_session.as_ref().map(|s| (host.to_string(), port, s.clone()));
+        Ok(connection)
+    }
+
+    /// Resets the per-connection state before connecting to another server.
+    /// Extensions are kept.
+    fn reset<Payload>(&mut self, opts: &Options<Payload>) {
+        self.logged_in = false;
+        self.user = User::new(opts.nick.as_bytes(), Some(opts.user.as_bytes()), None);
+        self.isupport = ISupport::new();
+        self.quirks = Quirks::new();
+        self.state = State::new();
+        match self.clock {
+            None => (),
+            Some(now) => self.state.set_clock(now)
+        }
+        self.events.clear();
+        self.scheduled.clear();
+    }
+
+    /// Closes the connection without sending anything to the server.
+    /// The event loop stops once the reader notices the socket was closed.
+    fn close(&mut self) {
         self.write_tx = None;
-        match procs {
+        match self.stream {
             None => (),
-            Some(procs) => {
-                for cmd in procs.into_iter() {
-                    cmd(self, payload);
-                }
+            Some(ref mut stream) => { let _ = stream.close_read(); }
+        }
+    }
+
+    /// Closes the connection if the server hasn't done so by the deadline
+    /// set by `quit_and_wait()`.
+    fn check_quit_deadline(&mut self) {
+        match self.quit_deadline {
+            Some(deadline) if self.now() >= deadline => {
+                info!("Server didn't close the connection after QUIT, closing it");
+                self.close();
             }
+            _ => ()
         }
+    }
 
-        // return the result
-        result
+    /// Pings the server if it's been quiet, and drops the connection if it's been
+    /// quiet for too long.
+    fn check_ping_timeout(&mut self) {
+        let timeout = match self.ping_timeout {
+            None => return,
+            Some(timeout) => max(timeout.num_milliseconds(), 0) as u64 * 1_000_000
+        };
+        let now = self.now();
+        if now - self.last_read >= timeout {
+            if self.disconnect.is_none() {
+                self.disconnect = Some(DisconnectPingTimeout);
+            }
+            self.close();
+        } else if now - self.last_read >= timeout / 2 && !self.ping_sent {
+            let token = now.to_string();
+            self.send_command(IRCCmd("PING".into_maybe_owned()), [token.as_bytes()], false);
+            self.ping_sent = true;
+        }
+    }
+
+    /// Queues an event to be sent to the callback once the current line,
+    /// command or scheduled job has been handled.
+    fn emit(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    fn flush_events<Payload>(&mut self, payload: &mut Payload,
+                             cb: &mut |&mut Conn, Event, &mut Payload| -> Control) {
+        while !self.events.is_empty() {
+            let events = mem::replace(&mut self.events, Vec::new());
+            for event in events.into_iter() {
+                self.dispatch(event, payload, cb);
+            }
+        }
+    }
+
+    /// Sends an event to the callback and does what it asks for. Returns what
+    /// the callback returned, or None if it panicked.
+    fn dispatch<Payload>(&mut self, event: Event, payload: &mut Payload,
+                         cb: &mut |&mut Conn, Event, &mut Payload| -> Control) -> Option<Control> {
+        let control = self.call(event, payload, cb);
+        match control {
+            None | Some(Continue) | Some(Handled) => (),
+            Some(Quit(ref msg)) => self.quit(msg.as_slice()),
+            Some(Reconnect) => {
+                // reconnecting is following a redirect to the same server
+                self.redirect = Some((self.host.as_slice().to_string(), self.port));
+                self.quit([]);
+            }
+        }
+        control
+    }
+
+    /// Sends an event to the callback, catching a panic in it.
+    ///
+    /// Returns None if the callback panicked, now or earlier. After a panic
+    /// the callback isn't called again: a QUIT is sent and the connection is
+    /// closed, which stops the event loop and the I/O tasks.
+    fn call<Payload>(&mut self, event: Event, payload: &mut Payload,
+                     cb: &mut |&mut Conn, Event, &mut Payload| -> Control) -> Option<Control> {
+        if self.panicked.is_some() {
+            return None;
+        }
+        let mut control = None;
+        let res = {
+            let conn = &mut *self;
+            let mut event = Some(event);
+            // the panic is not resumed, and after it the Conn is only used to
+            // quit and close the connection
+            unsafe { unwind::try(|| control = Some((*cb)(conn, event.take().unwrap(), payload))) }
+        };
+        match res {
+            Ok(()) => control,
+            Err(cause) => {
+                let msg = panic_message(cause);
+                info!("Callback panicked: {}, closing the connection", msg);
+                self.panicked = Some(msg);
+                self.events.clear();
+                self.quit_and_wait([], Duration::seconds(QUIT_WAIT_S));
+                self.close();
+                None
+            }
+        }
+    }
+
+    /// Returns ErrCallbackPanicked if the callback has panicked
+    fn check_panicked(&mut self) -> ::std::result::Result<(), Error> {
+        match self.panicked.take() {
+            None => Ok(()),
+            Some(msg) => Err(ErrCallbackPanicked(msg))
+        }
+    }
+
+    /// Schedules `job` to be run on the connection's task after `delay`.
+    ///
+    /// The event loop checks for due jobs every 100ms, so this is not suited for
+    /// precise timing. Jobs that haven't run by the time the connection shuts down
+    /// are dropped.
+    pub fn schedule(&mut self, delay: Duration, job: proc(&mut Conn)) {
+        let delay = max(delay.num_milliseconds(), 0) as u64;
+        self.scheduled.push(Scheduled {
+            at: self.now() + delay * 1_000_000,
+            job: job
+        });
+    }
+
+    fn run_scheduled(&mut self) {
+        let now = self.now();
+        loop {
+            // run due jobs in deadline order, including any that get scheduled
+            // by the jobs themselves with no delay
+            let next = self.scheduled.iter().enumerate()
+                                     .filter(|&(_, s)| s.at <= now)
+                                     .min_by(|&(_, s)| s.at)
+                                     .map(|(i, _)| i);
+            match next {
+                None => break,
+                Some(idx) => {
+                    let Scheduled { job, .. } = self.scheduled.remove(idx).unwrap();
+                    job(self);
+                }
+            }
+        }
     }
 
     /// Returns `true` if the connection is still active
     /// (or was at the last pass through the runloop).
+    /// Commands that run after the connection was lost see `false`.
     pub fn is_connected(&self) -> bool {
         self.write_tx.is_some()
     }
 
-    /// Returns the host that was used to create this Conn
-    pub fn host(&self) -> &'a str {
-        self.host
+    /// Returns `true` once the server has accepted our registration (sent 001).
+    pub fn is_registered(&self) -> bool {
+        self.logged_in
+    }
+
+    /// Returns why the connection was terminated, once it has been. Commands
+    /// that run after a disconnect get the same reason with CmdDisconnected.
+    pub fn disconnect_reason<'b>(&'b self) -> Option<&'b DisconnectReason> {
+        if self.is_connected() { None } else { self.disconnect.as_ref() }
+    }
+
+    /// Returns the host we're connected to.
+    /// This is the host that was used to create this Conn, unless we followed a redirect.
+    pub fn host<'b>(&'b self) -> &'b str {
+        self.host.as_slice()
+    }
+
+    /// Returns the name of the server we're connected to, as it gave it in
+    /// the prefix of its welcome (001) and in RPL_MYINFO (004). This is often
+    /// not the host we connected to, e.g. with round-robin DNS. None before
+    /// registration.
+    pub fn server_name<'b>(&'b self) -> Option<&'b [u8]> {
+        self.server_name.as_ref().map(|n| n.as_slice())
+    }
+
+    /// Returns the port we're connected to.
+    pub fn port(&self) -> u16 {
+        self.port
     }
 
     /// Returns the current User.
@@ -323,6 +1655,61 @@ impl<'a> Conn<'a> {
         &self.user
     }
 
+    /// Returns our user modes (e.g. 'i' for invisible or 'x' for a cloaked
+    /// host), as set by the server. They're learned from MODE lines for our
+    /// nick and from RPL_UMODEIS (221) replies.
+    pub fn user_modes<'b>(&'b self) -> &'b [char] {
+        self.user_modes.as_slice()
+    }
+
+    /// Returns `true` if we have the user mode `mode`. See `user_modes()`.
+    pub fn has_user_mode(&self, mode: char) -> bool {
+        self.user_modes.contains(&mode)
+    }
+
+    /// Changes our user modes, e.g. `conn.set_user_mode("+x")`.
+    /// The modes returned by `user_modes()` are updated once the server confirms.
+    pub fn set_user_mode(&mut self, modes: &str) {
+        let nick = self.user.nick().to_vec();
+        self.send_command(IRCCmd("MODE".into_maybe_owned()), [nick.as_slice(), modes.as_bytes()], false);
+    }
+
+    /// Asks the server for our user modes. The reply updates `user_modes()`.
+    pub fn refresh_user_modes(&mut self) {
+        let nick = self.user.nick().to_vec();
+        self.send_command(IRCCmd("MODE".into_maybe_owned()), [nick.as_slice()], false);
+    }
+
+    /// Returns the features advertised by the server in its 005 lines.
+    pub fn isupport<'b>(&'b self) -> &'b ISupport {
+        &self.isupport
+    }
+
+    /// Returns what's known about the network and the server software, which
+    /// decides things like whether WHOX is used and how quiets are set.
+    /// It's updated as the 004 and 005 lines arrive.
+    pub fn quirks<'b>(&'b self) -> &'b Quirks {
+        &self.quirks
+    }
+
+    /// Returns the state of the channels we're in.
+    pub fn state<'b>(&'b self) -> &'b State {
+        &self.state
+    }
+
+    /// Returns the typed key/value store attached to this connection.
+    ///
+    /// This is a place for handlers and add-ons to keep their own state
+    /// without going through the Payload.
+    pub fn extensions<'b>(&'b self) -> &'b Extensions {
+        &self.extensions
+    }
+
+    /// Returns the typed key/value store attached to this connection, mutably.
+    pub fn extensions_mut<'b>(&'b mut self) -> &'b mut Extensions {
+        &mut self.extensions
+    }
+
     /// Sends a command to the server.
     /// The line is truncated to 510 bytes (not including newline) before sending.
     ///
@@ -339,74 +1726,70 @@ impl<'a> Conn<'a> {
     ///
     /// The add_colon flag causes the final argument in the args list to have a ':' prepended.
     pub fn send_command(&mut self, cmd: Command, args: &[&[u8]], add_colon: bool) {
-        if !{
-            let chan = match self.write_tx {
-                None => return,
-                Some(ref mut c) => c
-            };
-            let mut line = [0u8, ..512];
-            let len = {
-                let mut buf = line.slice_to_mut(510);
-
-                fn append(buf: &mut &mut [u8], v: &[u8]) {
-                    let len = buf.clone_from_slice(v);
-                    // this should work:
-                    //   *buf = buf.slice_from_mut(len);
-                    // but I'm getting weird borrowck issues (see mozilla/rust#11361)
-                    *buf = unsafe { ::std::mem::transmute(buf.slice_from_mut(len)) };
-                }
-
-                let is_ctcp = cmd.is_ctcp();
-                match cmd {
-                    IRCCmd(cmd) => {
-                        append(&mut buf, cmd.as_slice().as_bytes());
-                    }
-                    IRCCode(code) => {
-                        uint::to_str_bytes(code, 10, |v| {
-                            append(&mut buf, v);
-                        });
-                    }
-                    IRCAction(ref dst) | IRCCTCP(ref dst,_) => {
-                        append(&mut buf, b"PRIVMSG ");
-                        append(&mut buf, dst.as_slice());
-                        append(&mut buf, b" :\x01");
-                        let action = match cmd {
-                            IRCAction(_) => { static b: &'static [u8] = b"ACTION"; b }
-                            IRCCTCP(_,ref action) => action.as_slice(),
-                            _ => unreachable!()
-                        };
-                        append(&mut buf, action);
-                    }
-                    IRCCTCPReply(dst, action) => {
-                        append(&mut buf, b"NOTICE ");
-                        append(&mut buf, dst.as_slice());
-                        append(&mut buf, b" :\x01");
-                        append(&mut buf, action.as_slice());
-                    }
+        if self.write_tx.is_none() {
+            return;
+        }
+        let mut line = [0u8, ..510];
+        let len = {
+            let mut buf = line.as_mut_slice();
+
+            fn append(buf: &mut &mut [u8], v: &[u8]) {
+                let len = buf.clone_from_slice(v);
+                // this should work:
+                //   *buf = buf.slice_from_mut(len);
+                // but I'm getting weird borrowck issues (see mozilla/rust#11361)
+                *buf = unsafe { ::std::mem::transmute(buf.slice_from_mut(len)) };
+            }
+
+            let is_ctcp = cmd.is_ctcp();
+            match cmd {
+                IRCCmd(cmd) => {
+                    append(&mut buf, cmd.as_slice().as_bytes());
                 }
-                if !args.is_empty() {
-                    for arg in args.init().iter() {
-                        append(&mut buf, b" ");
-                        append(&mut buf, arg.as_slice());
-                    }
-                    if add_colon {
-                        append(&mut buf, b" :");
-                    } else {
-                        append(&mut buf, b" ");
-                    }
-                    append(&mut buf, args.last().unwrap().as_slice());
+                IRCCode(code) => {
+                    uint::to_str_bytes(code, 10, |v| {
+                        for _ in range(0, 3 - min(v.len(), 3)) {
+                            append(&mut buf, b"0");
+                        }
+                        append(&mut buf, v);
+                    });
                 }
-                if is_ctcp {
-                    append(&mut buf, b"\x01");
+                IRCAction(ref dst) | IRCCTCP(_, ref dst) => {
+                    append(&mut buf, b"PRIVMSG ");
+                    append(&mut buf, dst.as_slice());
+                    append(&mut buf, b" :\x01");
+                    let action = match cmd {
+                        IRCAction(_) => { static b: &'static [u8] = b"ACTION"; b }
+                        IRCCTCP(ref action, _) => action.as_slice(),
+                        _ => unreachable!()
+                    };
+                    append(&mut buf, action);
                 }
-                510 - buf.len()
-            };
-            debug!("[DEBUG] Sent line: {}", String::from_utf8_lossy(line.slice_to(len)));
-            line.slice_from_mut(len).clone_from_slice(b"\r\n");
-            chan.send_opt(line.slice_to(len+2).to_vec()).is_ok()
-        } {
-            self.write_tx = None;
-        }
+                IRCCTCPReply(action, dst) => {
+                    append(&mut buf, b"NOTICE ");
+                    append(&mut buf, dst.as_slice());
+                    append(&mut buf, b" :\x01");
+                    append(&mut buf, action.as_slice());
+                }
+            }
+            if !args.is_empty() {
+                for arg in args.init().iter() {
+                    append(&mut buf, b" ");
+                    append(&mut buf, arg.as_slice());
+                }
+                if add_colon {
+                    append(&mut buf, b" :");
+                } else {
+                    append(&mut buf, b" ");
+                }
+                append(&mut buf, args.last().unwrap().as_slice());
+            }
+            if is_ctcp {
+                append(&mut buf, b"\x01");
+            }
+            510 - buf.len()
+        };
+        self.write_line(line.slice_to(len).to_vec());
     }
 
     /// Sends a raw command to the server
@@ -416,33 +1799,94 @@ impl<'a> Conn<'a> {
     pub fn send_raw(&mut self, raw: &[u8]) {
         let raw = chomp(raw);
         if raw.is_empty() { return }
-        if !{
-            let chan = match self.write_tx {
-                None => return,
-                Some(ref mut c) => c
-            };
-            let mut line = [0u8, ..512];
-            let len = line.slice_to_mut(510).clone_from_slice(raw);
-            debug!("[DEBUG] Sent line: {}", String::from_utf8_lossy(line.slice_to(len)));
-            line.slice_from_mut(len).clone_from_slice(b"\r\n");
-            chan.send_opt(line.slice_to(len+2).to_vec()).is_ok()
-        } {
+        let len = min(raw.len(), 510);
+        self.write_line(raw.slice_to(len).to_vec());
+    }
+
+    /// Hands a complete line to the writer task, or holds it back until we're
+    /// registered. See `Options.hold_until_registered`.
+    fn write_line(&mut self, line: Vec<u8>) {
+        if self.write_tx.is_none() {
+            return;
+        }
+        if self.hold_until_registered && !self.logged_in && !is_registration_line(line.as_slice()) {
+            if self.held_lines.len() >= MAX_HELD_LINES {
+                warn!("Too many lines held until registered, dropping: {}", String::from_utf8_lossy(line.as_slice()));
+                return;
+            }
+            debug!("[DEBUG] Holding line until registered: {}", String::from_utf8_lossy(line.as_slice()));
+            self.held_lines.push(line);
+            return;
+        }
+        let line = match audit::check(self, line) {
+            None => {
+                debug!("[DEBUG] Line dropped by the send hook");
+                return;
+            }
+            Some(line) => line
+        };
+        debug!("[DEBUG] Sent line: {}", String::from_utf8_lossy(line.as_slice()));
+        tap(&mut self.tap, Outbound(line.clone()));
+        let len = line.len() as u64 + 2;
+        if self.write_tx.as_ref().unwrap().send_opt(line).is_err() {
             self.write_tx = None;
+        } else {
+            self.bytes_sent += len;
+        }
+    }
+
+    /// Sends a complete line, as serialized by `Line::to_raw()`, prefix and
+    /// tags included. This is mostly useful for tests and for implementing
+    /// servers or bouncers, e.g. to send a numeric reply from a given server.
+    ///
+    /// Lines with a CR, LF or NUL byte in them aren't valid and aren't sent.
+    pub fn send_line(&mut self, line: &Line) {
+        let raw = line.to_raw();
+        if raw.iter().any(|&b| b == '\r' as u8 || b == '\n' as u8 || b == 0) {
+            warn!("Not sending invalid line: {}", line);
+            return;
         }
+        self.send_raw(raw.as_slice());
     }
 
     /// Sets the user's nickname.
-    pub fn set_nick(&mut self, nick: &[u8]) {
+    ///
+    /// A nick longer than the server's NICKLEN is truncated, without splitting
+    /// a UTF-8 character, and a NickTruncated event is sent. Returns an error
+    /// without sending anything if the nick is invalid, see `validate::nick()`.
+    pub fn set_nick(&mut self, nick: &[u8]) -> ::std::result::Result<(), InvalidName> {
+        let requested = nick;
+        let nick = match self.isupport.get_uint("NICKLEN") {
+            None => nick,
+            Some(max) => validate::truncate_nick(nick, max)
+        };
+        try!(validate::nick(nick, &self.isupport));
+        if nick.len() < requested.len() {
+            self.emit(NickTruncated(requested.to_vec(), nick.to_vec()));
+        }
         self.send_command(IRCCmd("NICK".into_maybe_owned()), [nick], false);
         // if we're logged in, watch for the NICK reply before changing our nick
         if !self.logged_in {
             self.user = self.user.with_nick(nick);
         }
+        Ok(())
+    }
+
+    /// Asks the server for our own user@host with a USERHOST query.
+    ///
+    /// The reply is handled internally and updates the User returned by `me()`.
+    /// This is done automatically after login if the welcome message doesn't
+    /// include our hostmask, and the hostmask is also kept up to date from
+    /// 396 (host hidden) replies, our own JOINs, and WHOIS replies about ourselves.
+    pub fn refresh_userhost(&mut self) {
+        let nick = self.user.nick().to_vec();
+        self.send_command(IRCCmd("USERHOST".into_maybe_owned()), [nick.as_slice()], false);
     }
 
     /// Quits the connection
     /// Pass [] for the message to use the default.
     pub fn quit(&mut self, msg: &[u8]) {
+        self.quitting = true;
         if msg.is_empty() {
             let args: &[&[u8]] = [];
             self.send_command(IRCCmd("QUIT".into_maybe_owned()), args, false);
@@ -451,28 +1895,288 @@ impl<'a> Conn<'a> {
         }
     }
 
+    /// Quits the connection, making sure the QUIT gets to the server.
+    ///
+    /// `quit()` only queues the QUIT, so a program that exits as soon as
+    /// `connect()` returns may not get it onto the wire. With this, the
+    /// connection waits for the server to close the socket, and `connect()`
+    /// doesn't return until the queued lines have been written. If that takes
+    /// longer than `timeout`, the connection is closed anyway.
+    pub fn quit_and_wait(&mut self, msg: &[u8], timeout: Duration) {
+        self.quit(msg);
+        let timeout = max(timeout.num_milliseconds(), 0) as u64 * 1_000_000;
+        self.quit_deadline = Some(self.now() + timeout);
+    }
+
+    /// Sends a PONG with the given token
+    pub fn pong(&mut self, token: &[u8]) {
+        self.send_command(IRCCmd("PONG".into_maybe_owned()), [token], true);
+    }
+
     /// Sends a PRIVMSG
+    ///
+    /// If the server supports CPRIVMSG and we're opped in a channel that `dst`
+    /// is also in, the message is sent with CPRIVMSG instead. This avoids the
+    /// "target change too fast" limits of some networks.
+    ///
+    /// A message longer than `max_message_len()` is split over several lines,
+    /// at a space where possible, so the server doesn't cut it off.
     pub fn privmsg(&mut self, dst: &[u8], msg: &[u8]) {
-        // NB: .as_slice() calls are necessary to work around mozilla/rust#8874
-        self.send_command(IRCCmd("PRIVMSG".into_maybe_owned()),
-                          [dst.as_slice(), msg.as_slice()], true)
+        let room = self.max_message_len("PRIVMSG", dst);
+        let chan = self.cmsg_channel("CPRIVMSG", dst);
+        for part in split_message(msg, room).into_iter() {
+            match chan {
+                Some(ref chan) => {
+                    self.send_command(IRCCmd("CPRIVMSG".into_maybe_owned()),
+                                      [dst.as_slice(), chan.as_slice(), part], true)
+                }
+                None => {
+                    // NB: .as_slice() calls are necessary to work around mozilla/rust#8874
+                    self.send_command(IRCCmd("PRIVMSG".into_maybe_owned()),
+                                      [dst.as_slice(), part], true)
+                }
+            }
+        }
     }
 
     /// Sends a NOTICE
+    ///
+    /// Like `privmsg()`, this uses CNOTICE when the server supports it and we're
+    /// opped in a channel that `dst` is also in, and splits long messages.
     pub fn notice(&mut self, dst: &[u8], msg: &[u8]) {
-        self.send_command(IRCCmd("NOTICE".into_maybe_owned()),
-                          [dst.as_slice(), msg.as_slice()], true)
+        let room = self.max_message_len("NOTICE", dst);
+        let chan = self.cmsg_channel("CNOTICE", dst);
+        for part in split_message(msg, room).into_iter() {
+            match chan {
+                Some(ref chan) => {
+                    self.send_command(IRCCmd("CNOTICE".into_maybe_owned()),
+                                      [dst.as_slice(), chan.as_slice(), part], true)
+                }
+                None => {
+                    self.send_command(IRCCmd("NOTICE".into_maybe_owned()),
+                                      [dst.as_slice(), part], true)
+                }
+            }
+        }
+    }
+
+    /// Answers a received PRIVMSG, NOTICE or ACTION: in the channel if it was
+    /// sent to one, and to the sender otherwise. Whether it's answered with a
+    /// PRIVMSG or a NOTICE, or not at all, depends on `Options.reply_policy`.
+    ///
+    /// Returns `false` if nothing was sent, because the line isn't a message
+    /// or the policy doesn't answer it.
+    pub fn reply(&mut self, line: &Line, msg: &[u8]) -> bool {
+        let (dst, is_notice) = match line.command {
+            IRCCmd(ref cmd) if cmd.as_slice() == "PRIVMSG" || cmd.as_slice() == "NOTICE" => {
+                match line.args.as_slice().head() {
+                    None => return false,
+                    Some(dst) => (dst.as_slice(), cmd.as_slice() == "NOTICE")
+                }
+            }
+            IRCAction(ref dst) => (dst.as_slice(), false),
+            _ => return false
+        };
+        let target = if self.isupport.is_channel(dst) {
+            dst.to_vec()
+        } else {
+            match line.prefix.as_ref() {
+                None => return false,
+                Some(user) => user.nick().to_vec()
+            }
+        };
+        match (self.reply_policy, is_notice) {
+            (SkipNotices, true) => return false,
+            (SkipNotices, false) | (ReplyWithPrivmsg, _) => self.privmsg(target.as_slice(), msg),
+            (ReplyWithNotice, _) => self.notice(target.as_slice(), msg)
+        }
+        true
+    }
+
+    /// Sends a CTCP query, e.g. `conn.ctcp(b"nick", b"VERSION", b"")`.
+    /// `params` is the text after the CTCP command, and may be empty.
+    pub fn ctcp(&mut self, dst: &[u8], command: &[u8], params: &[u8]) {
+        let cmd = IRCCTCP(command.to_vec(), dst.to_vec());
+        if params.is_empty() {
+            self.send_command(cmd, [], false);
+        } else {
+            self.send_command(cmd, [params], false);
+        }
+    }
+
+    /// Sends a CTCP reply, the NOTICE counterpart of `ctcp()`
+    pub fn ctcp_reply(&mut self, dst: &[u8], command: &[u8], params: &[u8]) {
+        let cmd = IRCCTCPReply(command.to_vec(), dst.to_vec());
+        if params.is_empty() {
+            self.send_command(cmd, [], false);
+        } else {
+            self.send_command(cmd, [params], false);
+        }
+    }
+
+    /// Sends the same PRIVMSG to several targets.
+    ///
+    /// The targets are combined into comma-separated lists, with as many targets per
+    /// line as the server's TARGMAX (or MAXTARGETS) allows and the line length permits.
+    /// A server that gives neither is sent one target per line.
+    pub fn privmsg_many(&mut self, targets: &[&[u8]], msg: &[u8]) {
+        let limit = self.isupport.targmax("PRIVMSG").unwrap_or(uint::MAX);
+        // the room left for the targets once our prefix and the message are counted
+        let room = self.max_message_len("PRIVMSG", b"");
+        let room = room - min(room, msg.len());
+        let mut batch = Vec::new();
+        let mut count = 0u;
+        for &target in targets.iter() {
+            if count > 0 && (count == limit || batch.len() + 1 + target.len() > room) {
+                self.send_command(IRCCmd("PRIVMSG".into_maybe_owned()),
+                                  [batch.as_slice(), msg], true);
+                batch.clear();
+                count = 0;
+            }
+            if count > 0 {
+                batch.push(',' as u8);
+            }
+            batch.push_all(target);
+            count += 1;
+        }
+        if count > 0 {
+            self.send_command(IRCCmd("PRIVMSG".into_maybe_owned()), [batch.as_slice(), msg], true);
+        }
+    }
+
+    /// Returns how long the text of a `cmd` (e.g. PRIVMSG) to `dst` can be
+    /// without the line being cut off when the server relays it.
+    ///
+    /// Relayed lines carry our `nick!user@host` as their prefix, so this
+    /// depends on our hostmask, which is kept up to date from 302 and 396
+    /// replies. Until the server has told us our host, the longest allowed
+    /// host (63 bytes) is assumed.
+    pub fn max_message_len(&self, cmd: &str, dst: &[u8]) -> uint {
+        let user = self.user.user().map_or(10, |u| u.len());
+        let host = self.user.host().map_or(63, |h| h.len());
+        // :nick!user@host <cmd> <dst> :<msg>
+        let used = 1 + self.user.nick().len() + 1 + user + 1 + host + 1 +
+                   cmd.len() + 1 + dst.len() + 2;
+        510 - min(510, used)
+    }
+
+    /// Sends a list of channel mode changes.
+    ///
+    /// The changes are split over as many MODE commands as necessary to respect
+    /// the server's MODES limit on parameters per command.
+    pub fn set_modes(&mut self, chan: &[u8], changes: &[ModeChange]) {
+        // MODE <chan> <modes> <params>
+        let room = 510 - min(510, "MODE  ".len() + chan.len());
+        for (modestr, params) in modes::format(changes, self.isupport.modes(), room).into_iter() {
+            let mut args = vec![chan, modestr.as_slice()];
+            args.extend(params.iter().map(|p| p.as_slice()));
+            self.send_command(IRCCmd("MODE".into_maybe_owned()), args.as_slice(), false);
+        }
+    }
+
+    /// Returns a channel we can use to send `cmd` (CPRIVMSG or CNOTICE) to `nick`,
+    /// if the server supports it.
+    fn cmsg_channel(&self, cmd: &str, nick: &[u8]) -> Option<Vec<u8>> {
+        if !self.isupport.contains(cmd) || self.isupport.is_channel(nick) {
+            return None;
+        }
+        let me = self.user.nick();
+        self.state.common_channels(nick).into_iter()
+                  .find(|c| self.state.is_op(c.name(), me))
+                  .map(|c| c.name().to_vec())
     }
 
     /// Sends a JOIN
     /// Pass [] for keys if there are none.
-    pub fn join(&mut self, room: &[u8], keys: &[u8]) {
+    ///
+    /// Keys are remembered, and if no key is given for a single channel, the
+    /// remembered one is used. See `channel_key()`.
+    ///
+    /// If the server refuses the JOIN, a JoinFailed event is sent. Returns an
+    /// error without sending anything if a channel name is invalid, see
+    /// `validate::channel()`. `room` can be a comma-separated list of
+    /// channels, or "0" to part all of them.
+    pub fn join(&mut self, room: &[u8], keys: &[u8]) -> ::std::result::Result<(), InvalidName> {
+        if room != b"0" {
+            for chan in room.split(|&b| b == ',' as u8) {
+                try!(validate::channel(chan, &self.isupport));
+            }
+        }
+        handlers::join_sent(self, room);
+        let remembered;
+        let keys = if keys.is_empty() {
+            remembered = self.channel_key(room);
+            remembered.as_ref().map_or(keys, |k| k.as_slice())
+        } else {
+            persist::remember_keys(self, room, keys);
+            keys
+        };
         if keys.is_empty() {
             self.send_command(IRCCmd("JOIN".into_maybe_owned()), [room], false);
         } else {
             self.send_command(IRCCmd("JOIN".into_maybe_owned()),
                               [room.as_slice(), keys.as_slice()], false);
         }
+        Ok(())
+    }
+
+    /// Joins several channels, given as (channel, key) pairs.
+    /// Pass [] as the key for channels that don't have one.
+    ///
+    /// The channels are combined into as few JOIN lines as the line length allows,
+    /// and no more per line than the server's TARGMAX gives for JOIN if it gives a
+    /// limit (MAXTARGETS doesn't apply). The first line is sent right away and the rest are
+    /// spaced out by `Options.join_delay`, to avoid tripping flood protection.
+    /// Channels with invalid names are skipped, see `validate::channel()`.
+    pub fn join_many(&mut self, chans: &[(&[u8], &[u8])]) {
+        // channels with keys have to come first so the keys line up
+        let mut chans = chans.iter().filter(|&&(chan, _)| match validate::channel(chan, &self.isupport) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("Not joining {}: {}", String::from_utf8_lossy(chan), e);
+                false
+            }
+        }).map(|&c| c).collect::<Vec<_>>();
+        chans.sort_by(|&(_, a), &(_, b)| a.is_empty().cmp(&b.is_empty()));
+        // only an explicit TARGMAX entry limits JOIN
+        let limit = self.isupport.targmax("JOIN").unwrap_or(uint::MAX);
+
+        let mut lines = Vec::new();
+        let (mut names, mut keys) = (Vec::new(), Vec::new());
+        let mut count = 0u;
+        for &(chan, key) in chans.iter() {
+            // JOIN <chans> <keys>
+            let len = "JOIN  ".len() + names.len() + keys.len() + 2 + chan.len() + key.len();
+            if count > 0 && (count == limit || len > 510) {
+                lines.push((names, keys));
+                names = Vec::new();
+                keys = Vec::new();
+                count = 0;
+            }
+            if count > 0 {
+                names.push(',' as u8);
+                if !key.is_empty() {
+                    keys.push(',' as u8);
+                }
+            }
+            names.push_all(chan);
+            keys.push_all(key);
+            count += 1;
+        }
+        if count > 0 {
+            lines.push((names, keys));
+        }
+
+        let delay = self.join_delay;
+        for (i, (names, keys)) in lines.into_iter().enumerate() {
+            if i == 0 {
+                let _ = self.join(names.as_slice(), keys.as_slice());
+            } else {
+                self.schedule(delay * (i as i32), proc(conn) {
+                    let _ = conn.join(names.as_slice(), keys.as_slice());
+                });
+            }
+        }
     }
 
     /// Sends a PART
@@ -487,6 +2191,49 @@ impl<'a> Conn<'a> {
     }
 }
 
+/// Sends a copy of a line to the tap, if there is one.
+/// The tap is dropped once nobody is listening to it.
+fn tap(tap: &mut Option<Sender<RawLine>>, line: RawLine) {
+    let closed = match *tap {
+        None => return,
+        Some(ref tx) => tx.send_opt(line).is_err()
+    };
+    if closed {
+        *tap = None;
+    }
+}
+
+/// Splits a message into pieces of at most `room` bytes. Pieces end at a
+/// space if there's one in their second half, and never inside a UTF-8
+/// sequence.
+fn split_message<'a>(msg: &'a [u8], room: uint) -> Vec<&'a [u8]> {
+    let room = max(room, 1);
+    let mut parts = Vec::new();
+    let mut rest = msg;
+    while rest.len() > room {
+        let mut end = room;
+        // continuation bytes look like 10xxxxxx
+        while end > 0 && rest[end] & 0xC0 == 0x80 {
+            end -= 1;
+        }
+        if end == 0 {
+            end = room;
+        }
+        match rest.slice_to(end).rposition_elem(&(' ' as u8)) {
+            Some(space) if space >= end / 2 && space > 0 => {
+                parts.push(rest.slice_to(space));
+                rest = rest.slice_from(space + 1);
+            }
+            _ => {
+                parts.push(rest.slice_to(end));
+                rest = rest.slice_from(end);
+            }
+        }
+    }
+    parts.push(rest);
+    parts
+}
+
 fn chomp_owned(s: &mut Vec<u8>) -> bool {
     let len = chomp(s.as_slice()).len();
     if len < s.len() {
@@ -533,6 +2280,37 @@ impl Command {
             IRCAction(_) | IRCCTCP(_,_) | IRCCTCPReply(_,_) => true,
             _ => false }
     }
+
+    /// Returns `true` if the command is in `names`, or if `names` is None.
+    /// Numerics are given as numbers, and CTCP commands count as PRIVMSG or NOTICE.
+    pub fn is_one_of(&self, names: Option<&[&str]>) -> bool {
+        let names = match names {
+            None => return true,
+            Some(names) => names
+        };
+        let name = match *self {
+            IRCCmd(ref cmd) => cmd.as_slice(),
+            IRCCode(code) => {
+                return names.iter().any(|&n| from_str::<uint>(n) == Some(code));
+            }
+            IRCAction(_) | IRCCTCP(_,_) => "PRIVMSG",
+            IRCCTCPReply(_,_) => "NOTICE"
+        };
+        names.iter().any(|&n| n == name)
+    }
+}
+
+/// A CTCP query or reply, as returned by `Line::ctcp()`
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub struct Ctcp {
+    /// The CTCP command, e.g. `VERSION` or `ACTION`
+    pub command: Vec<u8>,
+    /// Who the CTCP was sent to, a nick or a channel
+    pub target: Vec<u8>,
+    /// The text after the command. Empty if there was none.
+    pub params: Vec<u8>,
+    /// Whether this is a reply (sent with NOTICE) rather than a query
+    pub reply: bool
 }
 
 impl fmt::Show for Command {
@@ -558,6 +2336,9 @@ impl fmt::Show for Command {
 /// A parsed line
 #[deriving(PartialEq, Eq,Clone)]
 pub struct Line {
+    /// The IRCv3 message tags, as (name, value) pairs. Tags without a value
+    /// have an empty value.
+    pub tags: Vec<(String, String)>,
     /// The optional prefix
     pub prefix: Option<User>,
     /// The command
@@ -582,6 +2363,15 @@ impl fmt::Show for Line {
 impl Line {
     /// Parse a line into a Line struct
     pub fn parse(mut v: &[u8]) -> Option<Line> {
+        let mut tags = Vec::new();
+        if v.starts_with(b"@") {
+            let idx = match v.position_elem(&(' ' as u8)) {
+                None => return None,
+                Some(idx) => idx
+            };
+            tags = parse_tags(v.slice(1, idx));
+            v = v.slice_from(idx+1);
+        }
         let mut prefix = None;
         if v.starts_with(b":") {
             let idx = match v.position_elem(&(' ' as u8)) {
@@ -671,16 +2461,63 @@ impl Line {
             }
         }
         Some(Line{
+            tags: tags,
             prefix: prefix,
             command: command,
             args: args
         })
     }
 
+    /// Returns the CTCP query or reply carried by this line, if any. ACTIONs
+    /// are returned as CTCP queries with the command `ACTION`.
+    pub fn ctcp(&self) -> Option<Ctcp> {
+        let (command, target, reply) = match self.command {
+            IRCAction(ref dst) => (b"ACTION".to_vec(), dst.clone(), false),
+            IRCCTCP(ref cmd, ref dst) => (cmd.clone(), dst.clone(), false),
+            IRCCTCPReply(ref cmd, ref dst) => (cmd.clone(), dst.clone(), true),
+            _ => return None
+        };
+        Some(Ctcp {
+            command: command,
+            target: target,
+            params: self.args.as_slice().connect_vec(&(' ' as u8)),
+            reply: reply
+        })
+    }
+
+    /// Returns who sent the line, telling servers apart from users by the
+    /// form of the prefix and the users in `state`, as `User::is_server()`
+    /// does. Numeric replies only come from servers. None if the line has
+    /// no prefix.
+    pub fn source(&self, state: &State) -> Option<Prefix> {
+        self.prefix.as_ref().map(|p| match self.command {
+            IRCCode(_) if p.user().is_none() && p.host().is_none() => ServerPrefix(p.raw().to_vec()),
+            _ => Prefix::from_user(p, state)
+        })
+    }
+
+    /// Returns the value of the message tag `name`, if the line has it
+    pub fn tag<'a>(&'a self, name: &str) -> Option<&'a str> {
+        self.tags.iter().find(|&&(ref n, _)| n.as_slice() == name).map(|&(_, ref v)| v.as_slice())
+    }
+
+    /// Returns the ID that the server gave this message, from its `msgid`
+    /// tag. Servers with the "message-tags" capability enabled send the same
+    /// ID for the same message to everyone, including in playback.
+    pub fn msgid<'a>(&'a self) -> Option<&'a str> {
+        self.tag("msgid").and_then(|v| if v.is_empty() { None } else { Some(v) })
+    }
+
+    /// Returns the ID of the message that this one replies or reacts to,
+    /// from its `+draft/reply` tag. See `Conn::privmsg_reply()`.
+    pub fn reply_to<'a>(&'a self) -> Option<&'a str> {
+        self.tag("+draft/reply").and_then(|v| if v.is_empty() { None } else { Some(v) })
+    }
+
     /// Converts into the "raw" representation :prefix cmd args
     pub fn to_raw(&self) -> Vec<u8> {
         let mut cap = self.prefix.as_ref().map_or(0, |s| 1+s.raw().len()+1);
-        let mut found_space = false;
+        let mut needs_colon = false;
         cap += match self.command {
             IRCCmd(ref cmd) => cmd.len(),
             IRCCode(_) => 3,
@@ -706,14 +2543,31 @@ impl Line {
                 }
             }
             let last = self.args.last().unwrap();
-            found_space = last.contains(&(' ' as u8));
-            if found_space {
+            // without the colon, an empty arg would be dropped and a leading
+            // colon would be taken as the trailing marker
+            needs_colon = last.is_empty() || last.contains(&(' ' as u8)) ||
+                          last.as_slice().starts_with(b":");
+            if needs_colon {
                 cap += 1 + 1 /* : */ + last.len();
             } else {
                 cap += 1 + last.len();
             }
         }
         let mut res = Vec::with_capacity(cap);
+        if !self.tags.is_empty() {
+            res.push('@' as u8);
+            for (i, &(ref name, ref value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    res.push(';' as u8);
+                }
+                res.push_all(name.as_bytes());
+                if !value.is_empty() {
+                    res.push('=' as u8);
+                    escape_tag_value(value.as_slice(), &mut res);
+                }
+            }
+            res.push(' ' as u8);
+        }
         if self.prefix.is_some() {
             res.push(':' as u8);
             res.push_all(self.prefix.as_ref().unwrap().raw());
@@ -761,7 +2615,7 @@ impl Line {
                 }
             }
             res.push(' ' as u8);
-            if found_space {
+            if needs_colon {
                 res.push(':' as u8);
             }
             res.push_all(self.args.last().unwrap().as_slice());
@@ -770,11 +2624,135 @@ impl Line {
     }
 }
 
+/// Returns the real name to send in USER, without the bytes that would end
+/// the line, and falling back to the username if it's empty
+fn realname(real: &str, user: &str) -> Vec<u8> {
+    let real = real.bytes().filter(|&b| b != '\r' as u8 && b != '\n' as u8 && b != 0).collect::<Vec<u8>>();
+    if real.is_empty() { user.as_bytes().to_vec() } else { real }
+}
+
+/// Returns `true` if `line` is a PRIVMSG, NOTICE or CTCP sent from our nick
+fn is_own_message(conn: &Conn, line: &Line) -> bool {
+    let is_message = match line.command {
+        IRCCmd(ref cmd) => cmd.as_slice() == "PRIVMSG" || cmd.as_slice() == "NOTICE",
+        IRCAction(_) | IRCCTCP(..) | IRCCTCPReply(..) => true,
+        IRCCode(_) => false
+    };
+    is_message && line.prefix.as_ref().map_or(false, |user| {
+        conn.isupport.casemapping().eq_ignore_case(user.nick(), conn.user.nick())
+    })
+}
+
+/// Returns `true` if the raw line is one that may be sent before we're registered
+fn is_registration_line(line: &[u8]) -> bool {
+    let mut words = line.split(|&b| b == ' ' as u8).filter(|w| !w.is_empty())
+                        .skip_while(|w| w.starts_with(b"@") || w.starts_with(b":"));
+    let cmd = match words.next() {
+        None => return true,
+        Some(cmd) => cmd
+    };
+    ["NICK", "USER", "PASS", "WEBIRC", "CAP", "AUTHENTICATE", "PING", "PONG", "QUIT"].iter().any(|c| {
+        c.len() == cmd.len() && c.bytes().zip(cmd.iter()).all(|(a, &b)| a == (b as char).to_uppercase() as u8)
+    })
+}
+
+/// Parses the tags part of a line, without the leading @
+fn parse_tags(v: &[u8]) -> Vec<(String, String)> {
+    v.split(|&b| b == ';' as u8).filter(|t| !t.is_empty()).map(|tag| {
+        let (name, value) = match tag.position_elem(&('=' as u8)) {
+            None => (tag, [].as_slice()),
+            Some(idx) => (tag.slice_to(idx), tag.slice_from(idx+1))
+        };
+        let mut unescaped = Vec::with_capacity(value.len());
+        let mut escaped = false;
+        for &b in value.iter() {
+            if escaped {
+                unescaped.push(match b as char {
+                    ':' => ';' as u8,
+                    's' => ' ' as u8,
+                    'r' => '\r' as u8,
+                    'n' => '\n' as u8,
+                    _ => b
+                });
+                escaped = false;
+            } else if b == '\\' as u8 {
+                escaped = true;
+            } else {
+                unescaped.push(b);
+            }
+        }
+        (String::from_utf8_lossy(name).into_string(),
+         String::from_utf8_lossy(unescaped.as_slice()).into_string())
+    }).collect()
+}
+
+/// Returns the message of a panic, if it has one
+fn panic_message(cause: Box<Any + Send>) -> String {
+    let cause: &Any = &*cause;
+    match cause.downcast_ref::<&'static str>() {
+        Some(msg) => return msg.to_string(),
+        None => ()
+    }
+    match cause.downcast_ref::<String>() {
+        Some(msg) => msg.clone(),
+        None => "Box<Any>".to_string()
+    }
+}
+
+/// Escapes a tag value for sending
+fn escape_tag_value(value: &str, res: &mut Vec<u8>) {
+    for &b in value.as_bytes().iter() {
+        match b as char {
+            ';' => res.push_all(b"\\:"),
+            ' ' => res.push_all(b"\\s"),
+            '\\' => res.push_all(b"\\\\"),
+            '\r' => res.push_all(b"\\r"),
+            '\n' => res.push_all(b"\\n"),
+            _ => res.push(b)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Line,IRCCmd,IRCCode,IRCAction,IRCCTCP,IRCCTCPReply};
+    use super::{Line,IRCCmd,IRCCode,IRCAction,IRCCTCP,IRCCTCPReply,Event,Ctcp};
+    use super::{Options, connect, connect_async, connect_pooled, Handler, Reply, Connecting, Registering, Registered, LineReceived, Continue, Quit, Reconnect};
+    use super::{Spawner, TaskSpawner, Manual, Conn, Control, Connected, Disconnected, DisconnectPingTimeout, ReconnectScheduled};
+    use super::{Transport, TcpTransport, Connection, TlsOptions};
+    use super::{NickTruncated, ISupportChanged, JoinFailed, ServerNotice, InvitePolicy, Invited};
+    use super::{ReplyPolicy, SkipNotices, ReplyWithNotice, ReplyWithPrivmsg};
+    use super::{CmdState, CmdConnected, CmdDisconnected, DisconnectClosed, DisconnectKilled};
+    use super::{is_registration_line, realname, split_message, RegistrationModes, MAX_HELD_LINES};
+    use super::{SocketOptions, set_socket_options};
+    use std::time::Duration;
+    use std::io;
+    use std::io::{BufferedReader, Listener, Acceptor, IoResult};
+    use std::io::net::tcp::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use testserver;
     use User;
 
+    #[test]
+    fn command_is_one_of() {
+        let names = ["PRIVMSG", "353", "001"];
+        assert!(IRCCmd("PRIVMSG".into_maybe_owned()).is_one_of(Some(names.as_slice())));
+        assert!(!IRCCmd("JOIN".into_maybe_owned()).is_one_of(Some(names.as_slice())));
+        assert!(IRCCode(353).is_one_of(Some(names.as_slice())));
+        assert!(IRCCode(1).is_one_of(Some(names.as_slice())));
+        assert!(!IRCCode(366).is_one_of(Some(names.as_slice())));
+        assert!(IRCAction(b"#chan".to_vec()).is_one_of(Some(names.as_slice())));
+        assert!(!IRCCTCPReply(b"VERSION".to_vec(), b"me".to_vec()).is_one_of(Some(names.as_slice())));
+        assert!(IRCCode(366).is_one_of(None));
+    }
+
+    #[test]
+    fn events_are_send() {
+        // this is checked at compile time
+        fn assert_send<T: Send>() {}
+        assert_send::<Event>();
+        assert_send::<Line>();
+    }
+
     #[test]
     fn parse_line() {
         macro_rules! t(
@@ -799,6 +2777,7 @@ mod tests {
         )
         t!(b":sendak.freenode.net 001 asldfkj :Welcome to the freenode Internet Relay Chat Network asldfkj",
             Some(Line{
+                tags: Vec::new(),
                 prefix: Some(User::parse(b"sendak.freenode.net")),
                 command: IRCCode(1),
                 args: vec![b"asldfkj",
@@ -806,12 +2785,14 @@ mod tests {
             }));
         t!(b"004 asdf :This is a test",
             Some(Line{
+                tags: Vec::new(),
                 prefix: None,
                 command: IRCCode(4),
                 args: vec![b"asdf", b"This is a test"]
             }));
         t!(b":nick!user@host.com PRIVMSG #channel :Some message",
             Some(Line{
+                tags: Vec::new(),
                 prefix: Some(User::parse(b"nick!user@host.com")),
                 command: IRCCmd("PRIVMSG".into_maybe_owned()),
                 args: vec![b"#channel", b"Some message"]
@@ -820,12 +2801,14 @@ mod tests {
         t!(b":sendak  001 asdf :Test", None);
         t!(b"004",
             Some(Line{
+                tags: Vec::new(),
                 prefix: None,
                 command: IRCCode(4),
                 args: vec![]
             }));
         t!(b":bob!user@host.com PRIVMSG #channel :\x01ACTION does some stuff",
             Some(Line{
+                tags: Vec::new(),
                 prefix: Some(User::parse(b"bob!user@host.com")),
                 command: IRCAction(b"#channel"),
                 args: vec![b"does some stuff"]
@@ -833,17 +2816,847 @@ mod tests {
             b":bob!user@host.com PRIVMSG #channel :\x01ACTION does some stuff\x01");
         t!(b":bob!user@host.com PRIVMSG #channel :\x01VERSION\x01",
             Some(Line{
+                tags: Vec::new(),
                 prefix: Some(User::parse(b"bob!user@host.com")),
                 command: IRCCTCP(b"VERSION", b"#channel"),
                 args: vec![]
             }));
         t!(b":bob NOTICE #frobnitz :\x01RESPONSE to whatever\x01",
             Some(Line{
+                tags: Vec::new(),
                 prefix: Some(User::parse(b"bob")),
                 command: IRCCTCPReply(b"RESPONSE", b"#frobnitz"),
                 args: vec![b"to whatever"]
             }));
         t!(b":bob f\xC3\x83\xC2\xB6o", None);
         t!(b":bob f23", None);
+        t!(b"@time f23", None);
+    }
+
+    #[test]
+    fn registration_lines() {
+        assert!(is_registration_line(b"NICK foo"));
+        assert!(is_registration_line(b"cap REQ :sasl"));
+        assert!(is_registration_line(b"WEBIRC pw gateway host.example 192.0.2.1"));
+        assert!(is_registration_line(b"@label=1 PONG :x"));
+        assert!(!is_registration_line(b"JOIN #chan"));
+        assert!(!is_registration_line(b"NICKSERV IDENTIFY pw"));
+    }
+
+    #[test]
+    fn user_command_params() {
+        assert_eq!(RegistrationModes { invisible: true, wallops: false }.mask(), 8);
+        assert_eq!(RegistrationModes { invisible: true, wallops: true }.mask(), 12);
+        assert_eq!(RegistrationModes { invisible: false, wallops: false }.mask(), 0);
+        assert_eq!(realname(":) Jane Doe", "jane"), b":) Jane Doe".to_vec());
+        assert_eq!(realname("Jane\r\nQUIT", "jane"), b"JaneQUIT".to_vec());
+        assert_eq!(realname("", "jane"), b"jane".to_vec());
+        assert_eq!(realname("\n", "jane"), b"jane".to_vec());
+    }
+
+    #[test]
+    fn to_raw_numeric() {
+        let line = Line {
+            tags: Vec::new(),
+            prefix: Some(User::parse(b"irc.example.net")),
+            command: IRCCode(1),
+            args: vec![b"nick".to_vec(), b"Welcome to the network".to_vec()]
+        };
+        assert_eq!(line.to_raw(), b":irc.example.net 001 nick :Welcome to the network".to_vec());
+        let line = Line { prefix: None, command: IRCCode(42), args: Vec::new(), tags: Vec::new() };
+        assert_eq!(line.to_raw(), b"042".to_vec());
+    }
+
+    #[test]
+    fn parse_ctcp() {
+        let line = Line::parse(b":bob!u@h PRIVMSG me :\x01PING 1234 5678\x01").unwrap();
+        assert_eq!(line.ctcp(), Some(Ctcp {
+            command: b"PING".to_vec(),
+            target: b"me".to_vec(),
+            params: b"1234 5678".to_vec(),
+            reply: false
+        }));
+        let line = Line::parse(b":bob!u@h NOTICE me :\x01VERSION\x01").unwrap();
+        assert_eq!(line.ctcp(), Some(Ctcp {
+            command: b"VERSION".to_vec(),
+            target: b"me".to_vec(),
+            params: Vec::new(),
+            reply: true
+        }));
+        let line = Line::parse(b":bob!u@h PRIVMSG #chan :\x01ACTION waves\x01").unwrap();
+        assert_eq!(line.ctcp().map(|c| c.command), Some(b"ACTION".to_vec()));
+        assert_eq!(Line::parse(b":bob!u@h PRIVMSG me :hi").unwrap().ctcp(), None);
+    }
+
+    #[test]
+    fn round_trip_trailing() {
+        let round_trip = |raw: &[u8]| {
+            let line = Line::parse(raw).unwrap();
+            assert_eq!(Line::parse(line.to_raw().as_slice()), Some(line.clone()));
+            line.to_raw()
+        };
+        assert_eq!(round_trip(b"TOPIC #chan :"), b"TOPIC #chan :".to_vec());
+        assert_eq!(round_trip(b"PRIVMSG #chan ::)"), b"PRIVMSG #chan ::)".to_vec());
+        assert_eq!(round_trip(b"PRIVMSG #chan :hi"), b"PRIVMSG #chan hi".to_vec());
+        assert_eq!(round_trip(b"PRIVMSG #chan :a b"), b"PRIVMSG #chan :a b".to_vec());
+
+        let line = Line::parse(b"TOPIC #chan :").unwrap();
+        assert_eq!(line.args, vec![b"#chan".to_vec(), Vec::new()]);
+        let line = Line::parse(b"PRIVMSG #chan ::)").unwrap();
+        assert_eq!(line.args, vec![b"#chan".to_vec(), b":)".to_vec()]);
+    }
+
+    #[test]
+    fn parse_line_tags() {
+        let raw = b"@time=2014-06-01T12:00:00.000Z;msgid=a\\sb\\:c;+draft/x :nick PRIVMSG #chan :hi";
+        let line = Line::parse(raw).unwrap();
+        assert_eq!(line.tag("time"), Some("2014-06-01T12:00:00.000Z"));
+        assert_eq!(line.tag("msgid"), Some("a b;c"));
+        assert_eq!(line.msgid(), Some("a b;c"));
+        assert_eq!(line.tag("+draft/x"), Some(""));
+        assert_eq!(line.tag("account"), None);
+        assert_eq!(Line::parse(b"@msgid= :nick PRIVMSG #chan :hi").unwrap().msgid(), None);
+        assert_eq!(line.args, vec![b"#chan".to_vec(), b"hi".to_vec()]);
+        assert_eq!(line.to_raw().as_slice(), raw.as_slice());
+    }
+
+    struct MockSocket {
+        calls: Vec<String>,
+        fail: bool
+    }
+
+    impl MockSocket {
+        fn result(&self) -> IoResult<()> {
+            if self.fail { Err(io::standard_error(io::OtherIoError)) } else { Ok(()) }
+        }
+    }
+
+    impl SocketOptions for MockSocket {
+        fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()> {
+            self.calls.push(format!("nodelay {}", nodelay));
+            self.result()
+        }
+
+        fn set_keepalive(&mut self, delay_in_seconds: Option<uint>) -> IoResult<()> {
+            self.calls.push(format!("keepalive {}", delay_in_seconds));
+            self.result()
+        }
+    }
+
+    #[test]
+    fn socket_options() {
+        let mut opts: Options = Options::new("irc.example.net", 6667);
+        let mut socket = MockSocket { calls: Vec::new(), fail: false };
+        set_socket_options(&mut socket, &opts);
+        assert!(socket.calls.is_empty());
+        // keepalive is set in whole seconds, and at least one
+        opts.tcp_nodelay = true;
+        opts.tcp_keepalive = Some(Duration::milliseconds(500));
+        set_socket_options(&mut socket, &opts);
+        assert_eq!(socket.calls, vec!["nodelay true".to_string(), "keepalive Some(1)".to_string()]);
+        // a failure doesn't stop the rest
+        let mut socket = MockSocket { calls: Vec::new(), fail: true };
+        opts.tcp_keepalive = Some(Duration::minutes(2));
+        set_socket_options(&mut socket, &opts);
+        assert_eq!(socket.calls, vec!["nodelay true".to_string(), "keepalive Some(120)".to_string()]);
+
+        // and they can be set on a real socket
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let _acceptor = listener.listen().unwrap();
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        set_socket_options(&mut socket, &opts);
+        assert!(socket.write(b"PING x\r\n").is_ok());
+    }
+
+    #[test]
+    fn callback_panic_closes_connection() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let opts: Options = Options::new("127.0.0.1", port);
+            let mut events = 0u;
+            let res = connect(opts, (), |_, event, _| {
+                events += 1;
+                match event {
+                    Registering => panic!("oops"),
+                    _ => Continue
+                }
+            });
+            tx.send((res.err().map(|e| e.to_string()), events));
+        });
+
+        // the connection sends QUIT after the panic, and reading to the end
+        // only finishes once every task holding the socket is gone
+        let stream = acceptor.accept().unwrap();
+        let lines = BufferedReader::new(stream).lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["NICK ircnick\r\n".to_string(),
+                               "USER ircuser 8 * :rust-irclib user\r\n".to_string(),
+                               "QUIT\r\n".to_string()]);
+        // Connecting, Connected and Registering, but not Disconnected
+        assert_eq!(rx.recv(), (Some("callback panicked: oops".to_string()), 3));
+    }
+
+    #[test]
+    fn reconnect_scheduled() {
+        // the first server is reconnected to, then times out, then redirects
+        // us to the second one
+        let mut first = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = first.socket_name().unwrap().port;
+        let mut first = first.listen().unwrap();
+        let mut second = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let second_port = second.socket_name().unwrap().port;
+        let mut second = second.listen().unwrap();
+        fn serve(mut stream: TcpStream, greeting: &str) {
+            stream.write_str(greeting).unwrap();
+            // until the client quits or hangs up
+            for line in BufferedReader::new(stream).lines() {
+                if line.map(|l| l.as_slice().starts_with("QUIT")).unwrap_or(true) {
+                    break;
+                }
+            }
+        }
+        spawn(proc() {
+            let welcome = ":irc.example.net 001 ircnick :Welcome\r\n";
+            serve(first.accept().unwrap(), welcome);
+            serve(first.accept().unwrap(), welcome);
+            let redirect = format!(":irc.example.net 010 ircnick 127.0.0.1 {} :Try this one\r\n", second_port);
+            serve(first.accept().unwrap(), redirect.as_slice());
+            serve(second.accept().unwrap(), welcome);
+        });
+
+        let mut opts: Options = Options::new("127.0.0.1", port);
+        opts.ping_timeout = Some(Duration::milliseconds(400));
+        opts.follow_redirects = true;
+        let mut events = Vec::new();
+        let res = connect(opts, (), |_, event, _| {
+            let (event, control) = match event {
+                Connecting => ("connecting".to_string(), Continue),
+                Connected => ("connected".to_string(), Continue),
+                Registering => ("registering".to_string(), Continue),
+                Registered(_) => {
+                    let control = match events.iter().filter(|e| e.as_slice() == "registered").count() {
+                        0 => Reconnect,
+                        1 => Continue,
+                        _ => Quit(Vec::new())
+                    };
+                    ("registered".to_string(), control)
+                }
+                Disconnected(DisconnectPingTimeout) => ("timed out".to_string(), Reconnect),
+                Disconnected(_) => ("disconnected".to_string(), Continue),
+                ReconnectScheduled(host, port, delay) => (format!("reconnect {}:{} {}", host, port, delay), Continue),
+                _ => return Continue
+            };
+            events.push(event);
+            control
+        });
+        assert!(res.is_ok());
+        let attempt = ["connecting", "connected", "registering"];
+        let mut expected = Vec::new();
+        expected.extend(attempt.iter().map(|s| s.to_string()));
+        expected.push("registered".to_string());
+        expected.push("disconnected".to_string());
+        expected.push(format!("reconnect 127.0.0.1:{} None", port));
+        expected.extend(attempt.iter().map(|s| s.to_string()));
+        expected.push("registered".to_string());
+        expected.push("timed out".to_string());
+        expected.push(format!("reconnect 127.0.0.1:{} None", port));
+        expected.extend(attempt.iter().map(|s| s.to_string()));
+        expected.push("disconnected".to_string());
+        expected.push(format!("reconnect 127.0.0.1:{} None", second_port));
+        expected.extend(attempt.iter().map(|s| s.to_string()));
+        expected.push("registered".to_string());
+        expected.push("disconnected".to_string());
+        assert_eq!(events, expected);
+    }
+
+    /// Pretends to do TLS over TCP, handing out a new session every time
+    struct SessionTransport {
+        resumed: Arc<Mutex<Vec<Option<Vec<u8>>>>>,
+        sessions: u8
+    }
+
+    impl Transport for SessionTransport {
+        fn connect(&mut self, host: &str, port: u16, tls: Option<&TlsOptions>) -> IoResult<Connection> {
+            self.resumed.lock().push(tls.and_then(|t| t.session.clone()));
+            let mut connection = try!(TcpTransport.connect(host, port, None));
+            self.sessions += 1;
+            connection.tls_session = Some(vec![self.sessions]);
+            Ok(connection)
+        }
+    }
+
+    #[test]
+    fn tls_session_resumed() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        spawn(proc() {
+            for _ in range(0u, 2) {
+                let stream = acceptor.accept().unwrap();
+                let mut writer = stream.clone();
+                writer.write_str(":irc.example.net 001 ircnick :Welcome\r\n").unwrap();
+                for line in BufferedReader::new(stream).lines() {
+                    if line.map(|l| l.as_slice().starts_with("QUIT")).unwrap_or(true) {
+                        break;
+                    }
+                }
+            }
+        });
+        let resumed = Arc::new(Mutex::new(Vec::new()));
+        let mut opts: Options<uint> = Options::new("127.0.0.1", port);
+        opts.tls = Some(TlsOptions::new());
+        opts.transport = Some(box SessionTransport { resumed: resumed.clone(), sessions: 0 } as Box<Transport + Send>);
+        let res = connect(opts, 0u, |_, event, registered| match event {
+            Registered(_) if *registered == 0 => {
+                *registered += 1;
+                Reconnect
+            }
+            Registered(_) => Quit(Vec::new()),
+            _ => Continue
+        });
+        assert!(res.is_ok());
+        assert_eq!(*resumed.lock(), vec![None, Some(vec![1u8])]);
+        // the default transport doesn't do TLS
+        assert!(TcpTransport.connect("127.0.0.1", port, Some(&TlsOptions::new())).is_err());
+    }
+
+    #[test]
+    fn ping_before_registration() {
+        let (port, rx) = testserver::script(vec![
+            "C: NICK ircnick",
+            "C: USER ircuser 8 * :rust-irclib user",
+            "S: PING :1043895223",
+            "C: PONG 1043895223",
+            "S: PING :cookie with spaces",
+            "C: PONG :cookie with spaces",
+            // with our full mask, so that no USERHOST is sent
+            "S: :irc.example.net 001 ircnick :Welcome ircnick!ircuser@localhost",
+            "C: QUIT"
+        ]).unwrap();
+        let opts: Options = Options::new("127.0.0.1", port);
+        let res = connect(opts, (), |_, event, _| match event {
+            Registered(_) => Quit(Vec::new()),
+            _ => Continue
+        });
+        assert_eq!(rx.recv(), None);
+        assert!(res.is_ok());
+    }
+
+    /// Connects asking for `caps` and quits once registered. Returns the
+    /// capabilities that were enabled.
+    fn connect_with_caps(port: u16, caps: Vec<&'static str>) -> Vec<String> {
+        let mut opts: Options = Options::new("127.0.0.1", port);
+        opts.caps = caps;
+        let mut enabled = Vec::new();
+        let res = connect(opts, (), |conn, event, _| match event {
+            Registered(_) => {
+                enabled = conn.caps();
+                Quit(Vec::new())
+            }
+            _ => Continue
+        });
+        assert!(res.is_ok());
+        enabled
+    }
+
+    #[test]
+    fn cap_negotiation() {
+        let (port, rx) = testserver::script(vec![
+            "C: CAP LS 302",
+            "C: NICK ircnick",
+            "C: USER ircuser 8 * :rust-irclib user",
+            "S: :irc.example.net CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL",
+            "S: :irc.example.net CAP * LS :away-notify account-tag",
+            "C: CAP REQ :away-notify multi-prefix",
+            "S: :irc.example.net CAP * ACK :away-notify multi-prefix",
+            "C: CAP END",
+            "S: :irc.example.net 001 ircnick :Welcome ircnick!ircuser@localhost",
+            "C: QUIT"
+        ]).unwrap();
+        let enabled = connect_with_caps(port, vec!["away-notify", "multi-prefix", "extended-join"]);
+        assert_eq!(rx.recv(), None);
+        assert_eq!(enabled, vec!["away-notify".to_string(), "multi-prefix".to_string()]);
+    }
+
+    #[test]
+    fn cap_rejected() {
+        // old servers that don't know CAP register us without it
+        let replies = ["S: :old.example.net 421 * CAP :Unknown command",
+                       "S: :old.example.net 451 * :You have not registered"];
+        for &reply in replies.iter() {
+            let (port, rx) = testserver::script(vec![
+                "C: CAP LS 302",
+                "C: NICK ircnick",
+                "C: USER ircuser 8 * :rust-irclib user",
+                reply,
+                "S: :old.example.net 001 ircnick :Welcome ircnick!ircuser@localhost",
+                "C: QUIT"
+            ]).unwrap();
+            assert_eq!(connect_with_caps(port, vec!["away-notify"]), Vec::new());
+            assert_eq!(rx.recv(), None);
+        }
+    }
+
+    /// Records the names of the jobs it spawns
+    struct RecordingSpawner(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Spawner for RecordingSpawner {
+        fn spawn(&mut self, name: &'static str, job: proc(): Send) {
+            let &RecordingSpawner(ref names) = self;
+            names.lock().push(name);
+            TaskSpawner.spawn(name, job);
+        }
+    }
+
+    #[test]
+    fn custom_spawner() {
+        let (port, rx) = testserver::script(vec![
+            "C: NICK ircnick",
+            "C: USER ircuser 8 * :rust-irclib user",
+            "S: :irc.example.net 001 ircnick :Welcome ircnick!ircuser@localhost",
+            "C: QUIT"
+        ]).unwrap();
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let mut opts: Options = Options::new("127.0.0.1", port);
+        opts.spawner = Some(box RecordingSpawner(names.clone()) as Box<Spawner + Send>);
+        let res = connect(opts, (), |_, event, _| match event {
+            Registered(_) => Quit(Vec::new()),
+            _ => Continue
+        });
+        assert_eq!(rx.recv(), None);
+        assert!(res.is_ok());
+        assert_eq!(*names.lock(), vec!["libirc writer", "libirc reader"]);
+    }
+
+    #[test]
+    fn custom_spawner_async() {
+        let (port, rx) = testserver::script(vec![
+            "C: NICK ircnick",
+            "C: USER ircuser 8 * :rust-irclib user",
+            "S: :irc.example.net 001 ircnick :Welcome ircnick!ircuser@localhost",
+            "C: QUIT"
+        ]).unwrap();
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let mut opts: Options<'static, ()> = Options::new("127.0.0.1", port);
+        opts.spawner = Some(box RecordingSpawner(names.clone()) as Box<Spawner + Send>);
+        let handle = connect_async(opts, ());
+        loop {
+            match handle.events.recv() {
+                Registered(_) => break,
+                _ => ()
+            }
+        }
+        handle.commands.send(proc(conn, _, _) { conn.quit([]) });
+        assert!(handle.result.recv().is_ok());
+        assert_eq!(rx.recv(), None);
+        assert_eq!(*names.lock(), vec!["libirc connection", "libirc writer", "libirc reader"]);
+    }
+
+    /// Quits once registered
+    struct QuitHandler;
+
+    impl Handler for QuitHandler {
+        fn handle(&self, event: Event, reply: &Reply) {
+            match event {
+                Registered(_) => reply.quit([]),
+                _ => ()
+            }
+        }
+    }
+
+    #[test]
+    fn custom_spawner_pooled() {
+        let (port, rx) = testserver::script(vec![
+            "C: NICK ircnick",
+            "C: USER ircuser 8 * :rust-irclib user",
+            "S: :irc.example.net 001 ircnick :Welcome ircnick!ircuser@localhost",
+            "C: QUIT"
+        ]).unwrap();
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let mut opts: Options = Options::new("127.0.0.1", port);
+        opts.spawner = Some(box RecordingSpawner(names.clone()) as Box<Spawner + Send>);
+        assert!(connect_pooled(opts, 2, QuitHandler).is_ok());
+        assert_eq!(rx.recv(), None);
+        assert_eq!(*names.lock(), vec!["libirc worker", "libirc worker", "libirc writer", "libirc reader"]);
+    }
+
+    #[test]
+    fn manual_ping_timeout() {
+        let opts: Options<Vec<String>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        fn record(_: &mut Conn, event: Event, events: &mut Vec<String>) -> Control {
+            match event {
+                Connected => events.push("connected".to_string()),
+                Registered(_) => events.push("registered".to_string()),
+                Disconnected(DisconnectPingTimeout) => events.push("timed out".to_string()),
+                _ => ()
+            }
+            Continue
+        }
+        let out = conn.tick([], Duration::zero(), |c, e, p| record(c, e, p));
+        assert_eq!(out, vec![b"NICK ircnick".to_vec(), b"USER ircuser 8 * :rust-irclib user".to_vec()]);
+        let out = conn.tick([b":irc.example.net 001 ircnick :Welcome"], Duration::seconds(119),
+                            |c, e, p| record(c, e, p));
+        assert!(out.is_empty());
+        assert_eq!(*conn.payload(), vec!["connected".to_string(), "registered".to_string()]);
+        // quiet for half the timeout
+        let out = conn.tick([], Duration::seconds(1), |c, e, p| record(c, e, p));
+        assert_eq!(out, vec![b"PING 120000000000".to_vec()]);
+        let out = conn.tick([], Duration::seconds(120), |c, e, p| record(c, e, p));
+        assert!(out.is_empty());
+        assert!(!conn.conn().is_connected());
+        let summary = conn.server_closed(|c, e, p| record(c, e, p));
+        assert_eq!(summary.duration, Duration::seconds(240));
+        assert_eq!(conn.payload().last(), Some(&"timed out".to_string()));
+    }
+
+    #[test]
+    fn manual_reconnect() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome", b":ircnick!u@h JOIN #rust"], Duration::seconds(5));
+        assert!(conn.conn().is_registered());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.events();
+        conn.reconnect();
+        let out = conn.step([], Duration::zero());
+        assert_eq!(out, vec![b"NICK ircnick".to_vec(), b"USER ircuser 8 * :rust-irclib user".to_vec()]);
+        assert!(!conn.conn().is_registered());
+        assert!(conn.conn().state().channel(b"#rust").is_none());
+        let events = conn.events().into_iter().filter_map(|e| match e {
+            ReconnectScheduled(host, 6667, None) if host.as_slice() == "irc.example.net" => Some("scheduled"),
+            Connecting => Some("connecting"),
+            Connected => Some("connected"),
+            Registering => Some("registering"),
+            _ => None
+        }).collect::<Vec<&str>>();
+        assert_eq!(events, vec!["scheduled", "connecting", "connected", "registering"]);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        assert!(conn.conn().is_registered());
+    }
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message(b"", 10), vec![b""]);
+        assert_eq!(split_message(b"hello world", 20), vec![b"hello world"]);
+        assert_eq!(split_message(b"hello world again", 12), vec![b"hello world", b"again"]);
+        assert_eq!(split_message(b"abcdefghij", 4), vec![b"abcd", b"efgh", b"ij"]);
+        // é is two bytes
+        assert_eq!(split_message("aaé".as_bytes(), 3), vec![b"aa", "é".as_bytes()]);
+    }
+
+    #[test]
+    fn long_messages() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let msg = Vec::from_elem(500, 'a' as u8);
+        let room = conn.conn().max_message_len("NOTICE", b"#rust");
+        conn.conn().notice(b"#rust", msg.as_slice());
+        let out = conn.step([], Duration::zero());
+        let prefix = b"NOTICE #rust :";
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].len(), prefix.len() + room);
+        assert!(out.iter().all(|l| l.as_slice().starts_with(prefix)));
+        let text = out.iter().flat_map(|l| l.slice_from(prefix.len()).iter().map(|&b| b)).collect::<Vec<u8>>();
+        assert_eq!(text, msg);
+    }
+
+    #[test]
+    fn privmsg_many() {
+        fn sent(isupport: &[u8], targets: &[&[u8]], msg: &[u8]) -> Vec<Vec<u8>> {
+            let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+            let mut conn = Manual::new(opts, Vec::new());
+            conn.step([b":irc.example.net 001 ircnick :Welcome", isupport], Duration::zero());
+            conn.conn().privmsg_many(targets, msg);
+            conn.step([], Duration::zero())
+        }
+        let targets = [b"alice".as_slice(), b"bob", b"carol"];
+        // TARGMAX wins over MAXTARGETS
+        assert_eq!(sent(b":irc.example.net 005 ircnick TARGMAX=PRIVMSG:2 MAXTARGETS=4 :are supported",
+                        targets, b"hi"),
+                   vec![b"PRIVMSG alice,bob hi".to_vec(), b"PRIVMSG carol hi".to_vec()]);
+        assert_eq!(sent(b":irc.example.net 005 ircnick TARGMAX=PRIVMSG: :are supported", targets, b"hi"),
+                   vec![b"PRIVMSG alice,bob,carol hi".to_vec()]);
+        assert_eq!(sent(b":irc.example.net 005 ircnick MAXTARGETS=2 :are supported", targets, b"hi"),
+                   vec![b"PRIVMSG alice,bob hi".to_vec(), b"PRIVMSG carol hi".to_vec()]);
+        assert_eq!(sent(b":irc.example.net 005 ircnick NETWORK=Example :are supported", targets, b"hi"),
+                   vec![b"PRIVMSG alice hi".to_vec(), b"PRIVMSG bob hi".to_vec(), b"PRIVMSG carol hi".to_vec()]);
+
+        // room for two of the 9-byte targets next to the message, but not three
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 005 ircnick TARGMAX=PRIVMSG: :are supported"], Duration::zero());
+        let msg = Vec::from_elem(conn.conn().max_message_len("PRIVMSG", b"") - 19, 'a' as u8);
+        conn.conn().privmsg_many([b"#channel1", b"#channel2", b"#channel3"], msg.as_slice());
+        let out = conn.step([], Duration::zero());
+        let mut first = b"PRIVMSG #channel1,#channel2 ".to_vec();
+        first.push_all(msg.as_slice());
+        let mut second = b"PRIVMSG #channel3 ".to_vec();
+        second.push_all(msg.as_slice());
+        assert_eq!(out, vec![first, second]);
+    }
+
+    #[test]
+    fn join_many() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.join_delay = Duration::seconds(3);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 005 ircnick MAXTARGETS=1 :are supported"], Duration::zero());
+        // keyed channels go first so the keys line up, and MAXTARGETS doesn't limit JOIN
+        conn.conn().join_many([(b"#a", b""), (b"#b", b"bkey"), (b"#c", b""), (b"#d", b"dkey")]);
+        assert_eq!(conn.step([], Duration::zero()), vec![b"JOIN #b,#d,#a,#c bkey,dkey".to_vec()]);
+
+        // without a TARGMAX entry, only the line length splits them
+        let names = range(0u, 30).map(|i| format!("#channel-number-{:04}", i).into_bytes()).collect::<Vec<_>>();
+        let chans = names.iter().map(|n| (n.as_slice(), b"".as_slice())).collect::<Vec<_>>();
+        conn.conn().join_many(chans.as_slice());
+        let mut out = conn.step([], Duration::zero());
+        assert_eq!(out.len(), 1);
+        out.push_all(conn.step([], Duration::seconds(3)).as_slice());
+        assert_eq!(out.len(), 2);
+        assert!(out.iter().all(|l| l.len() <= 510));
+        assert_eq!(out[0].len(), "JOIN ".len() + 24 * 21 - 1);
+        let joined = out.iter().flat_map(|l| l.slice_from(5).split(|&b| b == ',' as u8))
+                        .map(|c| c.to_vec()).collect::<Vec<_>>();
+        assert_eq!(joined, names);
+
+        // TARGMAX limits the batches, which are spaced out by join_delay
+        conn.step([b":irc.example.net 005 ircnick TARGMAX=JOIN:2 :are supported"], Duration::zero());
+        conn.conn().join_many([(b"#a", b""), (b"#b", b""), (b"#c", b""), (b"#d", b""), (b"#e", b"")]);
+        assert_eq!(conn.step([], Duration::zero()), vec![b"JOIN #a,#b".to_vec()]);
+        assert!(conn.step([], Duration::seconds(2)).is_empty());
+        assert_eq!(conn.step([], Duration::seconds(1)), vec![b"JOIN #c,#d".to_vec()]);
+        assert!(conn.step([], Duration::seconds(2)).is_empty());
+        assert_eq!(conn.step([], Duration::seconds(1)), vec![b"JOIN #e".to_vec()]);
+    }
+
+    #[test]
+    fn own_host_casemapped() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        // the server may spell our nick with another case
+        conn.step([b":irc.example.net 302 ircnick :IRCNick=+u1@host.one"], Duration::zero());
+        assert_eq!(conn.conn().me().host(), Some(b"host.one"));
+        conn.step([b":irc.example.net 311 ircnick IRCNICK u2 host.two * :realname"], Duration::zero());
+        assert_eq!(conn.conn().me().host(), Some(b"host.two"));
+        conn.step([b":IrcNick!u3@host.three JOIN #rust"], Duration::zero());
+        assert_eq!(conn.conn().me().host(), Some(b"host.three"));
+        // someone else's replies don't change it
+        conn.step([b":irc.example.net 302 ircnick :bob=+b@elsewhere",
+                   b":irc.example.net 311 ircnick bob b elsewhere * :realname"], Duration::zero());
+        assert_eq!(conn.conn().me().host(), Some(b"host.three"));
+    }
+
+    #[test]
+    fn invites() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.auto_join_invites = Some(InvitePolicy { inviters: vec![b"bob".to_vec()], channels: Vec::new() });
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.events();
+        let out = conn.step([b":Bob!b@h INVITE IRCNick :#rust",
+                             b":carol!c@h INVITE ircnick :#other",
+                             b":bob!b@h INVITE someone :#third"], Duration::zero());
+        assert_eq!(out, vec![b"JOIN #rust".to_vec()]);
+        let invited = conn.events().into_iter().filter_map(|e| match e {
+            Invited(_, chan) => Some(chan),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(invited, vec![b"#rust".to_vec(), b"#other".to_vec()]);
+    }
+
+    #[test]
+    fn held_lines() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([], Duration::zero());
+        conn.conn().privmsg(b"#rust", b"from the failed attempt");
+        assert!(conn.step([], Duration::zero()).is_empty());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        for _ in range(0, MAX_HELD_LINES + 1) {
+            conn.conn().privmsg(b"alice", b"hi");
+        }
+        conn.step([], Duration::zero());
+        let out = conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let privmsgs = out.iter().filter(|l| l.as_slice().starts_with(b"PRIVMSG")).collect::<Vec<_>>();
+        assert_eq!(privmsgs.len(), MAX_HELD_LINES);
+        assert!(privmsgs.iter().all(|l| l.as_slice() == b"PRIVMSG alice :hi"));
+    }
+
+    #[test]
+    fn commands_after_disconnect() {
+        let (tx, rx) = channel();
+        let mut opts: Options<Vec<String>> = Options::new("irc.example.net", 6667);
+        opts.commands = Some(rx);
+        let mut conn = Manual::new(opts, Vec::new());
+        fn go(_: &mut Conn, _: Event, _: &mut Vec<String>) -> Control { Continue }
+        fn record(conn: &mut Conn, states: &mut Vec<String>, state: CmdState) {
+            match state {
+                CmdConnected => conn.privmsg(b"bob", b"hi"),
+                CmdDisconnected(DisconnectClosed) => states.push("closed".to_string()),
+                CmdDisconnected(_) => states.push("other".to_string())
+            }
+        }
+        conn.tick([b":irc.example.net 001 ircnick :Welcome"], Duration::zero(), |c, e, p| go(c, e, p));
+        tx.send(proc(conn: &mut Conn, states: &mut Vec<String>, state: CmdState) record(conn, states, state));
+        assert_eq!(conn.tick([], Duration::zero(), |c, e, p| go(c, e, p)), vec![b"PRIVMSG bob hi".to_vec()]);
+        tx.send(proc(conn: &mut Conn, states: &mut Vec<String>, state: CmdState) record(conn, states, state));
+        conn.server_closed(|c, e, p| go(c, e, p));
+        assert_eq!(*conn.payload(), vec!["closed".to_string()]);
+    }
+
+    #[test]
+    fn nick_truncated() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.nick = "averylongnick";
+        let mut conn = Manual::new(opts, Vec::new());
+        fn truncated(events: Vec<Event>) -> Vec<(Vec<u8>, Vec<u8>)> {
+            events.into_iter().filter_map(|e| match e {
+                NickTruncated(requested, nick) => Some((requested, nick)),
+                _ => None
+            }).collect()
+        }
+        conn.step([b":irc.example.net 001 averylong :Welcome",
+                   b":irc.example.net 005 averylong NICKLEN=9 :are supported by this server"], Duration::zero());
+        assert_eq!(truncated(conn.events()), vec![(b"averylongnick".to_vec(), b"averylong".to_vec())]);
+        assert_eq!(conn.conn().set_nick(b"another_long_one"), Ok(()));
+        let out = conn.step([], Duration::zero());
+        assert!(out.iter().any(|l| l.as_slice() == b"NICK another_l"));
+        assert_eq!(truncated(conn.events()), vec![(b"another_long_one".to_vec(), b"another_l".to_vec())]);
+    }
+
+    #[test]
+    fn killed() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":oper!o@h KILL someone :spamming",
+                   b":oper!o@h KILL IRCNick :flooding"], Duration::zero());
+        let summary = conn.server_closed(|_, event, events| { events.push(event); Continue });
+        match summary.reason {
+            DisconnectKilled(Some(ref killer), ref reason) => {
+                assert_eq!(killer.nick(), b"oper");
+                assert_eq!(reason.as_slice(), b"flooding");
+            }
+            ref reason => panic!("unexpected reason {}", reason)
+        }
+    }
+
+    #[test]
+    fn server_name() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        assert_eq!(conn.conn().server_name(), None);
+        // round-robin DNS put us on a different server than the one we asked for
+        conn.step([b":leaf.example.net 001 ircnick :Welcome",
+                   b":leaf.example.net 004 ircnick leaf.example.net ircd-1.0 iow beI",
+                   b":leaf.example.net NOTICE ircnick :*** Notice -- Client connecting",
+                   b":services.example.net NOTICE ircnick :*** Not from our server"], Duration::zero());
+        assert_eq!(conn.conn().server_name(), Some(b"leaf.example.net"));
+        let notices = conn.events().into_iter().filter(|e| match *e {
+            ServerNotice(_) => true,
+            _ => false
+        }).count();
+        assert_eq!(notices, 1);
+        conn.reconnect();
+        assert_eq!(conn.conn().server_name(), None);
+    }
+
+    #[test]
+    fn join_failed() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        fn failed(events: Vec<Event>) -> Vec<Vec<u8>> {
+            events.into_iter().filter_map(|e| match e {
+                JoinFailed(chan, _) => Some(chan),
+                _ => None
+            }).collect()
+        }
+        let banned = b":irc.example.net 474 ircnick #rust :Cannot join channel (+b)";
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        assert_eq!(conn.conn().join(b"#rust", []), Ok(()));
+        conn.step([banned, banned], Duration::zero());
+        assert_eq!(failed(conn.events()), vec![b"#rust".to_vec()]);
+        // a JOIN sent on the last connection doesn't match an error on this one
+        assert_eq!(conn.conn().join(b"#rust", []), Ok(()));
+        conn.step([], Duration::zero());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step([b":irc.example.net 001 ircnick :Welcome", banned], Duration::zero());
+        assert!(failed(conn.events()).is_empty());
+    }
+
+    #[test]
+    fn reply_policy() {
+        let replies = |policy: ReplyPolicy| {
+            let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+            opts.reply_policy = policy;
+            let mut conn = Manual::new(opts, Vec::new());
+            conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+            for raw in [b":bob!u@h PRIVMSG #rust :!help", b":bob!u@h PRIVMSG ircnick :!help",
+                        b":bob!u@h NOTICE ircnick :!help"].iter() {
+                conn.conn().reply(&Line::parse(*raw).unwrap(), b"no help here");
+            }
+            conn.step([], Duration::zero())
+        };
+        assert_eq!(replies(SkipNotices), vec![b"PRIVMSG #rust :no help here".to_vec(),
+                                              b"PRIVMSG bob :no help here".to_vec()]);
+        assert_eq!(replies(ReplyWithNotice), vec![b"NOTICE #rust :no help here".to_vec(),
+                                                  b"NOTICE bob :no help here".to_vec(),
+                                                  b"NOTICE bob :no help here".to_vec()]);
+        assert_eq!(replies(ReplyWithPrivmsg), vec![b"PRIVMSG #rust :no help here".to_vec(),
+                                                   b"PRIVMSG bob :no help here".to_vec(),
+                                                   b"PRIVMSG bob :no help here".to_vec()]);
+    }
+
+    #[test]
+    fn ignore_own_messages() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.ignore_own_messages = true;
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.events();
+        let out = conn.step([b":IRCNick!u@h PRIVMSG #rust :hello",
+                             b":ircnick!u@h PRIVMSG ircnick :\x01PING 1\x01",
+                             b":bob!u@h PRIVMSG #rust :hello"], Duration::zero());
+        assert!(out.is_empty());
+        let senders = conn.events().into_iter().filter_map(|e| match e {
+            LineReceived(line) => Some(line.prefix.map_or(Vec::new(), |u| u.nick().to_vec())),
+            _ => None
+        }).collect::<Vec<Vec<u8>>>();
+        assert_eq!(senders, vec![b"bob".to_vec()]);
+    }
+
+    #[test]
+    fn isupport_changed() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        fn changes(events: Vec<Event>) -> Vec<(String, Option<Vec<u8>>)> {
+            let mut changes = Vec::new();
+            for e in events.into_iter() {
+                match e {
+                    ISupportChanged(changed) => changes.extend(changed.into_iter()),
+                    _ => ()
+                }
+            }
+            changes
+        }
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 005 ircnick NICKLEN=9 TOPICLEN=300 :are supported by this server",
+                   b":irc.example.net 376 ircnick :End of /MOTD command."], Duration::zero());
+        assert!(changes(conn.events()).is_empty());
+        conn.step([b":irc.example.net 005 ircnick NICKLEN=9 NICKLEN=30 -TOPICLEN :are supported by this server"],
+                  Duration::zero());
+        assert_eq!(changes(conn.events()), vec![("NICKLEN".to_string(), Some(b"30".to_vec())),
+                                                ("TOPICLEN".to_string(), None)]);
+        assert_eq!(conn.conn().isupport().get_uint("NICKLEN"), Some(30));
     }
 }
@@ -0,0 +1,216 @@
+//! Channel moderation helpers
+
+use conn::{Conn, IRCCmd, AccessMatch, ByMask, ByAccount};
+use extban::ExtBan;
+use modes::ModeChange;
+use quirks::{QuietMode, QuietExtban, NoQuiet};
+
+impl<'a> Conn<'a> {
+    /// Gives channel operator status to `nick` in `chan`
+    pub fn op(&mut self, chan: &[u8], nick: &[u8]) {
+        self.set_modes(chan, [ModeChange::set('o', Some(nick))]);
+    }
+
+    /// Takes channel operator status from `nick` in `chan`
+    pub fn deop(&mut self, chan: &[u8], nick: &[u8]) {
+        self.set_modes(chan, [ModeChange::unset('o', Some(nick))]);
+    }
+
+    /// Gives voice to `nick` in `chan`
+    pub fn voice(&mut self, chan: &[u8], nick: &[u8]) {
+        self.set_modes(chan, [ModeChange::set('v', Some(nick))]);
+    }
+
+    /// Takes voice from `nick` in `chan`
+    pub fn devoice(&mut self, chan: &[u8], nick: &[u8]) {
+        self.set_modes(chan, [ModeChange::unset('v', Some(nick))]);
+    }
+
+    /// Sets the user limit (+l) of `chan`. Pass None to remove the limit.
+    pub fn set_limit(&mut self, chan: &[u8], limit: Option<uint>) {
+        match limit {
+            None => self.set_modes(chan, [ModeChange::unset('l', None)]),
+            Some(limit) => {
+                let limit = limit.to_string();
+                self.set_modes(chan, [ModeChange::set('l', Some(limit.as_bytes()))]);
+            }
+        }
+    }
+
+    /// Quiets `mask` in `chan`, so that matching users can stay but can't speak.
+    ///
+    /// This uses the +q list mode where the server has one (e.g. charybdis),
+    /// and a quiet extban such as `+b ~q:mask` otherwise (e.g. Unreal or
+    /// InspIRCd), as decided by `quirks()`. Returns `false` without sending
+    /// anything if the server has neither.
+    pub fn quiet(&mut self, chan: &[u8], mask: &[u8]) -> bool {
+        match self.quiet_mode(mask) {
+            None => false,
+            Some((mode, mask)) => {
+                self.set_modes(chan, [ModeChange::set(mode, Some(mask.as_slice()))]);
+                true
+            }
+        }
+    }
+
+    /// Removes a quiet set with `quiet()`. Returns `false` if the server
+    /// has no way to quiet.
+    pub fn unquiet(&mut self, chan: &[u8], mask: &[u8]) -> bool {
+        match self.quiet_mode(mask) {
+            None => false,
+            Some((mode, mask)) => {
+                self.set_modes(chan, [ModeChange::unset(mode, Some(mask.as_slice()))]);
+                true
+            }
+        }
+    }
+
+    /// Returns the mask used to ban `nick` from `chan`.
+    ///
+    /// This is `*!*@host` if the member's host is known from the channel state,
+    /// and `nick!*@*` otherwise.
+    pub fn ban_mask(&self, chan: &[u8], nick: &[u8]) -> Vec<u8> {
+        match self.state.member(chan, nick).and_then(|m| m.host()) {
+            Some(host) => {
+                let mut mask = b"*!*@".to_vec();
+                mask.push_all(host);
+                mask
+            }
+            None => {
+                let mut mask = nick.to_vec();
+                mask.push_all(b"!*@*");
+                mask
+            }
+        }
+    }
+
+    /// Returns the +b mask for an extended ban in this server's syntax, e.g.
+    /// `$a:name` for `ExtBan::account(b"name")` on charybdis. Returns None if
+    /// the server doesn't support that kind of extban.
+    pub fn extban_mask(&self, ban: &ExtBan) -> Option<Vec<u8>> {
+        self.isupport.extbans().and_then(|e| ban.to_mask(&self.quirks.ircd, &e))
+    }
+
+    /// Returns the +b mask that bans the users `entry` matches: the hostmask
+    /// itself, or an account extban such as `$a:name`. Returns None for an
+    /// account if the server doesn't support account extbans.
+    pub fn access_ban_mask(&self, entry: &AccessMatch) -> Option<Vec<u8>> {
+        match *entry {
+            ByMask(ref mask) => Some(mask.clone()),
+            ByAccount(ref account) => self.extban_mask(&ExtBan::account(account.as_slice()))
+        }
+    }
+
+    /// Bans the users `entry` matches from `chan`, see `access_ban_mask()`.
+    /// Returns `false` without sending anything if the server can't ban an
+    /// account.
+    pub fn ban_access(&mut self, chan: &[u8], entry: &AccessMatch) -> bool {
+        match self.access_ban_mask(entry) {
+            None => false,
+            Some(mask) => {
+                self.set_modes(chan, [ModeChange::set('b', Some(mask.as_slice()))]);
+                true
+            }
+        }
+    }
+
+    /// Bans `nick` from `chan` and then kicks them.
+    ///
+    /// The ban is set first so that the user can't rejoin in between. See
+    /// `ban_mask()` for the mask that's used. Pass [] for the reason to use
+    /// the default.
+    pub fn kickban(&mut self, chan: &[u8], nick: &[u8], reason: &[u8]) {
+        let mask = self.ban_mask(chan, nick);
+        self.set_modes(chan, [ModeChange::set('b', Some(mask.as_slice()))]);
+        self.kick(chan, nick, reason);
+    }
+
+    /// Kicks `nick` from `chan`.
+    /// Pass [] for the reason to use the default.
+    pub fn kick(&mut self, chan: &[u8], nick: &[u8], reason: &[u8]) {
+        if reason.is_empty() {
+            self.send_command(IRCCmd("KICK".into_maybe_owned()), [chan, nick], false);
+        } else {
+            self.send_command(IRCCmd("KICK".into_maybe_owned()), [chan, nick, reason], true);
+        }
+    }
+
+    /// Returns the list mode and parameter that quiet `mask`
+    fn quiet_mode(&self, mask: &[u8]) -> Option<(char, Vec<u8>)> {
+        match self.quirks.quiet {
+            QuietMode => Some(('q', mask.to_vec())),
+            QuietExtban(_) => self.extban_mask(&ExtBan::mute(mask)).map(|m| ('b', m)),
+            NoQuiet => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, ByMask, ByAccount};
+
+    /// Returns a connection to a server that has sent `lines` after 001
+    fn server(lines: &[&[u8]]) -> Manual<'static, Vec<Event>> {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.step(lines, Duration::zero());
+        conn
+    }
+
+    #[test]
+    fn quiet() {
+        // charybdis has a +q list
+        let mut conn = server([b":irc.example.net 004 ircnick irc.example.net charybdis-4.1.2 DQRSZagiloswz biklmnopstveIq",
+                               b":irc.example.net 005 ircnick CHANMODES=eIbq,k,flj,CFLMPQScgimnprstuz PREFIX=(ov)@+ :are supported"]);
+        assert!(conn.conn().quiet(b"#rust", b"*!*@spam.example"));
+        assert!(conn.conn().unquiet(b"#rust", b"*!*@spam.example"));
+        assert_eq!(conn.step([], Duration::zero()),
+                   vec![b"MODE #rust +q *!*@spam.example".to_vec(), b"MODE #rust -q *!*@spam.example".to_vec()]);
+
+        // Unreal's q is the owner prefix, so it mutes with an extban
+        let mut conn = server([b":irc.example.net 004 ircnick irc.example.net UnrealIRCd-6.1.0 iowrsxzdHtIDZRqpWGTSB lvhopsmntikraqbeIzMQNRTOVKDdGLPZSCcf",
+                               b":irc.example.net 005 ircnick CHANMODES=beI,fkL,lH,cdimnprstzCDGKMNOPQRSTVZ PREFIX=(qaohv)~&@%+ EXTBAN=~,acfjmnqrtCGOST :are supported"]);
+        assert!(conn.conn().quiet(b"#rust", b"*!*@spam.example"));
+        assert_eq!(conn.step([], Duration::zero()), vec![b"MODE #rust +b ~q:*!*@spam.example".to_vec()]);
+
+        // InspIRCd uses m, without a prefix
+        let mut conn = server([b":irc.example.net 004 ircnick irc.example.net InspIRCd-3 BIRSWcdghiklorswx ABCDEFGHIJKLMNOPQRSTXYZabcdefghijklmnopqrstuvwz",
+                               b":irc.example.net 005 ircnick CHANMODES=IXbeg,k,Hfjl,ACKMNOPQRSTcimnprstz EXTBAN=,ACNOQRSTUacjmnprswz :are supported"]);
+        assert!(conn.conn().quiet(b"#rust", b"*!*@spam.example"));
+        assert_eq!(conn.step([], Duration::zero()), vec![b"MODE #rust +b m:*!*@spam.example".to_vec()]);
+
+        // neither
+        let mut conn = server([b":irc.example.net 004 ircnick irc.example.net ircd-1.0 iow beIklmnopstv"]);
+        assert!(!conn.conn().quiet(b"#rust", b"*!*@spam.example"));
+        assert!(!conn.conn().unquiet(b"#rust", b"*!*@spam.example"));
+        assert!(conn.step([], Duration::zero()).is_empty());
+    }
+
+    #[test]
+    fn kickban() {
+        let mut conn = server([b":ircnick!u@h JOIN #rust", b":bob!b@bad.host JOIN #rust"]);
+        conn.conn().kickban(b"#rust", b"bob", b"go away");
+        // without a known host, the nick is banned
+        conn.conn().kickban(b"#rust", b"eve", []);
+        assert_eq!(conn.step([], Duration::zero()),
+                   vec![b"MODE #rust +b *!*@bad.host".to_vec(), b"KICK #rust bob :go away".to_vec(),
+                        b"MODE #rust +b eve!*@*".to_vec(), b"KICK #rust eve".to_vec()]);
+    }
+
+    #[test]
+    fn ban_access() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        // no EXTBAN yet
+        assert!(!conn.conn().ban_access(b"#rust", &ByAccount(b"troll".to_vec())));
+        assert!(conn.conn().ban_access(b"#rust", &ByMask(b"*!*@spam.example".to_vec())));
+        assert_eq!(conn.step([b":irc.example.net 005 ircnick EXTBAN=$,acjrsx :are supported by this server"],
+                             Duration::zero()),
+                   vec![b"MODE #rust +b *!*@spam.example".to_vec()]);
+        assert!(conn.conn().ban_access(b"#rust", &ByAccount(b"troll".to_vec())));
+        assert_eq!(conn.step([], Duration::zero()), vec![b"MODE #rust +b $a:troll".to_vec()]);
+    }
+}
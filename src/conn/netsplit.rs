@@ -0,0 +1,240 @@
+//! Grouping the QUITs and JOINs of netsplits, see `Options.netsplit_window`
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::mem;
+use std::time::Duration;
+
+use conn::{Conn, Line, IRCCmd, Netsplit, Netjoin};
+use User;
+
+/// How long users lost in a netsplit are waited for, in nanoseconds. Users
+/// who come back later are treated as having reconnected on their own.
+static REJOIN_WAIT_NS: u64 = 15 * 60 * 1_000_000_000;
+
+/// A netsplit, and the users lost in it
+struct Split {
+    /// The two servers that lost their link
+    servers: (Vec<u8>, Vec<u8>),
+    /// Users who quit in the split and haven't been reported yet
+    quits: Vec<User>,
+    /// Users who came back and haven't been reported yet
+    joins: Vec<User>,
+    /// The users lost in the split who haven't come back, by casemapped nick
+    gone: HashMap<Vec<u8>, User>,
+    /// When the last quit or join of the split arrived
+    last: u64
+}
+
+impl Split {
+    fn pending(&self) -> bool {
+        !self.quits.is_empty() || !self.joins.is_empty()
+    }
+}
+
+struct Netsplits(Vec<Split>);
+
+/// Forgets the splits seen on the last connection, along with the job that
+/// would have reported them
+pub fn reset(conn: &mut Conn) {
+    conn.extensions.remove::<Netsplits>();
+}
+
+/// Records the QUITs of netsplits and the JOINs of the users coming back
+/// afterwards. They're sent as Netsplit and Netjoin events once no more have
+/// arrived for `window`. Returns `true` if `line` is one of them, and so
+/// shouldn't be sent as a LineReceived event.
+pub fn saw_line(conn: &mut Conn, window: Duration, line: &Line) -> bool {
+    let (quit, join) = match line.command {
+        IRCCmd(ref s) => ("QUIT" == s.as_slice(), "JOIN" == s.as_slice()),
+        _ => return false
+    };
+    let user = match line.prefix {
+        Some(ref user) if quit || join => user.clone(),
+        _ => return false
+    };
+    let casemapping = conn.isupport.casemapping();
+    let nick = casemapping.lower(user.nick());
+    let now = conn.now();
+    let schedule = {
+        let &Netsplits(ref mut splits) = conn.extensions.get_or_insert_with(|| Netsplits(Vec::new()));
+        let split = if quit {
+            let servers = match line.args.as_slice().head().and_then(|r| split_servers(r.as_slice())) {
+                None => return false,
+                Some(servers) => servers
+            };
+            let (ref a, ref b) = servers;
+            let idx = splits.iter().position(|s| {
+                let (ref c, ref d) = s.servers;
+                (a == c && b == d) || (a == d && b == c)
+            });
+            match idx {
+                Some(idx) => &mut splits[idx],
+                None => {
+                    splits.push(Split {
+                        servers: servers.clone(),
+                        quits: Vec::new(),
+                        joins: Vec::new(),
+                        gone: HashMap::new(),
+                        last: now
+                    });
+                    splits.last_mut().unwrap()
+                }
+            }
+        } else {
+            // users come back with a JOIN for each channel, only the first
+            // counts. Someone else who took the nick meanwhile doesn't.
+            let split = splits.iter_mut().find(|s| {
+                now - s.last < REJOIN_WAIT_NS &&
+                    (s.gone.get(&nick).map_or(false, |u| u.raw() == user.raw()) ||
+                     s.joins.iter().any(|u| u.raw() == user.raw()))
+            });
+            match split {
+                None => return false,
+                Some(split) => split
+            }
+        };
+        let schedule = !split.pending();
+        split.last = now;
+        if quit {
+            split.gone.insert(nick, user.clone());
+            split.quits.push(user);
+        } else if split.gone.remove(&nick).is_some() {
+            split.joins.push(user);
+        }
+        schedule
+    };
+    if schedule {
+        conn.schedule(window, proc(conn) flush(conn, window));
+    }
+    true
+}
+
+/// Sends the events for the splits that have been quiet for `window`
+fn flush(conn: &mut Conn, window: Duration) {
+    let window_ns = max(window.num_milliseconds(), 0) as u64 * 1_000_000;
+    let now = conn.now();
+    let mut events = Vec::new();
+    let mut wait = None;
+    match conn.extensions.get_mut::<Netsplits>() {
+        None => return,
+        Some(&Netsplits(ref mut splits)) => {
+            for split in splits.iter_mut().filter(|s| s.pending()) {
+                if now - split.last < window_ns {
+                    let left = window_ns - (now - split.last);
+                    wait = Some(wait.map_or(left, |w| min(w, left)));
+                    continue;
+                }
+                let (ref a, ref b) = split.servers;
+                if !split.quits.is_empty() {
+                    events.push(Netsplit(a.clone(), b.clone(), mem::replace(&mut split.quits, Vec::new())));
+                }
+                if !split.joins.is_empty() {
+                    events.push(Netjoin(a.clone(), b.clone(), mem::replace(&mut split.joins, Vec::new())));
+                }
+            }
+            // forget splits that everyone came back from, or that are too old
+            splits.retain(|s| s.pending() || (!s.gone.is_empty() && now - s.last < REJOIN_WAIT_NS));
+        }
+    }
+    for event in events.into_iter() {
+        conn.emit(event);
+    }
+    match wait {
+        None => (),
+        Some(ns) => {
+            let delay = Duration::milliseconds((ns / 1_000_000) as i64 + 1);
+            conn.schedule(delay, proc(conn) flush(conn, window));
+        }
+    }
+}
+
+/// Returns the two servers named in the reason of a netsplit QUIT, e.g.
+/// `hub.example.net leaf.example.net`, or `*.net *.split` on networks that
+/// hide their servers.
+fn split_servers(reason: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut parts = reason.split(|&b| b == ' ' as u8);
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(a), Some(b), None) if a != b && is_server_name(a) && is_server_name(b) => {
+            Some((a.to_vec(), b.to_vec()))
+        }
+        _ => None
+    }
+}
+
+fn is_server_name(name: &[u8]) -> bool {
+    let dot = '.' as u8;
+    name.len() > 2 && name.contains(&dot) && name[0] != dot && name[name.len()-1] != dot &&
+        name.iter().all(|&b| match b as char {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '-' | '*' => true,
+            _ => false
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue, LineReceived, Netsplit, Netjoin};
+    use super::split_servers;
+
+    fn reported(events: Vec<Event>) -> Vec<String> {
+        events.into_iter().filter_map(|e| match e {
+            Netsplit(_, _, users) => Some(format!("split {}", users.len())),
+            Netjoin(_, _, users) => Some(format!("join {}", users.len())),
+            LineReceived(line) => line.prefix.map(|u| String::from_utf8_lossy(u.nick()).into_string()),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn grouping() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.netsplit_window = Some(Duration::seconds(2));
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.events();
+        conn.step([b":alice!a@host.a QUIT :hub.example.net leaf.example.net",
+                   b":bob!b@host.b QUIT :hub.example.net leaf.example.net"], Duration::zero());
+        conn.step([b":carol!c@host.c QUIT :leaf.example.net hub.example.net"], second);
+        assert!(reported(conn.events()).is_empty());
+        conn.step([], Duration::seconds(2));
+        assert_eq!(reported(conn.events()), vec!["split 3".to_string()]);
+        // someone else took bob's nick meanwhile
+        conn.step([b":alice!a@host.a JOIN #rust",
+                   b":alice!a@host.a JOIN #irc",
+                   b":bob!x@elsewhere JOIN #rust",
+                   b":carol!c@host.c JOIN #rust"], Duration::zero());
+        conn.step([], Duration::seconds(2));
+        assert_eq!(reported(conn.events()), vec!["bob".to_string(), "join 2".to_string()]);
+    }
+
+    #[test]
+    fn forgotten_on_reconnect() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.netsplit_window = Some(Duration::seconds(2));
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":alice!a@host.a QUIT :hub.example.net leaf.example.net"], Duration::zero());
+        // the job that would report the split is dropped with the connection
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":bob!b@host.b QUIT :hub.example.net leaf.example.net"], Duration::zero());
+        conn.events();
+        conn.step([], Duration::seconds(2));
+        assert_eq!(reported(conn.events()), vec!["split 1".to_string()]);
+    }
+
+    #[test]
+    fn test_split_servers() {
+        assert_eq!(split_servers(b"hub.example.net leaf.example.net"),
+                   Some((b"hub.example.net".to_vec(), b"leaf.example.net".to_vec())));
+        assert_eq!(split_servers(b"*.net *.split"), Some((b"*.net".to_vec(), b"*.split".to_vec())));
+        assert_eq!(split_servers(b"Quit: hub.example.net leaf.example.net"), None);
+        assert_eq!(split_servers(b"Ping timeout: 240 seconds"), None);
+        assert_eq!(split_servers(b"hub.example.net hub.example.net"), None);
+        assert_eq!(split_servers(b"see you.later"), None);
+        assert_eq!(split_servers(b"Remote host closed the connection"), None);
+    }
+}
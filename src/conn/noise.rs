@@ -0,0 +1,94 @@
+//! Recognizing routine notices that most callbacks don't care about
+
+use conn::{Conn, Line, IRCCmd};
+use state::State;
+
+/// Kinds of routine traffic that can be kept from the callback with
+/// `Options.ignore_noise`
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Noise {
+    /// Server NOTICEs starting with `***` that arrive before the end of the
+    /// MOTD, e.g. `*** Looking up your hostname...`
+    ConnectNotices,
+    /// Server NOTICEs and WALLOPS starting with `*** You are connected`,
+    /// which some servers send after the MOTD
+    ConnectedBanners,
+    /// NOTICEs sent to a `$` server mask, like the announcements from
+    /// services' Global
+    GlobalNotices
+}
+
+impl Noise {
+    /// Returns every kind of noise
+    pub fn all() -> Vec<Noise> {
+        vec![ConnectNotices, ConnectedBanners, GlobalNotices]
+    }
+}
+
+/// Returns `true` if `line` is one of the kinds of noise in `filters`
+pub fn is_noise(conn: &Conn, filters: &[Noise], line: &Line) -> bool {
+    if filters.is_empty() {
+        return false;
+    }
+    // the MOTD has ended once join_on_connect has been handled
+    match classify(line, &conn.state, !conn.autojoined) {
+        None => false,
+        Some(noise) => filters.contains(&noise)
+    }
+}
+
+/// Returns the kind of noise `line` is, if any. `state` tells servers from
+/// users, see `Line::source()`. `connecting` is `true` before the end of the
+/// MOTD.
+fn classify(line: &Line, state: &State, connecting: bool) -> Option<Noise> {
+    let (notice, wallops) = match line.command {
+        IRCCmd(ref s) => ("NOTICE" == s.as_slice(), "WALLOPS" == s.as_slice()),
+        _ => return None
+    };
+    if !(notice || wallops) || line.args.is_empty() {
+        return None;
+    }
+    if notice && line.args[0].as_slice().starts_with(b"$") {
+        return Some(GlobalNotices);
+    }
+    let from_server = line.source(state).map_or(true, |p| p.user().is_none());
+    let text = line.args[line.args.len()-1].as_slice();
+    if !from_server || !text.starts_with(b"***") {
+        return None;
+    }
+    if text.starts_with(b"*** You are connected") {
+        Some(ConnectedBanners)
+    } else if notice && connecting {
+        Some(ConnectNotices)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, ConnectNotices, ConnectedBanners, GlobalNotices};
+    use conn::Line;
+    use state::State;
+
+    #[test]
+    fn test_classify() {
+        let mut state = State::new();
+        for raw in [b":me!u@h JOIN #rust", b":bob!b@h JOIN #rust"].iter() {
+            state.handle_line(b"me", &Line::parse(*raw).unwrap());
+        }
+        let c = |raw: &[u8], connecting: bool| classify(&Line::parse(raw).unwrap(), &state, connecting);
+        assert_eq!(c(b":irc.example.net NOTICE * :*** Looking up your hostname...", true),
+                   Some(ConnectNotices));
+        assert_eq!(c(b":irc.example.net NOTICE me :*** Found your hostname", false), None);
+        assert_eq!(c(b":irc.example.net NOTICE me :*** You are connected to irc.example.net using TLS", false),
+                   Some(ConnectedBanners));
+        assert_eq!(c(b":Global!services@services.net NOTICE $$* :Network maintenance tonight", false),
+                   Some(GlobalNotices));
+        assert_eq!(c(b":nick!u@h NOTICE me :*** not from a server", true), None);
+        assert_eq!(c(b":leaf.example.net NOTICE me :*** Looking up your hostname...", true),
+                   Some(ConnectNotices));
+        assert_eq!(c(b":bob NOTICE me :*** a bare nick we know", true), None);
+        assert_eq!(c(b":nick!u@h PRIVMSG #chan :*** hi", true), None);
+    }
+}
@@ -0,0 +1,213 @@
+//! ISON queries and watch-list polling
+
+use std::collections::RingBuf;
+use std::mem;
+use std::time::Duration;
+
+use conn::{Conn, Line, IRCCmd, UserOnline, UserOffline};
+use conn::{memo, persist};
+
+/// Callback for the result of an ISON query.
+/// It's given the queried nicks that are currently online.
+pub type IsonCallback = proc(&mut Conn, Vec<Vec<u8>>);
+
+/// ISON queries waiting for their 303 reply.
+///
+/// Each ISON line that was sent has an entry in `queue`. The entry for the
+/// final line of a query carries the callback; results for the earlier lines
+/// accumulate in `found` until then.
+struct Pending {
+    queue: RingBuf<Option<IsonCallback>>,
+    found: Vec<Vec<u8>>
+}
+
+/// The watch list, with the last known status of each nick
+struct Watch {
+    nicks: Vec<(Vec<u8>, Option<bool>)>,
+    interval: Option<Duration>,
+    generation: uint
+}
+
+/// Maximum length of the nick list in a single ISON line
+static MAX_ISON_LEN: uint = 500;
+
+impl<'a> Conn<'a> {
+    /// Sends an ISON query for `nicks` and calls `cb` with the ones that are online.
+    ///
+    /// Long lists are split across several ISON lines, and `cb` is called once with
+    /// the combined result. Replies are matched to queries in the order they were
+    /// sent, so don't send ISON by hand while a query is outstanding.
+    pub fn ison(&mut self, nicks: &[&[u8]], cb: IsonCallback) {
+        if nicks.is_empty() || !self.is_connected() {
+            cb(self, Vec::new());
+            return;
+        }
+        let mut batches = Vec::new();
+        let mut batch = Vec::new();
+        let mut len = 0u;
+        for &nick in nicks.iter() {
+            if !batch.is_empty() && len + 1 + nick.len() > MAX_ISON_LEN {
+                batches.push(batch);
+                batch = Vec::new();
+                len = 0;
+            }
+            len += 1 + nick.len();
+            batch.push(nick);
+        }
+        batches.push(batch);
+
+        let count = batches.len();
+        for batch in batches.iter() {
+            let mut list = Vec::with_capacity(MAX_ISON_LEN);
+            for (i, nick) in batch.iter().enumerate() {
+                if i > 0 {
+                    list.push(' ' as u8);
+                }
+                list.push_all(*nick);
+            }
+            self.send_command(IRCCmd("ISON".into_maybe_owned()), [list.as_slice()], true);
+        }
+        let pending = self.extensions.get_or_insert_with(|| {
+            Pending { queue: RingBuf::new(), found: Vec::new() }
+        });
+        for _ in range(1, count) {
+            pending.queue.push_back(None);
+        }
+        pending.queue.push_back(Some(cb));
+    }
+
+    /// Adds `nick` to the watch list.
+    ///
+    /// While polling is enabled with `poll_ison()`, UserOnline and UserOffline
+    /// events are sent when a watched nick changes status.
+    pub fn watch(&mut self, nick: &[u8]) {
+        {
+            let watch = self.watch_list();
+            if watch.nicks.iter().any(|&(ref n, _)| same_nick(n.as_slice(), nick)) {
+                return;
+            }
+            watch.nicks.push((nick.to_vec(), None));
+        }
+        persist::watch_changed(self);
+    }
+
+    /// Removes `nick` from the watch list.
+    pub fn unwatch(&mut self, nick: &[u8]) {
+        self.watch_list().nicks.retain(|&(ref n, _)| !same_nick(n.as_slice(), nick));
+        persist::watch_changed(self);
+    }
+
+    /// Returns the watched nicks along with their last known online status.
+    /// The status is None for nicks that haven't been polled yet.
+    pub fn watched(&self) -> Vec<(Vec<u8>, Option<bool>)> {
+        self.extensions.get::<Watch>().map_or(Vec::new(), |w| w.nicks.clone())
+    }
+
+    /// Starts polling the watch list with ISON every `interval`.
+    /// Pass None to stop polling.
+    ///
+    /// This is meant for networks that support neither MONITOR nor WATCH.
+    pub fn poll_ison(&mut self, interval: Option<Duration>) {
+        let generation = {
+            let watch = self.watch_list();
+            watch.interval = interval;
+            watch.generation += 1;
+            watch.generation
+        };
+        if interval.is_some() {
+            poll(self, generation);
+        }
+    }
+
+    fn watch_list<'b>(&'b mut self) -> &'b mut Watch {
+        self.extensions.get_or_insert_with(|| {
+            Watch { nicks: Vec::new(), interval: None, generation: 0 }
+        })
+    }
+}
+
+fn poll(conn: &mut Conn, generation: uint) {
+    let (nicks, interval) = match conn.extensions.get::<Watch>() {
+        Some(watch) if watch.generation == generation => {
+            let nicks = watch.nicks.iter().map(|&(ref n, _)| n.clone()).collect::<Vec<_>>();
+            (nicks, watch.interval.unwrap())
+        }
+        // polling was stopped or restarted
+        _ => return
+    };
+    if !nicks.is_empty() {
+        let polled = nicks.clone();
+        let hack = nicks.iter().map(|v| v.as_slice()).collect::<Vec<_>>();
+        conn.ison(hack.as_slice(), proc(conn, online) {
+            update_status(conn, polled, online);
+        });
+    }
+    conn.schedule(interval, proc(conn) {
+        poll(conn, generation);
+    });
+}
+
+fn update_status(conn: &mut Conn, polled: Vec<Vec<u8>>, online: Vec<Vec<u8>>) {
+    let mut changes = Vec::new();
+    match conn.extensions.get_mut::<Watch>() {
+        None => return,
+        Some(watch) => {
+            for entry in watch.nicks.iter_mut() {
+                let (ref nick, ref mut status) = *entry;
+                if !polled.iter().any(|n| same_nick(n.as_slice(), nick.as_slice())) {
+                    // added since the query was sent
+                    continue;
+                }
+                let now = online.iter().any(|n| same_nick(n.as_slice(), nick.as_slice()));
+                if *status != Some(now) {
+                    // don't report nicks that were offline from the start
+                    if now || status.is_some() {
+                        changes.push((nick.clone(), now));
+                    }
+                    *status = Some(now);
+                }
+            }
+        }
+    }
+    for (nick, now) in changes.into_iter() {
+        if now {
+            conn.emit(UserOnline(nick.clone()));
+            memo::returned(conn, nick.as_slice());
+        } else {
+            conn.emit(UserOffline(nick));
+        }
+    }
+}
+
+// 303
+pub fn RPL_ISON(conn: &mut Conn, line: &Line) {
+    // :server 303 me :nick1 nick2
+    let (cb, found) = {
+        let pending = match conn.extensions.get_mut::<Pending>() {
+            None => return,
+            Some(pending) => pending
+        };
+        let cb = match pending.queue.pop_front() {
+            None => return,
+            Some(cb) => cb
+        };
+        if line.args.len() > 1 {
+            let nicks = line.args[1].as_slice().split(|&b| b == ' ' as u8)
+                                               .filter(|v| !v.is_empty());
+            pending.found.extend(nicks.map(|v| v.to_vec()));
+        }
+        match cb {
+            // more replies to come for this query
+            None => return,
+            Some(cb) => (cb, mem::replace(&mut pending.found, Vec::new()))
+        }
+    };
+    cb(conn, found);
+}
+
+fn same_nick(a: &[u8], b: &[u8]) -> bool {
+    fn lower(b: u8) -> u8 {
+        if b >= 'A' as u8 && b <= 'Z' as u8 { b - 'A' as u8 + 'a' as u8 } else { b }
+    }
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(&x, &y)| lower(x) == lower(y))
+}
@@ -0,0 +1,387 @@
+//! Helpers for IRC operators
+
+use std::cmp::max;
+use std::str;
+use std::time::Duration;
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
+
+use conn::{Conn, Line, IRCCmd, IRCCode, Wallops, Globops};
+use quirks::{Charybdis, Ratbox, Hybrid, InspIRCd, Unreal};
+
+/// A K-line (a server ban on a user@host mask), as listed by `list_klines()`
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Kline {
+    /// The banned user@host mask
+    pub mask: Vec<u8>,
+    /// The reason given for the ban
+    pub reason: Vec<u8>,
+    /// Who set the K-line, if the server says
+    pub setter: Option<Vec<u8>>,
+    /// How long the K-line lasts in seconds, if the server says. 0 means it's permanent.
+    pub duration: Option<u64>
+}
+
+impl Kline {
+    /// Parses a RPL_STATSKLINE (216) line, in either the hybrid/charybdis
+    /// format (`K host * user :reason`) or the InspIRCd one
+    /// (`user@host setter set-time duration :reason`)
+    pub fn parse(line: &Line) -> Option<Kline> {
+        match line.command {
+            IRCCode(216) => (),
+            _ => return None
+        }
+        match line.args.as_slice() {
+            // :server 216 me K host * user :reason
+            [_, ref kind, ref host, _, ref user, ref reason] if kind.as_slice() == b"K" ||
+                                                              kind.as_slice() == b"k" => {
+                let mut mask = user.clone();
+                mask.push('@' as u8);
+                mask.push_all(host.as_slice());
+                Some(Kline { mask: mask, reason: reason.clone(), setter: None, duration: None })
+            }
+            // :server 216 me user@host setter 1400000000 3600 :reason
+            [_, ref mask, ref setter, _, ref duration, ref reason] if mask.contains(&('@' as u8)) => {
+                Some(Kline {
+                    mask: mask.clone(),
+                    reason: reason.clone(),
+                    setter: Some(setter.clone()),
+                    duration: str::from_utf8(duration.as_slice()).and_then(|d| from_str(d))
+                })
+            }
+            _ => None
+        }
+    }
+}
+
+/// Callback for `list_klines()`. It's given the K-lines that the server listed.
+pub type KlineCallback = proc(&mut Conn, Vec<Kline>);
+
+/// A `list_klines()` waiting for its STATS replies
+struct KlineQuery {
+    /// The number of RPL_ENDOFSTATS still to come
+    remaining: uint,
+    klines: Vec<Kline>,
+    cb: KlineCallback
+}
+
+struct PendingKlines(Vec<KlineQuery>);
+
+/// Answers an oper CHALLENGE, see `challenge_oper()`. It's given the
+/// challenge, which is encrypted with the public key of the oper block, and
+/// returns the SHA-1 digest of the decrypted challenge, or None if it can't
+/// be decrypted. The RSA decryption (with OAEP padding) and the hashing are
+/// up to the application, e.g. using OpenSSL and the oper's private key.
+pub type ChallengeSolver = proc(Vec<u8>) -> Option<Vec<u8>>: Send;
+
+/// A CHALLENGE waiting for the server's challenge
+struct PendingChallenge {
+    /// The base64 challenge received so far
+    challenge: Vec<u8>,
+    solver: ChallengeSolver
+}
+
+impl<'a> Conn<'a> {
+    /// Becomes an IRC operator with OPER and a plaintext password
+    pub fn oper(&mut self, name: &[u8], password: &[u8]) {
+        self.send_command(IRCCmd("OPER".into_maybe_owned()), [name, password], false);
+    }
+
+    /// Becomes an IRC operator with the RSA challenge of ratbox and
+    /// charybdis-family servers, instead of sending a password.
+    ///
+    /// The server's challenge (740 and 741 replies) is passed to `solver`,
+    /// and its answer sent back with `CHALLENGE +<answer>`. The user mode
+    /// change (and a 381 reply) tell whether it worked; if the oper block
+    /// isn't found, the server replies with ERR_NOOPERHOST (491).
+    pub fn challenge_oper(&mut self, name: &[u8], solver: ChallengeSolver) {
+        self.extensions.insert(PendingChallenge { challenge: Vec::new(), solver: solver });
+        self.send_command(IRCCmd("CHALLENGE".into_maybe_owned()), [name], false);
+    }
+
+    /// Sends a WALLOPS to every user with user mode +w. This usually needs
+    /// operator privileges.
+    pub fn wallops(&mut self, msg: &[u8]) {
+        self.send_command(IRCCmd("WALLOPS".into_maybe_owned()), [msg], true);
+    }
+
+    /// Sends a GLOBOPS to the other IRC operators. Returns `false` without
+    /// sending anything if the server isn't known to support GLOBOPS (see
+    /// `Quirks.globops`).
+    pub fn globops(&mut self, msg: &[u8]) -> bool {
+        if !self.quirks.globops {
+            return false;
+        }
+        self.send_command(IRCCmd("GLOBOPS".into_maybe_owned()), [msg], true);
+        true
+    }
+
+    /// Adds a K-line on the user@host `mask`, for `duration` or permanently
+    /// if it's None. The syntax is chosen according to `quirks()`, since
+    /// ircds disagree on the argument order and the unit of the duration.
+    pub fn kline(&mut self, duration: Option<Duration>, mask: &[u8], reason: &[u8]) {
+        let secs = duration.map_or(0, |d| max(d.num_seconds(), 1));
+        match self.quirks.ircd {
+            InspIRCd | Unreal => {
+                // KLINE user@host 3600 :reason
+                let secs = secs.to_string();
+                self.send_command(IRCCmd("KLINE".into_maybe_owned()),
+                                  [mask, secs.as_bytes(), reason], true);
+            }
+            _ if duration.is_none() => {
+                self.send_command(IRCCmd("KLINE".into_maybe_owned()), [mask, reason], true);
+            }
+            _ => {
+                // KLINE 60 user@host :reason, with the duration in minutes
+                let mins = ((secs + 59) / 60).to_string();
+                self.send_command(IRCCmd("KLINE".into_maybe_owned()),
+                                  [mins.as_bytes(), mask, reason], true);
+            }
+        }
+    }
+
+    /// Removes the K-line on the user@host `mask`
+    pub fn unkline(&mut self, mask: &[u8]) {
+        match self.quirks.ircd {
+            // a KLINE without a reason removes it
+            InspIRCd => self.send_command(IRCCmd("KLINE".into_maybe_owned()), [mask], false),
+            Unreal => {
+                let mut arg = b"-".to_vec();
+                arg.push_all(mask);
+                self.send_command(IRCCmd("KLINE".into_maybe_owned()), [arg.as_slice()], false);
+            }
+            _ => self.send_command(IRCCmd("UNKLINE".into_maybe_owned()), [mask], false)
+        }
+    }
+
+    /// Lists the server's K-lines with STATS, and calls `cb` with them.
+    ///
+    /// On charybdis and other hybrid-derived servers, both the permanent
+    /// (STATS K) and temporary (STATS k) K-lines are listed. Unreal's list
+    /// uses a different numeric and isn't parsed. If the connection is lost
+    /// before the lists arrive, `cb` isn't called.
+    pub fn list_klines(&mut self, cb: KlineCallback) {
+        let letters = match self.quirks.ircd {
+            Charybdis | Ratbox | Hybrid => "Kk",
+            _ => "K"
+        };
+        for letter in letters.chars() {
+            let letter = letter.to_string();
+            self.send_command(IRCCmd("STATS".into_maybe_owned()), [letter.as_bytes()], false);
+        }
+        let query = KlineQuery { remaining: letters.len(), klines: Vec::new(), cb: cb };
+        let &PendingKlines(ref mut pending) = self.extensions.get_or_insert_with(|| PendingKlines(Vec::new()));
+        pending.push(query);
+    }
+}
+
+/// Forgets the CHALLENGE and K-line lists that the last connection was
+/// waiting on. Our own oper status goes with the user modes.
+pub fn reset(conn: &mut Conn) {
+    conn.extensions.remove::<PendingChallenge>();
+    conn.extensions.remove::<PendingKlines>();
+}
+
+// 216
+pub fn RPL_STATSKLINE(conn: &mut Conn, line: &Line) {
+    let kline = match Kline::parse(line) {
+        None => return,
+        Some(kline) => kline
+    };
+    match conn.extensions.get_mut::<PendingKlines>() {
+        Some(&PendingKlines(ref mut pending)) if !pending.is_empty() => {
+            pending.get_mut(0).klines.push(kline);
+        }
+        _ => ()
+    }
+}
+
+// 219
+pub fn RPL_ENDOFSTATS(conn: &mut Conn, line: &Line) {
+    // :server 219 me K :End of /STATS report
+    match line.args.as_slice() {
+        [_, ref letter, ..] if letter.as_slice() == b"K" || letter.as_slice() == b"k" => (),
+        _ => return
+    }
+    let query = match conn.extensions.get_mut::<PendingKlines>() {
+        Some(&PendingKlines(ref mut pending)) if !pending.is_empty() => {
+            pending.get_mut(0).remaining -= 1;
+            if pending[0].remaining > 0 {
+                return;
+            }
+            pending.remove(0).unwrap()
+        }
+        _ => return
+    };
+    let KlineQuery { klines, cb, .. } = query;
+    cb(conn, klines);
+}
+
+// 740
+pub fn RPL_RSACHALLENGE2(conn: &mut Conn, line: &Line) {
+    // :server 740 me :<part of the base64 challenge>
+    match (conn.extensions.get_mut::<PendingChallenge>(), line.args.as_slice().last()) {
+        (Some(pending), Some(part)) => pending.challenge.push_all(part.as_slice()),
+        _ => ()
+    }
+}
+
+// 741
+pub fn RPL_ENDOFRSACHALLENGE2(conn: &mut Conn, _line: &Line) {
+    let PendingChallenge { challenge, solver } = match conn.extensions.remove::<PendingChallenge>() {
+        None => return,
+        Some(pending) => pending
+    };
+    let challenge = match challenge.as_slice().from_base64() {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            info!("Couldn't decode the oper challenge: {}", e);
+            return;
+        }
+    };
+    match solver(challenge) {
+        None => info!("Couldn't answer the oper challenge"),
+        Some(answer) => {
+            let mut arg = b"+".to_vec();
+            arg.push_all(answer.as_slice().to_base64(STANDARD).as_bytes());
+            conn.send_command(IRCCmd("CHALLENGE".into_maybe_owned()), [arg.as_slice()], false);
+        }
+    }
+}
+
+// 491
+pub fn ERR_NOOPERHOST(conn: &mut Conn, _line: &Line) {
+    // no challenge is coming
+    conn.extensions.remove::<PendingChallenge>();
+}
+
+/// Sends a Wallops event for a WALLOPS line
+pub fn WALLOPS(conn: &mut Conn, line: &Line) {
+    // :nick!user@host WALLOPS :message
+    let source = line.source(&conn.state);
+    match line.args.as_slice().last() {
+        None => (),
+        Some(msg) => conn.emit(Wallops(source, msg.clone()))
+    }
+}
+
+/// Sends a Globops event for a GLOBOPS line
+pub fn GLOBOPS(conn: &mut Conn, line: &Line) {
+    let source = line.source(&conn.state);
+    match line.args.as_slice().last() {
+        None => (),
+        Some(msg) => conn.emit(Globops(source, msg.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use serialize::base64::{ToBase64, STANDARD};
+    use super::Kline;
+    use conn::{Line, Options, Event, Manual, Continue};
+
+    #[test]
+    fn test_parse_kline() {
+        let k = |raw: &[u8]| Kline::parse(&Line::parse(raw).unwrap());
+        assert_eq!(k(b":srv 216 me K bad.host * ~user :spamming"), Some(Kline {
+            mask: b"~user@bad.host".to_vec(),
+            reason: b"spamming".to_vec(),
+            setter: None,
+            duration: None
+        }));
+        assert_eq!(k(b":srv 216 me *@bad.host oper 1400000000 3600 :spamming"), Some(Kline {
+            mask: b"*@bad.host".to_vec(),
+            reason: b"spamming".to_vec(),
+            setter: Some(b"oper".to_vec()),
+            duration: Some(3600)
+        }));
+        assert_eq!(k(b":srv 216 me I * * :not a kline"), None);
+        assert_eq!(k(b":srv 219 me K :End of /STATS report"), None);
+    }
+
+    #[test]
+    fn list_klines() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 004 ircnick irc.example.net charybdis-4.1.2 DQRSZagiloswz biklmnopstveI"],
+                  Duration::zero());
+        let lists = Arc::new(Mutex::new(Vec::new()));
+        for _ in range(0u, 2) {
+            let l = lists.clone();
+            conn.conn().list_klines(proc(_, klines) { l.lock().push(klines) });
+        }
+        // permanent and temporary K-lines, for each query
+        assert_eq!(conn.step([], Duration::zero()),
+                   vec![b"STATS K".to_vec(), b"STATS k".to_vec(), b"STATS K".to_vec(), b"STATS k".to_vec()]);
+        conn.step([b":irc.example.net 216 ircnick K bad.host * ~user :spamming",
+                   b":irc.example.net 219 ircnick K :End of /STATS report"], Duration::zero());
+        // the first query needs both lists
+        assert!(lists.lock().is_empty());
+        conn.step([b":irc.example.net 216 ircnick k tmp.host * * :flooding",
+                   b":irc.example.net 219 ircnick k :End of /STATS report",
+                   b":irc.example.net 219 ircnick K :End of /STATS report",
+                   b":irc.example.net 219 ircnick k :End of /STATS report"], Duration::zero());
+        let kline = |mask: &[u8], reason: &[u8]| Kline {
+            mask: mask.to_vec(),
+            reason: reason.to_vec(),
+            setter: None,
+            duration: None
+        };
+        assert_eq!(*lists.lock(), vec![vec![kline(b"~user@bad.host", b"spamming"), kline(b"*@tmp.host", b"flooding")],
+                                       vec![]]);
+
+        // a query the last connection didn't finish doesn't get this one's replies
+        let l = lists.clone();
+        conn.conn().list_klines(proc(_, klines) { l.lock().push(klines) });
+        conn.step([], Duration::zero());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 216 ircnick K bad.host * ~user :spamming",
+                   b":irc.example.net 219 ircnick K :End of /STATS report",
+                   b":irc.example.net 219 ircnick k :End of /STATS report"], Duration::zero());
+        assert_eq!(lists.lock().len(), 2);
+    }
+
+    #[test]
+    fn challenge_oper() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let (tx, rx) = channel();
+        let solver = |answer: Option<&'static [u8]>| {
+            let tx = tx.clone();
+            proc(challenge: Vec<u8>) {
+                tx.send(challenge);
+                answer.map(|a| a.to_vec())
+            }
+        };
+        // the challenge comes in several 740 lines
+        let encoded = b"a secret challenge".to_base64(STANDARD);
+        let first = format!(":irc.example.net 740 ircnick :{}", encoded.as_slice().slice_to(10));
+        let second = format!(":irc.example.net 740 ircnick :{}", encoded.as_slice().slice_from(10));
+        let end = b":irc.example.net 741 ircnick :End of CHALLENGE";
+
+        conn.conn().challenge_oper(b"admin", solver(Some(b"digest")));
+        assert_eq!(conn.step([], Duration::zero()), vec![b"CHALLENGE admin".to_vec()]);
+        let out = conn.step([first.as_bytes(), second.as_bytes(), end], Duration::zero());
+        assert_eq!(rx.try_recv(), Ok(b"a secret challenge".to_vec()));
+        assert_eq!(out, vec![format!("CHALLENGE +{}", b"digest".to_base64(STANDARD)).into_bytes()]);
+
+        // a solver that can't answer sends nothing
+        conn.conn().challenge_oper(b"admin", solver(None));
+        conn.step([], Duration::zero());
+        assert!(conn.step([first.as_bytes(), second.as_bytes(), end], Duration::zero()).is_empty());
+        assert_eq!(rx.try_recv(), Ok(b"a secret challenge".to_vec()));
+
+        // no oper block: the challenge isn't coming, and a stray one is ignored
+        conn.conn().challenge_oper(b"nobody", solver(Some(b"digest")));
+        conn.step([], Duration::zero());
+        conn.step([b":irc.example.net 491 ircnick :No appropriate operator blocks were found for your host"],
+                  Duration::zero());
+        assert!(conn.step([first.as_bytes(), second.as_bytes(), end], Duration::zero()).is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+}
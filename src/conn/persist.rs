@@ -0,0 +1,159 @@
+//! Saving and restoring state with the StateStore from the Options
+
+use conn::Conn;
+use conn::{bans, seen};
+use conn::TimedBan;
+
+/// Key of the watch list in the store, one nick per line
+static WATCH_KEY: &'static str = "watch";
+/// Key of the channel keys in the store, one `channel key` pair per line
+static CHANKEYS_KEY: &'static str = "chankeys";
+/// Key of the pending timed bans in the store, one `expiry channel mask` per line
+static TIMEDBANS_KEY: &'static str = "timedbans";
+/// Key of the seen list in the store, one sighting per line
+static SEEN_KEY: &'static str = "seen";
+
+/// Channel keys that we were given, by casemapped channel name
+struct ChanKeys(Vec<(Vec<u8>, Vec<u8>)>);
+
+impl<'a> Conn<'a> {
+    /// Returns the key last used to join `chan`, if any.
+    ///
+    /// Keys given to `join()` are remembered (and saved to `Options.store`, if
+    /// there is one) until the server rejects them, and `join()` uses the
+    /// remembered key when it isn't given one.
+    pub fn channel_key(&self, chan: &[u8]) -> Option<Vec<u8>> {
+        let chan = self.isupport.casemapping().lower(chan);
+        self.extensions.get::<ChanKeys>().and_then(|&ChanKeys(ref known)| {
+            known.iter().find(|&&(ref c, _)| *c == chan).map(|&(_, ref k)| k.clone())
+        })
+    }
+}
+
+/// Loads the saved state from the store, if there is one
+pub fn load(conn: &mut Conn) {
+    for nick in load_lines(conn, WATCH_KEY).into_iter() {
+        conn.watch(nick.as_slice());
+    }
+    let keys = load_lines(conn, CHANKEYS_KEY).into_iter().filter_map(|line| {
+        let line = line.as_slice();
+        line.position_elem(&(' ' as u8)).map(|idx| {
+            (line.slice_to(idx).to_vec(), line.slice_from(idx+1).to_vec())
+        })
+    }).collect();
+    conn.extensions.insert(ChanKeys(keys));
+    let timed = load_lines(conn, TIMEDBANS_KEY).into_iter().filter_map(|line| {
+        let mut words = line.as_slice().splitn(2, |&b| b == ' ' as u8);
+        let expires = words.next().and_then(|w| ::std::str::from_utf8(w)).and_then(|w| from_str(w));
+        match (expires, words.next(), words.next()) {
+            (Some(expires), Some(chan), Some(mask)) => {
+                Some(TimedBan { chan: chan.to_vec(), mask: mask.to_vec(), expires: expires })
+            }
+            _ => None
+        }
+    }).collect();
+    bans::restore(conn, timed);
+    if seen::is_enabled(conn) {
+        let entries = load_lines(conn, SEEN_KEY).iter().filter_map(|l| seen::decode(l.as_slice())).collect();
+        seen::restore(conn, entries);
+    }
+}
+
+/// Saves the watch list to the store, if there is one
+pub fn watch_changed(conn: &mut Conn) {
+    let nicks = conn.watched().into_iter().map(|(nick, _)| nick).collect::<Vec<_>>();
+    save_lines(conn, WATCH_KEY, nicks.as_slice());
+}
+
+/// Saves the pending timed bans to the store, if there is one
+pub fn timed_bans_changed(conn: &mut Conn) {
+    let lines = conn.timed_bans().into_iter().map(|ban| {
+        let mut line = ban.expires.to_string().into_bytes();
+        line.push(' ' as u8);
+        line.push_all(ban.chan.as_slice());
+        line.push(' ' as u8);
+        line.push_all(ban.mask.as_slice());
+        line
+    }).collect::<Vec<_>>();
+    save_lines(conn, TIMEDBANS_KEY, lines.as_slice());
+}
+
+/// Saves the seen list to the store, if there is one
+pub fn seen_changed(conn: &mut Conn) {
+    let lines = seen::entries(conn).iter().map(|s| seen::encode(s)).collect::<Vec<_>>();
+    save_lines(conn, SEEN_KEY, lines.as_slice());
+}
+
+/// Remembers the keys for a JOIN. `chans` and `keys` are comma-separated
+/// lists as given to `join()`.
+pub fn remember_keys(conn: &mut Conn, chans: &[u8], keys: &[u8]) {
+    let casemapping = conn.isupport.casemapping();
+    let pairs = chans.split(|&b| b == ',' as u8).zip(keys.split(|&b| b == ',' as u8))
+                     .filter(|&(_, k)| !k.is_empty())
+                     .map(|(c, k)| (casemapping.lower(c), k.to_vec()))
+                     .collect::<Vec<_>>();
+    if pairs.is_empty() {
+        return;
+    }
+    {
+        let &ChanKeys(ref mut known) = conn.extensions.get_or_insert_with(|| ChanKeys(Vec::new()));
+        for (chan, key) in pairs.into_iter() {
+            known.retain(|&(ref c, _)| *c != chan);
+            known.push((chan, key));
+        }
+    }
+    save_keys(conn);
+}
+
+/// Forgets the key for `chan`, e.g. after the server rejected it
+pub fn forget_key(conn: &mut Conn, chan: &[u8]) {
+    let chan = conn.isupport.casemapping().lower(chan);
+    match conn.extensions.get_mut::<ChanKeys>() {
+        None => return,
+        Some(&ChanKeys(ref mut known)) => known.retain(|&(ref c, _)| *c != chan)
+    }
+    save_keys(conn);
+}
+
+fn save_keys(conn: &mut Conn) {
+    let lines = match conn.extensions.get::<ChanKeys>() {
+        None => return,
+        Some(&ChanKeys(ref known)) => known.iter().map(|&(ref chan, ref key)| {
+            let mut line = chan.clone();
+            line.push(' ' as u8);
+            line.push_all(key.as_slice());
+            line
+        }).collect::<Vec<_>>()
+    };
+    save_lines(conn, CHANKEYS_KEY, lines.as_slice());
+}
+
+fn load_lines(conn: &Conn, key: &str) -> Vec<Vec<u8>> {
+    let data = match conn.store {
+        None => return Vec::new(),
+        Some(ref store) => match store.load(key) {
+            Ok(data) => data.unwrap_or(Vec::new()),
+            Err(e) => {
+                warn!("Couldn't load {} from the state store: {}", key, e);
+                return Vec::new();
+            }
+        }
+    };
+    data.as_slice().split(|&b| b == '\n' as u8).filter(|l| !l.is_empty())
+                   .map(|l| l.to_vec()).collect()
+}
+
+fn save_lines(conn: &mut Conn, key: &str, lines: &[Vec<u8>]) {
+    let mut data = Vec::new();
+    for line in lines.iter() {
+        data.push_all(line.as_slice());
+        data.push('\n' as u8);
+    }
+    match conn.store {
+        None => (),
+        Some(ref mut store) => match store.save(key, data.as_slice()) {
+            Ok(()) => (),
+            Err(e) => warn!("Couldn't save {} to the state store: {}", key, e)
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Running the event handler on a pool of worker tasks
+
+use std::sync::{Arc, Mutex};
+use conn::{Cmd, CmdState, Conn, Event, Options, Result, Continue, Spawner, TaskSpawner, connect};
+
+/// An event handler that can run on several tasks at once. See `connect_pooled()`.
+pub trait Handler: Send + Sync {
+    /// Handles an event. `reply` is used to act on the connection.
+    fn handle(&self, event: Event, reply: &Reply);
+}
+
+/// A handle that worker tasks use to act on the connection.
+///
+/// Everything is done by sending a command to the connection's task, so the
+/// methods return right away and take effect once the connection gets to them.
+#[deriving(Clone)]
+pub struct Reply {
+    commands: Sender<Cmd>
+}
+
+impl Reply {
+    /// Runs `cmd` on the connection's task with a handle to the connection,
+    /// and whether it's still active. Returns `false` if the connection has
+    /// already terminated.
+    pub fn run(&self, cmd: proc(&mut Conn, CmdState): Send) -> bool {
+        self.commands.send_opt(proc(conn, _, state) { cmd(conn, state) }).is_ok()
+    }
+
+    /// Sends a PRIVMSG. See `Conn::privmsg()`.
+    pub fn privmsg(&self, dst: &[u8], msg: &[u8]) {
+        let (dst, msg) = (dst.to_vec(), msg.to_vec());
+        self.run(proc(conn, _) { conn.privmsg(dst.as_slice(), msg.as_slice()) });
+    }
+
+    /// Sends a NOTICE. See `Conn::notice()`.
+    pub fn notice(&self, dst: &[u8], msg: &[u8]) {
+        let (dst, msg) = (dst.to_vec(), msg.to_vec());
+        self.run(proc(conn, _) { conn.notice(dst.as_slice(), msg.as_slice()) });
+    }
+
+    /// Sends a raw line. See `Conn::send_raw()`.
+    pub fn send_raw(&self, raw: &[u8]) {
+        let raw = raw.to_vec();
+        self.run(proc(conn, _) { conn.send_raw(raw.as_slice()) });
+    }
+
+    /// Quits the connection. See `Conn::quit()`.
+    pub fn quit(&self, msg: &[u8]) {
+        let msg = msg.to_vec();
+        self.run(proc(conn, _) { conn.quit(msg.as_slice()) });
+    }
+}
+
+/// Connects to the remote server and runs `handler` on `workers` worker tasks.
+///
+/// The connection's own task only reads and writes lines and answers PINGs, so a
+/// slow handler (a database lookup, an HTTP request) can't get the connection
+/// timed out. Events are handed to whichever worker is free, so handlers may see
+/// them out of order. Any `opts.commands` channel is replaced.
+///
+/// Like `connect()`, this doesn't return until the connection is terminated.
+/// Workers finish the events they were already given before exiting.
+pub fn connect_pooled<H: Handler>(mut opts: Options<()>, workers: uint, handler: H) -> Result {
+    assert!(workers > 0, "connect_pooled needs at least one worker");
+    let (cmd_tx, cmd_rx) = channel();
+    opts.commands = Some(cmd_rx);
+    let (work_tx, work_rx) = channel();
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    let handler = Arc::new(handler);
+    let mut spawner = opts.spawner.take().unwrap_or_else(|| box TaskSpawner as Box<Spawner + Send>);
+
+    for _ in range(0, workers) {
+        let work_rx = work_rx.clone();
+        let handler = handler.clone();
+        let reply = Reply { commands: cmd_tx.clone() };
+        spawner.spawn("libirc worker", proc() {
+            loop {
+                // only hold the lock while waiting, not while handling
+                let event = match work_rx.lock().recv_opt() {
+                    Err(()) => break,
+                    Ok(event) => event
+                };
+                handler.handle(event, &reply);
+            }
+        });
+    }
+    drop(cmd_tx);
+    opts.spawner = Some(spawner);
+
+    connect(opts, (), |_, event, _| {
+        let _ = work_tx.send_opt(event);
+        Continue
+    })
+}
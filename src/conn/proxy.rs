@@ -0,0 +1,109 @@
+//! PROXY protocol headers, for connecting through a load balancer like
+//! haproxy. See `Options.proxy_header`.
+
+use std::io::{IoResult, TcpStream};
+use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+
+/// Which version of the PROXY protocol to speak
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ProxyVersion {
+    /// The human-readable version 1 header
+    ProxyV1,
+    /// The binary version 2 header
+    ProxyV2
+}
+
+/// The PROXY protocol header to send when connecting
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ProxyHeader {
+    /// The version of the protocol the receiving end expects
+    pub version: ProxyVersion,
+    /// The source address to claim. None uses the local address of the
+    /// connection.
+    pub source: Option<SocketAddr>
+}
+
+/// Writes `header` to a freshly opened connection, before anything else is sent
+pub fn send_header(socket: &mut TcpStream, header: &ProxyHeader) -> IoResult<()> {
+    let src = match header.source {
+        Some(src) => src,
+        None => try!(socket.socket_name())
+    };
+    let dst = try!(socket.peer_name());
+    try!(socket.write(encode(&header.version, src, dst).as_slice()));
+    socket.flush()
+}
+
+static V2_SIGNATURE: &'static [u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+/// Encodes a header saying that a connection from `src` to `dst` is being
+/// proxied. If the two addresses aren't of the same family, the header says
+/// that the source is unknown.
+pub fn encode(version: &ProxyVersion, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    match *version {
+        ProxyV1 => {
+            let family = match (src.ip, dst.ip) {
+                (Ipv4Addr(..), Ipv4Addr(..)) => "TCP4",
+                (Ipv6Addr(..), Ipv6Addr(..)) => "TCP6",
+                _ => return b"PROXY UNKNOWN\r\n".to_vec()
+            };
+            format!("PROXY {} {} {} {} {}\r\n", family, src.ip, dst.ip, src.port, dst.port).into_bytes()
+        }
+        ProxyV2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let mut addrs = Vec::new();
+            let family = match (src.ip, dst.ip) {
+                (Ipv4Addr(a, b, c, d), Ipv4Addr(e, f, g, h)) => {
+                    addrs.push_all([a, b, c, d, e, f, g, h]);
+                    0x11 // AF_INET, STREAM
+                }
+                (Ipv6Addr(a, b, c, d, e, f, g, h), Ipv6Addr(i, j, k, l, m, n, o, p)) => {
+                    for &part in [a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p].iter() {
+                        push_u16(&mut addrs, part);
+                    }
+                    0x21 // AF_INET6, STREAM
+                }
+                _ => 0x00 // AF_UNSPEC
+            };
+            if family == 0x00 {
+                // LOCAL: the receiver uses the connection's own addresses
+                header.push_all([0x20, 0x00, 0x00, 0x00]);
+                return header;
+            }
+            push_u16(&mut addrs, src.port);
+            push_u16(&mut addrs, dst.port);
+            // version 2, PROXY command
+            header.push_all([0x21, family]);
+            push_u16(&mut header, addrs.len() as u16);
+            header.push_all(addrs.as_slice());
+            header
+        }
+    }
+}
+
+fn push_u16(v: &mut Vec<u8>, n: u16) {
+    v.push((n >> 8) as u8);
+    v.push(n as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, ProxyV1, ProxyV2};
+    use std::io::net::ip::{SocketAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_encode() {
+        let src = SocketAddr { ip: Ipv4Addr(192, 0, 2, 1), port: 56324 };
+        let dst = SocketAddr { ip: Ipv4Addr(10, 0, 0, 5), port: 6667 };
+        assert_eq!(encode(&ProxyV1, src, dst), b"PROXY TCP4 192.0.2.1 10.0.0.5 56324 6667\r\n".to_vec());
+        assert_eq!(encode(&ProxyV2, src, dst),
+                   b"\r\n\r\n\x00\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00\x02\x01\x0a\x00\x00\x05\xdc\x04\x1a\x0b".to_vec());
+
+        let src6 = SocketAddr { ip: Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), port: 56324 };
+        assert_eq!(encode(&ProxyV1, src6, dst), b"PROXY UNKNOWN\r\n".to_vec());
+        assert_eq!(encode(&ProxyV2, src6, dst), b"\r\n\r\n\x00\r\nQUIT\n\x20\x00\x00\x00".to_vec());
+        let dst6 = SocketAddr { ip: Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2), port: 6667 };
+        assert_eq!(encode(&ProxyV1, src6, dst6), b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 6667\r\n".to_vec());
+        assert_eq!(encode(&ProxyV2, src6, dst6).len(), 16 + 36);
+    }
+}
@@ -0,0 +1,111 @@
+//! Replies and reactions to earlier messages, the `+draft/reply` and
+//! `+draft/react` client tags
+//!
+//! Messages are referred to by the ID that the server gave them in their
+//! `msgid` tag.
+
+use std::cmp::min;
+
+use conn::{Conn, Line, IRCCmd, Reacted};
+use conn::split_message;
+
+impl<'a> Conn<'a> {
+    /// Sends a PRIVMSG to `target` that replies to the message with the ID
+    /// `msgid`, by tagging it with `+draft/reply`. Returns `false` if the
+    /// server hasn't enabled the "message-tags" capability, in which case the
+    /// message is sent without the tag.
+    ///
+    /// Long messages are split like `privmsg()` splits them, and each line
+    /// carries the tag.
+    pub fn privmsg_reply(&mut self, target: &[u8], msgid: &str, msg: &[u8]) -> bool {
+        if !self.has_cap("message-tags") {
+            self.privmsg(target, msg);
+            return false;
+        }
+        // the tag counts towards the length of the line we send, but not
+        // towards the one the server relays
+        let sent = "@+draft/reply= PRIVMSG  :".len() + msgid.len() + target.len();
+        let room = min(self.max_message_len("PRIVMSG", target), 510 - min(510, sent));
+        for part in split_message(msg, room).into_iter() {
+            let line = Line {
+                tags: vec![("+draft/reply".to_string(), msgid.to_string())],
+                prefix: None,
+                command: IRCCmd("PRIVMSG".into_maybe_owned()),
+                args: vec![target.to_vec(), part.to_vec()]
+            };
+            self.send_raw(line.to_raw().as_slice());
+        }
+        true
+    }
+
+    /// Reacts to the message with the ID `msgid` in `target` with
+    /// `reaction`, usually an emoji, with a TAGMSG. Returns `false` if nothing
+    /// could be sent because the server hasn't enabled the "message-tags"
+    /// capability.
+    pub fn react(&mut self, target: &[u8], msgid: &str, reaction: &str) -> bool {
+        if !self.has_cap("message-tags") {
+            return false;
+        }
+        let line = Line {
+            tags: vec![("+draft/reply".to_string(), msgid.to_string()),
+                       ("+draft/react".to_string(), reaction.to_string())],
+            prefix: None,
+            command: IRCCmd("TAGMSG".into_maybe_owned()),
+            args: vec![target.to_vec()]
+        };
+        self.send_raw(line.to_raw().as_slice());
+        true
+    }
+}
+
+/// Sends a Reacted event for a TAGMSG with a `+draft/react` tag
+pub fn TAGMSG(conn: &mut Conn, line: &Line) {
+    // @+draft/reply=<msgid>;+draft/react=<reaction> :nick!user@host TAGMSG <target>
+    let (msgid, reaction) = match (line.reply_to(), line.tag("+draft/react")) {
+        (Some(msgid), Some(reaction)) if !reaction.is_empty() => (msgid.to_string(), reaction.to_string()),
+        _ => return
+    };
+    let user = match line.prefix {
+        Some(ref user) if !user.is_server(&conn.state) => user.clone(),
+        _ => return
+    };
+    let target = match line.args.as_slice().head() {
+        None => return,
+        Some(target) => target.clone()
+    };
+    conn.emit(Reacted(user, target, msgid, reaction));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, LineReceived, Reacted};
+
+    #[test]
+    fn replies_and_reactions() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.caps = vec!["message-tags"];
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([], Duration::zero());
+        assert!(!conn.conn().react(b"#rust", "abc", "+1"));
+        conn.step([b":irc.example.net CAP * LS :message-tags",
+                   b":irc.example.net CAP * ACK :message-tags",
+                   b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        assert!(conn.conn().privmsg_reply(b"#rust", "abc", b"me too"));
+        assert!(conn.conn().react(b"#rust", "abc;1", "+1"));
+        assert_eq!(conn.step([], Duration::zero()),
+                   vec![b"@+draft/reply=abc PRIVMSG #rust :me too".to_vec(),
+                        b"@+draft/reply=abc\\:1;+draft/react=+1 TAGMSG #rust".to_vec()]);
+        conn.events();
+        conn.step([b"@msgid=def;+draft/reply=abc :alice!a@host PRIVMSG #rust :same",
+                   b"@+draft/reply=def;+draft/react=+1 :bob!b@host TAGMSG #rust"], Duration::zero());
+        let events = conn.events().into_iter().filter_map(|e| match e {
+            LineReceived(ref line) if line.reply_to().is_some() => {
+                Some(format!("reply to {}", line.reply_to().unwrap()))
+            }
+            Reacted(user, _, msgid, reaction) => Some(format!("{} reacted {} to {}", user.nick(), reaction, msgid)),
+            _ => None
+        }).collect::<Vec<String>>();
+        assert_eq!(events, vec!["reply to abc".to_string(), "bob reacted +1 to def".to_string()]);
+    }
+}
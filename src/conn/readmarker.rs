@@ -0,0 +1,112 @@
+//! Read markers, the draft/read-marker extension
+//!
+//! A bouncer or server that supports it remembers how far each target was
+//! read, so all the clients attached to the same account agree on it.
+
+use conn::{Conn, Line, IRCCmd, ReadMarkerChanged};
+
+/// The last read markers the server told us about, by casemapped target.
+/// None means the server doesn't know one.
+struct ReadMarkers(Vec<(Vec<u8>, Option<String>)>);
+
+impl<'a> Conn<'a> {
+    /// Marks `target`, a channel or nick, as read up to `time`, an ISO 8601
+    /// timestamp like the server-time tag, e.g. `2014-06-01T12:00:00.000Z`.
+    /// Returns `false` if nothing could be sent because the server hasn't
+    /// enabled the "draft/read-marker" capability, which has to be asked for
+    /// with `Options.caps`.
+    ///
+    /// The server only moves markers forward. It answers with the marker it
+    /// keeps, which is sent as a ReadMarkerChanged event like the updates
+    /// from other clients.
+    pub fn set_read_marker(&mut self, target: &[u8], time: &str) -> bool {
+        if !self.has_cap("draft/read-marker") {
+            return false;
+        }
+        let mut timestamp = b"timestamp=".to_vec();
+        timestamp.push_all(time.as_bytes());
+        self.send_command(IRCCmd("MARKREAD".into_maybe_owned()), [target, timestamp.as_slice()], false);
+        true
+    }
+
+    /// Asks the server for the read marker of `target`, which is sent as a
+    /// ReadMarkerChanged event. Servers send it on their own for the
+    /// channels we join. Returns `false` if the server hasn't enabled the
+    /// "draft/read-marker" capability.
+    pub fn fetch_read_marker(&mut self, target: &[u8]) -> bool {
+        if !self.has_cap("draft/read-marker") {
+            return false;
+        }
+        self.send_command(IRCCmd("MARKREAD".into_maybe_owned()), [target], false);
+        true
+    }
+
+    /// Returns the last read marker the server sent for `target`, if it has
+    /// sent one that isn't empty
+    pub fn read_marker(&self, target: &[u8]) -> Option<String> {
+        let key = self.isupport.casemapping().lower(target);
+        match self.extensions.get::<ReadMarkers>() {
+            None => None,
+            Some(&ReadMarkers(ref markers)) => {
+                markers.iter().find(|&&(ref t, _)| *t == key).and_then(|&(_, ref time)| time.clone())
+            }
+        }
+    }
+}
+
+/// Forgets the markers of the last connection
+pub fn reset(conn: &mut Conn) {
+    conn.extensions.remove::<ReadMarkers>();
+}
+
+pub fn MARKREAD(conn: &mut Conn, line: &Line) {
+    // :server MARKREAD <target> timestamp=<time>, or timestamp=* if there's none
+    let (target, param) = match line.args.as_slice() {
+        [ref target, ref param, ..] => (target.clone(), param.as_slice()),
+        _ => return
+    };
+    if !param.starts_with(b"timestamp=") {
+        return;
+    }
+    let time = param.slice_from("timestamp=".len());
+    let time = if time.is_empty() || time == b"*" {
+        None
+    } else {
+        Some(String::from_utf8_lossy(time).into_string())
+    };
+    let key = conn.isupport.casemapping().lower(target.as_slice());
+    {
+        let &ReadMarkers(ref mut markers) = conn.extensions.get_or_insert_with(|| ReadMarkers(Vec::new()));
+        markers.retain(|&(ref t, _)| *t != key);
+        markers.push((key, time.clone()));
+    }
+    conn.emit(ReadMarkerChanged(target, time));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, ReadMarkerChanged};
+
+    #[test]
+    fn read_marker() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.caps = vec!["draft/read-marker"];
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net CAP * LS :draft/read-marker",
+                   b":irc.example.net CAP * ACK :draft/read-marker",
+                   b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        assert!(conn.conn().set_read_marker(b"#rust", "2014-06-01T12:00:00.000Z"));
+        assert_eq!(conn.step([b":irc.example.net MARKREAD #Rust timestamp=*"], Duration::zero()),
+                   vec![b"MARKREAD #rust timestamp=2014-06-01T12:00:00.000Z".to_vec()]);
+        assert_eq!(conn.conn().read_marker(b"#rust"), None);
+        // another client read further
+        conn.step([b":irc.example.net MARKREAD #rust timestamp=2014-06-01T12:05:00.000Z"], Duration::zero());
+        assert_eq!(conn.conn().read_marker(b"#RUST"), Some("2014-06-01T12:05:00.000Z".to_string()));
+        let markers = conn.events().into_iter().filter_map(|e| match e {
+            ReadMarkerChanged(_, time) => Some(time),
+            _ => None
+        }).collect::<Vec<Option<String>>>();
+        assert_eq!(markers, vec![None, Some("2014-06-01T12:05:00.000Z".to_string())]);
+    }
+}
@@ -0,0 +1,193 @@
+//! Rejoining channels we're kicked from, see `Options.rejoin_on_kick`
+
+use std::cmp::min;
+use std::time::Duration;
+
+use conn::{Conn, Line, RejoinScheduled, RejoinGaveUp};
+use errors::IrcError;
+
+/// How channels we're kicked from are rejoined. See `Options.rejoin_on_kick`.
+///
+/// The first JOIN is sent `delay` after the KICK. If the server refuses it,
+/// e.g. because we've been banned (ERR_BANNEDFROMCHAN, 474), the delay is
+/// doubled up to `max_delay` for the next try, until `max_attempts` JOINs
+/// have failed.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct RejoinPolicy {
+    /// How long to wait after the KICK before the first JOIN
+    pub delay: Duration,
+    /// The longest to wait between two JOINs
+    pub max_delay: Duration,
+    /// The number of failed JOINs after which we give up
+    pub max_attempts: uint
+}
+
+impl RejoinPolicy {
+    /// Returns a policy that rejoins `delay` after the KICK, and tries up to
+    /// 5 times with at most 5 minutes in between
+    pub fn new(delay: Duration) -> RejoinPolicy {
+        RejoinPolicy {
+            delay: delay,
+            max_delay: Duration::minutes(5),
+            max_attempts: 5
+        }
+    }
+}
+
+/// A channel we were kicked from and are trying to rejoin
+struct Rejoin {
+    /// The casemapped channel name
+    chan: Vec<u8>,
+    /// The channel name we were kicked from
+    name: Vec<u8>,
+    /// The JOINs that failed so far
+    failed: uint,
+    /// The delay before the last JOIN
+    delay: Duration
+}
+
+struct Rejoins(Vec<Rejoin>);
+
+/// Forgets the rejoins of the last connection, whose JOINs won't be sent
+pub fn reset(conn: &mut Conn) {
+    conn.extensions.remove::<Rejoins>();
+}
+
+/// Keys the rejoins by the new casemapping
+pub fn rekey(conn: &mut Conn) {
+    let casemapping = conn.isupport.casemapping();
+    match conn.extensions.get_mut::<Rejoins>() {
+        None => (),
+        Some(&Rejoins(ref mut rejoins)) => for rejoin in rejoins.iter_mut() {
+            rejoin.chan = casemapping.lower(rejoin.name.as_slice());
+        }
+    }
+}
+
+/// Schedules a rejoin when we're kicked
+pub fn KICK(conn: &mut Conn, line: &Line) {
+    // :op KICK #chan nick :reason
+    let policy = match conn.rejoin_policy {
+        None => return,
+        Some(ref policy) => policy.clone()
+    };
+    let casemapping = conn.isupport.casemapping();
+    let chan = match line.args.as_slice() {
+        [ref chan, ref nick, ..] if casemapping.eq_ignore_case(nick.as_slice(), conn.user.nick()) => chan.clone(),
+        _ => return
+    };
+    let key = casemapping.lower(chan.as_slice());
+    {
+        let &Rejoins(ref mut rejoins) = conn.extensions.get_or_insert_with(|| Rejoins(Vec::new()));
+        rejoins.retain(|r| r.chan != key);
+        rejoins.push(Rejoin { chan: key, name: chan.clone(), failed: 0, delay: policy.delay });
+    }
+    schedule(conn, chan, policy.delay);
+}
+
+/// Forgets about rejoining a channel once we're back in it
+pub fn joined(conn: &mut Conn, chan: &[u8]) {
+    let key = conn.isupport.casemapping().lower(chan);
+    match conn.extensions.get_mut::<Rejoins>() {
+        None => (),
+        Some(&Rejoins(ref mut rejoins)) => rejoins.retain(|r| r.chan != key)
+    }
+}
+
+/// Tries again later if a rejoin failed, or gives up
+pub fn join_failed(conn: &mut Conn, chan: &[u8], err: &IrcError) {
+    let policy = match conn.rejoin_policy {
+        None => return,
+        Some(ref policy) => policy.clone()
+    };
+    let key = conn.isupport.casemapping().lower(chan);
+    let next = match conn.extensions.get_mut::<Rejoins>() {
+        None => return,
+        Some(&Rejoins(ref mut rejoins)) => {
+            let idx = match rejoins.iter().position(|r| r.chan == key) {
+                None => return,
+                Some(idx) => idx
+            };
+            let next = {
+                let rejoin = rejoins.get_mut(idx);
+                rejoin.failed += 1;
+                rejoin.delay = min(rejoin.delay * 2, policy.max_delay);
+                if rejoin.failed < policy.max_attempts { Some(rejoin.delay) } else { None }
+            };
+            if next.is_none() {
+                rejoins.remove(idx);
+            }
+            next
+        }
+    };
+    match next {
+        None => {
+            info!("Giving up on rejoining {}", String::from_utf8_lossy(chan));
+            conn.emit(RejoinGaveUp(chan.to_vec(), err.clone()));
+        }
+        Some(delay) => schedule(conn, chan.to_vec(), delay)
+    }
+}
+
+fn schedule(conn: &mut Conn, chan: Vec<u8>, delay: Duration) {
+    conn.emit(RejoinScheduled(chan.clone(), delay));
+    conn.schedule(delay, proc(conn: &mut Conn) attempt(conn, chan));
+}
+
+/// Sends the JOIN for a rejoin, unless it was given up on or we're back in
+/// the channel meanwhile
+fn attempt(conn: &mut Conn, chan: Vec<u8>) {
+    let key = conn.isupport.casemapping().lower(chan.as_slice());
+    let pending = match conn.extensions.get::<Rejoins>() {
+        None => false,
+        Some(&Rejoins(ref rejoins)) => rejoins.iter().any(|r| r.chan == key)
+    };
+    if !pending {
+        return;
+    }
+    if conn.state.channel(chan.as_slice()).is_some() {
+        joined(conn, chan.as_slice());
+        return;
+    }
+    // the key the channel was joined with is remembered
+    match conn.join(chan.as_slice(), []) {
+        Ok(()) => (),
+        Err(e) => info!("Can't rejoin {}: {}", String::from_utf8_lossy(chan.as_slice()), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, RejoinScheduled, RejoinGaveUp};
+    use super::RejoinPolicy;
+
+    #[test]
+    fn rejoin_backoff() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.rejoin_on_kick = Some(RejoinPolicy {
+            delay: Duration::seconds(1),
+            max_delay: Duration::seconds(3),
+            max_attempts: 3
+        });
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        let banned = b":irc.example.net 474 ircnick #rust :Cannot join channel (+b)";
+        conn.step([b":irc.example.net 001 ircnick :Welcome", b":ircnick!u@h JOIN #rust"], Duration::zero());
+        assert!(conn.step([b":op!u@h KICK #rust ircnick :out"], Duration::zero()).is_empty());
+        assert_eq!(conn.step([], second), vec![b"JOIN #rust".to_vec()]);
+        // banned: wait 2s, then 3s
+        assert!(conn.step([banned], second).is_empty());
+        assert_eq!(conn.step([], second), vec![b"JOIN #rust".to_vec()]);
+        conn.step([banned], Duration::seconds(2));
+        assert_eq!(conn.step([], second), vec![b"JOIN #rust".to_vec()]);
+        conn.step([banned], Duration::minutes(10));
+        let events = conn.events().into_iter().filter_map(|e| match e {
+            RejoinScheduled(_, delay) => Some(format!("rejoin in {}s", delay.num_seconds())),
+            RejoinGaveUp(_, err) => Some(format!("gave up after {}", err.code)),
+            _ => None
+        }).collect::<Vec<String>>();
+        assert_eq!(events, vec!["rejoin in 1s".to_string(), "rejoin in 2s".to_string(),
+                                "rejoin in 3s".to_string(), "gave up after 474".to_string()]);
+    }
+}
@@ -0,0 +1,166 @@
+//! Repairing the tracked state of a channel, see `Conn::resync()`
+
+use conn::{Conn, Line, IRCCmd, IRCCode, ChannelResynced};
+use state::Channel;
+
+/// What was out of sync in a channel repaired with `Conn::resync()`
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ChannelResync {
+    /// Members that were missing from the tracked channel
+    pub joined: Vec<Vec<u8>>,
+    /// Tracked members that weren't in the channel anymore
+    pub left: Vec<Vec<u8>>,
+    /// Members whose membership modes (op, voice etc.) were wrong
+    pub member_modes: Vec<Vec<u8>>,
+    /// Whether the channel modes were wrong
+    pub modes_changed: bool,
+    /// Whether the topic was wrong
+    pub topic_changed: bool
+}
+
+impl ChannelResync {
+    /// Returns `true` if the tracked channel was already correct
+    pub fn in_sync(&self) -> bool {
+        self.joined.is_empty() && self.left.is_empty() && self.member_modes.is_empty() &&
+            !self.modes_changed && !self.topic_changed
+    }
+}
+
+/// The tracked state of a channel when its resync was started
+struct Snapshot {
+    chan: Vec<u8>,
+    /// The lowercased nick, nick and membership modes of each member
+    members: Vec<(Vec<u8>, Vec<u8>, Vec<char>)>,
+    modes: Vec<(char, Option<Vec<u8>>)>,
+    topic: Option<Vec<u8>>
+}
+
+/// The resyncs that are waiting for the server's replies
+struct PendingResyncs(Vec<Snapshot>);
+
+impl<'a> Conn<'a> {
+    /// Asks the server for the member list, modes and topic of `chan` again
+    /// and replaces the tracked ones with them. This repairs a channel that
+    /// went out of sync, e.g. because lines were lost around a netsplit.
+    ///
+    /// Once the replies are in, a ChannelResynced event says what was wrong.
+    /// Returns `false` if we aren't in `chan`.
+    pub fn resync(&mut self, chan: &[u8]) -> bool {
+        let snapshot = match self.state.channel(chan) {
+            None => return false,
+            Some(channel) => snapshot(self, channel)
+        };
+        {
+            let &PendingResyncs(ref mut pending) =
+                self.extensions.get_or_insert_with(|| PendingResyncs(Vec::new()));
+            pending.push(snapshot);
+        }
+        // the TOPIC reply comes last, so that's when the resync is done
+        self.send_command(IRCCmd("NAMES".into_maybe_owned()), [chan], false);
+        self.send_command(IRCCmd("MODE".into_maybe_owned()), [chan], false);
+        self.send_command(IRCCmd("TOPIC".into_maybe_owned()), [chan], false);
+        true
+    }
+}
+
+fn snapshot(conn: &Conn, channel: &Channel) -> Snapshot {
+    let casemapping = conn.isupport.casemapping();
+    Snapshot {
+        chan: channel.name().to_vec(),
+        members: channel.members().iter().map(|m| {
+            (casemapping.lower(m.nick()), m.nick().to_vec(), m.modes().to_vec())
+        }).collect(),
+        modes: channel.modes().to_vec(),
+        topic: channel.topic().map(|t| t.to_vec())
+    }
+}
+
+/// Finishes a resync once the topic of its channel arrives. The state has
+/// already been updated from the replies by then.
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    let chan = match line.command {
+        // :server 331 me #chan :No topic is set
+        // :server 332 me #chan :the topic
+        // :server 442 me #chan :You're not on that channel
+        IRCCode(331) | IRCCode(332) | IRCCode(442) if line.args.len() > 1 => line.args[1].as_slice(),
+        _ => return
+    };
+    let casemapping = conn.isupport.casemapping();
+    let before = match conn.extensions.get_mut::<PendingResyncs>() {
+        None => return,
+        Some(&PendingResyncs(ref mut pending)) => {
+            match pending.iter().position(|s| casemapping.eq_ignore_case(s.chan.as_slice(), chan)) {
+                None => return,
+                Some(i) => pending.remove(i).unwrap()
+            }
+        }
+    };
+    let resync = match conn.state.channel(chan) {
+        None => return,
+        Some(channel) => diff(&before, &snapshot(conn, channel))
+    };
+    conn.emit(ChannelResynced(before.chan, resync));
+}
+
+/// Compares the state of a channel before and after a resync
+fn diff(before: &Snapshot, after: &Snapshot) -> ChannelResync {
+    let mut resync = ChannelResync {
+        joined: Vec::new(),
+        left: Vec::new(),
+        member_modes: Vec::new(),
+        modes_changed: false,
+        topic_changed: before.topic != after.topic
+    };
+    for &(ref key, ref nick, ref modes) in after.members.iter() {
+        match member_modes(before.members.as_slice(), key.as_slice()) {
+            None => resync.joined.push(nick.clone()),
+            Some(old) if old != modes => resync.member_modes.push(nick.clone()),
+            Some(_) => ()
+        }
+    }
+    for &(ref key, ref nick, _) in before.members.iter() {
+        if member_modes(after.members.as_slice(), key.as_slice()).is_none() {
+            resync.left.push(nick.clone());
+        }
+    }
+    resync.modes_changed = before.modes.len() != after.modes.len() ||
+                           !before.modes.iter().all(|m| after.modes.contains(m));
+    resync
+}
+
+/// Returns the membership modes of the member with the lowercased nick `key`
+fn member_modes<'a>(members: &'a [(Vec<u8>, Vec<u8>, Vec<char>)], key: &[u8]) -> Option<&'a Vec<char>> {
+    members.iter().find(|&&(ref k, _, _)| k.as_slice() == key).map(|&(_, _, ref modes)| modes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Snapshot, diff};
+    use isupport::ISupport;
+
+    fn snapshot(members: &[(&str, &str)], topic: Option<&str>) -> Snapshot {
+        let casemapping = ISupport::new().casemapping();
+        Snapshot {
+            chan: b"#rust".to_vec(),
+            members: members.iter().map(|&(nick, modes)| {
+                (casemapping.lower(nick.as_bytes()), nick.as_bytes().to_vec(), modes.chars().collect())
+            }).collect(),
+            modes: vec![('n', None)],
+            topic: topic.map(|t| t.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = snapshot([("me", ""), ("Op", "o"), ("gone", "")], Some("topic"));
+        let after = snapshot([("me", ""), ("op", ""), ("New", "v")], Some("topic"));
+        let resync = diff(&before, &after);
+        assert_eq!(resync.joined, vec![b"New".to_vec()]);
+        assert_eq!(resync.left, vec![b"gone".to_vec()]);
+        assert_eq!(resync.member_modes, vec![b"op".to_vec()]);
+        assert!(!resync.topic_changed && !resync.modes_changed);
+        assert!(!resync.in_sync());
+        assert!(diff(&before, &before).in_sync());
+        assert!(diff(&before, &snapshot([("me", ""), ("Op", "o"), ("gone", "")], None)).topic_changed);
+    }
+}
@@ -0,0 +1,119 @@
+//! Routing lines to handlers registered for a channel
+
+use conn::{Conn, Line, IRCCmd, IRCAction, IRCCTCP, IRCCTCPReply};
+use isupport::ISupport;
+
+/// A handler for the lines about one channel. See `Conn::on_channel()`.
+pub trait ChannelHandler {
+    /// Handles a line about the channel the handler was registered for
+    fn handle(&mut self, conn: &mut Conn, line: &Line);
+}
+
+/// The registered handlers, by channel
+struct ChannelHandlers {
+    handlers: Vec<(Vec<u8>, Box<ChannelHandler + 'static>)>,
+    /// Channels whose handlers were removed while the handlers were running
+    removed: Vec<Vec<u8>>
+}
+
+impl ChannelHandlers {
+    fn new() -> ChannelHandlers {
+        ChannelHandlers { handlers: Vec::new(), removed: Vec::new() }
+    }
+}
+
+impl<'a> Conn<'a> {
+    /// Registers `handler` for the lines about `chan`: messages, notices and
+    /// CTCPs sent to it (including ones to `@#chan` and the like, see
+    /// STATUSMSG), and its JOINs, PARTs, KICKs, MODEs and TOPICs. Channel
+    /// names are compared using the server's casemapping.
+    ///
+    /// Handlers run before the line's LineReceived event, and regardless of
+    /// `Options.subscribe`. A channel can have several handlers, which run in
+    /// the order they were registered.
+    pub fn on_channel(&mut self, chan: &[u8], handler: Box<ChannelHandler + 'static>) {
+        let handlers = self.extensions.get_or_insert_with(|| ChannelHandlers::new());
+        handlers.handlers.push((chan.to_vec(), handler));
+    }
+
+    /// Removes the handlers registered for `chan`
+    pub fn remove_channel_handlers(&mut self, chan: &[u8]) {
+        let casemapping = self.isupport.casemapping();
+        let handlers = self.extensions.get_or_insert_with(|| ChannelHandlers::new());
+        handlers.handlers.retain(|&(ref c, _)| !casemapping.eq_ignore_case(c.as_slice(), chan));
+        handlers.removed.push(chan.to_vec());
+    }
+}
+
+/// Returns the channel a line is about, if any
+fn channel<'b>(isupport: &ISupport, line: &'b Line) -> Option<&'b [u8]> {
+    let target = match line.command {
+        IRCCmd(ref cmd) => match cmd.as_slice() {
+            "PRIVMSG" | "NOTICE" | "JOIN" | "PART" | "KICK" | "MODE" | "TOPIC" => {
+                match line.args.as_slice().head() {
+                    None => return None,
+                    Some(target) => target.as_slice()
+                }
+            }
+            _ => return None
+        },
+        IRCAction(ref dst) | IRCCTCP(_, ref dst) | IRCCTCPReply(_, ref dst) => dst.as_slice(),
+        _ => return None
+    };
+    // PRIVMSG @#chan goes to the channel's operators
+    let statusmsg = isupport.get("STATUSMSG").unwrap_or(b"");
+    let start = target.iter().position(|b| !statusmsg.contains(b)).unwrap_or(target.len());
+    let target = target.slice_from(start);
+    if isupport.is_channel(target) { Some(target) } else { None }
+}
+
+/// Runs the handlers registered for the channel `line` is about
+pub fn dispatch(conn: &mut Conn, line: &Line) {
+    let chan = match channel(&conn.isupport, line) {
+        None => return,
+        Some(chan) => chan
+    };
+    // the handlers are taken out while they run, since they get the connection
+    let mut handlers = match conn.extensions.remove::<ChannelHandlers>() {
+        None => return,
+        Some(ChannelHandlers { handlers, .. }) => handlers
+    };
+    let casemapping = conn.isupport.casemapping();
+    for &(ref c, ref mut handler) in handlers.iter_mut() {
+        if casemapping.eq_ignore_case(c.as_slice(), chan) {
+            handler.handle(conn, line);
+        }
+    }
+    // apply what the handlers changed
+    match conn.extensions.remove::<ChannelHandlers>() {
+        None => (),
+        Some(ChannelHandlers { handlers: added, removed }) => {
+            handlers.retain(|&(ref c, _)| {
+                !removed.iter().any(|r| casemapping.eq_ignore_case(c.as_slice(), r.as_slice()))
+            });
+            handlers.extend(added.into_iter());
+        }
+    }
+    conn.extensions.insert(ChannelHandlers { handlers: handlers, removed: Vec::new() });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::channel;
+    use conn::Line;
+    use isupport::ISupport;
+
+    #[test]
+    fn test_channel() {
+        let mut isupport = ISupport::new();
+        isupport.add_tokens([b"me".to_vec(), b"STATUSMSG=@+".to_vec(), b"are supported".to_vec()]);
+        let c = |raw: &[u8]| channel(&isupport, &Line::parse(raw).unwrap()).map(|c| c.to_vec());
+        assert_eq!(c(b":nick!u@h PRIVMSG #rust :hi"), Some(b"#rust".to_vec()));
+        assert_eq!(c(b":nick!u@h NOTICE @#rust :ops only"), Some(b"#rust".to_vec()));
+        assert_eq!(c(b":nick!u@h PRIVMSG #rust :\x01ACTION waves\x01"), Some(b"#rust".to_vec()));
+        assert_eq!(c(b":nick!u@h KICK #rust other :bye"), Some(b"#rust".to_vec()));
+        assert_eq!(c(b":nick!u@h PRIVMSG me :hi"), None);
+        assert_eq!(c(b":nick!u@h QUIT :bye"), None);
+        assert_eq!(c(b":server 332 me #rust :topic"), None);
+    }
+}
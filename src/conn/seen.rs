@@ -0,0 +1,286 @@
+//! Remembering when each nick was last seen and what they were doing
+
+use std::collections::HashMap;
+use std::mem;
+use std::str;
+use std::time::Duration;
+use time;
+
+use conn::{Conn, Line, IRCCmd, IRCAction};
+use conn::persist;
+
+/// What a nick was doing when they were last seen
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum SeenAction {
+    /// Joining the channel
+    SeenJoin(Vec<u8>),
+    /// Leaving the channel, with the part message
+    SeenPart(Vec<u8>, Vec<u8>),
+    /// Quitting, with the quit message
+    SeenQuit(Vec<u8>),
+    /// Sending a message to the channel
+    SeenMessage(Vec<u8>, Vec<u8>),
+    /// Sending a CTCP ACTION (`/me`) to the channel
+    SeenEmote(Vec<u8>, Vec<u8>),
+    /// Changing to the new nick
+    SeenNickChange(Vec<u8>)
+}
+
+/// When a nick was last seen, and what they were doing
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct LastSeen {
+    /// The nick, as it was last seen
+    pub nick: Vec<u8>,
+    /// When they were seen, in seconds since the epoch
+    pub time: i64,
+    /// What they were doing
+    pub action: SeenAction
+}
+
+/// How long changes are collected before the seen list is saved, in seconds
+static SAVE_DELAY_S: i64 = 60;
+
+/// How many nicks are remembered. The ones seen longest ago are forgotten first.
+static MAX_SEEN: uint = 10000;
+
+struct SeenList {
+    /// The last sighting of each nick, by casemapped nick
+    nicks: HashMap<Vec<u8>, LastSeen>,
+    save_scheduled: bool
+}
+
+impl<'a> Conn<'a> {
+    /// Returns when `nick` was last seen joining, parting, quitting, changing
+    /// nick or talking, in any channel we're in. Returns None if they haven't
+    /// been seen, or if `Options.track_seen` isn't set.
+    pub fn last_seen(&self, nick: &[u8]) -> Option<LastSeen> {
+        let nick = self.isupport.casemapping().lower(nick);
+        self.extensions.get::<SeenList>().and_then(|s| s.nicks.get(&nick).map(|l| l.clone()))
+    }
+}
+
+/// Starts tracking, for `Options.track_seen`
+pub fn enable(conn: &mut Conn) {
+    conn.extensions.insert(SeenList { nicks: HashMap::new(), save_scheduled: false });
+}
+
+/// Forgets that a save was scheduled on the last connection. The sightings
+/// are kept.
+pub fn reset(conn: &mut Conn) {
+    conn.extensions.get_mut::<SeenList>().map(|l| l.save_scheduled = false);
+}
+
+/// Keys the sightings by the new casemapping
+pub fn rekey(conn: &mut Conn) {
+    let casemapping = conn.isupport.casemapping();
+    match conn.extensions.get_mut::<SeenList>() {
+        None => (),
+        Some(seen) => {
+            let nicks = mem::replace(&mut seen.nicks, HashMap::new());
+            seen.nicks = nicks.into_iter().map(|(_, l)| (casemapping.lower(l.nick.as_slice()), l)).collect();
+        }
+    }
+}
+
+/// Returns `true` if tracking is on
+pub fn is_enabled(conn: &Conn) -> bool {
+    conn.extensions.get::<SeenList>().is_some()
+}
+
+/// Returns every sighting, for saving
+pub fn entries(conn: &Conn) -> Vec<LastSeen> {
+    conn.extensions.get::<SeenList>().map_or(Vec::new(), |s| s.nicks.values().map(|l| l.clone()).collect())
+}
+
+/// Restores sightings loaded from the store
+pub fn restore(conn: &mut Conn, entries: Vec<LastSeen>) {
+    let casemapping = conn.isupport.casemapping();
+    match conn.extensions.get_mut::<SeenList>() {
+        None => (),
+        Some(seen) => {
+            for entry in entries.into_iter() {
+                seen.nicks.insert(casemapping.lower(entry.nick.as_slice()), entry);
+            }
+            trim(&mut seen.nicks);
+        }
+    }
+}
+
+/// Forgets the nicks seen longest ago, until at most MAX_SEEN are left
+fn trim(nicks: &mut HashMap<Vec<u8>, LastSeen>) {
+    while nicks.len() > MAX_SEEN {
+        let oldest = match nicks.iter().min_by(|&(_, l)| l.time) {
+            None => return,
+            Some((key, _)) => key.clone()
+        };
+        nicks.remove(&oldest);
+    }
+}
+
+/// Records the sighting in a line, if it is one
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    if !is_enabled(conn) {
+        return;
+    }
+    let nick = match line.prefix {
+        None => return,
+        Some(ref user) => user.nick().to_vec()
+    };
+    let arg = |i: uint| if i < line.args.len() { line.args[i].clone() } else { Vec::new() };
+    // private messages to us aren't anyone's business
+    let action = match line.command {
+        IRCCmd(ref s) if "JOIN" == s.as_slice() => SeenJoin(arg(0)),
+        IRCCmd(ref s) if "PART" == s.as_slice() => SeenPart(arg(0), arg(1)),
+        IRCCmd(ref s) if "QUIT" == s.as_slice() => SeenQuit(arg(0)),
+        IRCCmd(ref s) if "PRIVMSG" == s.as_slice() && conn.isupport.is_channel(arg(0).as_slice()) => {
+            SeenMessage(arg(0), arg(1))
+        }
+        IRCCmd(ref s) if "NICK" == s.as_slice() => SeenNickChange(arg(0)),
+        IRCAction(ref dst) if conn.isupport.is_channel(dst.as_slice()) => SeenEmote(dst.clone(), arg(0)),
+        _ => return
+    };
+    let seen = LastSeen { nick: nick, time: time::get_time().sec, action: action };
+    let key = conn.isupport.casemapping().lower(seen.nick.as_slice());
+    let schedule = {
+        let list = conn.extensions.get_mut::<SeenList>().unwrap();
+        list.nicks.insert(key, seen);
+        trim(&mut list.nicks);
+        !list.save_scheduled
+    };
+    if schedule && conn.store.is_some() {
+        conn.extensions.get_mut::<SeenList>().unwrap().save_scheduled = true;
+        conn.schedule(Duration::seconds(SAVE_DELAY_S), proc(conn) {
+            conn.extensions.get_mut::<SeenList>().map(|l| l.save_scheduled = false);
+            persist::seen_changed(conn);
+        });
+    }
+}
+
+/// Encodes a sighting as a single line for the store:
+/// `time nick KIND target text`, with only the fields the kind has
+pub fn encode(seen: &LastSeen) -> Vec<u8> {
+    let mut line = seen.time.to_string().into_bytes();
+    {
+        let push = |v: &[u8]| {
+            line.push(' ' as u8);
+            line.push_all(v);
+        };
+        push(seen.nick.as_slice());
+        match seen.action {
+            SeenJoin(ref chan) => { push(b"JOIN"); push(chan.as_slice()); }
+            SeenPart(ref chan, ref msg) => { push(b"PART"); push(chan.as_slice()); push(msg.as_slice()); }
+            SeenQuit(ref msg) => { push(b"QUIT"); push(msg.as_slice()); }
+            SeenMessage(ref dst, ref text) => { push(b"PRIVMSG"); push(dst.as_slice()); push(text.as_slice()); }
+            SeenEmote(ref dst, ref text) => { push(b"ACTION"); push(dst.as_slice()); push(text.as_slice()); }
+            SeenNickChange(ref nick) => { push(b"NICK"); push(nick.as_slice()); }
+        }
+    }
+    line
+}
+
+/// Decodes a line written by `encode()`
+pub fn decode(line: &[u8]) -> Option<LastSeen> {
+    let mut words = line.splitn(2, |&b| b == ' ' as u8);
+    let (time, nick, rest) = match (words.next(), words.next(), words.next()) {
+        (Some(time), Some(nick), Some(rest)) => (time, nick, rest),
+        _ => return None
+    };
+    let time = match str::from_utf8(time).and_then(|t| from_str(t)) {
+        None => return None,
+        Some(time) => time
+    };
+    let (kind, rest) = split(rest);
+    let (target, text) = split(rest);
+    let action = if kind == b"JOIN" {
+        SeenJoin(rest.to_vec())
+    } else if kind == b"QUIT" {
+        SeenQuit(rest.to_vec())
+    } else if kind == b"NICK" {
+        SeenNickChange(rest.to_vec())
+    } else if kind == b"PART" {
+        SeenPart(target.to_vec(), text.to_vec())
+    } else if kind == b"PRIVMSG" {
+        SeenMessage(target.to_vec(), text.to_vec())
+    } else if kind == b"ACTION" {
+        SeenEmote(target.to_vec(), text.to_vec())
+    } else {
+        return None
+    };
+    Some(LastSeen { nick: nick.to_vec(), time: time, action: action })
+}
+
+fn split<'a>(v: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+    match v.position_elem(&(' ' as u8)) {
+        None => (v, [].as_slice()),
+        Some(idx) => (v.slice_to(idx), v.slice_from(idx+1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoResult;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue};
+    use store::StateStore;
+    use super::{encode, decode, LastSeen, SeenJoin, SeenPart, SeenQuit, SeenMessage, SeenEmote};
+
+    /// Counts how often the seen list is saved
+    struct Saves(Arc<Mutex<uint>>);
+
+    impl StateStore for Saves {
+        fn load(&self, _: &str) -> IoResult<Option<Vec<u8>>> {
+            Ok(None)
+        }
+
+        fn save(&mut self, key: &str, _: &[u8]) -> IoResult<()> {
+            let &Saves(ref count) = self;
+            if key == "seen" {
+                *count.lock() += 1;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn seen() {
+        let saves = Arc::new(Mutex::new(0u));
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.track_seen = true;
+        opts.store = Some(box Saves(saves.clone()));
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":alice!a@h PRIVMSG #rust :hi all",
+                   b":bob!b@h PRIVMSG ircnick :psst"], Duration::zero());
+        assert_eq!(conn.conn().last_seen(b"Alice").map(|l| l.action),
+                   Some(SeenMessage(b"#rust".to_vec(), b"hi all".to_vec())));
+        assert_eq!(conn.conn().last_seen(b"bob"), None);
+        // the connection is lost before the save
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":carol!c@h JOIN #rust"], Duration::zero());
+        assert_eq!(*saves.lock(), 0);
+        conn.step([], Duration::seconds(60));
+        assert_eq!(*saves.lock(), 1);
+        assert!(conn.conn().last_seen(b"alice").is_some());
+    }
+
+    #[test]
+    fn test_encode() {
+        let seen = [
+            LastSeen { nick: b"nick".to_vec(), time: 1400000000, action: SeenJoin(b"#chan".to_vec()) },
+            LastSeen { nick: b"nick".to_vec(), time: 5, action: SeenPart(b"#chan".to_vec(), b"bye now".to_vec()) },
+            LastSeen { nick: b"nick".to_vec(), time: 5, action: SeenPart(b"#chan".to_vec(), Vec::new()) },
+            LastSeen { nick: b"nick".to_vec(), time: 5, action: SeenQuit(b"Ping timeout".to_vec()) },
+            LastSeen { nick: b"nick".to_vec(), time: 5, action: SeenMessage(b"#chan".to_vec(), b"hi all".to_vec()) },
+            LastSeen { nick: b"nick".to_vec(), time: 5, action: SeenEmote(b"#chan".to_vec(), b"waves".to_vec()) }
+        ];
+        assert_eq!(encode(&seen[1]), b"5 nick PART #chan bye now".to_vec());
+        for s in seen.iter() {
+            assert_eq!(decode(encode(s).as_slice()).as_ref(), Some(s));
+        }
+        assert_eq!(decode(b"x nick JOIN #chan"), None);
+        assert_eq!(decode(b"5 nick KICK #chan"), None);
+    }
+}
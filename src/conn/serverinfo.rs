@@ -0,0 +1,517 @@
+//! Queries about a server: VERSION, TIME, ADMIN, INFO and LUSERS
+
+use std::default::Default;
+use std::from_str::from_str;
+use std::str;
+
+use conn::{Conn, Line, IRCCmd, IRCCode};
+use isupport;
+
+/// A server's reply to VERSION (RPL_VERSION, 351)
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ServerVersion {
+    /// The server software and version, e.g. `charybdis-4.1.2.`
+    pub version: Vec<u8>,
+    /// The name of the server
+    pub server: Vec<u8>,
+    /// Free-form comments, often the compile options
+    pub comments: Vec<u8>
+}
+
+/// A server's reply to TIME (RPL_TIME, 391)
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ServerTime {
+    /// The name of the server
+    pub server: Vec<u8>,
+    /// The server's local time, in whatever format it chose
+    pub time: Vec<u8>,
+    /// The time in seconds since the epoch, if the server sent it too
+    pub timestamp: Option<i64>
+}
+
+/// A server's reply to ADMIN (RPL_ADMINME 256 to RPL_ADMINEMAIL 259)
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct AdminInfo {
+    /// The name of the server
+    pub server: Vec<u8>,
+    /// Usually the server's location
+    pub location1: Option<Vec<u8>>,
+    /// Usually the institution running the server
+    pub location2: Option<Vec<u8>>,
+    /// Who to contact about the server
+    pub email: Option<Vec<u8>>
+}
+
+/// The size of the network, from the LUSERS replies (251 to 255, 265
+/// and 266). Servers leave out the numbers they don't track.
+#[deriving(Clone, PartialEq, Eq, Show, Default)]
+pub struct NetworkStats {
+    /// The number of visible users on the network
+    pub users: Option<uint>,
+    /// The number of invisible users (+i) on the network
+    pub invisible: Option<uint>,
+    /// The number of servers on the network
+    pub servers: Option<uint>,
+    /// The number of IRC operators online
+    pub opers: Option<uint>,
+    /// The number of connections that haven't registered yet
+    pub unknown: Option<uint>,
+    /// The number of channels
+    pub channels: Option<uint>,
+    /// The number of clients on our server
+    pub local_clients: Option<uint>,
+    /// The number of servers linked to our server
+    pub local_servers: Option<uint>,
+    /// The number of users on our server
+    pub local_users: Option<uint>,
+    /// The most users our server has had at once
+    pub local_max: Option<uint>,
+    /// The number of users on the network
+    pub global_users: Option<uint>,
+    /// The most users the network has had at once
+    pub global_max: Option<uint>
+}
+
+/// Callback for the result of `Conn::server_version()`.
+/// It's given None if the server doesn't exist.
+pub type VersionCallback = proc(&mut Conn, Option<ServerVersion>);
+/// Callback for the result of `Conn::server_time()`.
+/// It's given None if the server doesn't exist.
+pub type TimeCallback = proc(&mut Conn, Option<ServerTime>);
+/// Callback for the result of `Conn::admin_info()`.
+/// It's given None if the server doesn't exist or has no admin info.
+pub type AdminCallback = proc(&mut Conn, Option<AdminInfo>);
+/// Callback for the result of `Conn::server_info()`.
+/// It's given the lines of the reply, or None if the server doesn't exist.
+pub type InfoCallback = proc(&mut Conn, Option<Vec<Vec<u8>>>);
+/// Callback for the result of `Conn::lusers()`.
+pub type LusersCallback = proc(&mut Conn, NetworkStats);
+
+/// A query waiting for its reply
+enum Query {
+    VersionQuery(VersionCallback),
+    TimeQuery(TimeCallback),
+    AdminQuery(AdminCallback, AdminInfo),
+    InfoQuery(InfoCallback, Vec<Vec<u8>>),
+    LusersQuery(LusersCallback)
+}
+
+/// A query that was sent, and the server it was sent to if it wasn't ours
+struct Sent {
+    server: Option<Vec<u8>>,
+    query: Query
+}
+
+/// The queries that were sent, oldest first. Servers answer them in order,
+/// but some don't answer at all, so a reply goes to the oldest query of its
+/// kind.
+struct Pending(Vec<Sent>);
+
+/// The network statistics from the last LUSERS replies
+struct Lusers {
+    stats: NetworkStats,
+    /// Whether the server sends RPL_GLOBALUSERS (266), which ends its
+    /// replies. Otherwise RPL_LUSERME (255) does.
+    sends_global: bool
+}
+
+impl<'a> Conn<'a> {
+    /// Asks `server` (or the one we're connected to, if None) for its
+    /// version with VERSION, and calls `cb` with the reply
+    pub fn server_version(&mut self, server: Option<&[u8]>, cb: VersionCallback) {
+        self.server_query("VERSION", server, VersionQuery(cb));
+    }
+
+    /// Asks `server` (or the one we're connected to, if None) for its local
+    /// time with TIME, and calls `cb` with the reply
+    pub fn server_time(&mut self, server: Option<&[u8]>, cb: TimeCallback) {
+        self.server_query("TIME", server, TimeQuery(cb));
+    }
+
+    /// Asks `server` (or the one we're connected to, if None) who runs it
+    /// with ADMIN, and calls `cb` with the reply
+    pub fn admin_info(&mut self, server: Option<&[u8]>, cb: AdminCallback) {
+        let info = AdminInfo { server: Vec::new(), location1: None, location2: None, email: None };
+        self.server_query("ADMIN", server, AdminQuery(cb, info));
+    }
+
+    /// Asks `server` (or the one we're connected to, if None) for its INFO
+    /// text, usually the authors and history of the server software, and
+    /// calls `cb` with its lines
+    pub fn server_info(&mut self, server: Option<&[u8]>, cb: InfoCallback) {
+        self.server_query("INFO", server, InfoQuery(cb, Vec::new()));
+    }
+
+    /// Returns the network statistics the server sent when we connected, or
+    /// in reply to the last `lusers()`. None if it hasn't sent any.
+    pub fn network_stats(&self) -> Option<NetworkStats> {
+        self.extensions.get::<Lusers>().map(|l| l.stats.clone())
+    }
+
+    /// Asks the server for up to date network statistics with LUSERS, and
+    /// calls `cb` with them. `network_stats()` is updated as well.
+    pub fn lusers(&mut self, cb: LusersCallback) {
+        self.server_query("LUSERS", None, LusersQuery(cb));
+    }
+
+    /// Sends a query, and queues its callback for the reply. Replies are
+    /// matched to queries in the order they were sent, so don't send these
+    /// commands by hand while a query is outstanding.
+    fn server_query(&mut self, cmd: &'static str, server: Option<&[u8]>, query: Query) {
+        match server {
+            None => self.send_command(IRCCmd(cmd.into_maybe_owned()), [], false),
+            Some(server) => self.send_command(IRCCmd(cmd.into_maybe_owned()), [server], false)
+        }
+        let &Pending(ref mut queries) = self.extensions.get_or_insert_with(|| Pending(Vec::new()));
+        queries.push(Sent { server: server.map(|s| s.to_vec()), query: query });
+    }
+}
+
+/// Fails the queries of the last connection, whose replies won't come
+pub fn reset(conn: &mut Conn) {
+    let queries = match conn.extensions.remove::<Pending>() {
+        None => return,
+        Some(Pending(queries)) => queries
+    };
+    for sent in queries.into_iter() {
+        fail(conn, sent.query);
+    }
+}
+
+/// Removes the oldest query that `is_for` says the reply is for
+fn pop_query(conn: &mut Conn, is_for: |&Sent| -> bool) -> Option<Query> {
+    let queries = match conn.extensions.get_mut::<Pending>() {
+        None => return None,
+        Some(&Pending(ref mut queries)) => queries
+    };
+    match queries.iter().position(|q| is_for(q)) {
+        None => None,
+        Some(idx) => queries.remove(idx).map(|sent| sent.query)
+    }
+}
+
+/// Calls the callback of a query that failed
+fn fail(conn: &mut Conn, query: Query) {
+    match query {
+        VersionQuery(cb) => cb(conn, None),
+        TimeQuery(cb) => cb(conn, None),
+        AdminQuery(cb, _) => cb(conn, None),
+        InfoQuery(cb, _) => cb(conn, None),
+        LusersQuery(cb) => {
+            let stats = conn.network_stats().unwrap_or_default();
+            cb(conn, stats)
+        }
+    }
+}
+
+/// Returns the last argument of a reply, the one after the colon
+fn text(line: &Line) -> Vec<u8> {
+    line.args.last().map_or(Vec::new(), |t| t.clone())
+}
+
+pub fn RPL_VERSION(conn: &mut Conn, line: &Line) {
+    // :server 351 me version server :comments
+    if line.args.len() < 3 {
+        return;
+    }
+    match pop_query(conn, |q| match q.query { VersionQuery(..) => true, _ => false }) {
+        Some(VersionQuery(cb)) => {
+            cb(conn, Some(ServerVersion {
+                version: line.args[1].clone(),
+                server: line.args[2].clone(),
+                comments: if line.args.len() > 3 { text(line) } else { Vec::new() }
+            }));
+        }
+        _ => ()
+    }
+}
+
+pub fn RPL_TIME(conn: &mut Conn, line: &Line) {
+    // :server 391 me server :Tuesday June 3 2014 -- 12:00:00 +00:00
+    // some servers send the timestamp too:
+    // :server 391 me server 1401796800 0 :Tuesday June 3 2014 -- 12:00:00 +00:00
+    if line.args.len() < 3 {
+        return;
+    }
+    let timestamp = if line.args.len() > 3 {
+        str::from_utf8(line.args[2].as_slice()).and_then(|t| from_str::<i64>(t))
+    } else {
+        None
+    };
+    match pop_query(conn, |q| match q.query { TimeQuery(..) => true, _ => false }) {
+        Some(TimeQuery(cb)) => {
+            cb(conn, Some(ServerTime { server: line.args[1].clone(), time: text(line), timestamp: timestamp }));
+        }
+        _ => ()
+    }
+}
+
+/// Handles RPL_ADMINME (256), RPL_ADMINLOC1 (257), RPL_ADMINLOC2 (258)
+/// and RPL_ADMINEMAIL (259), which ends the reply
+pub fn admin_reply(conn: &mut Conn, line: &Line) {
+    // :server 256 me server :Administrative info
+    // :server 257 me :Location
+    if line.args.len() < 2 {
+        return;
+    }
+    match conn.extensions.get_mut::<Pending>() {
+        Some(&Pending(ref mut queries)) => match queries.iter_mut().find(|q| is_admin(&q.query)) {
+            Some(&Sent { query: AdminQuery(_, ref mut info), .. }) => {
+                match line.command {
+                    IRCCode(256) if line.args.len() > 2 => info.server = line.args[1].clone(),
+                    IRCCode(257) => info.location1 = Some(text(line)),
+                    IRCCode(258) => info.location2 = Some(text(line)),
+                    IRCCode(259) => info.email = Some(text(line)),
+                    _ => ()
+                }
+            }
+            _ => return
+        },
+        None => return
+    }
+    match line.command {
+        IRCCode(259) => match pop_query(conn, |q| is_admin(&q.query)) {
+            Some(AdminQuery(cb, info)) => cb(conn, Some(info)),
+            _ => ()
+        },
+        _ => ()
+    }
+}
+
+pub fn RPL_INFO(conn: &mut Conn, line: &Line) {
+    // :server 371 me :text
+    match conn.extensions.get_mut::<Pending>() {
+        Some(&Pending(ref mut queries)) => match queries.iter_mut().find(|q| is_info(&q.query)) {
+            Some(&Sent { query: InfoQuery(_, ref mut lines), .. }) => lines.push(text(line)),
+            _ => ()
+        },
+        None => ()
+    }
+}
+
+pub fn RPL_ENDOFINFO(conn: &mut Conn, _line: &Line) {
+    match pop_query(conn, |q| is_info(&q.query)) {
+        Some(InfoQuery(cb, lines)) => cb(conn, Some(lines)),
+        _ => ()
+    }
+}
+
+fn is_admin(query: &Query) -> bool {
+    match *query { AdminQuery(..) => true, _ => false }
+}
+
+fn is_info(query: &Query) -> bool {
+    match *query { InfoQuery(..) => true, _ => false }
+}
+
+/// Handles ERR_NOSUCHSERVER (402) and ERR_NOADMININFO (423), which fail
+/// the oldest query sent to the server they name. Other commands get 402
+/// too, so nothing fails if no query was sent to that server.
+pub fn query_failed(conn: &mut Conn, line: &Line) {
+    // :server 402 me <server> :No such server
+    // :server 423 me <server> :No administrative info available
+    if line.args.len() < 2 {
+        return;
+    }
+    let admin_only = match line.command { IRCCode(423) => true, _ => false };
+    let server = line.args[1].as_slice();
+    // queries without a server went to ours
+    let ours = conn.server_name().map(|s| s.to_vec());
+    let query = pop_query(conn, |q| {
+        let queried = q.server.as_ref().or(ours.as_ref());
+        let matches = queried.map_or(false, |s| isupport::CaseAscii.eq_ignore_case(s.as_slice(), server));
+        matches && (!admin_only || is_admin(&q.query))
+    });
+    match query {
+        None => (),
+        Some(query) => fail(conn, query)
+    }
+}
+
+/// Handles the LUSERS replies, which servers also send right after
+/// registering us
+pub fn lusers_reply(conn: &mut Conn, line: &Line) {
+    let nums = numbers(line);
+    let num = |i: uint| nums.as_slice().get(i).map(|&n| n);
+    let done = {
+        let lusers = conn.extensions.get_or_insert_with(|| {
+            Lusers { stats: Default::default(), sends_global: false }
+        });
+        let stats = &mut lusers.stats;
+        match line.command {
+            // :server 251 me :There are 5 users and 10 invisible on 3 servers
+            IRCCode(251) => {
+                // a new set of replies
+                *stats = Default::default();
+                stats.users = num(0);
+                stats.invisible = num(1);
+                stats.servers = num(2);
+            }
+            // :server 252 me 2 :IRC Operators online
+            IRCCode(252) => stats.opers = num(0),
+            IRCCode(253) => stats.unknown = num(0),
+            IRCCode(254) => stats.channels = num(0),
+            // :server 255 me :I have 7 clients and 1 servers
+            IRCCode(255) => {
+                stats.local_clients = num(0);
+                stats.local_servers = num(1);
+            }
+            // :server 265 me 7 12 :Current local users 7, max 12
+            IRCCode(265) => {
+                stats.local_users = num(0);
+                stats.local_max = num(1);
+            }
+            IRCCode(266) => {
+                stats.global_users = num(0);
+                stats.global_max = num(1);
+                lusers.sends_global = true;
+            }
+            _ => ()
+        }
+        match line.command {
+            IRCCode(255) => !lusers.sends_global,
+            IRCCode(266) => true,
+            _ => false
+        }
+    };
+    if !done {
+        return;
+    }
+    match pop_query(conn, |q| match q.query { LusersQuery(..) => true, _ => false }) {
+        Some(LusersQuery(cb)) => {
+            let stats = conn.network_stats().unwrap_or_default();
+            cb(conn, stats)
+        }
+        _ => ()
+    }
+}
+
+/// Returns the numbers in the arguments of a reply, leaving out our nick.
+/// Numbers inside the text (`:There are 5 users...`) count too.
+fn numbers(line: &Line) -> Vec<uint> {
+    let mut nums = Vec::new();
+    for arg in line.args.iter().skip(1) {
+        for word in arg.as_slice().split(|&b| b == ' ' as u8) {
+            let word = word.slice_to(word.iter().position(|&b| b == ',' as u8).unwrap_or(word.len()));
+            match str::from_utf8(word).and_then(|w| from_str::<uint>(w)) {
+                None => (),
+                Some(n) => nums.push(n)
+            }
+        }
+    }
+    nums
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use super::{numbers, ServerVersion, ServerTime, AdminInfo};
+    use conn::{Line, Manual, Options, Event, Continue};
+
+    #[test]
+    fn queries() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let versions = Arc::new(Mutex::new(Vec::new()));
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let admins = Arc::new(Mutex::new(Vec::new()));
+        // never answered
+        let t = times.clone();
+        conn.conn().server_time(None, proc(_, time) { t.lock().push(time.map(|t| t.time)) });
+        let v = versions.clone();
+        conn.conn().server_version(Some(b"hub.example.net"), proc(_, v2) { v.lock().push(v2.map(|v| v.version)) });
+        let v = versions.clone();
+        conn.conn().server_version(None, proc(_, v2) { v.lock().push(v2.map(|v| v.version)) });
+        assert_eq!(conn.step([b":irc.example.net 402 ircnick nowhere.example.net :No such server",
+                              b":irc.example.net 402 ircnick HUB.example.net :No such server",
+                              b":irc.example.net 351 ircnick charybdis-4.1.2. irc.example.net :TS6ow"],
+                             Duration::zero()),
+                   vec![b"TIME".to_vec(), b"VERSION hub.example.net".to_vec(), b"VERSION".to_vec()]);
+        assert_eq!(*versions.lock(), vec![None, Some(b"charybdis-4.1.2.".to_vec())]);
+        assert!(times.lock().is_empty());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        assert_eq!(*times.lock(), vec![None]);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let a = admins.clone();
+        conn.conn().admin_info(None, proc(_, info) { a.lock().push(info.map(|i| i.server)) });
+        let a = admins.clone();
+        conn.conn().admin_info(None, proc(_, info) { a.lock().push(info.map(|i| i.server)) });
+        conn.step([b":irc.example.net 423 ircnick irc.example.net :No administrative info available",
+                   b":irc.example.net 256 ircnick irc.example.net :Administrative info",
+                   b":irc.example.net 257 ircnick :Somewhere",
+                   b":irc.example.net 259 ircnick :admin@example.net"], Duration::zero());
+        assert_eq!(*admins.lock(), vec![None, Some(b"irc.example.net".to_vec())]);
+    }
+
+    #[test]
+    fn replies() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        let versions = Arc::new(Mutex::new(Vec::new()));
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let v = versions.clone();
+        conn.conn().server_version(None, proc(_, version) { v.lock().push(version) });
+        let v = versions.clone();
+        conn.conn().server_version(None, proc(_, version) { v.lock().push(version) });
+        let t = times.clone();
+        conn.conn().server_time(None, proc(_, time) { t.lock().push(time) });
+        let t = times.clone();
+        conn.conn().server_time(None, proc(_, time) { t.lock().push(time) });
+        conn.step([b":irc.example.net 351 ircnick charybdis-4.1.2. irc.example.net :TS6ow",
+                   b":irc.example.net 351 ircnick ircd-2.11 irc.example.net",
+                   b":irc.example.net 391 ircnick irc.example.net :Tuesday June 3 2014 -- 12:00:00 +00:00",
+                   b":irc.example.net 391 ircnick irc.example.net 1401796800 0 :Tuesday June 3 2014 -- 12:00:00 +00:00"],
+                  Duration::zero());
+        assert_eq!(*versions.lock(), vec![
+            Some(ServerVersion { version: b"charybdis-4.1.2.".to_vec(), server: b"irc.example.net".to_vec(),
+                                 comments: b"TS6ow".to_vec() }),
+            Some(ServerVersion { version: b"ircd-2.11".to_vec(), server: b"irc.example.net".to_vec(),
+                                 comments: Vec::new() })]);
+        let time = b"Tuesday June 3 2014 -- 12:00:00 +00:00".to_vec();
+        assert_eq!(*times.lock(), vec![
+            Some(ServerTime { server: b"irc.example.net".to_vec(), time: time.clone(), timestamp: None }),
+            Some(ServerTime { server: b"irc.example.net".to_vec(), time: time, timestamp: Some(1401796800) })]);
+
+        let admins = Arc::new(Mutex::new(Vec::new()));
+        let infos = Arc::new(Mutex::new(Vec::new()));
+        let a = admins.clone();
+        conn.conn().admin_info(None, proc(_, info) { a.lock().push(info) });
+        let i = infos.clone();
+        conn.conn().server_info(None, proc(_, lines) { i.lock().push(lines) });
+        // the replies can come in between each other's lines
+        conn.step([b":irc.example.net 256 ircnick irc.example.net :Administrative info",
+                   b":irc.example.net 371 ircnick :charybdis-4.1.2.",
+                   b":irc.example.net 257 ircnick :Somewhere",
+                   b":irc.example.net 258 ircnick :Example Network",
+                   b":irc.example.net 371 ircnick :Based on the original code written by Jarkko Oikarinen",
+                   b":irc.example.net 259 ircnick :admin@example.net",
+                   b":irc.example.net 374 ircnick :End of /INFO list."], Duration::zero());
+        assert_eq!(*admins.lock(), vec![Some(AdminInfo {
+            server: b"irc.example.net".to_vec(),
+            location1: Some(b"Somewhere".to_vec()),
+            location2: Some(b"Example Network".to_vec()),
+            email: Some(b"admin@example.net".to_vec())
+        })]);
+        assert_eq!(*infos.lock(), vec![Some(vec![b"charybdis-4.1.2.".to_vec(),
+                                                 b"Based on the original code written by Jarkko Oikarinen".to_vec()])]);
+        // replies nobody asked for are ignored
+        conn.step([b":irc.example.net 351 ircnick ircd-2.11 irc.example.net",
+                   b":irc.example.net 371 ircnick :stray",
+                   b":irc.example.net 374 ircnick :End of /INFO list."], Duration::zero());
+        assert_eq!(versions.lock().len(), 2);
+        assert_eq!(infos.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_numbers() {
+        let n = |raw: &[u8]| numbers(&Line::parse(raw).unwrap());
+        assert_eq!(n(b":server 251 me :There are 5 users and 10 invisible on 3 servers"), vec![5, 10, 3]);
+        assert_eq!(n(b":server 252 me 2 :IRC Operators online"), vec![2]);
+        assert_eq!(n(b":server 265 me 7 12 :Current local users 7, max 12"), vec![7, 12, 7, 12]);
+        assert_eq!(n(b":server 266 me :Current global users: 70  Max: 120"), vec![70, 120]);
+    }
+}
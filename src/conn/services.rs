@@ -0,0 +1,505 @@
+//! Helpers for talking to network services
+
+use std::collections::RingBuf;
+use std::from_str::from_str;
+use std::time::Duration;
+
+use conn::{Conn, Line, IRCCmd, ChanServDone, ChanServFailed};
+use modes;
+use quirks::{ImplicitSelf, ExplicitSelf};
+
+/// How many times a message that a service refused as too fast is sent again
+static MAX_RETRIES: uint = 3;
+
+/// How long to wait after a service says we're too fast, if it doesn't say
+static SLOW_DOWN_SECS: i64 = 10;
+
+/// How long after sending a message a complaint about it is expected, in nanoseconds
+static COMPLAINT_WINDOW_NS: u64 = 30 * 1_000_000_000;
+
+/// What services say when they ignore us for sending too fast, lowercased.
+/// Asking us to wait a number of seconds counts too, see `too_fast()`.
+static TOO_FAST: &'static [&'static str] = &["too fast", "too quickly", "rate limit", "command limit"];
+
+/// How long to wait for the outcome of a ChanServ request, in nanoseconds
+static CHANSERV_EXPIRY_NS: u64 = 60 * 1_000_000_000;
+
+/// What ChanServ says when it refuses a request, lowercased
+static REFUSED: &'static [&'static str] = &["not authorized", "access denied", "permission denied",
+                                             "is not registered", "isn't registered", "you do not have",
+                                             "insufficient privileges", "no bans found"];
+
+/// What a ChanServ helper asked for, see the ChanServDone event
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ChanServRequest {
+    /// `Conn::chanserv_op()`
+    ChanServOp,
+    /// `Conn::chanserv_unban()`
+    ChanServUnban,
+    /// `Conn::chanserv_invite()`
+    ChanServInvite,
+    /// `Conn::chanserv_topic()`
+    ChanServTopic
+}
+
+/// A ChanServ request whose outcome we haven't seen yet
+struct PendingRequest {
+    /// The casemapped channel name
+    key: Vec<u8>,
+    chan: Vec<u8>,
+    request: ChanServRequest,
+    sent: u64
+}
+
+struct ChanServPending(Vec<PendingRequest>);
+
+/// A message to a service
+struct ServiceMsg {
+    service: Vec<u8>,
+    msg: Vec<u8>,
+    /// How many times it was sent again already
+    retries: uint
+}
+
+/// The messages waiting to be sent to services, see `Conn::services_msg()`
+struct ServicesQueue {
+    queued: RingBuf<ServiceMsg>,
+    /// The last message sent, and when
+    last: Option<(ServiceMsg, u64)>,
+    /// When the next message may be sent
+    next_at: u64,
+    /// Whether a job to send the next message is scheduled
+    scheduled: bool
+}
+
+impl<'a> Conn<'a> {
+    /// Sends a PRIVMSG to a service like NickServ or ChanServ, spaced out from
+    /// the other messages to services by `Options.services_interval`.
+    ///
+    /// Services put clients that send commands too quickly on an ignore list,
+    /// which is easy to trip with the server's own, higher, flood limit. If a
+    /// service still says we're sending too fast, the message is sent again
+    /// after the wait it asks for (or 10 seconds), up to 3 times.
+    pub fn services_msg(&mut self, service: &[u8], msg: &[u8]) {
+        let msg = ServiceMsg { service: service.to_vec(), msg: msg.to_vec(), retries: 0 };
+        queue(self).queued.push_back(msg);
+        flush(self);
+    }
+
+    /// Asks HostServ for `host` as our virtual host. Networks usually need
+    /// a staff member to approve the request first.
+    ///
+    /// Once a vhost is active the server tells us with a 396, which updates
+    /// our tracked hostmask (and with it `max_message_len()`) and sends a
+    /// HostChanged event.
+    pub fn request_vhost(&mut self, host: &[u8]) {
+        let mut msg = b"REQUEST ".to_vec();
+        msg.push_all(host);
+        self.hostserv(msg.as_slice());
+    }
+
+    /// Asks HostServ to turn on our approved vhost. Services that don't
+    /// apply vhosts automatically on identify need this.
+    pub fn vhost_on(&mut self) {
+        self.hostserv(b"ON");
+    }
+
+    fn hostserv(&mut self, msg: &[u8]) {
+        self.services_msg(b"HostServ", msg);
+    }
+
+    /// Asks ChanServ to op us in `chan`. A ChanServDone event is sent when
+    /// we're opped, or ChanServFailed if ChanServ refuses.
+    pub fn chanserv_op(&mut self, chan: &[u8]) {
+        let nick = self.user.nick().to_vec();
+        match self.quirks.chanserv {
+            ImplicitSelf => self.chanserv(ChanServOp, chan, b"OP", []),
+            ExplicitSelf => self.chanserv(ChanServOp, chan, b"OP", [nick.as_slice()])
+        }
+    }
+
+    /// Asks ChanServ to remove the bans on `chan` that match us, e.g. to get
+    /// back into a channel we were banned from. A ChanServDone event is sent
+    /// when a matching ban is removed or ChanServ says it unbanned us, and
+    /// ChanServFailed if it refuses.
+    pub fn chanserv_unban(&mut self, chan: &[u8]) {
+        match self.quirks.chanserv {
+            ImplicitSelf => self.chanserv(ChanServUnban, chan, b"UNBAN", []),
+            ExplicitSelf => self.chanserv(ChanServUnban, chan, b"UNBAN", [b"ME"])
+        }
+    }
+
+    /// Asks ChanServ to invite us to `chan`, e.g. when it's invite-only. A
+    /// ChanServDone event is sent when the INVITE arrives, or ChanServFailed
+    /// if ChanServ refuses. Joining is still up to the caller, see
+    /// `Options.auto_join_invites`.
+    pub fn chanserv_invite(&mut self, chan: &[u8]) {
+        self.chanserv(ChanServInvite, chan, b"INVITE", []);
+    }
+
+    /// Asks ChanServ to set the topic of `chan`, for channels where we can't
+    /// set it ourselves. A ChanServDone event is sent when the topic changes,
+    /// or ChanServFailed if ChanServ refuses.
+    pub fn chanserv_topic(&mut self, chan: &[u8], topic: &[u8]) {
+        self.chanserv(ChanServTopic, chan, b"TOPIC", [topic]);
+    }
+
+    fn chanserv(&mut self, request: ChanServRequest, chan: &[u8], cmd: &[u8], args: &[&[u8]]) {
+        let mut msg = cmd.to_vec();
+        msg.push(' ' as u8);
+        msg.push_all(chan);
+        for arg in args.iter() {
+            msg.push(' ' as u8);
+            msg.push_all(*arg);
+        }
+        let key = self.isupport.casemapping().lower(chan);
+        let now = self.now();
+        {
+            let &ChanServPending(ref mut pending) = self.extensions.get_or_insert_with(|| {
+                ChanServPending(Vec::new())
+            });
+            pending.retain(|p| now - p.sent < CHANSERV_EXPIRY_NS && !(p.key == key && p.request == request));
+            pending.push(PendingRequest { key: key, chan: chan.to_vec(), request: request, sent: now });
+        }
+        self.services_msg(b"ChanServ", msg.as_slice());
+    }
+}
+
+fn queue<'a>(conn: &'a mut Conn) -> &'a mut ServicesQueue {
+    conn.extensions.get_or_insert_with(|| {
+        ServicesQueue { queued: RingBuf::new(), last: None, next_at: 0, scheduled: false }
+    })
+}
+
+/// Sends the next queued message if it's time, and schedules sending the
+/// one after
+fn flush(conn: &mut Conn) {
+    let now = conn.now();
+    let interval = conn.services_interval.num_milliseconds() as u64 * 1_000_000;
+    let (send, wait) = {
+        let queue = queue(conn);
+        if queue.scheduled || queue.queued.is_empty() {
+            return;
+        }
+        if queue.next_at > now {
+            (None, queue.next_at - now)
+        } else {
+            let next = queue.queued.pop_front().unwrap();
+            let send = (next.service.clone(), next.msg.clone());
+            queue.last = Some((next, now));
+            queue.next_at = now + interval;
+            if queue.queued.is_empty() {
+                (Some(send), 0)
+            } else {
+                (Some(send), interval)
+            }
+        }
+    };
+    match send {
+        None => (),
+        Some((service, msg)) => conn.privmsg(service.as_slice(), msg.as_slice())
+    }
+    if wait > 0 {
+        queue(conn).scheduled = true;
+        conn.schedule(Duration::microseconds((wait / 1000) as i64), proc(conn: &mut Conn) {
+            queue(conn).scheduled = false;
+            flush(conn);
+        });
+    }
+}
+
+/// Forgets the messages and ChanServ requests of the last connection. The
+/// job that would have sent the next message is gone with it.
+pub fn reset(conn: &mut Conn) {
+    conn.extensions.remove::<ServicesQueue>();
+    conn.extensions.remove::<ChanServPending>();
+}
+
+/// Watches for what services say and do in answer to our requests
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    if !slow_down(conn, line) && conn.extensions.contains::<ChanServPending>() {
+        chanserv_outcome(conn, line);
+    }
+}
+
+/// Watches for a service saying that we're sending too fast, and sends the
+/// message it ignored again later. Returns `true` if the line said so.
+fn slow_down(conn: &mut Conn, line: &Line) -> bool {
+    let text = match (&line.command, line.args.as_slice()) {
+        (&IRCCmd(ref cmd), [_, ref text]) if cmd.as_slice() == "NOTICE" => text,
+        _ => return false
+    };
+    let (from, now) = match line.prefix {
+        Some(ref user) => (user.nick(), conn.now()),
+        None => return false
+    };
+    let casemapping = conn.isupport.casemapping();
+    let complained = match conn.extensions.get::<ServicesQueue>() {
+        Some(&ServicesQueue { last: Some((ref last, sent)), .. }) => {
+            casemapping.eq_ignore_case(last.service.as_slice(), from) && now - sent < COMPLAINT_WINDOW_NS
+        }
+        _ => false
+    };
+    if !complained {
+        return false;
+    }
+    let lower = text.iter().map(|&b| (b as char).to_lowercase()).collect::<String>();
+    if !too_fast(lower.as_slice()) {
+        return false;
+    }
+    let wait = Duration::seconds(requested_wait(lower.as_slice()).unwrap_or(SLOW_DOWN_SECS));
+    {
+        let queue = queue(conn);
+        let (mut last, _) = queue.last.take().unwrap();
+        if last.retries < MAX_RETRIES {
+            info!("{} says we're sending too fast, trying again in {}s",
+                  String::from_utf8_lossy(from), wait.num_seconds());
+            last.retries += 1;
+            queue.queued.push_front(last);
+        } else {
+            info!("{} keeps saying we're sending too fast, dropping: {}",
+                  String::from_utf8_lossy(from), String::from_utf8_lossy(last.msg.as_slice()));
+        }
+        queue.next_at = now + wait.num_milliseconds() as u64 * 1_000_000;
+    }
+    flush(conn);
+    true
+}
+
+/// Sends ChanServDone when a line shows that a ChanServ request worked, and
+/// ChanServFailed when ChanServ refuses one
+fn chanserv_outcome(conn: &mut Conn, line: &Line) {
+    let casemapping = conn.isupport.casemapping();
+    let args = line.args.as_slice();
+    // (channel, request, ChanServ's message if it refused)
+    let outcome = match line.command {
+        IRCCmd(ref cmd) if cmd.as_slice() == "MODE" && args.len() > 1 => {
+            if !conn.isupport.is_channel(args[0].as_slice()) {
+                return;
+            }
+            let changes = modes::parse(&conn.isupport, args[1].as_slice(), args.slice_from(2));
+            let me = conn.user.raw();
+            let request = changes.iter().filter_map(|c| match (c.set, c.mode, c.param.as_ref()) {
+                (true, 'o', Some(nick)) if casemapping.eq_ignore_case(nick.as_slice(), conn.user.nick()) => {
+                    Some(ChanServOp)
+                }
+                (false, 'b', Some(ban)) if casemapping.glob_match(ban.as_slice(), me) => Some(ChanServUnban),
+                _ => None
+            }).next();
+            match request {
+                None => return,
+                Some(request) => (args[0].clone(), request, None)
+            }
+        }
+        IRCCmd(ref cmd) if cmd.as_slice() == "INVITE" && args.len() > 1 => {
+            if !casemapping.eq_ignore_case(args[0].as_slice(), conn.user.nick()) {
+                return;
+            }
+            (args[1].clone(), ChanServInvite, None)
+        }
+        // ChanServ sets the topic itself
+        IRCCmd(ref cmd) if cmd.as_slice() == "TOPIC" && !args.is_empty() && from_chanserv(conn, line) => {
+            (args[0].clone(), ChanServTopic, None)
+        }
+        IRCCmd(ref cmd) if cmd.as_slice() == "NOTICE" && args.len() > 1 && from_chanserv(conn, line) => {
+            let text = args[1].as_slice();
+            let lower = text.iter().map(|&b| (b as char).to_lowercase()).collect::<String>();
+            // the channels the notice names, or if it names none, the one
+            // of the message ChanServ is answering
+            let mut keys = text.split(|&b| b == ' ' as u8).map(|w| trim_word(w))
+                               .filter(|w| conn.isupport.is_channel(*w))
+                               .map(|w| casemapping.lower(w)).collect::<Vec<_>>();
+            if keys.is_empty() {
+                match answering(conn) {
+                    None => return,
+                    Some(key) => keys.push(key)
+                }
+            }
+            // the oldest request for one of those channels
+            let pending = match conn.extensions.get::<ChanServPending>() {
+                None => return,
+                Some(&ChanServPending(ref pending)) => {
+                    match pending.iter().find(|p| keys.contains(&p.key)) {
+                        None => return,
+                        Some(p) => (p.chan.clone(), p.request.clone())
+                    }
+                }
+            };
+            let (chan, request) = pending;
+            if request == ChanServUnban && lower.as_slice().contains("unbanned") {
+                (chan, request, None)
+            } else if REFUSED.iter().any(|p| lower.as_slice().contains(*p)) {
+                (chan, request, Some(text.to_vec()))
+            } else {
+                return;
+            }
+        }
+        _ => return
+    };
+    let (chan, request, refused) = outcome;
+    let key = casemapping.lower(chan.as_slice());
+    let found = match conn.extensions.get_mut::<ChanServPending>() {
+        None => None,
+        Some(&ChanServPending(ref mut pending)) => {
+            match pending.iter().position(|p| p.key == key && p.request == request) {
+                None => None,
+                Some(idx) => pending.remove(idx)
+            }
+        }
+    };
+    match (found, refused) {
+        (None, _) => (),
+        (Some(p), None) => conn.emit(ChanServDone(p.chan, request)),
+        (Some(p), Some(msg)) => conn.emit(ChanServFailed(p.chan, request, msg))
+    }
+}
+
+fn from_chanserv(conn: &Conn, line: &Line) -> bool {
+    line.prefix.as_ref().map_or(false, |u| conn.isupport.casemapping().eq_ignore_case(u.nick(), b"ChanServ"))
+}
+
+/// Returns the casemapped channel of the last message sent to ChanServ, if
+/// it was recent enough to be what ChanServ is answering
+fn answering(conn: &Conn) -> Option<Vec<u8>> {
+    let now = conn.now();
+    match conn.extensions.get::<ServicesQueue>() {
+        Some(&ServicesQueue { last: Some((ref last, sent)), .. }) => {
+            let casemapping = conn.isupport.casemapping();
+            let to_chanserv = casemapping.eq_ignore_case(last.service.as_slice(), b"ChanServ");
+            if !to_chanserv || now - sent >= COMPLAINT_WINDOW_NS {
+                return None;
+            }
+            // e.g. OP #chan
+            last.msg.as_slice().split(|&b| b == ' ' as u8).nth(1).map(|chan| casemapping.lower(chan))
+        }
+        _ => None
+    }
+}
+
+/// Strips the formatting and punctuation services put around a word
+fn trim_word(word: &[u8]) -> &[u8] {
+    fn trimmed(&b: &u8) -> bool {
+        b == 0x02 || b == 0x1f || b == '.' as u8 || b == ',' as u8 || b == '"' as u8
+    }
+    let start = word.iter().position(|b| !trimmed(b)).unwrap_or(word.len());
+    let end = word.iter().rposition(|b| !trimmed(b)).map_or(start, |i| i + 1);
+    word.slice(start, end)
+}
+
+/// Returns `true` if a lowercased notice from a service says we're sending
+/// too fast
+fn too_fast(text: &str) -> bool {
+    TOO_FAST.iter().any(|p| text.contains(*p)) || (text.contains("wait") && requested_wait(text).is_some())
+}
+
+/// Returns the number of seconds in "please wait 5 seconds" and the like
+fn requested_wait(text: &str) -> Option<i64> {
+    let words = text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).collect::<Vec<_>>();
+    words.as_slice().windows(2).filter_map(|pair| {
+        if pair[1].starts_with("second") { from_str::<i64>(pair[0]) } else { None }
+    }).next()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue, ChanServDone, ChanServFailed, HostChanged};
+    use super::{requested_wait, too_fast};
+
+    #[test]
+    fn test_requested_wait() {
+        assert_eq!(requested_wait("please wait 5 seconds and retry."), Some(5));
+        assert_eq!(requested_wait("you must wait 30 seconds before using this command again."), Some(30));
+        assert_eq!(requested_wait("you are sending too fast."), None);
+    }
+
+    #[test]
+    fn test_too_fast() {
+        assert!(too_fast("you are sending too fast. please wait 5 seconds."));
+        assert!(too_fast("please wait 30 seconds and retry."));
+        assert!(!too_fast("this channel is protected from flooding."));
+        assert!(!too_fast("please wait while your request is reviewed."));
+    }
+
+    #[test]
+    fn services_queue() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.conn().services_msg(b"NickServ", b"INFO alice");
+        conn.conn().services_msg(b"ChanServ", b"INFO #rust");
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG NickServ :INFO alice".to_vec()]);
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG ChanServ :INFO #rust".to_vec()]);
+        // ChanServ ignored it, so it's sent again once the wait is over
+        let too_fast = b":ChanServ!s@services. NOTICE ircnick :You are sending too fast. Please wait 5 seconds.";
+        assert!(conn.step([too_fast], Duration::seconds(4)).is_empty());
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG ChanServ :INFO #rust".to_vec()]);
+        // the queue starts over on a new connection
+        conn.conn().services_msg(b"NickServ", b"INFO bob");
+        conn.conn().services_msg(b"NickServ", b"INFO carol");
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG NickServ :INFO bob".to_vec()]);
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.conn().services_msg(b"NickServ", b"IDENTIFY hunter2");
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG NickServ :IDENTIFY hunter2".to_vec()]);
+    }
+
+    #[test]
+    fn vhost() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.conn().request_vhost(b"rust.example");
+        conn.conn().vhost_on();
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG HostServ :REQUEST rust.example".to_vec()]);
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG HostServ ON".to_vec()]);
+        // the longest host is assumed until the server tells us ours
+        let before = conn.conn().max_message_len("PRIVMSG", b"#rust");
+        conn.events();
+        conn.step([b":irc.example.net 396 ircnick rust.example :is now your displayed host"], Duration::zero());
+        assert_eq!(conn.conn().max_message_len("PRIVMSG", b"#rust"), before + 63 - "rust.example".len());
+        // :ircnick!ircuser@rust.example PRIVMSG #rust :
+        assert_eq!(conn.conn().max_message_len("PRIVMSG", b"#rust"), 465);
+        conn.step([b":irc.example.net 396 ircnick ident@cloak.example :is now your displayed host"],
+                  Duration::zero());
+        let hosts = conn.events().into_iter().filter_map(|e| match e {
+            HostChanged(host) => Some(host),
+            _ => None
+        }).collect::<Vec<Vec<u8>>>();
+        assert_eq!(hosts, vec![b"rust.example".to_vec(), b"cloak.example".to_vec()]);
+        assert_eq!(conn.conn().max_message_len("PRIVMSG", b"#rust"), 465 + 2 - 1);
+    }
+
+    #[test]
+    fn chanserv_helpers() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.conn().chanserv_op(b"#rust");
+        conn.conn().chanserv_unban(b"#Secret");
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG ChanServ :OP #rust".to_vec()]);
+        assert_eq!(conn.step([b":ChanServ!s@services. MODE #rust +o ircnick"], second),
+                   vec![b"PRIVMSG ChanServ :UNBAN #Secret".to_vec()]);
+        conn.step([b":ChanServ!s@services. NOTICE ircnick :You are not authorized to perform this operation."],
+                  Duration::zero());
+        conn.conn().chanserv_topic(b"#rust", b"Rust | be nice");
+        assert_eq!(conn.step([], second), vec![b"PRIVMSG ChanServ :TOPIC #rust Rust | be nice".to_vec()]);
+        // neither is about the request
+        conn.step([b":ChanServ!s@services. NOTICE ircnick :\x02#other\x02 is not registered.",
+                   b":troll!u@h TOPIC #rust :lol"], Duration::zero());
+        conn.step([b":ChanServ!s@services. TOPIC #rust :Rust | be nice"], Duration::zero());
+        let events = conn.events().into_iter().filter_map(|e| match e {
+            ChanServDone(chan, req) => Some(format!("{} {}", String::from_utf8_lossy(chan.as_slice()), req)),
+            ChanServFailed(chan, req, _) => {
+                Some(format!("{} {} failed", String::from_utf8_lossy(chan.as_slice()), req))
+            }
+            _ => None
+        }).collect::<Vec<String>>();
+        assert_eq!(events, vec!["#rust ChanServOp".to_string(), "#Secret ChanServUnban failed".to_string(),
+                                "#rust ChanServTopic".to_string()]);
+    }
+}
@@ -0,0 +1,25 @@
+//! Starting the tasks a connection needs
+
+use std::task::TaskBuilder;
+
+/// A way of running the tasks that a connection uses for reading and writing
+/// lines and for the ident responder, and the ones started by `connect_async()`
+/// and `connect_pooled()`. See `Options.spawner`.
+///
+/// The jobs block on I/O and run until the connection is closed, so a spawner
+/// has to run each one concurrently with the caller, e.g. on a native thread
+/// or a task in the embedding program's own pool.
+pub trait Spawner {
+    /// Runs `job` concurrently. `name` says what it is, e.g. "libirc reader".
+    fn spawn(&mut self, name: &'static str, job: proc(): Send);
+}
+
+/// Spawns a task with `TaskBuilder`, which uses whichever runtime (green
+/// or native) the calling task runs on. This is the default.
+pub struct TaskSpawner;
+
+impl Spawner for TaskSpawner {
+    fn spawn(&mut self, name: &'static str, job: proc(): Send) {
+        TaskBuilder::new().named(name).spawn(job);
+    }
+}
@@ -0,0 +1,277 @@
+//! Keeping channel topics the way we want them, see `Conn::guard_topic()`
+
+use std::time::Duration;
+
+use conn::{Conn, Line, IRCCmd, IRCCode, TopicRestored};
+
+/// The least time between two restores of the same topic, in nanoseconds,
+/// so two clients guarding different topics don't flood the channel
+static RESTORE_INTERVAL_NS: u64 = 10 * 1_000_000_000;
+
+struct Guard {
+    /// The casemapped channel name
+    chan: Vec<u8>,
+    /// The channel name it was guarded with
+    name: Vec<u8>,
+    topic: Vec<u8>,
+    /// When we last set the topic back
+    restored_at: Option<u64>,
+    /// Whether a restore is waiting out RESTORE_INTERVAL_NS
+    scheduled: bool
+}
+
+struct TopicGuards(Vec<Guard>);
+
+impl<'a> Conn<'a> {
+    /// Sets the topic of `chan`. An empty topic clears it.
+    ///
+    /// If the topic of `chan` is guarded, this changes the guarded topic too,
+    /// which is how the topic is changed on purpose. See `guard_topic()`.
+    pub fn set_topic(&mut self, chan: &[u8], topic: &[u8]) {
+        self.send_command(IRCCmd("TOPIC".into_maybe_owned()), [chan, topic], true);
+    }
+
+    /// Keeps the topic of `chan` set to `topic`: whenever someone else
+    /// changes it and we're opped, it's set back, with a TopicRestored event.
+    /// If we aren't opped, it's set back once we are.
+    ///
+    /// Topics we set ourselves, with `set_topic()` or from another client of
+    /// the same bouncer, replace the guarded topic. Guards are kept across
+    /// reconnects.
+    pub fn guard_topic(&mut self, chan: &[u8], topic: &[u8]) {
+        let key = self.isupport.casemapping().lower(chan);
+        {
+            let &TopicGuards(ref mut guards) = self.extensions.get_or_insert_with(|| TopicGuards(Vec::new()));
+            guards.retain(|g| g.chan != key);
+            guards.push(Guard {
+                chan: key,
+                name: chan.to_vec(),
+                topic: topic.to_vec(),
+                restored_at: None,
+                scheduled: false
+            });
+        }
+        check(self, chan);
+    }
+
+    /// Stops guarding the topic of `chan`. Returns `false` if it wasn't guarded.
+    pub fn unguard_topic(&mut self, chan: &[u8]) -> bool {
+        let key = self.isupport.casemapping().lower(chan);
+        match self.extensions.get_mut::<TopicGuards>() {
+            None => false,
+            Some(&TopicGuards(ref mut guards)) => {
+                let len = guards.len();
+                guards.retain(|g| g.chan != key);
+                guards.len() != len
+            }
+        }
+    }
+
+    /// Returns the topic that `chan` is kept at, if it's guarded
+    pub fn guarded_topic(&self, chan: &[u8]) -> Option<Vec<u8>> {
+        let key = self.isupport.casemapping().lower(chan);
+        match self.extensions.get::<TopicGuards>() {
+            None => None,
+            Some(&TopicGuards(ref guards)) => guards.iter().find(|g| g.chan == key).map(|g| g.topic.clone())
+        }
+    }
+}
+
+/// Keys the guards by the new casemapping
+pub fn rekey(conn: &mut Conn) {
+    let casemapping = conn.isupport.casemapping();
+    match conn.extensions.get_mut::<TopicGuards>() {
+        None => (),
+        Some(&TopicGuards(ref mut guards)) => for guard in guards.iter_mut() {
+            guard.chan = casemapping.lower(guard.name.as_slice());
+        }
+    }
+}
+
+/// Forgets the restores that were waiting on the last connection, whose
+/// jobs are gone. The guards are kept.
+pub fn reset(conn: &mut Conn) {
+    match conn.extensions.get_mut::<TopicGuards>() {
+        None => (),
+        Some(&TopicGuards(ref mut guards)) => for guard in guards.iter_mut() {
+            guard.scheduled = false;
+        }
+    }
+}
+
+/// Restores guarded topics when they're changed, or when we're opped
+pub fn saw_line(conn: &mut Conn, line: &Line) {
+    if conn.extensions.get::<TopicGuards>().is_none() {
+        return;
+    }
+    match line.command {
+        IRCCmd(ref cmd) if "TOPIC" == cmd.as_slice() && line.args.len() > 1 => {
+            // :nick!user@host TOPIC #chan :new topic
+            let casemapping = conn.isupport.casemapping();
+            let ours = line.prefix.as_ref().map_or(false, |u| casemapping.eq_ignore_case(u.nick(), conn.user.nick()));
+            if ours {
+                adopt(conn, line.args[0].as_slice(), line.args[1].as_slice());
+            } else {
+                check(conn, line.args[0].as_slice());
+            }
+        }
+        IRCCmd(ref cmd) if "MODE" == cmd.as_slice() && !line.args.is_empty() => {
+            check(conn, line.args[0].as_slice());
+        }
+        // RPL_NOTOPIC, RPL_TOPIC: me #chan [:topic]
+        // RPL_ENDOFNAMES: me #chan :End of /NAMES list., when we may already be opped
+        IRCCode(331) | IRCCode(332) | IRCCode(366) if line.args.len() > 1 => check(conn, line.args[1].as_slice()),
+        _ => ()
+    }
+}
+
+/// Makes a topic that we set the guarded one
+fn adopt(conn: &mut Conn, chan: &[u8], topic: &[u8]) {
+    let key = conn.isupport.casemapping().lower(chan);
+    match conn.extensions.get_mut::<TopicGuards>() {
+        None => (),
+        Some(&TopicGuards(ref mut guards)) => match guards.iter_mut().find(|g| g.chan == key) {
+            None => (),
+            Some(guard) => guard.topic = topic.to_vec()
+        }
+    }
+}
+
+/// Sets the topic of `chan` back if it's guarded, it differs and we're opped
+fn check(conn: &mut Conn, chan: &[u8]) {
+    let key = conn.isupport.casemapping().lower(chan);
+    let current = match conn.state.channel(chan) {
+        // not in the channel, or not even a channel
+        None => return,
+        Some(c) => c.topic().map_or(Vec::new(), |t| t.to_vec())
+    };
+    if !conn.state.is_op(chan, conn.user.nick()) {
+        return;
+    }
+    let now = conn.now();
+    let (topic, wait) = match conn.extensions.get_mut::<TopicGuards>() {
+        None => return,
+        Some(&TopicGuards(ref mut guards)) => {
+            let guard = match guards.iter_mut().find(|g| g.chan == key) {
+                None => return,
+                Some(guard) => guard
+            };
+            if guard.topic == current || guard.scheduled {
+                return;
+            }
+            match guard.restored_at {
+                Some(at) if now - at < RESTORE_INTERVAL_NS => {
+                    guard.scheduled = true;
+                    (guard.topic.clone(), Some(RESTORE_INTERVAL_NS - (now - at)))
+                }
+                _ => {
+                    guard.restored_at = Some(now);
+                    (guard.topic.clone(), None)
+                }
+            }
+        }
+    };
+    match wait {
+        Some(wait) => {
+            let chan = chan.to_vec();
+            conn.schedule(Duration::milliseconds((wait / 1_000_000) as i64 + 1), proc(conn: &mut Conn) {
+                retry(conn, chan);
+            });
+        }
+        None => {
+            info!("Restoring the topic of {}", String::from_utf8_lossy(chan));
+            conn.set_topic(chan, topic.as_slice());
+            conn.emit(TopicRestored(chan.to_vec(), current));
+        }
+    }
+}
+
+/// Checks the topic again once the restore interval is over
+fn retry(conn: &mut Conn, chan: Vec<u8>) {
+    let key = conn.isupport.casemapping().lower(chan.as_slice());
+    match conn.extensions.get_mut::<TopicGuards>() {
+        None => return,
+        Some(&TopicGuards(ref mut guards)) => match guards.iter_mut().find(|g| g.chan == key) {
+            None => return,
+            Some(guard) => guard.scheduled = false
+        }
+    }
+    check(conn, chan.as_slice());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Continue, TopicRestored};
+
+    #[test]
+    fn topic_guard() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":ircnick!u@h JOIN #rust",
+                   b":irc.example.net 332 ircnick #rust :old topic"], Duration::zero());
+        conn.conn().guard_topic(b"#rust", b"Rust | be nice");
+        // not opped yet
+        assert!(conn.step([], second).is_empty());
+        assert_eq!(conn.step([b":ChanServ!s@services. MODE #rust +o ircnick"], second),
+                   vec![b"TOPIC #rust :Rust | be nice".to_vec()]);
+        conn.step([b":ircnick!u@h TOPIC #rust :Rust | be nice"], second);
+        // changed back, but not more than once per 10 seconds
+        assert!(conn.step([b":troll!u@h TOPIC #rust :lol"], second).is_empty());
+        assert_eq!(conn.step([], Duration::seconds(8)), vec![b"TOPIC #rust :Rust | be nice".to_vec()]);
+        // our own changes replace the guarded topic
+        conn.conn().set_topic(b"#rust", b"Rust 1.0 is out");
+        conn.step([b":ircnick!u@h TOPIC #rust :Rust 1.0 is out"], second);
+        assert_eq!(conn.conn().guarded_topic(b"#RUST"), Some(b"Rust 1.0 is out".to_vec()));
+        assert!(conn.conn().unguard_topic(b"#rust"));
+        assert!(conn.step([b":troll!u@h TOPIC #rust :lol"], second).is_empty());
+        let replaced = conn.events().into_iter().filter_map(|e| match e {
+            TopicRestored(_, topic) => Some(topic),
+            _ => None
+        }).collect::<Vec<Vec<u8>>>();
+        assert_eq!(replaced, vec![b"old topic".to_vec(), b"lol".to_vec()]);
+    }
+
+    #[test]
+    fn guard_after_reconnect() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        conn.step([b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        conn.conn().guard_topic(b"#rust", b"Rust | be nice");
+        // opped as soon as we join
+        assert_eq!(conn.step([b":ircnick!u@h JOIN #rust",
+                              b":irc.example.net 332 ircnick #rust :old topic",
+                              b":irc.example.net 353 ircnick = #rust :@ircnick",
+                              b":irc.example.net 366 ircnick #rust :End of /NAMES list."], Duration::zero()),
+                   vec![b"TOPIC #rust :Rust | be nice".to_vec()]);
+        assert!(conn.step([b":IrcNick!u@h TOPIC #rust :new topic"], second).is_empty());
+        assert_eq!(conn.conn().guarded_topic(b"#rust"), Some(b"new topic".to_vec()));
+        // the restore waits, and is lost with the connection
+        assert!(conn.step([b":troll!u@h TOPIC #rust :lol"], second).is_empty());
+        conn.server_closed(|_, event, events| { events.push(event); Continue });
+        conn.reconnect();
+        let out = conn.step([b":irc.example.net 001 ircnick :Welcome",
+                             b":ircnick!u@h JOIN #rust",
+                             b":irc.example.net 332 ircnick #rust :lol",
+                             b":irc.example.net 353 ircnick = #rust :@ircnick",
+                             b":irc.example.net 366 ircnick #rust :End of /NAMES list."], Duration::seconds(10));
+        assert_eq!(out.last(), Some(&b"TOPIC #rust :new topic".to_vec()));
+    }
+
+    #[test]
+    fn casemapping_changed() {
+        let opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        let mut conn = Manual::new(opts, Vec::new());
+        conn.conn().guard_topic(b"#Rust[1]", b"Rust | be nice");
+        // rfc1459 until the server says otherwise
+        assert_eq!(conn.conn().guarded_topic(b"#rust{1}"), Some(b"Rust | be nice".to_vec()));
+        conn.step([b":irc.example.net 001 ircnick :Welcome",
+                   b":irc.example.net 005 ircnick CASEMAPPING=ascii :are supported by this server"],
+                  Duration::zero());
+        assert_eq!(conn.conn().guarded_topic(b"#rust{1}"), None);
+        assert_eq!(conn.conn().guarded_topic(b"#RUST[1]"), Some(b"Rust | be nice".to_vec()));
+    }
+}
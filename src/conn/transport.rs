@@ -0,0 +1,479 @@
+//! Transports that carry IRC lines to and from the server
+
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::io::{BufferedReader, IoError, IoResult, TcpStream};
+use std::rand::{task_rng, Rng};
+use std::sync::{Arc, Mutex};
+use serialize::base64::{ToBase64, STANDARD};
+
+/// Reads lines from the server
+pub trait LineReader: Send {
+    /// Returns the next line, without the line terminator.
+    /// Returns an EndOfFile error once the server has closed the connection.
+    fn read_line(&mut self) -> IoResult<Vec<u8>>;
+}
+
+/// Writes lines to the server
+pub trait LineWriter: Send {
+    /// Writes a line. The line doesn't include a terminator.
+    fn write_line(&mut self, line: &[u8]) -> IoResult<()>;
+}
+
+/// An open connection to a server, as returned by `Transport::connect()`.
+/// The reader and writer are used from separate tasks.
+pub struct Connection {
+    /// The reading half of the connection
+    pub reader: Box<LineReader + Send>,
+    /// The writing half of the connection
+    pub writer: Box<LineWriter + Send>,
+    /// The underlying socket. This is used to close the connection, and to set
+    /// socket options.
+    pub socket: TcpStream,
+    /// The TLS session that was set up, in whatever form the transport can
+    /// resume it from, or None for plain connections. See
+    /// `TlsOptions.session`.
+    pub tls_session: Option<Vec<u8>>
+}
+
+/// TLS protocol versions, oldest first
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show)]
+pub enum TlsVersion {
+    /// TLS 1.0
+    Tls10,
+    /// TLS 1.1
+    Tls11,
+    /// TLS 1.2
+    Tls12
+}
+
+/// How to set up TLS, see `Options.tls`.
+///
+/// The library doesn't include TLS, so these are handed to the transport,
+/// which does the handshake. `TcpTransport` and `WebSocketTransport` refuse
+/// to connect when they're given TLS options.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct TlsOptions {
+    /// The name to send with SNI and to check the certificate against,
+    /// instead of the host being connected to. For servers behind SNI-based
+    /// routing that are reached through another name.
+    pub sni: Option<String>,
+    /// Whether to resume the session of the last connection to the same
+    /// server when reconnecting, which saves a full handshake
+    pub resume_sessions: bool,
+    /// The oldest protocol version to accept
+    pub min_version: TlsVersion,
+    /// The protocols to offer with ALPN, most preferred first. Empty means
+    /// ALPN isn't used.
+    pub alpn: Vec<String>,
+    /// The session to resume: the `Connection.tls_session` of the last
+    /// connection to the same host and port. It's filled in by the
+    /// connection when `resume_sessions` is set, so leave it None.
+    pub session: Option<Vec<u8>>
+}
+
+impl TlsOptions {
+    /// Returns options that resume sessions, require TLS 1.2 and use
+    /// neither SNI overrides nor ALPN
+    pub fn new() -> TlsOptions {
+        TlsOptions {
+            sni: None,
+            resume_sessions: true,
+            min_version: Tls12,
+            alpn: Vec::new(),
+            session: None
+        }
+    }
+
+    /// Returns the name to send with SNI when connecting to `host`
+    pub fn sni_name<'a>(&'a self, host: &'a str) -> &'a str {
+        self.sni.as_ref().map_or(host, |s| s.as_slice())
+    }
+}
+
+/// A way of connecting to a server. See `Options.transport`.
+///
+/// This is also how TLS is added, as the library doesn't include it. A
+/// transport is kept for the whole of `connect()` and used again for
+/// reconnects and redirects.
+pub trait Transport {
+    /// Opens a connection to the given server. `tls` is `Options.tls`, with
+    /// the session to resume filled in; a transport that can't honour it
+    /// should fail rather than connect without TLS.
+    fn connect(&mut self, host: &str, port: u16, tls: Option<&TlsOptions>) -> IoResult<Connection>;
+}
+
+/// Fails if TLS was asked for, for the transports that don't do it
+fn refuse_tls(tls: Option<&TlsOptions>) -> IoResult<()> {
+    match tls {
+        None => Ok(()),
+        Some(_) => Err(IoError {
+            kind: io::InvalidInput,
+            desc: "TLS isn't supported by this transport",
+            detail: None
+        })
+    }
+}
+
+/// The usual transport: lines terminated with \r\n over a TCP connection.
+/// This is the default.
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect(&mut self, host: &str, port: u16, tls: Option<&TlsOptions>) -> IoResult<Connection> {
+        try!(refuse_tls(tls));
+        let socket = try!(TcpStream::connect((host, port)));
+        Ok(Connection {
+            reader: box TcpLineReader::new(socket.clone()),
+            writer: box TcpLineWriter::new(socket.clone()),
+            socket: socket,
+            tls_session: None
+        })
+    }
+}
+
+/// Reads lines terminated with \r\n or \n from a byte stream
+pub struct TcpLineReader<R> {
+    stream: BufferedReader<R>
+}
+
+impl<R: Reader + Send> TcpLineReader<R> {
+    /// Returns a TcpLineReader that reads from `stream`
+    pub fn new(stream: R) -> TcpLineReader<R> {
+        TcpLineReader { stream: BufferedReader::new(stream) }
+    }
+}
+
+impl<R: Reader + Send> LineReader for TcpLineReader<R> {
+    fn read_line(&mut self) -> IoResult<Vec<u8>> {
+        loop {
+            let mut line = try!(self.stream.read_until('\n' as u8));
+            if !super::chomp_owned(&mut line) {
+                // no line terminator? Must have hit EOF
+                return Err(io::standard_error(io::EndOfFile));
+            }
+            if !line.is_empty() {
+                return Ok(line);
+            }
+        }
+    }
+}
+
+/// Writes lines terminated with \r\n to a byte stream, flushing after each
+pub struct TcpLineWriter<W> {
+    stream: W
+}
+
+impl<W: Writer + Send> TcpLineWriter<W> {
+    /// Returns a TcpLineWriter that writes to `stream`
+    pub fn new(stream: W) -> TcpLineWriter<W> {
+        TcpLineWriter { stream: stream }
+    }
+}
+
+impl<W: Writer + Send> LineWriter for TcpLineWriter<W> {
+    fn write_line(&mut self, line: &[u8]) -> IoResult<()> {
+        try!(self.stream.write(line));
+        try!(self.stream.write(b"\r\n"));
+        self.stream.flush()
+    }
+}
+
+/// The longest WebSocket message we accept from the server, in bytes. IRC
+/// lines with tags are at most 8703 bytes, so anything longer is broken or
+/// hostile.
+static MAX_WS_MESSAGE: uint = 16384;
+
+/// The GUID appended to the key of a WebSocket handshake, see RFC 6455
+static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// IRC over WebSocket, as offered by some servers and web gateways.
+///
+/// Each line is sent as its own WebSocket message, without a line terminator.
+/// This doesn't do TLS, so it only supports ws:// endpoints.
+pub struct WebSocketTransport {
+    /// The path to request, e.g. "/webirc"
+    pub path: String,
+    /// Whether to use binary messages (the binary.ircv3.net subprotocol) instead
+    /// of text messages (text.ircv3.net). Text messages must be valid UTF-8.
+    pub binary: bool
+}
+
+impl WebSocketTransport {
+    /// Returns a WebSocketTransport that requests the given path with text messages
+    pub fn new(path: &str) -> WebSocketTransport {
+        WebSocketTransport { path: path.to_string(), binary: false }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn connect(&mut self, host: &str, port: u16, tls: Option<&TlsOptions>) -> IoResult<Connection> {
+        try!(refuse_tls(tls));
+        let mut socket = try!(TcpStream::connect((host, port)));
+        let mut key = [0u8, ..16];
+        task_rng().fill_bytes(key);
+        let key = key.to_base64(STANDARD);
+        let protocol = if self.binary { "binary.ircv3.net" } else { "text.ircv3.net" };
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\n\
+                               Connection: Upgrade\r\nSec-WebSocket-Key: {}\r\n\
+                               Sec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+                              self.path, host, port, key, protocol);
+        try!(socket.write(request.as_bytes()));
+        try!(socket.flush());
+
+        let mut reader = BufferedReader::new(socket.clone());
+        let status = try!(reader.read_line());
+        if !status.as_slice().starts_with("HTTP/1.1 101") {
+            return Err(IoError {
+                kind: io::OtherIoError,
+                desc: "WebSocket handshake refused",
+                detail: Some(status.as_slice().trim().to_string())
+            });
+        }
+        // the server proves it understood the handshake with the accept key
+        let mut accepted = false;
+        loop {
+            let header = try!(reader.read_line());
+            let header = header.as_slice().trim();
+            if header.is_empty() {
+                break;
+            }
+            match header.find(':') {
+                Some(i) if header.slice_to(i).trim().eq_ignore_ascii_case("Sec-WebSocket-Accept") => {
+                    accepted = header.slice_from(i + 1).trim() == accept_key(key.as_slice()).as_slice();
+                }
+                _ => ()
+            }
+        }
+        if !accepted {
+            return Err(IoError {
+                kind: io::OtherIoError,
+                desc: "WebSocket handshake failed",
+                detail: Some("missing or wrong Sec-WebSocket-Accept".to_string())
+            });
+        }
+
+        let writer = Arc::new(Mutex::new(socket.clone()));
+        Ok(Connection {
+            reader: box WsLineReader { stream: reader, writer: writer.clone() },
+            writer: box WsLineWriter { writer: writer, binary: self.binary },
+            socket: socket,
+            tls_session: None
+        })
+    }
+}
+
+struct WsLineReader {
+    stream: BufferedReader<TcpStream>,
+    /// Shared with the WsLineWriter, for answering pings
+    writer: Arc<Mutex<TcpStream>>
+}
+
+impl LineReader for WsLineReader {
+    fn read_line(&mut self) -> IoResult<Vec<u8>> {
+        let mut message = Vec::new();
+        loop {
+            let (fin, opcode, payload) = try!(read_frame(&mut self.stream, MAX_WS_MESSAGE - message.len()));
+            match opcode {
+                // continuation, text, binary
+                0x0 | 0x1 | 0x2 => {
+                    message.push_all(payload.as_slice());
+                    if fin {
+                        // be lenient about servers that terminate lines anyway
+                        let len = super::chomp(message.as_slice()).len();
+                        message.truncate(len);
+                        if !message.is_empty() {
+                            return Ok(message);
+                        }
+                    }
+                }
+                // close
+                0x8 => return Err(io::standard_error(io::EndOfFile)),
+                // ping
+                0x9 => try!(write_frame(&mut *self.writer.lock(), 0xA, payload.as_slice())),
+                // pong, or something we don't know
+                _ => ()
+            }
+        }
+    }
+}
+
+struct WsLineWriter {
+    writer: Arc<Mutex<TcpStream>>,
+    binary: bool
+}
+
+impl LineWriter for WsLineWriter {
+    fn write_line(&mut self, line: &[u8]) -> IoResult<()> {
+        let opcode = if self.binary { 0x2 } else { 0x1 };
+        write_frame(&mut *self.writer.lock(), opcode, line)
+    }
+}
+
+/// Reads a WebSocket frame, returning whether it's the final one of its
+/// message, its opcode and its payload. Frames longer than `max` bytes are an
+/// InvalidInput error, without reading their payload.
+fn read_frame<R: Reader>(r: &mut R, max: uint) -> IoResult<(bool, u8, Vec<u8>)> {
+    let head = try!(r.read_u8());
+    let (fin, opcode) = (head & 0x80 != 0, head & 0x0f);
+    let second = try!(r.read_u8());
+    let len = match second & 0x7f {
+        126 => try!(r.read_be_u16()) as u64,
+        127 => try!(r.read_be_u64()),
+        len => len as u64
+    };
+    if len > max as u64 {
+        return Err(IoError {
+            kind: io::InvalidInput,
+            desc: "WebSocket message too long",
+            detail: Some(format!("{} bytes", len))
+        });
+    }
+    let mask = if second & 0x80 != 0 {
+        Some(try!(r.read_exact(4)))
+    } else {
+        None
+    };
+    let mut payload = try!(r.read_exact(len as uint));
+    match mask {
+        None => (),
+        Some(mask) => {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+        }
+    }
+    Ok((fin, opcode, payload))
+}
+
+/// Returns the Sec-WebSocket-Accept value that the server has to answer
+/// the handshake key `key` with
+fn accept_key(key: &str) -> String {
+    let mut input = key.to_string();
+    input.push_str(WS_GUID);
+    sha1(input.as_bytes()).as_slice().to_base64(STANDARD)
+}
+
+/// Returns the SHA-1 digest of `data`, which only the WebSocket handshake needs
+fn sha1(data: &[u8]) -> [u8, ..20] {
+    fn rotl(x: u32, n: uint) -> u32 {
+        (x << n) | (x >> (32 - n))
+    }
+    let mut h = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    let bits = data.len() as u64 * 8;
+    for i in range(0u, 8).rev() {
+        msg.push((bits >> (i * 8)) as u8);
+    }
+    for chunk in msg.as_slice().chunks(64) {
+        let mut w = [0u32, ..80];
+        for i in range(0u, 16) {
+            w[i] = (chunk[4 * i] as u32 << 24) | (chunk[4 * i + 1] as u32 << 16) |
+                   (chunk[4 * i + 2] as u32 << 8) | chunk[4 * i + 3] as u32;
+        }
+        for i in range(16u, 80) {
+            w[i] = rotl(w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16], 1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0u, 80) {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5A827999u32),
+                20...39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = rotl(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotl(b, 30);
+            b = a;
+            a = temp;
+        }
+        h[0] += a;
+        h[1] += b;
+        h[2] += c;
+        h[3] += d;
+        h[4] += e;
+    }
+    let mut digest = [0u8, ..20];
+    for (i, &word) in h.iter().enumerate() {
+        for j in range(0u, 4) {
+            digest[4 * i + j] = (word >> (24 - 8 * j)) as u8;
+        }
+    }
+    digest
+}
+
+/// Writes a single, final WebSocket frame. Frames from clients have to be masked.
+fn write_frame<W: Writer>(w: &mut W, opcode: u8, payload: &[u8]) -> IoResult<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        frame.push(0x80 | 126);
+        frame.push((payload.len() >> 8) as u8);
+        frame.push(payload.len() as u8);
+    } else {
+        frame.push(0x80 | 127);
+        for i in range(0u, 8).rev() {
+            frame.push((payload.len() as u64 >> (i * 8)) as u8);
+        }
+    }
+    let mut mask = [0u8, ..4];
+    task_rng().fill_bytes(mask);
+    frame.push_all(mask);
+    frame.extend(payload.iter().enumerate().map(|(i, &b)| b ^ mask[i % 4]));
+    try!(w.write(frame.as_slice()));
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_frame, read_frame, accept_key, sha1};
+    use std::io::{MemReader, MemWriter, InvalidInput};
+    use serialize::hex::ToHex;
+
+    #[test]
+    fn test_write_frame() {
+        let mut w = MemWriter::new();
+        write_frame(&mut w, 0x1, b"PING x").unwrap();
+        let frame = w.unwrap();
+        assert_eq!(frame.slice_to(2), [0x81u8, 0x86].as_slice());
+        let mask = frame.slice(2, 6).to_vec();
+        let payload = frame.slice_from(6).iter().enumerate().map(|(i, &b)| b ^ mask[i % 4])
+                           .collect::<Vec<u8>>();
+        assert_eq!(payload, b"PING x".to_vec());
+
+        let mut w = MemWriter::new();
+        write_frame(&mut w, 0x2, [0u8, ..300].as_slice()).unwrap();
+        let frame = w.unwrap();
+        assert_eq!(frame.slice_to(4), [0x82u8, 0xfe, 0x01, 0x2c].as_slice());
+        assert_eq!(frame.len(), 4 + 4 + 300);
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let mut r = MemReader::new(vec![0x81, 0x02, b'h', b'i', 0x01, 0x7e, 0x01, 0x00]);
+        assert_eq!(read_frame(&mut r, 100).unwrap(), (true, 0x1, b"hi".to_vec()));
+        // 256 bytes is over the limit
+        assert_eq!(read_frame(&mut r, 100).unwrap_err().kind, InvalidInput);
+        // a 64-bit length isn't trusted either
+        let mut r = MemReader::new(vec![0x82, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(read_frame(&mut r, 100).unwrap_err().kind, InvalidInput);
+        let mut r = MemReader::new(vec![0x89, 0x82, 1, 2, 3, 4, b'a' ^ 1, b'b' ^ 2]);
+        assert_eq!(read_frame(&mut r, 100).unwrap(), (true, 0x9, b"ab".to_vec()));
+    }
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(sha1(b"abc").as_slice().to_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d".to_string());
+        assert_eq!(sha1(b"").as_slice().to_hex(), "da39a3ee5e6b4b0d3255bfef95601890afd80709".to_string());
+        // the example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string());
+    }
+}
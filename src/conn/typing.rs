@@ -0,0 +1,143 @@
+//! Typing notifications, the `+typing` client tag
+
+use conn::{Conn, Line, Typing};
+
+/// How often `active` is sent at most to the same target, in nanoseconds.
+/// Receivers assume we stopped typing if they don't hear from us for longer
+/// than that.
+static ACTIVE_INTERVAL_NS: u64 = 3 * 1_000_000_000;
+
+/// What someone is doing with the message they're writing
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum TypingState {
+    /// They're typing
+    TypingActive,
+    /// They stopped typing, but haven't cleared the input
+    TypingPaused,
+    /// They cleared the input or sent the message
+    TypingDone
+}
+
+impl TypingState {
+    /// Returns the state for a `+typing` tag value
+    pub fn parse(value: &str) -> Option<TypingState> {
+        match value {
+            "active" => Some(TypingActive),
+            "paused" => Some(TypingPaused),
+            "done" => Some(TypingDone),
+            _ => None
+        }
+    }
+
+    /// Returns the `+typing` tag value for the state
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            TypingActive => "active",
+            TypingPaused => "paused",
+            TypingDone => "done"
+        }
+    }
+}
+
+/// When `active` was last sent, by casemapped target
+struct TypingSent(Vec<(Vec<u8>, u64)>);
+
+impl<'a> Conn<'a> {
+    /// Tells `target`, a channel or nick, whether we're typing, with a TAGMSG
+    /// carrying the `+typing` tag. Returns `false` if nothing could be sent
+    /// because the server hasn't enabled the "message-tags" capability, which
+    /// has to be asked for with `Options.caps`.
+    ///
+    /// `active` can be set on every keystroke: it's only sent again to the
+    /// same target after 3 seconds.
+    pub fn set_typing(&mut self, target: &[u8], state: TypingState) -> bool {
+        if !self.has_cap("message-tags") {
+            return false;
+        }
+        let key = self.isupport.casemapping().lower(target);
+        let now = self.now();
+        {
+            let &TypingSent(ref mut sent) = self.extensions.get_or_insert_with(|| TypingSent(Vec::new()));
+            let recent = sent.iter().find(|&&(ref t, _)| *t == key)
+                             .map_or(false, |&(_, at)| now - at < ACTIVE_INTERVAL_NS);
+            if state == TypingActive && recent {
+                return true;
+            }
+            sent.retain(|&(ref t, _)| *t != key);
+            if state == TypingActive {
+                sent.push((key, now));
+            }
+        }
+        let mut line = b"@+typing=".to_vec();
+        line.push_all(state.as_str().as_bytes());
+        line.push_all(b" TAGMSG ");
+        line.push_all(target);
+        self.send_raw(line.as_slice());
+        true
+    }
+}
+
+/// Forgets when `active` was sent, since the targets were casemapped
+/// differently. At worst it's sent again a little early.
+pub fn rekey(conn: &mut Conn) {
+    conn.extensions.remove::<TypingSent>();
+}
+
+/// Sends a Typing event for a TAGMSG with a `+typing` tag
+pub fn TAGMSG(conn: &mut Conn, line: &Line) {
+    // @+typing=active :nick!user@host TAGMSG <target>
+    let state = match line.tag("+typing").and_then(|v| TypingState::parse(v)) {
+        None => return,
+        Some(state) => state
+    };
+    let user = match line.prefix {
+        Some(ref user) if !user.is_server(&conn.state) => user.clone(),
+        _ => return
+    };
+    let target = match line.args.as_slice().head() {
+        None => return,
+        Some(target) => target.clone()
+    };
+    if conn.isupport.casemapping().eq_ignore_case(user.nick(), conn.user.nick()) {
+        // echo-message, or another client of a bouncer
+        return;
+    }
+    conn.emit(Typing(user, target, state));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use conn::{Manual, Options, Event, Typing};
+    use super::{TypingActive, TypingPaused, TypingDone};
+
+    #[test]
+    fn typing() {
+        let mut opts: Options<Vec<Event>> = Options::new("irc.example.net", 6667);
+        opts.caps = vec!["message-tags"];
+        let mut conn = Manual::new(opts, Vec::new());
+        let second = Duration::seconds(1);
+        conn.step([], Duration::zero());
+        assert!(!conn.conn().set_typing(b"#rust", TypingActive));
+        conn.step([b":irc.example.net CAP * LS :message-tags",
+                   b":irc.example.net CAP * ACK :message-tags",
+                   b":irc.example.net 001 ircnick :Welcome"], Duration::zero());
+        assert!(conn.conn().set_typing(b"#rust", TypingActive));
+        assert_eq!(conn.step([], second), vec![b"@+typing=active TAGMSG #rust".to_vec()]);
+        // too soon to repeat
+        conn.conn().set_typing(b"#rust", TypingActive);
+        assert!(conn.step([], Duration::seconds(2)).is_empty());
+        conn.conn().set_typing(b"#rust", TypingActive);
+        conn.conn().set_typing(b"#rust", TypingDone);
+        assert_eq!(conn.step([], second),
+                   vec![b"@+typing=active TAGMSG #rust".to_vec(), b"@+typing=done TAGMSG #rust".to_vec()]);
+        conn.events();
+        conn.step([b"@+typing=paused :alice!a@host TAGMSG #rust",
+                   b"@+typing=bogus :bob!b@host TAGMSG #rust"], Duration::zero());
+        let typing = conn.events().into_iter().filter_map(|e| match e {
+            Typing(user, target, state) => Some((user.nick().to_vec(), target, state)),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(typing, vec![(b"alice".to_vec(), b"#rust".to_vec(), TypingPaused)]);
+    }
+}
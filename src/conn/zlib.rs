@@ -0,0 +1,350 @@
+//! A zlib-compressed link to the server, for bouncers that offer one.
+//! Only built with the "zlib" feature, and linked against the system's libz.
+
+use std::io;
+use std::io::{IoError, IoResult, TcpStream};
+use std::mem;
+use libc::{c_char, c_int, c_uint, c_ulong, c_void};
+
+use conn::{Connection, Transport, TlsOptions, TcpLineReader, TcpLineWriter};
+
+/// Compresses the link.
+///
+/// Each side of the connection is a single zlib stream, flushed with
+/// Z_SYNC_FLUSH after every line so the other end can decompress it right
+/// away. When `Options.tls` is set, the handshake is done by the
+/// `TlsConnector` and the compression goes on top of the TLS stream.
+pub struct ZlibTransport {
+    tls: Option<Box<TlsConnector + Send>>
+}
+
+impl ZlibTransport {
+    /// Returns a ZlibTransport for plain TCP connections. It fails to connect
+    /// if TLS is asked for.
+    pub fn new() -> ZlibTransport {
+        ZlibTransport { tls: None }
+    }
+
+    /// Returns a ZlibTransport that uses `connector` for TLS
+    pub fn with_tls(connector: Box<TlsConnector + Send>) -> ZlibTransport {
+        ZlibTransport { tls: Some(connector) }
+    }
+}
+
+impl Transport for ZlibTransport {
+    fn connect(&mut self, host: &str, port: u16, tls: Option<&TlsOptions>) -> IoResult<Connection> {
+        let (opts, connector) = match (tls, self.tls.as_mut()) {
+            (None, _) => {
+                let socket = try!(TcpStream::connect((host, port)));
+                let reader = try!(ZlibReader::new(socket.clone()));
+                let writer = try!(ZlibWriter::new(socket.clone()));
+                return Ok(Connection {
+                    reader: box TcpLineReader::new(reader),
+                    writer: box TcpLineWriter::new(writer),
+                    socket: socket,
+                    tls_session: None
+                });
+            }
+            (Some(opts), Some(connector)) => (opts, connector),
+            (Some(_), None) => return Err(IoError {
+                kind: io::InvalidInput,
+                desc: "TLS isn't supported by this transport",
+                detail: None
+            })
+        };
+        let socket = try!(TcpStream::connect((host, port)));
+        let stream = try!(connector.handshake(socket.clone(), host, opts));
+        let reader = try!(ZlibReader::new(stream.reader));
+        let writer = try!(ZlibWriter::new(stream.writer));
+        Ok(Connection {
+            reader: box TcpLineReader::new(reader),
+            writer: box TcpLineWriter::new(writer),
+            socket: socket,
+            tls_session: stream.session
+        })
+    }
+}
+
+/// Sets up TLS for a `ZlibTransport`. The library doesn't include TLS, so
+/// this is how one is plugged in underneath the compression.
+pub trait TlsConnector {
+    /// Does the handshake over `socket`, which is connected to `host`.
+    /// `tls` is `Options.tls`, with the session to resume filled in.
+    fn handshake(&mut self, socket: TcpStream, host: &str, tls: &TlsOptions) -> IoResult<TlsStream>;
+}
+
+/// A TLS stream returned by `TlsConnector::handshake()`
+pub struct TlsStream {
+    /// The reading half of the decrypted stream
+    pub reader: Box<Reader + Send>,
+    /// The writing half of the decrypted stream
+    pub writer: Box<Writer + Send>,
+    /// The session that was set up, see `Connection.tls_session`
+    pub session: Option<Vec<u8>>
+}
+
+/// Decompresses a zlib stream as it's read from `R`
+pub struct ZlibReader<R> {
+    inner: R,
+    stream: Box<ZStream>,
+    /// Compressed bytes read from `inner`, of which the last `stream.avail_in`
+    /// haven't been decompressed yet
+    input: Vec<u8>
+}
+
+impl<R: Reader> ZlibReader<R> {
+    /// Returns a ZlibReader that reads the compressed stream from `inner`
+    pub fn new(inner: R) -> IoResult<ZlibReader<R>> {
+        let mut stream: Box<ZStream> = box unsafe { mem::zeroed() };
+        let rc = unsafe {
+            inflateInit_(&mut *stream, ZLIB_VERSION.as_ptr() as *const c_char, mem::size_of::<ZStream>() as c_int)
+        };
+        try!(check(rc));
+        Ok(ZlibReader { inner: inner, stream: stream, input: Vec::from_elem(BUF_SIZE, 0u8) })
+    }
+}
+
+impl<R: Reader> Reader for ZlibReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.stream.avail_in == 0 {
+                let n = try!(self.inner.read(self.input.as_mut_slice()));
+                self.stream.next_in = self.input.as_ptr();
+                self.stream.avail_in = n as c_uint;
+            }
+            self.stream.next_out = buf.as_mut_ptr();
+            self.stream.avail_out = buf.len() as c_uint;
+            let rc = unsafe { inflate(&mut *self.stream, Z_SYNC_FLUSH) };
+            if rc != Z_STREAM_END && rc != Z_BUF_ERROR {
+                try!(check(rc));
+            }
+            let n = buf.len() - self.stream.avail_out as uint;
+            if n > 0 {
+                return Ok(n);
+            }
+            if rc == Z_STREAM_END {
+                return Err(io::standard_error(io::EndOfFile));
+            }
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<R> Drop for ZlibReader<R> {
+    fn drop(&mut self) {
+        unsafe { inflateEnd(&mut *self.stream); }
+    }
+}
+
+/// Compresses what's written to it into a zlib stream written to `W`.
+/// Everything written so far is sent when it's flushed.
+pub struct ZlibWriter<W> {
+    inner: W,
+    stream: Box<ZStream>
+}
+
+impl<W: Writer> ZlibWriter<W> {
+    /// Returns a ZlibWriter that writes the compressed stream to `inner`
+    pub fn new(inner: W) -> IoResult<ZlibWriter<W>> {
+        let mut stream: Box<ZStream> = box unsafe { mem::zeroed() };
+        let rc = unsafe {
+            deflateInit_(&mut *stream, Z_DEFAULT_COMPRESSION, ZLIB_VERSION.as_ptr() as *const c_char,
+                         mem::size_of::<ZStream>() as c_int)
+        };
+        try!(check(rc));
+        Ok(ZlibWriter { inner: inner, stream: stream })
+    }
+
+    fn deflate(&mut self, buf: &[u8], flush: c_int) -> IoResult<()> {
+        let mut out = [0u8, ..BUF_SIZE];
+        self.stream.next_in = buf.as_ptr();
+        self.stream.avail_in = buf.len() as c_uint;
+        loop {
+            self.stream.next_out = out.as_mut_ptr();
+            self.stream.avail_out = out.len() as c_uint;
+            let rc = unsafe { deflate(&mut *self.stream, flush) };
+            if rc != Z_BUF_ERROR {
+                try!(check(rc));
+            }
+            let n = out.len() - self.stream.avail_out as uint;
+            try!(self.inner.write(out.slice_to(n)));
+            // deflate has more to give only if it filled the buffer
+            if self.stream.avail_out != 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Writer> Writer for ZlibWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.deflate(buf, Z_NO_FLUSH)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        try!(self.deflate([], Z_SYNC_FLUSH));
+        self.inner.flush()
+    }
+}
+
+#[unsafe_destructor]
+impl<W> Drop for ZlibWriter<W> {
+    fn drop(&mut self) {
+        unsafe { deflateEnd(&mut *self.stream); }
+    }
+}
+
+static BUF_SIZE: uint = 4096;
+
+/// The version of the zlib headers this was written against. libz only
+/// checks the major version.
+static ZLIB_VERSION: &'static [u8] = b"1.2.8\0";
+
+static Z_NO_FLUSH: c_int = 0;
+static Z_SYNC_FLUSH: c_int = 2;
+static Z_OK: c_int = 0;
+static Z_STREAM_END: c_int = 1;
+static Z_BUF_ERROR: c_int = -5;
+static Z_DEFAULT_COMPRESSION: c_int = -1;
+
+/// `z_stream` from zlib.h. zlib keeps a pointer to it, so it's boxed and
+/// never moved once it's initialized.
+#[repr(C)]
+struct ZStream {
+    next_in: *const u8,
+    avail_in: c_uint,
+    total_in: c_ulong,
+    next_out: *mut u8,
+    avail_out: c_uint,
+    total_out: c_ulong,
+    msg: *const c_char,
+    state: *mut c_void,
+    zalloc: *mut c_void,
+    zfree: *mut c_void,
+    opaque: *mut c_void,
+    data_type: c_int,
+    adler: c_ulong,
+    reserved: c_ulong
+}
+
+#[link(name = "z")]
+extern {
+    fn deflateInit_(strm: *mut ZStream, level: c_int, version: *const c_char, stream_size: c_int) -> c_int;
+    fn deflate(strm: *mut ZStream, flush: c_int) -> c_int;
+    fn deflateEnd(strm: *mut ZStream) -> c_int;
+    fn inflateInit_(strm: *mut ZStream, version: *const c_char, stream_size: c_int) -> c_int;
+    fn inflate(strm: *mut ZStream, flush: c_int) -> c_int;
+    fn inflateEnd(strm: *mut ZStream) -> c_int;
+}
+
+/// Turns a zlib return code other than Z_OK into an error
+fn check(rc: c_int) -> IoResult<()> {
+    if rc == Z_OK {
+        return Ok(());
+    }
+    Err(IoError {
+        kind: io::OtherIoError,
+        desc: "zlib error",
+        detail: Some(format!("return code {}", rc))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{MemReader, MemWriter, BufReader, EndOfFile};
+    use std::io::net::tcp::TcpListener;
+    use std::io::{Listener, Acceptor, IoResult, TcpStream};
+    use conn::{Transport, LineReader, LineWriter, TlsOptions};
+    use super::{ZlibReader, ZlibWriter, ZlibTransport, TlsConnector, TlsStream};
+
+    #[test]
+    fn test_roundtrip() {
+        let mut w = ZlibWriter::new(MemWriter::new()).unwrap();
+        w.write(b"PRIVMSG #rust :hi\r\n").unwrap();
+        w.flush().unwrap();
+        let first = w.inner.get_ref().len();
+        assert!(first > 0);
+        w.write(b"PRIVMSG #rust :hi again\r\n").unwrap();
+        // nothing is sent until it's flushed
+        assert_eq!(w.inner.get_ref().len(), first);
+        w.flush().unwrap();
+        let compressed = w.inner.get_ref().to_vec();
+
+        // a line can be read as soon as its flush has arrived
+        let mut r = ZlibReader::new(BufReader::new(compressed.slice_to(first))).unwrap();
+        assert_eq!(r.read_exact(19).unwrap(), b"PRIVMSG #rust :hi\r\n".to_vec());
+        let mut r = ZlibReader::new(MemReader::new(compressed)).unwrap();
+        assert_eq!(r.read_to_end().unwrap(), b"PRIVMSG #rust :hi\r\nPRIVMSG #rust :hi again\r\n".to_vec());
+        match ZlibReader::new(MemReader::new(b"not zlib".to_vec())).unwrap().read_to_end() {
+            Err(ref e) if e.kind != EndOfFile => (),
+            res => panic!("unexpected result {}", res)
+        }
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        spawn(proc() {
+            let stream = acceptor.accept().unwrap();
+            let mut w = ZlibWriter::new(stream).unwrap();
+            w.write(b":irc.example.net 001 ircnick :Welcome\r\n").unwrap();
+            w.flush().unwrap();
+        });
+        let mut connection = ZlibTransport::new().connect("127.0.0.1", port, None).unwrap();
+        assert_eq!(connection.reader.read_line().unwrap(), b":irc.example.net 001 ircnick :Welcome".to_vec());
+        assert!(ZlibTransport::new().connect("127.0.0.1", port, Some(&TlsOptions::new())).is_err());
+    }
+
+    /// Stands in for TLS: checks the handshake happens before anything is
+    /// compressed, and hands back the socket itself with a session
+    struct MockConnector {
+        handshakes: Sender<(String, Option<Vec<u8>>)>
+    }
+
+    impl TlsConnector for MockConnector {
+        fn handshake(&mut self, mut socket: TcpStream, host: &str, tls: &TlsOptions) -> IoResult<TlsStream> {
+            try!(socket.write(b"HELLO\n"));
+            assert_eq!(try!(socket.read_exact(6)), b"HELLO\n".to_vec());
+            self.handshakes.send((host.to_string(), tls.session.clone()));
+            Ok(TlsStream {
+                reader: box socket.clone(),
+                writer: box socket,
+                session: Some(vec![7u8])
+            })
+        }
+    }
+
+    #[test]
+    fn test_transport_tls() {
+        let mut listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let mut stream = acceptor.accept().unwrap();
+            // the handshake is uncompressed
+            assert_eq!(stream.read_exact(6).unwrap(), b"HELLO\n".to_vec());
+            stream.write(b"HELLO\n").unwrap();
+            let mut w = ZlibWriter::new(stream.clone()).unwrap();
+            w.write(b":irc.example.net 001 ircnick :Welcome\r\n").unwrap();
+            w.flush().unwrap();
+            let mut r = ZlibReader::new(stream).unwrap();
+            tx.send(r.read_exact(19).unwrap());
+        });
+        let (htx, hrx) = channel();
+        let mut transport = ZlibTransport::with_tls(box MockConnector { handshakes: htx });
+        let mut tls = TlsOptions::new();
+        tls.session = Some(vec![1u8]);
+        let mut connection = transport.connect("127.0.0.1", port, Some(&tls)).unwrap();
+        assert_eq!(hrx.recv(), ("127.0.0.1".to_string(), Some(vec![1u8])));
+        assert_eq!(connection.tls_session, Some(vec![7u8]));
+        assert_eq!(connection.reader.read_line().unwrap(), b":irc.example.net 001 ircnick :Welcome".to_vec());
+        connection.writer.write_line(b"PRIVMSG #rust :hi").unwrap();
+        assert_eq!(rx.recv(), b"PRIVMSG #rust :hi\r\n".to_vec());
+    }
+}
@@ -0,0 +1,213 @@
+//! Parsing of DCC requests
+//!
+//! DCC requests are sent as CTCP DCC queries. Only the requests are parsed
+//! here; making the connections and transferring files is up to the application.
+
+use std::io::net::ip::{IpAddr, Ipv4Addr};
+
+/// A DCC request
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Dcc {
+    /// DCC SEND: an offer to send us a file
+    DccSend(DccOffer),
+    /// DCC CHAT: an offer of a direct chat. The offer's filename is the chat
+    /// protocol, usually `chat`.
+    DccChat(DccOffer),
+    /// DCC RESUME: a request to resume a file we offered from a position
+    DccResume(DccPosition),
+    /// DCC ACCEPT: the answer to a DCC RESUME we sent
+    DccAccept(DccPosition)
+}
+
+/// The details of a DCC SEND or CHAT
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct DccOffer {
+    /// The file name, without any quotes
+    pub filename: Vec<u8>,
+    /// The IPv4 address to connect to, as a number
+    pub ip: u32,
+    /// The port to connect to. 0 for passive (reverse) DCC, where we're asked
+    /// to listen instead.
+    pub port: u16,
+    /// The size of the file, if given
+    pub size: Option<u64>,
+    /// The token identifying a passive DCC, if given
+    pub token: Option<Vec<u8>>
+}
+
+impl DccOffer {
+    /// Returns the address to connect to as an IpAddr
+    pub fn ip_addr(&self) -> IpAddr {
+        let ip = self.ip;
+        Ipv4Addr((ip >> 24) as u8, (ip >> 16) as u8, (ip >> 8) as u8, ip as u8)
+    }
+}
+
+/// The details of a DCC RESUME or ACCEPT
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct DccPosition {
+    /// The file name, without any quotes
+    pub filename: Vec<u8>,
+    /// The port of the transfer, identifying it. 0 for passive DCC.
+    pub port: u16,
+    /// The position in the file to resume from
+    pub position: u64,
+    /// The token identifying a passive DCC, if given
+    pub token: Option<Vec<u8>>
+}
+
+/// Parses the parameters of a CTCP DCC query, e.g.
+/// `SEND "my file.txt" 3232235777 5000 1024`.
+/// Returns None if the request is malformed or of an unknown type.
+pub fn parse(params: &[u8]) -> Option<Dcc> {
+    let (kind, rest) = match split_word(params) {
+        None => return None,
+        Some(v) => v
+    };
+    let (filename, rest) = match split_filename(rest) {
+        None => return None,
+        Some(v) => v
+    };
+    let words = rest.split(|&b| b == ' ' as u8).filter(|w| !w.is_empty()).collect::<Vec<_>>();
+    let num = |i: uint| -> Option<u64> {
+        if i < words.len() {
+            ::std::str::from_utf8(words[i]).and_then(|s| from_str::<u64>(s))
+        } else {
+            None
+        }
+    };
+    let token = |i: uint| if i < words.len() { Some(words[i].to_vec()) } else { None };
+    let port = |i: uint| num(i).and_then(|p| if p <= 0xffff { Some(p as u16) } else { None });
+
+    if is(kind, "SEND") || is(kind, "CHAT") {
+        let ip = match num(0) {
+            Some(ip) if ip <= 0xffffffff => ip as u32,
+            _ => return None
+        };
+        let port = match port(1) {
+            None => return None,
+            Some(port) => port
+        };
+        let offer = DccOffer {
+            filename: filename,
+            ip: ip,
+            port: port,
+            size: num(2),
+            token: token(3)
+        };
+        Some(if is(kind, "SEND") { DccSend(offer) } else { DccChat(offer) })
+    } else if is(kind, "RESUME") || is(kind, "ACCEPT") {
+        let (port, position) = match (port(0), num(1)) {
+            (Some(port), Some(position)) => (port, position),
+            _ => return None
+        };
+        let pos = DccPosition {
+            filename: filename,
+            port: port,
+            position: position,
+            token: token(2)
+        };
+        Some(if is(kind, "RESUME") { DccResume(pos) } else { DccAccept(pos) })
+    } else {
+        None
+    }
+}
+
+/// Compares the request type to an uppercase name, ignoring ASCII case
+fn is(kind: &[u8], name: &str) -> bool {
+    kind.len() == name.len() && kind.iter().zip(name.bytes()).all(|(&a, b)| {
+        (a as char).to_uppercase() == b as char
+    })
+}
+
+/// Splits off the first space-separated word
+fn split_word<'a>(v: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let v = trim_spaces(v);
+    if v.is_empty() {
+        return None;
+    }
+    Some(match v.position_elem(&(' ' as u8)) {
+        None => (v, [].as_slice()),
+        Some(idx) => (v.slice_to(idx), v.slice_from(idx+1))
+    })
+}
+
+/// Splits off the file name, which may be in double quotes if it contains spaces
+fn split_filename<'a>(v: &'a [u8]) -> Option<(Vec<u8>, &'a [u8])> {
+    let v = trim_spaces(v);
+    if v.starts_with(b"\"") {
+        let v = v.slice_from(1);
+        v.position_elem(&('"' as u8)).map(|idx| (v.slice_to(idx).to_vec(), v.slice_from(idx+1)))
+    } else {
+        split_word(v).map(|(name, rest)| (name.to_vec(), rest))
+    }
+}
+
+fn trim_spaces<'a>(mut v: &'a [u8]) -> &'a [u8] {
+    while v.starts_with(b" ") {
+        v = v.slice_from(1);
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, DccSend, DccChat, DccResume, DccAccept, DccOffer, DccPosition};
+    use std::io::net::ip::Ipv4Addr;
+
+    #[test]
+    fn test_parse_send() {
+        let dcc = parse(b"SEND \"my file.txt\" 3232235777 5000 1024");
+        assert_eq!(dcc, Some(DccSend(DccOffer {
+            filename: b"my file.txt".to_vec(),
+            ip: 3232235777,
+            port: 5000,
+            size: Some(1024),
+            token: None
+        })));
+        match dcc {
+            Some(DccSend(offer)) => assert_eq!(offer.ip_addr(), Ipv4Addr(192, 168, 1, 1)),
+            _ => fail!()
+        }
+        // passive
+        assert_eq!(parse(b"SEND file.txt 3232235777 0 1024 42"), Some(DccSend(DccOffer {
+            filename: b"file.txt".to_vec(),
+            ip: 3232235777,
+            port: 0,
+            size: Some(1024),
+            token: Some(b"42".to_vec())
+        })));
+        assert_eq!(parse(b"chat chat 16909060 1234"), Some(DccChat(DccOffer {
+            filename: b"chat".to_vec(),
+            ip: 16909060,
+            port: 1234,
+            size: None,
+            token: None
+        })));
+    }
+
+    #[test]
+    fn test_parse_resume() {
+        assert_eq!(parse(b"RESUME \"a b\" 5000 512"), Some(DccResume(DccPosition {
+            filename: b"a b".to_vec(),
+            port: 5000,
+            position: 512,
+            token: None
+        })));
+        assert_eq!(parse(b"ACCEPT file 0 512 42"), Some(DccAccept(DccPosition {
+            filename: b"file".to_vec(),
+            port: 0,
+            position: 512,
+            token: Some(b"42".to_vec())
+        })));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse(b""), None);
+        assert_eq!(parse(b"SEND \"unterminated 1 2"), None);
+        assert_eq!(parse(b"SEND file 1 70000"), None);
+        assert_eq!(parse(b"SEND file 99999999999 5000"), None);
+        assert_eq!(parse(b"FOO file 1 2"), None);
+    }
+}
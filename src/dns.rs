@@ -0,0 +1,250 @@
+//! SRV record lookups for finding a network's servers
+
+use std::io;
+use std::io::{File, BufferedReader, IoError, IoResult};
+use std::io::net::ip::{SocketAddr, Ipv4Addr, IpAddr};
+use std::io::net::udp::UdpSocket;
+use std::rand::{task_rng, Rng};
+
+/// A DNS SRV record
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct SrvRecord {
+    /// Lower values are tried first
+    pub priority: u16,
+    /// Relative weight among records with the same priority
+    pub weight: u16,
+    /// The port the service is on
+    pub port: u16,
+    /// The host the service is on
+    pub target: String
+}
+
+/// Looks up SRV records. See `Options.resolver`.
+pub trait Resolver {
+    /// Returns the SRV records for `name`, e.g. `_ircs._tcp.example.net`.
+    /// Returns an empty list if there are none.
+    fn resolve_srv(&mut self, name: &str) -> IoResult<Vec<SrvRecord>>;
+}
+
+/// A Resolver that asks the first nameserver in /etc/resolv.conf over UDP.
+/// This is the default.
+pub struct DnsResolver {
+    /// The nameserver to ask. If None, it's read from /etc/resolv.conf.
+    pub nameserver: Option<SocketAddr>,
+    /// How long to wait for an answer, in milliseconds
+    pub timeout_ms: u64
+}
+
+impl DnsResolver {
+    /// Returns a DnsResolver using the system's nameserver
+    pub fn new() -> DnsResolver {
+        DnsResolver { nameserver: None, timeout_ms: 5000 }
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn resolve_srv(&mut self, name: &str) -> IoResult<Vec<SrvRecord>> {
+        let server = match self.nameserver {
+            Some(addr) => addr,
+            None => try!(system_nameserver())
+        };
+        let id = task_rng().gen::<u16>();
+        let query = srv_query(id, name);
+        let mut socket = try!(UdpSocket::bind(SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 }));
+        socket.set_timeout(Some(self.timeout_ms));
+        try!(socket.send_to(query.as_slice(), server));
+        let mut buf = [0u8, ..4096];
+        loop {
+            let (len, from) = try!(socket.recv_from(buf));
+            if from != server {
+                continue;
+            }
+            match parse_srv_response(id, buf.slice_to(len)) {
+                // not the answer to our query
+                None if len < 2 || ((buf[0] as u16) << 8 | buf[1] as u16) != id => continue,
+                None => return Err(IoError {
+                    kind: io::OtherIoError,
+                    desc: "malformed DNS response",
+                    detail: None
+                }),
+                Some(records) => return Ok(records)
+            }
+        }
+    }
+}
+
+/// Returns the first nameserver from /etc/resolv.conf
+fn system_nameserver() -> IoResult<SocketAddr> {
+    let file = try!(File::open(&Path::new("/etc/resolv.conf")));
+    for line in BufferedReader::new(file).lines() {
+        let line = try!(line);
+        let mut words = line.as_slice().words();
+        if words.next() != Some("nameserver") {
+            continue;
+        }
+        match words.next().and_then(|ip| from_str::<IpAddr>(ip)) {
+            Some(ip) => return Ok(SocketAddr { ip: ip, port: 53 }),
+            None => ()
+        }
+    }
+    Err(IoError { kind: io::OtherIoError, desc: "no nameserver in /etc/resolv.conf", detail: None })
+}
+
+/// Orders SRV records the way RFC 2782 says to try them: by priority, and
+/// randomly weighted by weight within a priority. Records for "." (meaning
+/// the service isn't available) are dropped.
+pub fn order_records(records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    let mut records = records.into_iter().filter(|r| r.target.as_slice() != ".")
+                                         .collect::<Vec<_>>();
+    records.sort_by(|a, b| a.priority.cmp(&b.priority));
+    let mut res = Vec::with_capacity(records.len());
+    let mut rng = task_rng();
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let count = records.iter().take_while(|r| r.priority == priority).count();
+        let total = records.iter().take(count).fold(0u, |sum, r| sum + r.weight as uint);
+        let mut pick = if total == 0 { 0 } else { rng.gen_range(0, total + 1) };
+        let mut idx = 0;
+        for (i, r) in records.iter().take(count).enumerate() {
+            idx = i;
+            if pick <= r.weight as uint {
+                break;
+            }
+            pick -= r.weight as uint;
+        }
+        res.push(records.remove(idx).unwrap());
+    }
+    res
+}
+
+/// Builds a DNS query for the SRV records of `name`
+fn srv_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(18 + name.len());
+    // header: id, recursion desired, 1 question
+    query.push_all([(id >> 8) as u8, id as u8, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        query.push(label.len() as u8);
+        query.push_all(label.as_bytes());
+    }
+    // root label, type SRV (33), class IN
+    query.push_all([0, 0, 33, 0, 1]);
+    query
+}
+
+/// Parses the SRV records out of a DNS response with the given id.
+/// Returns None if the response is malformed or has the wrong id.
+fn parse_srv_response(id: u16, msg: &[u8]) -> Option<Vec<SrvRecord>> {
+    fn u16_at(msg: &[u8], pos: uint) -> Option<u16> {
+        if pos + 2 > msg.len() { None } else { Some((msg[pos] as u16) << 8 | msg[pos+1] as u16) }
+    }
+    if msg.len() < 12 || u16_at(msg, 0) != Some(id) {
+        return None;
+    }
+    let (questions, answers) = (u16_at(msg, 4).unwrap(), u16_at(msg, 6).unwrap());
+    let mut pos = 12;
+    for _ in range(0, questions) {
+        let (_, next) = match read_name(msg, pos) { None => return None, Some(v) => v };
+        pos = next + 4;
+    }
+    let mut records = Vec::new();
+    for _ in range(0, answers) {
+        let (_, next) = match read_name(msg, pos) { None => return None, Some(v) => v };
+        let rtype = match u16_at(msg, next) { None => return None, Some(t) => t };
+        let rdlen = match u16_at(msg, next + 8) { None => return None, Some(l) => l as uint };
+        let rdata = next + 10;
+        if rdata + rdlen > msg.len() {
+            return None;
+        }
+        if rtype == 33 && rdlen >= 7 {
+            let target = match read_name(msg, rdata + 6) { None => return None, Some((t, _)) => t };
+            records.push(SrvRecord {
+                priority: u16_at(msg, rdata).unwrap(),
+                weight: u16_at(msg, rdata + 2).unwrap(),
+                port: u16_at(msg, rdata + 4).unwrap(),
+                target: target
+            });
+        }
+        pos = rdata + rdlen;
+    }
+    Some(records)
+}
+
+/// Reads a possibly compressed domain name at `pos`. Returns the name and
+/// the position right after it.
+fn read_name(msg: &[u8], mut pos: uint) -> Option<(String, uint)> {
+    let mut name = String::new();
+    let mut end = None;
+    // bound the number of pointers followed, in case they loop
+    for _ in range(0u, 128) {
+        if pos >= msg.len() {
+            return None;
+        }
+        let len = msg[pos] as uint;
+        if len & 0xc0 == 0xc0 {
+            if pos + 1 >= msg.len() {
+                return None;
+            }
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            pos = (len & 0x3f) << 8 | msg[pos+1] as uint;
+        } else if len == 0 {
+            if name.is_empty() {
+                name.push('.');
+            }
+            return Some((name, end.unwrap_or(pos + 1)));
+        } else {
+            if pos + 1 + len > msg.len() {
+                return None;
+            }
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(String::from_utf8_lossy(msg.slice(pos + 1, pos + 1 + len)).as_slice());
+            pos += 1 + len;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{srv_query, parse_srv_response, order_records, SrvRecord};
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord { priority: priority, weight: weight, port: 6697, target: target.to_string() }
+    }
+
+    #[test]
+    fn test_parse_srv_response() {
+        let mut msg = srv_query(0x1234, "_ircs._tcp.example.net");
+        // turn the query into a response with one answer
+        *msg.get_mut(2) = 0x81;
+        *msg.get_mut(7) = 1;
+        // name: pointer to the question, type SRV, class IN, ttl, rdlength
+        msg.push_all([0xc0, 12, 0, 33, 0, 1, 0, 0, 0x0e, 0x10, 0, 12]);
+        // priority 10, weight 5, port 6697, target irc.example.net
+        msg.push_all([0, 10, 0, 5, 0x1a, 0x29, 3]);
+        msg.push_all(b"irc");
+        // "example.net" is at offset 12 + 1 + 5 + 1 + 4
+        msg.push_all([0xc0, 23]);
+        assert_eq!(parse_srv_response(0x1234, msg.as_slice()), Some(vec![SrvRecord {
+            priority: 10,
+            weight: 5,
+            port: 6697,
+            target: "irc.example.net".to_string()
+        }]));
+        assert_eq!(parse_srv_response(0x4321, msg.as_slice()), None);
+        assert_eq!(parse_srv_response(0x1234, msg.slice_to(msg.len() - 3)), None);
+    }
+
+    #[test]
+    fn test_order_records() {
+        let ordered = order_records(vec![record(20, 0, "c"), record(10, 0, "a"),
+                                         record(30, 0, "."), record(10, 0, "b")]);
+        let targets = ordered.iter().map(|r| r.target.as_slice()).collect::<Vec<_>>();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(targets[2], "c");
+        assert!(targets.slice_to(2).contains(&"a") && targets.slice_to(2).contains(&"b"));
+    }
+}
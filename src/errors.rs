@@ -0,0 +1,214 @@
+//! Structured error replies from the server
+
+use conn::{Line, IRCCmd, IRCCode};
+
+/// The kind of an error reply. The common ones have their own variant.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ErrorKind {
+    /// 401: the nick or channel doesn't exist
+    NoSuchNick,
+    /// 402: the server doesn't exist
+    NoSuchServer,
+    /// 403: the channel doesn't exist
+    NoSuchChannel,
+    /// 404: we can't send to the channel, e.g. because it's moderated
+    CannotSendToChan,
+    /// 405: we've joined too many channels
+    TooManyChannels,
+    /// 406: there was no such nick (reply to WHOWAS)
+    WasNoSuchNick,
+    /// 421: the server doesn't know the command
+    UnknownCommand,
+    /// 441: the nick isn't in the channel
+    UserNotInChannel,
+    /// 442: we aren't in the channel
+    NotOnChannel,
+    /// 443: the nick is already in the channel
+    UserOnChannel,
+    /// 461: the command needs more parameters
+    NeedMoreParams,
+    /// 464: the server password was wrong
+    PasswdMismatch,
+    /// 465: we're banned from the server
+    YoureBannedCreep,
+    /// 471: the channel is full (+l)
+    ChannelIsFull,
+    /// 473: the channel is invite-only (+i)
+    InviteOnlyChan,
+    /// 474: we're banned from the channel (+b)
+    BannedFromChan,
+    /// 475: we gave the wrong channel key (+k)
+    BadChannelKey,
+    /// 477: we need to be identified to services
+    NeedReggedNick,
+    /// 481: we aren't an IRC operator
+    NoPrivileges,
+    /// 482: we aren't a channel operator
+    ChanOPrivsNeeded,
+    /// Any other 4xx or 5xx numeric
+    OtherError
+}
+
+/// An error reply (a 4xx or 5xx numeric) from the server
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct IrcError {
+    /// The kind of error
+    pub kind: ErrorKind,
+    /// The numeric
+    pub code: uint,
+    /// What the error is about: a nick, channel or command, depending on the error.
+    /// None if the reply only has a message.
+    pub target: Option<Vec<u8>>,
+    /// The human-readable message from the server
+    pub message: Vec<u8>
+}
+
+impl IrcError {
+    /// Returns the IrcError for a line, if it's an error reply
+    pub fn from_line(line: &Line) -> Option<IrcError> {
+        let code = match line.command {
+            IRCCode(code) if code >= 400 && code < 600 => code,
+            _ => return None
+        };
+        let kind = match code {
+            401 => NoSuchNick,
+            402 => NoSuchServer,
+            403 => NoSuchChannel,
+            404 => CannotSendToChan,
+            405 => TooManyChannels,
+            406 => WasNoSuchNick,
+            421 => UnknownCommand,
+            441 => UserNotInChannel,
+            442 => NotOnChannel,
+            443 => UserOnChannel,
+            461 => NeedMoreParams,
+            464 => PasswdMismatch,
+            465 => YoureBannedCreep,
+            471 => ChannelIsFull,
+            473 => InviteOnlyChan,
+            474 => BannedFromChan,
+            475 => BadChannelKey,
+            477 => NeedReggedNick,
+            481 => NoPrivileges,
+            482 => ChanOPrivsNeeded,
+            _ => OtherError
+        };
+        // :server 401 me target :message
+        let args = line.args.as_slice();
+        Some(IrcError {
+            kind: kind,
+            code: code,
+            target: if args.len() > 2 { Some(args[1].clone()) } else { None },
+            message: if args.len() > 1 { args[args.len()-1].clone() } else { Vec::new() }
+        })
+    }
+}
+
+/// How serious an IRCv3 standard reply is
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ReplySeverity {
+    /// FAIL: the command failed
+    Fail,
+    /// WARN: the command worked, but something about it should be fixed
+    Warn,
+    /// NOTE: just information
+    Note
+}
+
+/// An IRCv3 standard reply: a FAIL, WARN or NOTE line, e.g.
+/// `FAIL CHATHISTORY MESSAGE_ERROR the_given_command :Messages could not be retrieved`.
+/// Modern servers and bouncers like Ergo and soju use these instead of numerics.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct StandardReply {
+    /// FAIL, WARN or NOTE
+    pub severity: ReplySeverity,
+    /// The command the reply is about, or `*` if it isn't about one
+    pub command: Vec<u8>,
+    /// The machine-readable code, e.g. `ACCOUNT_REQUIRED`
+    pub code: Vec<u8>,
+    /// The parameters between the code and the description, which depend on
+    /// the command and code
+    pub context: Vec<Vec<u8>>,
+    /// The human-readable description
+    pub description: Vec<u8>
+}
+
+impl StandardReply {
+    /// Returns the StandardReply for a line, if it's a FAIL, WARN or NOTE
+    pub fn from_line(line: &Line) -> Option<StandardReply> {
+        let severity = match line.command {
+            IRCCmd(ref cmd) => match cmd.as_slice() {
+                "FAIL" => Fail,
+                "WARN" => Warn,
+                "NOTE" => Note,
+                _ => return None
+            },
+            _ => return None
+        };
+        // FAIL <command> <code> [<context>...] :<description>
+        let args = line.args.as_slice();
+        if args.len() < 3 {
+            return None;
+        }
+        Some(StandardReply {
+            severity: severity,
+            command: args[0].clone(),
+            code: args[1].clone(),
+            context: args.slice(2, args.len() - 1).to_vec(),
+            description: args[args.len()-1].clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IrcError, ChanOPrivsNeeded, OtherError, PasswdMismatch};
+    use super::{StandardReply, Fail, Note};
+    use conn::Line;
+
+    #[test]
+    fn test_from_line() {
+        let line = Line::parse(b":srv 482 me #chan :You're not channel operator").unwrap();
+        assert_eq!(IrcError::from_line(&line), Some(IrcError {
+            kind: ChanOPrivsNeeded,
+            code: 482,
+            target: Some(b"#chan".to_vec()),
+            message: b"You're not channel operator".to_vec()
+        }));
+
+        let line = Line::parse(b":srv 464 me :Password incorrect").unwrap();
+        let err = IrcError::from_line(&line).unwrap();
+        assert_eq!(err.kind, PasswdMismatch);
+        assert_eq!(err.target, None);
+
+        let line = Line::parse(b":srv 599 me x :whatever").unwrap();
+        assert_eq!(IrcError::from_line(&line).map(|e| e.kind), Some(OtherError));
+
+        let line = Line::parse(b":srv 376 me :End of /MOTD command.").unwrap();
+        assert_eq!(IrcError::from_line(&line), None);
+    }
+
+    #[test]
+    fn test_standard_reply() {
+        let line = Line::parse(b":srv FAIL CHATHISTORY MESSAGE_ERROR LATEST #rust :Messages could not be retrieved")
+                       .unwrap();
+        assert_eq!(StandardReply::from_line(&line), Some(StandardReply {
+            severity: Fail,
+            command: b"CHATHISTORY".to_vec(),
+            code: b"MESSAGE_ERROR".to_vec(),
+            context: vec![b"LATEST".to_vec(), b"#rust".to_vec()],
+            description: b"Messages could not be retrieved".to_vec()
+        }));
+
+        let line = Line::parse(b":srv NOTE * OPER_MESSAGE :The message").unwrap();
+        let reply = StandardReply::from_line(&line).unwrap();
+        assert_eq!(reply.severity, Note);
+        assert_eq!(reply.command, b"*".to_vec());
+        assert!(reply.context.is_empty());
+
+        let line = Line::parse(b":srv WARN REHASH").unwrap();
+        assert_eq!(StandardReply::from_line(&line), None);
+        let line = Line::parse(b":srv 401 me nick :No such nick").unwrap();
+        assert_eq!(StandardReply::from_line(&line), None);
+    }
+}
@@ -0,0 +1,119 @@
+//! Building extended bans
+//!
+//! Extended bans match on something other than a hostmask, e.g. a services
+//! account. Every ircd spells them differently (`$a:name` on charybdis,
+//! `~a:name` on Unreal, `R:name` on InspIRCd), so an ExtBan is only turned
+//! into a mask once the server is known.
+
+use isupport::ExtBans;
+use quirks::{Ircd, InspIRCd};
+
+/// What an extended ban matches on
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ExtBanKind {
+    /// Users logged in to a services account
+    ExtAccount,
+    /// Users whose realname matches a glob
+    ExtRealname,
+    /// Users in a channel
+    ExtChannel,
+    /// Users on a server matching a glob
+    ExtServer,
+    /// Users matching a hostmask, who may stay but can't speak
+    ExtMute
+}
+
+/// An extended ban
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ExtBan {
+    /// What the ban matches on
+    pub kind: ExtBanKind,
+    /// The account, glob, channel or mask to match
+    pub value: Vec<u8>
+}
+
+impl ExtBan {
+    /// Bans users logged in to `account`
+    pub fn account(account: &[u8]) -> ExtBan {
+        ExtBan { kind: ExtAccount, value: account.to_vec() }
+    }
+
+    /// Bans users whose realname matches `glob`
+    pub fn realname_glob(glob: &[u8]) -> ExtBan {
+        ExtBan { kind: ExtRealname, value: glob.to_vec() }
+    }
+
+    /// Bans users who are in `chan`
+    pub fn channel(chan: &[u8]) -> ExtBan {
+        ExtBan { kind: ExtChannel, value: chan.to_vec() }
+    }
+
+    /// Bans users on servers matching `glob`
+    pub fn server(glob: &[u8]) -> ExtBan {
+        ExtBan { kind: ExtServer, value: glob.to_vec() }
+    }
+
+    /// Mutes users matching the hostmask `mask`
+    pub fn mute(mask: &[u8]) -> ExtBan {
+        ExtBan { kind: ExtMute, value: mask.to_vec() }
+    }
+
+    /// Returns the extban type letter used for this ban by `ircd`, or None
+    /// if it has none. Unknown ircds are assumed to follow charybdis and Unreal.
+    pub fn letter(&self, ircd: &Ircd) -> Option<char> {
+        let insp = *ircd == InspIRCd;
+        match self.kind {
+            ExtAccount => Some(if insp { 'R' } else { 'a' }),
+            ExtRealname => Some('r'),
+            ExtChannel => Some(if insp { 'j' } else { 'c' }),
+            ExtServer => Some('s'),
+            ExtMute => Some(if insp { 'm' } else { 'q' })
+        }
+    }
+
+    /// Returns the ban mask to set with +b, e.g. `$a:name`. Returns None if
+    /// the server doesn't advertise the needed extban type in EXTBAN.
+    pub fn to_mask(&self, ircd: &Ircd, extbans: &ExtBans) -> Option<Vec<u8>> {
+        let letter = match self.letter(ircd) {
+            Some(l) if extbans.types.contains(&l) => l,
+            _ => return None
+        };
+        let mut mask = Vec::with_capacity(self.value.len() + 3);
+        match extbans.prefix {
+            None => (),
+            Some(prefix) => mask.push(prefix as u8)
+        }
+        mask.push(letter as u8);
+        mask.push(':' as u8);
+        mask.push_all(self.value.as_slice());
+        Some(mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExtBan;
+    use isupport::ExtBans;
+    use quirks::{Charybdis, InspIRCd, Unreal};
+
+    #[test]
+    fn test_to_mask() {
+        let charybdis = ExtBans { prefix: Some('$'), types: vec!['a', 'c', 'j', 'r', 's', 'x'] };
+        let unreal = ExtBans { prefix: Some('~'), types: vec!['q', 'j', 'n', 'c', 'r', 'a'] };
+        let insp = ExtBans { prefix: None, types: vec!['R', 'j', 'm', 'r', 's'] };
+
+        let ban = ExtBan::account(b"spammer");
+        assert_eq!(ban.to_mask(&Charybdis, &charybdis), Some(b"$a:spammer".to_vec()));
+        assert_eq!(ban.to_mask(&Unreal, &unreal), Some(b"~a:spammer".to_vec()));
+        assert_eq!(ban.to_mask(&InspIRCd, &insp), Some(b"R:spammer".to_vec()));
+
+        let ban = ExtBan::realname_glob(b"*bot*");
+        assert_eq!(ban.to_mask(&Charybdis, &charybdis), Some(b"$r:*bot*".to_vec()));
+        assert_eq!(ExtBan::channel(b"#spam").to_mask(&InspIRCd, &insp), Some(b"j:#spam".to_vec()));
+        assert_eq!(ExtBan::mute(b"*!*@host").to_mask(&Unreal, &unreal), Some(b"~q:*!*@host".to_vec()));
+
+        // not advertised
+        assert_eq!(ExtBan::mute(b"*!*@host").to_mask(&Charybdis, &charybdis), None);
+        assert_eq!(ExtBan::server(b"*.net").to_mask(&Unreal, &unreal), None);
+    }
+}
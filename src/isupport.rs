@@ -0,0 +1,387 @@
+//! Server feature advertisement (RPL_ISUPPORT, numeric 005)
+
+use std::collections::HashMap;
+use std::str;
+
+/// The casemapping rules used by a server to compare nicks and channel names
+#[deriving(PartialEq, Eq, Clone, Show)]
+pub enum CaseMapping {
+    /// Only A-Z are the uppercase versions of a-z
+    CaseAscii,
+    /// Like CaseAscii, but also []\^ are the uppercase versions of {}|~
+    CaseRfc1459,
+    /// Like CaseRfc1459, but without ~ and ^
+    CaseStrictRfc1459
+}
+
+impl CaseMapping {
+    /// Returns the lowercase version of a single byte
+    pub fn to_lower(&self, b: u8) -> u8 {
+        match b as char {
+            'A'...'Z' => b - 'A' as u8 + 'a' as u8,
+            '[' | ']' | '\\' if *self != CaseAscii => b - '[' as u8 + '{' as u8,
+            '^' if *self == CaseRfc1459 => '~' as u8,
+            _ => b
+        }
+    }
+
+    /// Returns the lowercase version of a nick or channel name
+    pub fn lower(&self, s: &[u8]) -> Vec<u8> {
+        s.iter().map(|&b| self.to_lower(b)).collect()
+    }
+
+    /// Returns `true` if the two names are equal under this casemapping
+    pub fn eq_ignore_case(&self, a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(&x, &y)| {
+            self.to_lower(x) == self.to_lower(y)
+        })
+    }
+
+    /// Returns `true` if `s` matches the glob `pattern` under this casemapping.
+    /// `*` matches any run of characters and `?` matches a single one, as in
+    /// ban masks.
+    pub fn glob_match(&self, pattern: &[u8], s: &[u8]) -> bool {
+        // backtracking matcher: on a mismatch, let the last * eat one more char
+        let (mut p, mut i) = (0u, 0u);
+        let mut star: Option<(uint, uint)> = None;
+        while i < s.len() {
+            if p < pattern.len() && pattern[p] == '*' as u8 {
+                star = Some((p, i));
+                p += 1;
+            } else if p < pattern.len() &&
+                      (pattern[p] == '?' as u8 || self.to_lower(pattern[p]) == self.to_lower(s[i])) {
+                p += 1;
+                i += 1;
+            } else {
+                match star {
+                    None => return false,
+                    Some((sp, si)) => {
+                        star = Some((sp, si + 1));
+                        p = sp + 1;
+                        i = si + 1;
+                    }
+                }
+            }
+        }
+        pattern.slice_from(p).iter().all(|&b| b == '*' as u8)
+    }
+}
+
+/// The channel modes that the server supports, grouped by how they take parameters
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ChanModes {
+    /// Modes that add or remove an entry from a list, e.g. bans. Always have a parameter.
+    pub list: Vec<char>,
+    /// Modes that always have a parameter, e.g. the channel key
+    pub always: Vec<char>,
+    /// Modes that only have a parameter when set, e.g. the user limit
+    pub on_set: Vec<char>,
+    /// Modes that never have a parameter
+    pub never: Vec<char>
+}
+
+/// The extended ban types that the server supports, e.g. bans on an account
+/// rather than a hostmask
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ExtBans {
+    /// The character that starts an extban, e.g. `~`, or None if extbans are
+    /// just the type and a colon (as on InspIRCd)
+    pub prefix: Option<char>,
+    /// The supported extban types, e.g. `a` for account
+    pub types: Vec<char>
+}
+
+/// The features advertised by the server with 005 lines.
+///
+/// The accessors return the RFC defaults for any tokens the server didn't send.
+#[deriving(Clone)]
+pub struct ISupport {
+    tokens: HashMap<String, Vec<u8>>
+}
+
+impl ISupport {
+    /// Returns a new ISupport with no tokens
+    pub fn new() -> ISupport {
+        ISupport { tokens: HashMap::new() }
+    }
+
+    /// Adds the tokens from the arguments of a 005 line.
+    ///
+    /// The first argument (our nick) and the final argument (the "are supported
+    /// by this server" text) are skipped. Tokens of the form -NAME remove a
+    /// previously advertised token.
+    ///
+    /// Returns the tokens that were added, changed or removed, with their new
+    /// value, or None for the removed ones. Tokens sent again with the same
+    /// value aren't included.
+    pub fn add_tokens(&mut self, args: &[Vec<u8>]) -> Vec<(String, Option<Vec<u8>>)> {
+        let mut changed = Vec::new();
+        if args.len() < 3 {
+            return changed;
+        }
+        for token in args.slice(1, args.len()-1).iter() {
+            let token = token.as_slice();
+            let (name, value) = match token.position_elem(&('=' as u8)) {
+                None => (token, [].as_slice()),
+                Some(idx) => (token.slice_to(idx), token.slice_from(idx+1))
+            };
+            let name = match str::from_utf8(name) {
+                Some(name) if !name.is_empty() => name,
+                _ => continue
+            };
+            if name.starts_with("-") {
+                let name = name.slice_from(1).to_string();
+                if self.tokens.remove(&name).is_some() {
+                    changed.push((name, None));
+                }
+            } else {
+                let value = unescape(value);
+                if self.tokens.get(&name.to_string()) != Some(&value) {
+                    self.tokens.insert(name.to_string(), value.clone());
+                    changed.push((name.to_string(), Some(value)));
+                }
+            }
+        }
+        changed
+    }
+
+    /// Returns the value of the given token, if the server sent it.
+    /// Tokens without a value have an empty value.
+    pub fn get<'a>(&'a self, name: &str) -> Option<&'a [u8]> {
+        self.tokens.get(&name.to_string()).map(|v| v.as_slice())
+    }
+
+    /// Returns `true` if the server sent the given token
+    pub fn contains(&self, name: &str) -> bool {
+        self.tokens.contains_key(&name.to_string())
+    }
+
+    /// Returns the value of the given token parsed as a number
+    pub fn get_uint(&self, name: &str) -> Option<uint> {
+        self.get(name).and_then(|v| str::from_utf8(v)).and_then(|v| from_str(v))
+    }
+
+    /// Returns the server's casemapping (CASEMAPPING, default rfc1459)
+    pub fn casemapping(&self) -> CaseMapping {
+        match self.get("CASEMAPPING") {
+            Some(b"ascii") => CaseAscii,
+            Some(b"strict-rfc1459") => CaseStrictRfc1459,
+            _ => CaseRfc1459
+        }
+    }
+
+    /// Returns the characters that start a channel name (CHANTYPES, default #&)
+    pub fn chantypes<'a>(&'a self) -> &'a [u8] {
+        self.get("CHANTYPES").unwrap_or(b"#&")
+    }
+
+    /// Returns `true` if `name` looks like a channel name according to CHANTYPES
+    pub fn is_channel(&self, name: &[u8]) -> bool {
+        !name.is_empty() && self.chantypes().contains(&name[0])
+    }
+
+    /// Returns the channel membership modes along with their prefix symbols,
+    /// from the highest rank to the lowest (PREFIX, default (ov)@+)
+    pub fn prefix(&self) -> Vec<(char, char)> {
+        let value = self.get("PREFIX").unwrap_or(b"(ov)@+");
+        if !value.starts_with(b"(") {
+            return Vec::new();
+        }
+        match value.position_elem(&(')' as u8)) {
+            None => Vec::new(),
+            Some(idx) => {
+                let modes = value.slice(1, idx);
+                let symbols = value.slice_from(idx+1);
+                modes.iter().zip(symbols.iter()).map(|(&m, &s)| (m as char, s as char)).collect()
+            }
+        }
+    }
+
+    /// Returns the supported channel modes (CHANMODES, default b,k,l,imnpst).
+    /// The membership modes from PREFIX are not included.
+    pub fn chanmodes(&self) -> ChanModes {
+        let value = self.get("CHANMODES").unwrap_or(b"b,k,l,imnpst");
+        let mut groups = value.split(|&b| b == ',' as u8).map(|g| {
+            g.iter().map(|&b| b as char).collect::<Vec<char>>()
+        });
+        ChanModes {
+            list: groups.next().unwrap_or(Vec::new()),
+            always: groups.next().unwrap_or(Vec::new()),
+            on_set: groups.next().unwrap_or(Vec::new()),
+            never: groups.next().unwrap_or(Vec::new())
+        }
+    }
+
+    /// Returns the supported extended ban types (EXTBAN), or None if the
+    /// server doesn't support extbans
+    pub fn extbans(&self) -> Option<ExtBans> {
+        let value = match self.get("EXTBAN") {
+            None => return None,
+            Some(value) => value
+        };
+        let (prefix, types) = match value.position_elem(&(',' as u8)) {
+            None => return None,
+            Some(idx) => (value.slice_to(idx), value.slice_from(idx+1))
+        };
+        Some(ExtBans {
+            prefix: prefix.iter().next().map(|&b| b as char),
+            types: types.iter().map(|&b| b as char).collect()
+        })
+    }
+
+    /// Returns the maximum number of targets allowed in a single `cmd` (e.g. PRIVMSG),
+    /// or None if there is no limit.
+    ///
+    /// This uses TARGMAX if the server sent it. PRIVMSG and NOTICE fall back to
+    /// MAXTARGETS, and to a single target if neither one mentions them; other
+    /// commands have no limit unless TARGMAX gives one.
+    pub fn targmax(&self, cmd: &str) -> Option<uint> {
+        match self.get("TARGMAX") {
+            None => (),
+            Some(value) => {
+                for entry in value.split(|&b| b == ',' as u8) {
+                    let (name, limit) = match entry.position_elem(&(':' as u8)) {
+                        None => (entry, [].as_slice()),
+                        Some(idx) => (entry.slice_to(idx), entry.slice_from(idx+1))
+                    };
+                    if name == cmd.as_bytes() {
+                        return str::from_utf8(limit).and_then(|v| from_str(v));
+                    }
+                }
+            }
+        }
+        match cmd {
+            "PRIVMSG" | "NOTICE" => Some(self.get_uint("MAXTARGETS").unwrap_or(1)),
+            _ => None
+        }
+    }
+
+    /// Returns the maximum number of parameterized mode changes per MODE command
+    /// (MODES, default 3)
+    pub fn modes(&self) -> uint {
+        match self.get("MODES") {
+            None => 3,
+            // no value means no limit
+            Some(b"") => 512,
+            Some(_) => self.get_uint("MODES").unwrap_or(3)
+        }
+    }
+}
+
+/// Decodes the \xHH escapes used in token values
+fn unescape(v: &[u8]) -> Vec<u8> {
+    fn hex(b: u8) -> Option<u8> {
+        match b as char {
+            '0'...'9' => Some(b - '0' as u8),
+            'a'...'f' => Some(b - 'a' as u8 + 10),
+            'A'...'F' => Some(b - 'A' as u8 + 10),
+            _ => None
+        }
+    }
+    let mut res = Vec::with_capacity(v.len());
+    let mut i = 0;
+    while i < v.len() {
+        if v[i] == '\\' as u8 && i + 3 < v.len() && v[i+1] == 'x' as u8 {
+            match (hex(v[i+2]), hex(v[i+3])) {
+                (Some(hi), Some(lo)) => {
+                    res.push(hi * 16 + lo);
+                    i += 4;
+                    continue;
+                }
+                _ => ()
+            }
+        }
+        res.push(v[i]);
+        i += 1;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ISupport, ChanModes, ExtBans, CaseAscii, CaseRfc1459, CaseStrictRfc1459};
+
+    fn isupport(tokens: &[&[u8]]) -> ISupport {
+        let mut args = vec![b"me".to_vec()];
+        args.extend(tokens.iter().map(|t| t.to_vec()));
+        args.push(b"are supported by this server".to_vec());
+        let mut isupport = ISupport::new();
+        isupport.add_tokens(args.as_slice());
+        isupport
+    }
+
+    #[test]
+    fn test_defaults() {
+        let isupport = ISupport::new();
+        assert_eq!(isupport.casemapping(), CaseRfc1459);
+        assert_eq!(isupport.prefix(), vec![('o', '@'), ('v', '+')]);
+        assert_eq!(isupport.modes(), 3);
+        assert_eq!(isupport.targmax("PRIVMSG"), Some(1));
+        assert!(isupport.is_channel(b"#rust"));
+        assert!(!isupport.is_channel(b"rust"));
+        assert!(isupport.get("NETWORK").is_none());
+    }
+
+    #[test]
+    fn test_add_tokens() {
+        let mut isupport = isupport([b"CASEMAPPING=ascii", b"PREFIX=(qaohv)~&@%+",
+                                     b"CHANMODES=beI,k,l,imnpst", b"MODES=4", b"EXCEPTS",
+                                     b"NETWORK=Some\\x20Net"]);
+        assert_eq!(isupport.casemapping(), CaseAscii);
+        assert_eq!(isupport.prefix(), vec![('q', '~'), ('a', '&'), ('o', '@'),
+                                           ('h', '%'), ('v', '+')]);
+        assert_eq!(isupport.chanmodes(), ChanModes {
+            list: vec!['b', 'e', 'I'],
+            always: vec!['k'],
+            on_set: vec!['l'],
+            never: vec!['i', 'm', 'n', 'p', 's', 't']
+        });
+        assert_eq!(isupport.modes(), 4);
+        assert_eq!(isupport.get("EXCEPTS"), Some(b""));
+        assert_eq!(isupport.get("NETWORK"), Some(b"Some Net"));
+
+        let args = vec![b"me".to_vec(), b"-EXCEPTS".to_vec(), b"MODES=4".to_vec(), b"MODES=6".to_vec(),
+                        b"-INVEX".to_vec(), b"are supported".to_vec()];
+        assert_eq!(isupport.add_tokens(args.as_slice()),
+                   vec![("EXCEPTS".to_string(), None), ("MODES".to_string(), Some(b"6".to_vec()))]);
+        assert!(!isupport.contains("EXCEPTS"));
+    }
+
+    #[test]
+    fn test_targmax() {
+        let targets = isupport([b"TARGMAX=NAMES:1,PRIVMSG:4,JOIN:", b"MAXTARGETS=2"]);
+        assert_eq!(targets.targmax("PRIVMSG"), Some(4));
+        assert_eq!(targets.targmax("JOIN"), None);
+        assert_eq!(targets.targmax("NOTICE"), Some(2));
+        assert_eq!(targets.targmax("KICK"), None);
+        assert_eq!(isupport([b"MODES=4"]).targmax("NOTICE"), Some(1));
+        assert_eq!(isupport([b"MAXTARGETS=2"]).targmax("JOIN"), None);
+    }
+
+    #[test]
+    fn test_extbans() {
+        assert_eq!(ISupport::new().extbans(), None);
+        assert_eq!(isupport([b"EXTBAN=~,qjncrRa"]).extbans(), Some(ExtBans {
+            prefix: Some('~'),
+            types: vec!['q', 'j', 'n', 'c', 'r', 'R', 'a']
+        }));
+        assert_eq!(isupport([b"EXTBAN=,mR"]).extbans(), Some(ExtBans { prefix: None, types: vec!['m', 'R'] }));
+    }
+
+    #[test]
+    fn test_casemapping() {
+        assert!(CaseRfc1459.eq_ignore_case(b"Nick[a]~", b"nick{A}^"));
+        assert!(!CaseStrictRfc1459.eq_ignore_case(b"nick~", b"nick^"));
+        assert!(CaseStrictRfc1459.eq_ignore_case(b"NICK\\", b"nick|"));
+        assert!(!CaseAscii.eq_ignore_case(b"nick[", b"nick{"));
+        assert_eq!(CaseRfc1459.lower(b"#Rust[]"), b"#rust{}".to_vec());
+        assert_eq!(CaseRfc1459.lower(b"Nick^"), b"nick~".to_vec());
+        assert_eq!(CaseRfc1459.lower(b"nick~"), b"nick~".to_vec());
+        assert_eq!(CaseStrictRfc1459.lower(b"Nick^"), b"nick^".to_vec());
+        assert!(CaseRfc1459.glob_match(b"*!*@*.Example.com", b"nick{}!user@host.example.com"));
+        assert!(CaseRfc1459.glob_match(b"nick[]!?ser@*", b"nick{}!user@host"));
+        assert!(CaseRfc1459.glob_match(b"*", b""));
+        assert!(!CaseRfc1459.glob_match(b"*!*@other.com", b"nick!user@host.com"));
+        assert!(!CaseRfc1459.glob_match(b"nick", b"nick!user@host"));
+    }
+}
@@ -3,15 +3,38 @@
 
 //! Library for communicating with IRC servers
 
-#![feature(macro_rules, default_type_params, phase)]
+#![feature(macro_rules, default_type_params, phase, unsafe_destructor)]
 #![warn(missing_doc)]
 
 #[phase(syntax, link)]
 extern crate log;
+extern crate serialize;
+extern crate time;
+#[cfg(feature = "zlib")]
+extern crate libc;
 
 use std::{fmt, str};
 
+use state::State;
+
+#[cfg(feature = "cli")]
+pub mod cli;
 pub mod conn;
+pub mod dcc;
+pub mod dns;
+pub mod errors;
+pub mod extban;
+pub mod isupport;
+pub mod modes;
+pub mod quirks;
+pub mod relay;
+pub mod snotice;
+pub mod state;
+pub mod store;
+#[cfg(any(test, feature = "testserver"))]
+pub mod testserver;
+pub mod validate;
+pub mod who;
 
 /// Representation of an IRC user
 #[deriving(Clone)]
@@ -95,6 +118,45 @@ impl User {
     pub fn with_nick(&self, nick: &[u8]) -> User {
         User::new(nick, self.user(), self.host())
     }
+
+    /// Returns `true` if this is the prefix of a server, ours or a remote
+    /// one: a bare name, without a username or hostname, that isn't the nick
+    /// of a user in `state`.
+    ///
+    /// Some services and bouncers send bare nicks for users too, which is
+    /// why the users we share a channel with are checked.
+    pub fn is_server(&self, state: &State) -> bool {
+        self.user.is_none() && self.host.is_none() && state.user(self.nick()).is_none()
+    }
+}
+
+/// The source of a line, as returned by `Line::source()`
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Prefix {
+    /// A server, e.g. `irc.example.net`
+    ServerPrefix(Vec<u8>),
+    /// A user, e.g. `nick!user@host`
+    UserPrefix(User)
+}
+
+impl Prefix {
+    /// Classifies a parsed line prefix, given the users we know of.
+    /// See `User::is_server()`.
+    pub fn from_user(user: &User, state: &State) -> Prefix {
+        if user.is_server(state) {
+            ServerPrefix(user.raw().to_vec())
+        } else {
+            UserPrefix(user.clone())
+        }
+    }
+
+    /// Returns the user, or None for a server
+    pub fn user<'a>(&'a self) -> Option<&'a User> {
+        match *self {
+            ServerPrefix(_) => None,
+            UserPrefix(ref user) => Some(user)
+        }
+    }
 }
 
 impl PartialEq for User {
@@ -113,7 +175,9 @@ impl fmt::Show for User {
 
 #[cfg(test)]
 mod tests {
-    use super::User;
+    use super::{User, Prefix, ServerPrefix, UserPrefix};
+    use conn::Line;
+    use state::State;
 
     #[test]
     fn test_user_new() {
@@ -169,4 +233,33 @@ mod tests {
         assert_eq!(user.user(), None);
         assert_eq!(user.host(), None);
     }
+
+    #[test]
+    fn test_prefix() {
+        let mut state = State::new();
+        for raw in [b":me!u@h JOIN #rust", b":Bob!b@h JOIN #rust"].iter() {
+            state.handle_line(b"me", &Line::parse(*raw).unwrap());
+        }
+        assert_eq!(Prefix::from_user(&User::parse(b"irc.example.net"), &state),
+                   ServerPrefix(b"irc.example.net".to_vec()));
+        // remote servers too, e.g. for notices relayed across the network
+        assert_eq!(Prefix::from_user(&User::parse(b"services.example.net"), &state),
+                   ServerPrefix(b"services.example.net".to_vec()));
+        // a bare nick is a user only if we know them
+        assert_eq!(Prefix::from_user(&User::parse(b"bob"), &state), UserPrefix(User::parse(b"bob")));
+        assert_eq!(Prefix::from_user(&User::parse(b"carol"), &state), ServerPrefix(b"carol".to_vec()));
+        assert_eq!(Prefix::from_user(&User::parse(b"nick!user@host.example.net"), &state),
+                   UserPrefix(User::parse(b"nick!user@host.example.net")));
+        assert_eq!(Prefix::from_user(&User::parse(b"irc.example.net!u@h"), &state),
+                   UserPrefix(User::parse(b"irc.example.net!u@h")));
+        assert!(Prefix::from_user(&User::parse(b"irc.example.net"), &state).user().is_none());
+
+        let source = |raw: &[u8]| Line::parse(raw).unwrap().source(&state);
+        assert_eq!(source(b":leaf.example.net NOTICE me :*** Notice -- remote"),
+                   Some(ServerPrefix(b"leaf.example.net".to_vec())));
+        assert_eq!(source(b":leaf.example.net 391 me leaf.example.net :Tuesday"),
+                   Some(ServerPrefix(b"leaf.example.net".to_vec())));
+        assert_eq!(source(b":bob PRIVMSG #rust :hi"), Some(UserPrefix(User::parse(b"bob"))));
+        assert_eq!(source(b"PING :token"), None);
+    }
 }
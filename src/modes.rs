@@ -0,0 +1,177 @@
+//! Parsing of channel mode changes
+
+use isupport::ISupport;
+
+/// A single mode change, e.g. the `+o nick` in `MODE #chan +o-v nick nick2`
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct ModeChange {
+    /// `true` if the mode is being set, `false` if it's being unset
+    pub set: bool,
+    /// The mode letter
+    pub mode: char,
+    /// The mode parameter, if this mode takes one
+    pub param: Option<Vec<u8>>
+}
+
+impl ModeChange {
+    /// Returns a ModeChange that sets `mode`
+    pub fn set(mode: char, param: Option<&[u8]>) -> ModeChange {
+        ModeChange { set: true, mode: mode, param: param.map(|p| p.to_vec()) }
+    }
+
+    /// Returns a ModeChange that unsets `mode`
+    pub fn unset(mode: char, param: Option<&[u8]>) -> ModeChange {
+        ModeChange { set: false, mode: mode, param: param.map(|p| p.to_vec()) }
+    }
+}
+
+/// Returns `true` if the channel mode `mode` takes a parameter, according to
+/// the PREFIX and CHANMODES advertised by the server.
+pub fn takes_param(isupport: &ISupport, mode: char, set: bool) -> bool {
+    let chanmodes = isupport.chanmodes();
+    isupport.prefix().iter().any(|&(m, _)| m == mode) ||
+        chanmodes.list.contains(&mode) || chanmodes.always.contains(&mode) ||
+        (set && chanmodes.on_set.contains(&mode))
+}
+
+/// Parses the mode string and parameters of a channel MODE command or
+/// RPL_CHANNELMODEIS reply into individual changes.
+///
+/// Modes that should have a parameter but don't (because there are too few
+/// parameters) get a param of None.
+pub fn parse(isupport: &ISupport, modes: &[u8], params: &[Vec<u8>]) -> Vec<ModeChange> {
+    let mut params = params.iter();
+    let mut set = true;
+    let mut res = Vec::new();
+    for &b in modes.iter() {
+        match b as char {
+            '+' => set = true,
+            '-' => set = false,
+            mode => {
+                let param = if takes_param(isupport, mode, set) {
+                    params.next().map(|p| p.clone())
+                } else {
+                    None
+                };
+                res.push(ModeChange { set: set, mode: mode, param: param });
+            }
+        }
+    }
+    res
+}
+
+/// Applies a user mode string like `+ix-w` to the user modes in `modes`.
+/// A leading mode without a sign counts as set, as in RPL_UMODEIS replies
+/// from some servers. Parameters (e.g. server notice masks) are ignored.
+pub fn apply_user_modes(modes: &mut Vec<char>, modestr: &[u8]) {
+    let mut set = true;
+    for &b in modestr.iter() {
+        match b as char {
+            '+' => set = true,
+            '-' => set = false,
+            c if c.is_alphabetic() => {
+                modes.retain(|&m| m != c);
+                if set {
+                    modes.push(c);
+                }
+            }
+            _ => ()
+        }
+    }
+}
+
+/// Formats mode changes into groups of mode string and parameters, each of
+/// which can be sent as a single MODE command.
+///
+/// Each group has at most `max_params` parameters, and the mode string plus
+/// parameters (separated by spaces) take at most `max_len` bytes. A single
+/// change that doesn't fit in `max_len` gets a group of its own.
+pub fn format(changes: &[ModeChange], max_params: uint,
+              max_len: uint) -> Vec<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut res = Vec::new();
+    let mut modestr = Vec::new();
+    let mut params: Vec<Vec<u8>> = Vec::new();
+    let mut len = 0u;
+    let mut set = None;
+    for change in changes.iter() {
+        let cost = 2 + change.param.as_ref().map_or(0, |p| p.len() + 1);
+        let full = change.param.is_some() && params.len() >= max_params;
+        if !modestr.is_empty() && (full || len + cost > max_len) {
+            res.push((modestr, params));
+            modestr = Vec::new();
+            params = Vec::new();
+            len = 0;
+            set = None;
+        }
+        if set != Some(change.set) {
+            modestr.push(if change.set { '+' as u8 } else { '-' as u8 });
+            set = Some(change.set);
+        }
+        modestr.push(change.mode as u8);
+        match change.param {
+            None => (),
+            Some(ref p) => params.push(p.clone())
+        }
+        len += cost;
+    }
+    if !modestr.is_empty() {
+        res.push((modestr, params));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, format, apply_user_modes, ModeChange};
+    use isupport::ISupport;
+
+    #[test]
+    fn test_parse() {
+        let isupport = ISupport::new();
+        let params = vec![b"nick".to_vec(), b"*!*@host".to_vec(), b"10".to_vec(), b"key".to_vec()];
+        assert_eq!(parse(&isupport, b"+ob-m+lk", params.as_slice()), vec![
+            ModeChange::set('o', Some(b"nick")),
+            ModeChange::set('b', Some(b"*!*@host")),
+            ModeChange::unset('m', None),
+            ModeChange::set('l', Some(b"10")),
+            ModeChange::set('k', Some(b"key")),
+        ]);
+
+        let params = vec![b"key".to_vec()];
+        assert_eq!(parse(&isupport, b"-lkv", params.as_slice()), vec![
+            ModeChange::unset('l', None),
+            ModeChange::unset('k', Some(b"key")),
+            ModeChange::unset('v', None),
+        ]);
+    }
+
+    #[test]
+    fn test_apply_user_modes() {
+        let mut modes = Vec::new();
+        apply_user_modes(&mut modes, b"+iwx");
+        assert_eq!(modes, vec!['i', 'w', 'x']);
+        apply_user_modes(&mut modes, b"-w+Z-x");
+        assert_eq!(modes, vec!['i', 'Z']);
+        apply_user_modes(&mut modes, b"i");
+        assert_eq!(modes, vec!['Z', 'i']);
+    }
+
+    #[test]
+    fn test_format() {
+        let changes = vec![ModeChange::set('o', Some(b"a")), ModeChange::set('o', Some(b"b")),
+                           ModeChange::set('m', None), ModeChange::unset('v', Some(b"c")),
+                           ModeChange::unset('v', Some(b"d"))];
+        assert_eq!(format(changes.as_slice(), 3, 510), vec![
+            (b"+oom-v".to_vec(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]),
+            (b"-v".to_vec(), vec![b"d".to_vec()]),
+        ]);
+        assert_eq!(format(changes.slice_to(3), 1, 510), vec![
+            (b"+o".to_vec(), vec![b"a".to_vec()]),
+            (b"+om".to_vec(), vec![b"b".to_vec()]),
+        ]);
+        assert_eq!(format(changes.slice_to(2), 10, 6), vec![
+            (b"+o".to_vec(), vec![b"a".to_vec()]),
+            (b"+o".to_vec(), vec![b"b".to_vec()]),
+        ]);
+    }
+}
@@ -0,0 +1,226 @@
+//! Identifying the network and server software, and what they do differently
+//!
+//! The profile is worked out from the NETWORK token in 005 and the version
+//! string in 004, and is what other parts of the library check before using
+//! a feature that not every server has.
+
+use extban::ExtBan;
+use isupport::ISupport;
+use snotice;
+use snotice::SnoticePattern;
+
+/// The server software, as far as it can be told from its version string
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Ircd {
+    /// charybdis and its descendants (solanum, ircd-seven)
+    Charybdis,
+    /// ircd-ratbox
+    Ratbox,
+    /// ircd-hybrid and oftc-hybrid
+    Hybrid,
+    /// InspIRCd
+    InspIRCd,
+    /// UnrealIRCd
+    Unreal,
+    /// Bahamut (DALnet)
+    Bahamut,
+    /// Ergo, formerly Oragono
+    Ergo,
+    /// ngIRCd
+    NgIRCd,
+    /// Anything else, or no version seen yet
+    UnknownIrcd
+}
+
+/// How a channel quiet (muting a user without banning them) is set
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum QuietStyle {
+    /// With the +q list mode
+    QuietMode,
+    /// With a ban using the given extban type, e.g. `+b ~q:mask` on Unreal
+    /// or `+b m:mask` on InspIRCd
+    QuietExtban(char),
+    /// The server has no quiets
+    NoQuiet
+}
+
+/// The argument order NickServ expects for IDENTIFY
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum NickServStyle {
+    /// `IDENTIFY [account] password`, as on Atheme and Anope
+    AccountFirst,
+    /// `IDENTIFY password [account]`, as on OFTC
+    PasswordFirst
+}
+
+/// How ChanServ is told that a command is about ourselves
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ChanServStyle {
+    /// `OP #chan` and `UNBAN #chan`, as on Atheme and Anope
+    ImplicitSelf,
+    /// `OP #chan nick` and `UNBAN #chan ME`, as on DALnet
+    ExplicitSelf
+}
+
+/// What's known about the network and its quirks
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Quirks {
+    /// The network name, from NETWORK
+    pub network: Option<Vec<u8>>,
+    /// The server's version string, from 004
+    pub version: Option<Vec<u8>>,
+    /// The server software
+    pub ircd: Ircd,
+    /// Whether WHO supports WHOX field selection
+    pub whox: bool,
+    /// Whether the server supports MONITOR
+    pub monitor: bool,
+    /// Whether the server has the GLOBOPS command
+    pub globops: bool,
+    /// How quiets are set
+    pub quiet: QuietStyle,
+    /// The NickServ IDENTIFY syntax
+    pub nickserv: NickServStyle,
+    /// The ChanServ syntax
+    pub chanserv: ChanServStyle,
+    /// The patterns used to parse server notices, see the `snotice` module
+    pub snotice_patterns: &'static [SnoticePattern]
+}
+
+impl Quirks {
+    /// Returns the profile for a server we know nothing about yet
+    pub fn new() -> Quirks {
+        Quirks::detect(None, &ISupport::new())
+    }
+
+    /// Works out the profile from the 004 version string and the 005 tokens
+    pub fn detect(version: Option<&[u8]>, isupport: &ISupport) -> Quirks {
+        let ircd = version.map_or(UnknownIrcd, detect_ircd);
+        let network = isupport.get("NETWORK").map(|n| n.to_vec());
+        let nickserv = match network {
+            Some(ref n) if eq_ignore_case(n.as_slice(), "OFTC") => PasswordFirst,
+            _ => AccountFirst
+        };
+        let chanserv = match network {
+            Some(ref n) if eq_ignore_case(n.as_slice(), "DALnet") => ExplicitSelf,
+            _ if ircd == Bahamut => ExplicitSelf,
+            _ => ImplicitSelf
+        };
+        Quirks {
+            network: network,
+            version: version.map(|v| v.to_vec()),
+            ircd: ircd.clone(),
+            whox: isupport.contains("WHOX"),
+            monitor: isupport.contains("MONITOR"),
+            globops: ircd == Unreal || ircd == Bahamut || ircd == InspIRCd,
+            quiet: quiet_style(&ircd, isupport),
+            nickserv: nickserv,
+            chanserv: chanserv,
+            snotice_patterns: snotice::patterns(&ircd)
+        }
+    }
+}
+
+/// Identifies the server software from its version string
+pub fn detect_ircd(version: &[u8]) -> Ircd {
+    let version = version.iter().map(|&b| (b as char).to_lowercase()).collect::<String>();
+    let has = |name: &str| version.as_slice().contains(name);
+    if has("charybdis") || has("solanum") || has("seven") {
+        Charybdis
+    } else if has("ratbox") {
+        Ratbox
+    } else if has("hybrid") {
+        Hybrid
+    } else if has("inspircd") {
+        InspIRCd
+    } else if has("unreal") {
+        Unreal
+    } else if has("bahamut") {
+        Bahamut
+    } else if has("ergo") || has("oragono") {
+        Ergo
+    } else if has("ngircd") {
+        NgIRCd
+    } else {
+        UnknownIrcd
+    }
+}
+
+fn quiet_style(ircd: &Ircd, isupport: &ISupport) -> QuietStyle {
+    // on some servers q is the channel owner prefix instead
+    if isupport.chanmodes().list.contains(&'q') &&
+       !isupport.prefix().iter().any(|&(m, _)| m == 'q') {
+        return QuietMode;
+    }
+    let kind = ExtBan::mute(b"").letter(ircd);
+    match (isupport.extbans(), kind) {
+        (Some(ref extbans), Some(kind)) if extbans.types.contains(&kind) => QuietExtban(kind),
+        _ => NoQuiet
+    }
+}
+
+fn eq_ignore_case(a: &[u8], b: &str) -> bool {
+    a.len() == b.len() && a.iter().zip(b.bytes()).all(|(&a, b)| {
+        (a as char).to_lowercase() == (b as char).to_lowercase()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quirks, detect_ircd};
+    use super::{Charybdis, Hybrid, InspIRCd, Unreal, UnknownIrcd};
+    use super::{QuietMode, QuietExtban, NoQuiet, AccountFirst, PasswordFirst, ImplicitSelf, ExplicitSelf};
+    use isupport::ISupport;
+
+    fn isupport(tokens: &[&str]) -> ISupport {
+        let mut args = vec![b"me".to_vec()];
+        args.extend(tokens.iter().map(|t| t.as_bytes().to_vec()));
+        args.push(b"are supported by this server".to_vec());
+        let mut isupport = ISupport::new();
+        isupport.add_tokens(args.as_slice());
+        isupport
+    }
+
+    #[test]
+    fn test_detect_ircd() {
+        assert_eq!(detect_ircd(b"solanum-1.0-dev"), Charybdis);
+        assert_eq!(detect_ircd(b"hybrid-7.2.2+oftc1.7.3"), Hybrid);
+        assert_eq!(detect_ircd(b"InspIRCd-3"), InspIRCd);
+        assert_eq!(detect_ircd(b"UnrealIRCd-5.0.9"), Unreal);
+        assert_eq!(detect_ircd(b"u2.10.12.14"), UnknownIrcd);
+    }
+
+    #[test]
+    fn test_detect() {
+        let q = Quirks::detect(Some(b"solanum-1.0-dev"),
+                               &isupport(["NETWORK=Libera.Chat", "CHANMODES=eIbq,k,flj,CFLMPQScgimnprstuz",
+                                          "WHOX", "MONITOR=100"]));
+        assert_eq!(q.network, Some(b"Libera.Chat".to_vec()));
+        assert_eq!(q.ircd, Charybdis);
+        assert!(q.whox && q.monitor);
+        assert_eq!(q.quiet, QuietMode);
+        assert_eq!(q.nickserv, AccountFirst);
+        assert_eq!(q.chanserv, ImplicitSelf);
+
+        let q = Quirks::detect(Some(b"UnrealIRCd-5.0.9"),
+                               &isupport(["PREFIX=(qaohv)~&@%+", "CHANMODES=beI,kLf,l,psmntirzMQNRTOVKDdGPZSCc",
+                                          "EXTBAN=~,qjncrRa"]));
+        assert_eq!(q.quiet, QuietExtban('q'));
+        assert!(!q.whox);
+        assert!(q.globops);
+
+        let q = Quirks::detect(Some(b"InspIRCd-3"), &isupport(["EXTBAN=,ACNOQRSTUcjmprsz"]));
+        assert_eq!(q.quiet, QuietExtban('m'));
+        let q = Quirks::detect(Some(b"InspIRCd-3"), &isupport(["EXTBAN=,ACNOQRSTUcjprsz"]));
+        assert_eq!(q.quiet, NoQuiet);
+
+        let q = Quirks::detect(Some(b"hybrid-7.2.2+oftc1.7.3"), &isupport(["NETWORK=OFTC"]));
+        assert_eq!(q.quiet, NoQuiet);
+        assert_eq!(q.nickserv, PasswordFirst);
+
+        let q = Quirks::detect(Some(b"bahamut-2.1.4"), &isupport(["NETWORK=DALnet"]));
+        assert_eq!(q.chanserv, ExplicitSelf);
+
+        assert_eq!(Quirks::new().ircd, UnknownIrcd);
+    }
+}
@@ -0,0 +1,234 @@
+//! Relaying messages between channels on different connections
+//!
+//! A Relay knows the command channel of every connection taking part, and the
+//! channel pairs to mirror. Give each connection's callback a clone of the Relay
+//! and pass it the lines it receives with `Relay::handle()`:
+//!
+//! ```ignore
+//! let (tx_a, rx_a) = channel();
+//! let (tx_b, rx_b) = channel();
+//! let mut relay = Relay::new();
+//! let net_a = relay.add_network("a", tx_a);
+//! let net_b = relay.add_network("b", tx_b);
+//! relay.link(net_a, b"#chan", net_b, b"#chan");
+//! // opts_a.commands = Some(rx_a), and in the callback for network a:
+//! //     LineReceived(line) => relay.handle(net_a, conn, &line)
+//! ```
+
+use conn::{Cmd, Conn, Line, IRCCmd, IRCAction};
+
+/// Mirrors PRIVMSGs and ACTIONs between linked channels on different connections
+pub struct Relay<Payload=()> {
+    networks: Vec<(String, Sender<Cmd<Payload>>)>,
+    links: Vec<Link>,
+    /// Whether to remove colors and other formatting codes from relayed messages.
+    /// Defaults to `false`.
+    pub strip_formatting: bool,
+    /// Whether to put the network name in front of relayed messages, e.g.
+    /// `[net] <nick> text`. Defaults to `true`.
+    pub show_network: bool,
+    /// Nicks whose messages are never relayed, e.g. other relay bots.
+    /// Our own messages are never relayed either way.
+    pub ignore: Vec<Vec<u8>>
+}
+
+/// A one-way link from a channel on one network to a channel on another
+#[deriving(Clone)]
+struct Link {
+    from: uint,
+    from_chan: Vec<u8>,
+    to: uint,
+    to_chan: Vec<u8>
+}
+
+impl<Payload: Send> Clone for Relay<Payload> {
+    fn clone(&self) -> Relay<Payload> {
+        Relay {
+            networks: self.networks.clone(),
+            links: self.links.clone(),
+            strip_formatting: self.strip_formatting,
+            show_network: self.show_network,
+            ignore: self.ignore.clone()
+        }
+    }
+}
+
+impl<Payload: Send> Relay<Payload> {
+    /// Returns a new Relay with no networks
+    pub fn new() -> Relay<Payload> {
+        Relay {
+            networks: Vec::new(),
+            links: Vec::new(),
+            strip_formatting: false,
+            show_network: true,
+            ignore: Vec::new()
+        }
+    }
+
+    /// Adds a network, given the sender for its `Options.commands` channel.
+    /// Returns the id to use for it with `link()` and `handle()`.
+    pub fn add_network(&mut self, name: &str, commands: Sender<Cmd<Payload>>) -> uint {
+        self.networks.push((name.to_string(), commands));
+        self.networks.len() - 1
+    }
+
+    /// Mirrors messages between `chan_a` on network `a` and `chan_b` on network `b`,
+    /// in both directions.
+    pub fn link(&mut self, a: uint, chan_a: &[u8], b: uint, chan_b: &[u8]) {
+        self.link_one_way(a, chan_a, b, chan_b);
+        self.link_one_way(b, chan_b, a, chan_a);
+    }
+
+    /// Mirrors messages from `from_chan` on network `from` to `to_chan` on network `to`
+    pub fn link_one_way(&mut self, from: uint, from_chan: &[u8], to: uint, to_chan: &[u8]) {
+        assert!(from < self.networks.len() && to < self.networks.len(), "unknown network");
+        self.links.push(Link {
+            from: from,
+            from_chan: from_chan.to_vec(),
+            to: to,
+            to_chan: to_chan.to_vec()
+        });
+    }
+
+    /// Relays `line`, received by `conn` on network `net`, to the channels linked
+    /// to its target. Lines other than channel PRIVMSGs and ACTIONs are ignored.
+    pub fn handle(&self, net: uint, conn: &Conn, line: &Line) {
+        let nick = match line.prefix {
+            None => return,
+            Some(ref user) => user.nick()
+        };
+        let (chan, action) = match line.command {
+            IRCCmd(ref s) if "PRIVMSG" == s.as_slice() && line.args.len() == 2 => {
+                (line.args[0].as_slice(), false)
+            }
+            IRCAction(ref dst) => (dst.as_slice(), true),
+            _ => return
+        };
+        let text = match line.args.last() {
+            None => return,
+            Some(text) => text.as_slice()
+        };
+        let casemapping = conn.isupport().casemapping();
+        if casemapping.eq_ignore_case(nick, conn.me().nick()) ||
+           self.ignore.iter().any(|n| casemapping.eq_ignore_case(n.as_slice(), nick)) {
+            return;
+        }
+
+        let msg = {
+            let &(ref name, _) = &self.networks[net];
+            let name = if self.show_network { Some(name.as_slice()) } else { None };
+            format_message(name, nick, text, action, self.strip_formatting)
+        };
+        for link in self.links.iter() {
+            if link.from != net || !casemapping.eq_ignore_case(link.from_chan.as_slice(), chan) {
+                continue;
+            }
+            let (msg, to_chan) = (msg.clone(), link.to_chan.clone());
+            let &(_, ref commands) = &self.networks[link.to];
+            // the other connection may have shut down, in which case there's nobody to tell
+            let _ = commands.send_opt(proc(conn, _, _) {
+                conn.privmsg(to_chan.as_slice(), msg.as_slice());
+            });
+        }
+    }
+}
+
+/// Formats a relayed message as `[net] <nick> text`, or `[net] * nick text`
+/// for an ACTION.
+pub fn format_message(network: Option<&str>, nick: &[u8], text: &[u8], action: bool,
+                      strip: bool) -> Vec<u8> {
+    let mut msg = Vec::new();
+    match network {
+        None => (),
+        Some(network) => {
+            msg.push('[' as u8);
+            msg.push_all(network.as_bytes());
+            msg.push_all(b"] ");
+        }
+    }
+    if action {
+        msg.push_all(b"* ");
+        msg.push_all(nick);
+        msg.push(' ' as u8);
+    } else {
+        msg.push('<' as u8);
+        msg.push_all(nick);
+        msg.push_all(b"> ");
+    }
+    if strip {
+        msg.push_all(strip_formatting(text).as_slice());
+    } else {
+        msg.push_all(text);
+    }
+    msg
+}
+
+/// Removes mIRC formatting codes (bold, colors, italics etc.) from a message
+pub fn strip_formatting(text: &[u8]) -> Vec<u8> {
+    fn digits(text: &[u8], i: uint) -> uint {
+        let mut n = 0;
+        while n < 2 && i + n < text.len() && (text[i+n] as char).is_digit() {
+            n += 1;
+        }
+        n
+    }
+    let mut res = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            // color: ^C[fg[,bg]]
+            0x03 => {
+                i += 1;
+                let fg = digits(text, i);
+                i += fg;
+                if fg > 0 && i + 1 < text.len() && text[i] == ',' as u8 {
+                    let bg = digits(text, i+1);
+                    if bg > 0 {
+                        i += 1 + bg;
+                    }
+                }
+            }
+            // hex color: ^D[rrggbb[,rrggbb]]
+            0x04 => {
+                i += 1;
+                let hex = |i: uint| i + 6 <= text.len() && text.slice(i, i+6).iter().all(|&b| {
+                    (b as char).is_digit_radix(16)
+                });
+                if hex(i) {
+                    i += 6;
+                    if i < text.len() && text[i] == ',' as u8 && hex(i+1) {
+                        i += 7;
+                    }
+                }
+            }
+            // bold, monospace, italics, strikethrough, underline, reverse, reset
+            0x02 | 0x11 | 0x1d | 0x1e | 0x1f | 0x16 | 0x0f => i += 1,
+            b => {
+                res.push(b);
+                i += 1;
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_message, strip_formatting};
+
+    #[test]
+    fn test_strip_formatting() {
+        assert_eq!(strip_formatting(b"\x02bold\x02 \x0304red\x03 \x0312,01blue\x0f x"),
+                   b"bold red blue x".to_vec());
+        assert_eq!(strip_formatting(b"\x031,2\x03,3 \x1ditalic\x1d"), b",3 italic".to_vec());
+        assert_eq!(strip_formatting(b"\x04FF0000red\x04 plain"), b"red plain".to_vec());
+    }
+
+    #[test]
+    fn test_format_message() {
+        assert_eq!(format_message(Some("net"), b"nick", b"hi", false, false),
+                   b"[net] <nick> hi".to_vec());
+        assert_eq!(format_message(None, b"nick", b"\x02waves\x02", true, true),
+                   b"* nick waves".to_vec());
+    }
+}
@@ -0,0 +1,205 @@
+//! Parsing of server notices, for IRC operators
+//!
+//! Opers are sent notices about clients connecting and exiting, K-lines being
+//! added and so on. Their wording differs between ircds, so each ircd has its
+//! own set of patterns (see `Quirks.snotice_patterns`).
+//!
+//! A pattern is matched against the whole notice text. `{nick}`, `{user}`,
+//! `{host}`, `{ip}`, `{mask}` and `{reason}` capture the text up to whatever
+//! follows them in the pattern, and `{*}` matches anything without capturing it.
+
+use quirks::{Ircd, Charybdis, Ratbox, Hybrid, InspIRCd, Unreal};
+
+/// What a server notice is about
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum SnoticeKind {
+    /// A client connected
+    SnoticeConnect,
+    /// A client disconnected
+    SnoticeExit,
+    /// A K-line (ban on user@host) was added
+    SnoticeKline,
+    /// A notice that none of the patterns matched
+    SnoticeOther
+}
+
+/// A server notice, with the fields extracted from it
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Snotice {
+    /// What the notice is about
+    pub kind: SnoticeKind,
+    /// The nick of the client the notice is about
+    pub nick: Option<Vec<u8>>,
+    /// Their username
+    pub user: Option<Vec<u8>>,
+    /// Their hostname
+    pub host: Option<Vec<u8>>,
+    /// Their IP address
+    pub ip: Option<Vec<u8>>,
+    /// The user@host mask of a K-line
+    pub mask: Option<Vec<u8>>,
+    /// The quit message, or the reason for a K-line
+    pub reason: Option<Vec<u8>>,
+    /// The full text of the notice
+    pub text: Vec<u8>
+}
+
+/// A pattern for one kind of server notice
+pub type SnoticePattern = (SnoticeKind, &'static str);
+
+static CHARYBDIS: &'static [SnoticePattern] = &[
+    (SnoticeConnect, "*** Notice -- Client connecting: {nick} ({user}@{host}) [{ip}]{*}"),
+    (SnoticeExit, "*** Notice -- Client exiting: {nick} ({user}@{host}) [{reason}] [{ip}]"),
+    (SnoticeKline, "*** Notice -- {*} added {*}K-Line for [{mask}] [{reason}]"),
+];
+
+static INSPIRCD: &'static [SnoticePattern] = &[
+    (SnoticeConnect, "*** CONNECT: Client connecting on port {*}: {nick}!{user}@{host} ({ip}){*}"),
+    (SnoticeExit, "*** QUIT: Client exiting: {nick}!{user}@{host} ({ip}) [{reason}]"),
+    (SnoticeKline, "*** XLINE: {*} added {*}K-line for {mask}{*}: {reason}"),
+];
+
+static UNREAL: &'static [SnoticePattern] = &[
+    (SnoticeConnect, "*** Client connecting: {nick} ({user}@{host}) [{ip}]{*}"),
+    (SnoticeExit, "*** Client exiting: {nick} ({user}@{host}) [{reason}]"),
+    (SnoticeKline, "*** K-Line added: '{mask}' {*}[reason: {reason}]{*}"),
+];
+
+/// Returns the server notice patterns for `ircd`. Unknown ircds get the
+/// charybdis ones, which most hybrid-derived servers share.
+pub fn patterns(ircd: &Ircd) -> &'static [SnoticePattern] {
+    match *ircd {
+        Charybdis | Ratbox | Hybrid => CHARYBDIS,
+        InspIRCd => INSPIRCD,
+        Unreal => UNREAL,
+        _ => CHARYBDIS
+    }
+}
+
+impl Snotice {
+    /// Parses the text of a server notice with the given patterns. Notices
+    /// that don't match any of them are SnoticeOther.
+    pub fn parse(text: &[u8], patterns: &[SnoticePattern]) -> Snotice {
+        let mut notice = Snotice {
+            kind: SnoticeOther,
+            nick: None,
+            user: None,
+            host: None,
+            ip: None,
+            mask: None,
+            reason: None,
+            text: text.to_vec()
+        };
+        for &(ref kind, pattern) in patterns.iter() {
+            match match_pattern(pattern.as_bytes(), text) {
+                None => continue,
+                Some(fields) => {
+                    notice.kind = kind.clone();
+                    for (name, value) in fields.into_iter() {
+                        let value = Some(value.to_vec());
+                        match name {
+                            "nick" => notice.nick = value,
+                            "user" => notice.user = value,
+                            "host" => notice.host = value,
+                            "ip" => notice.ip = value,
+                            "mask" => notice.mask = value,
+                            "reason" => notice.reason = value,
+                            _ => ()
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+        notice
+    }
+}
+
+/// Matches `text` against a pattern, returning the captured fields
+fn match_pattern<'a, 'b>(pattern: &'a [u8], text: &'b [u8]) -> Option<Vec<(&'a str, &'b [u8])>> {
+    let mut fields = Vec::new();
+    let (mut pattern, mut text) = (pattern, text);
+    while !pattern.is_empty() {
+        if pattern[0] != '{' as u8 {
+            // match literal text up to the next field
+            let len = pattern.position_elem(&('{' as u8)).unwrap_or(pattern.len());
+            if !text.starts_with(pattern.slice_to(len)) {
+                return None;
+            }
+            pattern = pattern.slice_from(len);
+            text = text.slice_from(len);
+            continue;
+        }
+        let end = match pattern.position_elem(&('}' as u8)) {
+            None => return None,
+            Some(end) => end
+        };
+        let name = ::std::str::from_utf8(pattern.slice(1, end)).unwrap_or("*");
+        pattern = pattern.slice_from(end+1);
+        // the field runs up to the first occurrence of the following literal
+        let literal = pattern.slice_to(pattern.position_elem(&('{' as u8)).unwrap_or(pattern.len()));
+        let len = if literal.is_empty() {
+            text.len()
+        } else {
+            match find(text, literal) {
+                None => return None,
+                Some(idx) => idx
+            }
+        };
+        if name != "*" {
+            fields.push((name, text.slice_to(len)));
+        }
+        text = text.slice_from(len);
+    }
+    if text.is_empty() { Some(fields) } else { None }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<uint> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    range(0, haystack.len() - needle.len() + 1).find(|&i| haystack.slice_from(i).starts_with(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Snotice, patterns, SnoticeConnect, SnoticeExit, SnoticeKline, SnoticeOther};
+    use quirks::{Charybdis, InspIRCd, Unreal};
+
+    #[test]
+    fn test_charybdis() {
+        let p = patterns(&Charybdis);
+        let n = Snotice::parse(b"*** Notice -- Client connecting: nick (~user@host.net) [1.2.3.4] \
+                                 {users} [Real Name]", p);
+        assert_eq!(n.kind, SnoticeConnect);
+        assert_eq!(n.nick, Some(b"nick".to_vec()));
+        assert_eq!(n.user, Some(b"~user".to_vec()));
+        assert_eq!(n.host, Some(b"host.net".to_vec()));
+        assert_eq!(n.ip, Some(b"1.2.3.4".to_vec()));
+
+        let n = Snotice::parse(b"*** Notice -- Client exiting: nick (~user@host.net) [Quit: bye] \
+                                 [1.2.3.4]", p);
+        assert_eq!(n.kind, SnoticeExit);
+        assert_eq!(n.reason, Some(b"Quit: bye".to_vec()));
+        assert_eq!(n.ip, Some(b"1.2.3.4".to_vec()));
+
+        let n = Snotice::parse(b"*** Notice -- oper!o@staff{oper} added temporary 10 min. K-Line for \
+                                 [*@bad.host] [spam]", p);
+        assert_eq!(n.kind, SnoticeKline);
+        assert_eq!((n.mask, n.reason), (Some(b"*@bad.host".to_vec()), Some(b"spam".to_vec())));
+
+        assert_eq!(Snotice::parse(b"*** Notice -- Nick change: From a to b [~u@h]", p).kind, SnoticeOther);
+    }
+
+    #[test]
+    fn test_others() {
+        let n = Snotice::parse(b"*** CONNECT: Client connecting on port 6697 (class main): \
+                                 nick!user@host.net (1.2.3.4) [Real Name]", patterns(&InspIRCd));
+        assert_eq!(n.kind, SnoticeConnect);
+        assert_eq!((n.nick, n.ip), (Some(b"nick".to_vec()), Some(b"1.2.3.4".to_vec())));
+
+        let n = Snotice::parse(b"*** Client exiting: nick (user@host.net) [Ping timeout]", patterns(&Unreal));
+        assert_eq!(n.kind, SnoticeExit);
+        assert_eq!(n.reason, Some(b"Ping timeout".to_vec()));
+    }
+}
@@ -0,0 +1,999 @@
+//! Tracking of the channels we're in and their members
+
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet, RingBuf};
+use std::mem;
+use std::time::Duration;
+use time;
+
+use conn::{Line, IRCCmd, IRCCode, IRCAction};
+use isupport::{ISupport, CaseMapping};
+use modes;
+use who::WhoReply;
+use User;
+
+/// A member of a tracked channel
+#[deriving(Clone)]
+pub struct Member {
+    nick: Vec<u8>,
+    user: Option<Vec<u8>>,
+    host: Option<Vec<u8>>,
+    modes: Vec<char>
+}
+
+impl Member {
+    /// Returns the member's nick
+    pub fn nick<'a>(&'a self) -> &'a [u8] {
+        self.nick.as_slice()
+    }
+
+    /// Returns the member's username, if known.
+    /// It's known for members that joined after us, or if the server uses userhost-in-names.
+    pub fn user<'a>(&'a self) -> Option<&'a [u8]> {
+        self.user.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns the member's hostname, if known. See `user()`.
+    pub fn host<'a>(&'a self) -> Option<&'a [u8]> {
+        self.host.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns the member's channel membership modes (e.g. 'o' or 'v'),
+    /// from the highest rank to the lowest.
+    pub fn modes<'a>(&'a self) -> &'a [char] {
+        self.modes.as_slice()
+    }
+}
+
+/// What we know about a user that shares a channel with us.
+///
+/// Besides the JOIN and NAMES lines, the details are filled in from WHO and
+/// WHOIS replies, and from the extended-join, account-notify, away-notify and
+/// chghost capabilities if they were enabled. Details that were never seen are
+/// None, so that "not logged in" can be told apart from "unknown".
+#[deriving(Clone)]
+pub struct TrackedUser {
+    nick: Vec<u8>,
+    user: Option<Vec<u8>>,
+    host: Option<Vec<u8>>,
+    realname: Option<Vec<u8>>,
+    account: Option<Option<Vec<u8>>>,
+    away: Option<bool>,
+    oper: Option<bool>
+}
+
+impl TrackedUser {
+    fn new(nick: &[u8]) -> TrackedUser {
+        TrackedUser {
+            nick: nick.to_vec(),
+            user: None,
+            host: None,
+            realname: None,
+            account: None,
+            away: None,
+            oper: None
+        }
+    }
+
+    /// Returns the user's nick
+    pub fn nick<'a>(&'a self) -> &'a [u8] {
+        self.nick.as_slice()
+    }
+
+    /// Returns the user's username, if known
+    pub fn user<'a>(&'a self) -> Option<&'a [u8]> {
+        self.user.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns the user's hostname, if known
+    pub fn host<'a>(&'a self) -> Option<&'a [u8]> {
+        self.host.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns the user's realname (gecos), if known
+    pub fn realname<'a>(&'a self) -> Option<&'a [u8]> {
+        self.realname.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns the services account the user is logged in to.
+    /// `Some(None)` means they're known not to be logged in, `None` that we don't know.
+    pub fn account<'a>(&'a self) -> Option<Option<&'a [u8]>> {
+        self.account.as_ref().map(|a| a.as_ref().map(|v| v.as_slice()))
+    }
+
+    /// Returns whether the user is away, if known
+    pub fn is_away(&self) -> Option<bool> {
+        self.away
+    }
+
+    /// Returns whether the user is an IRC operator, if known
+    pub fn is_oper(&self) -> Option<bool> {
+        self.oper
+    }
+
+    fn set_userhost(&mut self, user: Option<&[u8]>, host: Option<&[u8]>) {
+        if user.is_some() {
+            self.user = user.map(|v| v.to_vec());
+        }
+        if host.is_some() {
+            self.host = host.map(|v| v.to_vec());
+        }
+    }
+}
+
+/// How long messages are remembered for the channel statistics, in nanoseconds
+static STATS_WINDOW_NS: u64 = 3600 * 1_000_000_000;
+
+/// Activity statistics of a tracked channel, counted since we joined it
+#[deriving(Clone)]
+pub struct ChannelStats {
+    /// When each message of the last hour was sent, and the casemapped nick
+    /// that sent it, oldest first
+    recent: RingBuf<(u64, Vec<u8>)>,
+    total_messages: u64,
+    peak_members: uint,
+    /// The time in nanoseconds when driven by `conn::Manual`, instead of the system clock
+    clock: Option<u64>
+}
+
+impl ChannelStats {
+    fn new(clock: Option<u64>) -> ChannelStats {
+        ChannelStats { recent: RingBuf::new(), total_messages: 0, peak_members: 0, clock: clock }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.unwrap_or_else(|| time::precise_time_ns())
+    }
+
+    /// Returns the number of messages (PRIVMSGs, NOTICEs and ACTIONs) sent
+    /// to the channel since we joined it
+    pub fn total_messages(&self) -> u64 {
+        self.total_messages
+    }
+
+    /// Returns the highest number of members the channel has had since we joined it
+    pub fn peak_members(&self) -> uint {
+        self.peak_members
+    }
+
+    /// Returns the number of messages sent to the channel within `window`.
+    /// Only the last hour is remembered, so longer windows count the last hour.
+    pub fn messages_within(&self, window: Duration) -> uint {
+        self.messages_since(self.now(), window_ns(window))
+    }
+
+    /// Returns the number of different nicks that sent messages to the
+    /// channel within `window`. Only the last hour is remembered.
+    pub fn active_users_within(&self, window: Duration) -> uint {
+        self.active_since(self.now(), window_ns(window))
+    }
+
+    fn messages_since(&self, now: u64, window: u64) -> uint {
+        self.recent.iter().filter(|&&(t, _)| now - t <= window).count()
+    }
+
+    fn active_since(&self, now: u64, window: u64) -> uint {
+        self.recent.iter().filter(|&&(t, _)| now - t <= window)
+                   .map(|&(_, ref nick)| nick).collect::<HashSet<_>>().len()
+    }
+
+    fn record(&mut self, now: u64, nick: Vec<u8>) {
+        loop {
+            match self.recent.front() {
+                Some(&(t, _)) if now - t > STATS_WINDOW_NS => (),
+                _ => break
+            }
+            self.recent.pop_front();
+        }
+        self.recent.push_back((now, nick));
+        self.total_messages += 1;
+    }
+}
+
+fn window_ns(window: Duration) -> u64 {
+    min(max(window.num_milliseconds(), 0) as u64 * 1_000_000, STATS_WINDOW_NS)
+}
+
+/// A tracked channel
+#[deriving(Clone)]
+pub struct Channel {
+    name: Vec<u8>,
+    topic: Option<Vec<u8>>,
+    modes: Vec<(char, Option<Vec<u8>>)>,
+    members: HashMap<Vec<u8>, Member>,
+    stats: ChannelStats
+}
+
+impl Channel {
+    fn new(name: &[u8], clock: Option<u64>) -> Channel {
+        Channel {
+            name: name.to_vec(),
+            topic: None,
+            modes: Vec::new(),
+            members: HashMap::new(),
+            stats: ChannelStats::new(clock)
+        }
+    }
+
+    /// Returns the channel name
+    pub fn name<'a>(&'a self) -> &'a [u8] {
+        self.name.as_slice()
+    }
+
+    /// Returns the channel topic, if it has one
+    pub fn topic<'a>(&'a self) -> Option<&'a [u8]> {
+        self.topic.as_ref().map(|t| t.as_slice())
+    }
+
+    /// Returns the channel modes that are set, along with their parameters.
+    /// List modes (bans etc.) and membership modes are not included.
+    pub fn modes<'a>(&'a self) -> &'a [(char, Option<Vec<u8>>)] {
+        self.modes.as_slice()
+    }
+
+    /// Returns the members of the channel, in no particular order.
+    pub fn members<'a>(&'a self) -> Vec<&'a Member> {
+        self.members.values().collect()
+    }
+
+    /// Returns the channel's activity statistics
+    pub fn stats<'a>(&'a self) -> &'a ChannelStats {
+        &self.stats
+    }
+}
+
+/// State of the channels we're in.
+///
+/// The state is built from the JOIN, PART, KICK, QUIT, NICK, MODE and TOPIC
+/// lines we receive, along with the NAMES, TOPIC and channel MODE replies.
+/// Channels are only tracked while we are in them, and users while they share
+/// a channel with us. Names are compared using the server's casemapping.
+///
+/// A complete NAMES reply replaces the member list of a channel, so asking
+/// for NAMES again (see `Conn::resync()`) repairs a list that went out of
+/// sync.
+pub struct State {
+    isupport: ISupport,
+    casemapping: CaseMapping,
+    channels: HashMap<Vec<u8>, Channel>,
+    users: HashMap<Vec<u8>, TrackedUser>,
+    /// The nicks listed so far in NAMES replies that haven't ended yet
+    names: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+    /// See `set_clock()`
+    clock: Option<u64>
+}
+
+impl State {
+    /// Returns a new State that isn't tracking any channels
+    pub fn new() -> State {
+        let isupport = ISupport::new();
+        State {
+            casemapping: isupport.casemapping(),
+            isupport: isupport,
+            channels: HashMap::new(),
+            users: HashMap::new(),
+            names: HashMap::new(),
+            clock: None
+        }
+    }
+
+    /// Makes the channel statistics use `now`, in nanoseconds, as the current
+    /// time instead of the system clock, as a Conn driven by `conn::Manual` does
+    pub fn set_clock(&mut self, now: u64) {
+        self.clock = Some(now);
+        for (_, chan) in self.channels.iter_mut() {
+            chan.stats.clock = Some(now);
+        }
+    }
+
+    /// Updates the server features used to interpret modes and compare names
+    pub fn set_isupport(&mut self, isupport: &ISupport) {
+        self.isupport = isupport.clone();
+        let casemapping = isupport.casemapping();
+        if casemapping != self.casemapping {
+            self.casemapping = casemapping;
+            self.rekey();
+        }
+    }
+
+    /// Casemaps the names that channels, members and users are looked up by
+    /// again, after the server changed its CASEMAPPING
+    fn rekey(&mut self) {
+        let casemapping = self.casemapping;
+        for (_, mut chan) in mem::replace(&mut self.channels, HashMap::new()).into_iter() {
+            let members = mem::replace(&mut chan.members, HashMap::new());
+            chan.members = members.into_iter().map(|(_, m)| (casemapping.lower(m.nick.as_slice()), m)).collect();
+            self.channels.insert(casemapping.lower(chan.name.as_slice()), chan);
+        }
+        let users = mem::replace(&mut self.users, HashMap::new());
+        self.users = users.into_iter().map(|(_, u)| (casemapping.lower(u.nick.as_slice()), u)).collect();
+        let names = mem::replace(&mut self.names, HashMap::new());
+        self.names = names.into_iter().map(|(chan, nicks)| {
+            (casemapping.lower(chan.as_slice()), nicks.into_iter().map(|n| casemapping.lower(n.as_slice())).collect())
+        }).collect();
+    }
+
+    /// Returns the tracked channel with the given name
+    pub fn channel<'a>(&'a self, name: &[u8]) -> Option<&'a Channel> {
+        self.channels.get(&self.casemapping.lower(name))
+    }
+
+    /// Returns all tracked channels, in no particular order
+    pub fn channels<'a>(&'a self) -> Vec<&'a Channel> {
+        self.channels.values().collect()
+    }
+
+    /// Returns the member with the given nick in the given channel
+    pub fn member<'a>(&'a self, chan: &[u8], nick: &[u8]) -> Option<&'a Member> {
+        self.channel(chan).and_then(|c| c.members.get(&self.casemapping.lower(nick)))
+    }
+
+    /// Returns the nicks of the members of `chan` that start with `prefix`,
+    /// ignoring case, e.g. for tab completion. They're sorted in casemapped
+    /// order. An empty prefix matches every member.
+    pub fn complete<'a>(&'a self, chan: &[u8], prefix: &[u8]) -> Vec<&'a [u8]> {
+        let prefix = self.casemapping.lower(prefix);
+        let mut found = match self.channel(chan) {
+            None => return Vec::new(),
+            Some(c) => c.members.iter().filter(|&(lower, _)| lower.as_slice().starts_with(prefix.as_slice()))
+                                       .collect::<Vec<_>>()
+        };
+        found.sort_by(|&(a, _), &(b, _)| a.cmp(b));
+        found.into_iter().map(|(_, m)| m.nick()).collect()
+    }
+
+    /// Returns what we know about `nick`, if they share a channel with us
+    pub fn user<'a>(&'a self, nick: &[u8]) -> Option<&'a TrackedUser> {
+        self.users.get(&self.casemapping.lower(nick))
+    }
+
+    /// Returns all users that share a channel with us, in no particular order
+    pub fn users<'a>(&'a self) -> Vec<&'a TrackedUser> {
+        self.users.values().collect()
+    }
+
+    /// Returns the tracked channels that `nick` is in
+    pub fn common_channels<'a>(&'a self, nick: &[u8]) -> Vec<&'a Channel> {
+        let nick = self.casemapping.lower(nick);
+        self.channels.values().filter(|c| c.members.contains_key(&nick)).collect()
+    }
+
+    /// Returns `true` if `nick` is opped in `chan`, or has a membership mode
+    /// that ranks higher than op (e.g. channel owner).
+    pub fn is_op(&self, chan: &[u8], nick: &[u8]) -> bool {
+        self.has_rank(chan, nick, 'o')
+    }
+
+    /// Returns `true` if `nick` is voiced in `chan`, or has a membership mode
+    /// that ranks higher than voice.
+    pub fn is_voiced(&self, chan: &[u8], nick: &[u8]) -> bool {
+        self.has_rank(chan, nick, 'v')
+    }
+
+    /// Returns `true` if the channel mode `mode` (e.g. 'm' for moderated)
+    /// is set on `chan`. List modes like bans are not tracked.
+    pub fn channel_mode(&self, chan: &[u8], mode: char) -> bool {
+        self.channel(chan).map_or(false, |c| c.modes.iter().any(|&(m, _)| m == mode))
+    }
+
+    /// Returns the parameter of the channel mode `mode` on `chan`, e.g. the
+    /// key for 'k' or the user limit for 'l'. None if the mode isn't set or
+    /// has no parameter.
+    pub fn channel_mode_param<'a>(&'a self, chan: &[u8], mode: char) -> Option<&'a [u8]> {
+        self.channel(chan).and_then(|c| {
+            c.modes.iter().find(|&&(m, _)| m == mode)
+                   .and_then(|&(_, ref param)| param.as_ref().map(|p| p.as_slice()))
+        })
+    }
+
+    /// Returns the number of members in `chan`, or 0 if it isn't tracked
+    pub fn member_count(&self, chan: &[u8]) -> uint {
+        self.channel(chan).map_or(0, |c| c.members.len())
+    }
+
+    /// Returns `true` if `nick` has the membership mode `mode` in `chan`,
+    /// or one that ranks higher according to PREFIX.
+    pub fn has_rank(&self, chan: &[u8], nick: &[u8], mode: char) -> bool {
+        let member = match self.member(chan, nick) {
+            None => return false,
+            Some(member) => member
+        };
+        let prefix = self.isupport.prefix();
+        let rank = match prefix.iter().position(|&(m, _)| m == mode) {
+            None => return member.modes.contains(&mode),
+            Some(rank) => rank
+        };
+        member.modes.iter().any(|m| {
+            prefix.iter().position(|&(p, _)| p == *m).map_or(false, |r| r <= rank)
+        })
+    }
+
+    /// Updates the state from a line received from the server.
+    /// `me` is our current nick.
+    pub fn handle_line(&mut self, me: &[u8], line: &Line) {
+        let args = line.args.as_slice();
+        let nick = line.prefix.as_ref().map(|p| p.nick());
+        match line.command {
+            IRCCmd(ref cmd) => match cmd.as_slice() {
+                "JOIN" if !args.is_empty() && nick.is_some() => {
+                    self.join(me, args[0].as_slice(), line.prefix.as_ref().unwrap());
+                    // extended-join: JOIN #chan account :realname
+                    if args.len() > 2 {
+                        self.update_user(nick.unwrap(), |u| {
+                            u.account = Some(account_name(args[1].as_slice()));
+                            u.realname = Some(args[2].clone());
+                        });
+                    }
+                }
+                "PART" if !args.is_empty() && nick.is_some() => {
+                    for chan in args[0].as_slice().split(|&b| b == ',' as u8) {
+                        self.part(me, chan, nick.unwrap());
+                    }
+                }
+                "KICK" if args.len() > 1 => {
+                    self.part(me, args[0].as_slice(), args[1].as_slice());
+                }
+                "QUIT" if nick.is_some() => {
+                    let nick = self.casemapping.lower(nick.unwrap());
+                    for chan in self.channels.values_mut() {
+                        chan.members.remove(&nick);
+                    }
+                    self.users.remove(&nick);
+                }
+                "ACCOUNT" if !args.is_empty() && nick.is_some() => {
+                    self.update_user(nick.unwrap(), |u| {
+                        u.account = Some(account_name(args[0].as_slice()));
+                    });
+                }
+                "AWAY" if nick.is_some() => {
+                    self.update_user(nick.unwrap(), |u| u.away = Some(!args.is_empty()));
+                }
+                "CHGHOST" if args.len() > 1 && nick.is_some() => {
+                    let (user, host) = (args[0].as_slice(), args[1].as_slice());
+                    self.update_user(nick.unwrap(), |u| u.set_userhost(Some(user), Some(host)));
+                    let key = self.casemapping.lower(nick.unwrap());
+                    for chan in self.channels.values_mut() {
+                        match chan.members.get_mut(&key) {
+                            None => (),
+                            Some(member) => {
+                                member.user = Some(user.to_vec());
+                                member.host = Some(host.to_vec());
+                            }
+                        }
+                    }
+                }
+                "NICK" if !args.is_empty() && nick.is_some() => {
+                    self.rename(nick.unwrap(), args[0].as_slice());
+                }
+                "TOPIC" if args.len() > 1 => {
+                    self.set_topic(args[0].as_slice(), Some(args[1].as_slice()));
+                }
+                "MODE" if args.len() > 1 && self.isupport.is_channel(args[0].as_slice()) => {
+                    self.apply_modes(args[0].as_slice(), args[1].as_slice(), args.slice_from(2));
+                }
+                "PRIVMSG" | "NOTICE" if !args.is_empty() && nick.is_some() => {
+                    self.saw_message(args[0].as_slice(), nick.unwrap());
+                }
+                _ => ()
+            },
+            IRCAction(ref dst) if nick.is_some() => self.saw_message(dst.as_slice(), nick.unwrap()),
+            // RPL_CHANNELMODEIS
+            IRCCode(324) if args.len() > 2 => {
+                match self.channels.get_mut(&self.casemapping.lower(args[1].as_slice())) {
+                    None => return,
+                    Some(chan) => chan.modes.clear()
+                }
+                self.apply_modes(args[1].as_slice(), args[2].as_slice(), args.slice_from(3));
+            }
+            // RPL_AWAY, in reply to a WHOIS or a message
+            IRCCode(301) if args.len() > 1 => {
+                self.update_user(args[1].as_slice(), |u| u.away = Some(true));
+            }
+            // RPL_WHOISUSER
+            IRCCode(311) if args.len() > 5 => {
+                // :server 311 me nick user host * :realname
+                self.update_user(args[1].as_slice(), |u| {
+                    u.set_userhost(Some(args[2].as_slice()), Some(args[3].as_slice()));
+                    u.realname = Some(args[5].clone());
+                });
+            }
+            // RPL_WHOISOPERATOR
+            IRCCode(313) if args.len() > 1 => {
+                self.update_user(args[1].as_slice(), |u| u.oper = Some(true));
+            }
+            // RPL_WHOISACCOUNT
+            IRCCode(330) if args.len() > 2 => {
+                // :server 330 me nick account :is logged in as
+                self.update_user(args[1].as_slice(), |u| u.account = Some(Some(args[2].clone())));
+            }
+            // RPL_WHOREPLY
+            IRCCode(352) => {
+                let prefix = self.isupport.prefix().iter().map(|&(_, s)| s).collect::<Vec<_>>();
+                match WhoReply::parse(line, prefix.as_slice()) {
+                    None => (),
+                    Some(reply) => self.who_reply(&reply)
+                }
+            }
+            // RPL_NOTOPIC
+            IRCCode(331) if args.len() > 1 => self.set_topic(args[1].as_slice(), None),
+            // RPL_TOPIC
+            IRCCode(332) if args.len() > 2 => {
+                self.set_topic(args[1].as_slice(), Some(args[2].as_slice()));
+            }
+            // RPL_NAMREPLY
+            IRCCode(353) if args.len() > 2 => {
+                // :server 353 me = #chan :@op +voice nick
+                // some servers leave out the channel type
+                let chan = args[args.len()-2].as_slice();
+                let names = args[args.len()-1].as_slice();
+                self.add_names(chan, names);
+            }
+            // RPL_ENDOFNAMES
+            IRCCode(366) if args.len() > 1 => self.end_names(args[1].as_slice()),
+            _ => ()
+        }
+    }
+
+    fn join(&mut self, me: &[u8], chan: &[u8], who: &User) {
+        let nick = who.nick();
+        let key = self.casemapping.lower(chan);
+        if self.casemapping.eq_ignore_case(me, nick) {
+            self.channels.insert(key.clone(), Channel::new(chan, self.clock));
+        }
+        match self.channels.get_mut(&key) {
+            None => return,
+            Some(chan) => {
+                let member = Member {
+                    nick: nick.to_vec(),
+                    user: who.user().map(|v| v.to_vec()),
+                    host: who.host().map(|v| v.to_vec()),
+                    modes: Vec::new()
+                };
+                chan.members.insert(self.casemapping.lower(nick), member);
+                chan.stats.peak_members = max(chan.stats.peak_members, chan.members.len());
+            }
+        }
+        self.track_user(who);
+    }
+
+    /// Counts a message to `target` in the channel statistics, if it's a channel
+    fn saw_message(&mut self, target: &[u8], nick: &[u8]) {
+        let nick = self.casemapping.lower(nick);
+        match self.channels.get_mut(&self.casemapping.lower(target)) {
+            None => (),
+            Some(chan) => {
+                let now = chan.stats.now();
+                chan.stats.record(now, nick)
+            }
+        }
+    }
+
+    /// Starts tracking a user that's in one of our channels, or updates their
+    /// user@host if we already are
+    fn track_user(&mut self, who: &User) {
+        let nick = who.nick();
+        let key = self.casemapping.lower(nick);
+        if !self.users.contains_key(&key) {
+            self.users.insert(key.clone(), TrackedUser::new(nick));
+        }
+        let user = self.users.get_mut(&key).unwrap();
+        user.nick = nick.to_vec();
+        user.set_userhost(who.user(), who.host());
+    }
+
+    fn who_reply(&mut self, reply: &WhoReply) {
+        let (user, host) = (reply.user.as_slice(), reply.host.as_slice());
+        self.update_user(reply.nick.as_slice(), |u| {
+            u.set_userhost(Some(user), Some(host));
+            u.realname = Some(reply.realname.clone());
+            u.away = Some(reply.away);
+            u.oper = Some(reply.oper);
+        });
+        let chan = match reply.channel {
+            None => return,
+            Some(ref chan) => self.casemapping.lower(chan.as_slice())
+        };
+        let nick = self.casemapping.lower(reply.nick.as_slice());
+        match self.channels.get_mut(&chan).and_then(|c| c.members.get_mut(&nick)) {
+            None => (),
+            Some(member) => {
+                member.user = Some(user.to_vec());
+                member.host = Some(host.to_vec());
+            }
+        }
+    }
+
+    /// Applies `f` to the tracked user `nick`, if there is one
+    fn update_user(&mut self, nick: &[u8], f: |&mut TrackedUser|) {
+        match self.users.get_mut(&self.casemapping.lower(nick)) {
+            None => (),
+            Some(user) => f(user)
+        }
+    }
+
+    /// Stops tracking users that no longer share a channel with us
+    fn forget_users(&mut self) {
+        let channels = &self.channels;
+        let gone = self.users.keys().filter(|nick| {
+            !channels.values().any(|c| c.members.contains_key(*nick))
+        }).map(|nick| nick.clone()).collect::<Vec<_>>();
+        for nick in gone.iter() {
+            self.users.remove(nick);
+        }
+    }
+
+    fn part(&mut self, me: &[u8], chan: &[u8], nick: &[u8]) {
+        let key = self.casemapping.lower(chan);
+        if self.casemapping.eq_ignore_case(me, nick) {
+            self.channels.remove(&key);
+        } else {
+            match self.channels.get_mut(&key) {
+                None => (),
+                Some(chan) => { chan.members.remove(&self.casemapping.lower(nick)); }
+            }
+        }
+        self.forget_users();
+    }
+
+    fn rename(&mut self, old: &[u8], new: &[u8]) {
+        let (old, newkey) = (self.casemapping.lower(old), self.casemapping.lower(new));
+        match self.users.remove(&old) {
+            None => (),
+            Some(mut user) => {
+                user.nick = new.to_vec();
+                self.users.insert(newkey.clone(), user);
+            }
+        }
+        for chan in self.channels.values_mut() {
+            match chan.members.remove(&old) {
+                None => (),
+                Some(mut member) => {
+                    member.nick = new.to_vec();
+                    chan.members.insert(newkey.clone(), member);
+                }
+            }
+        }
+    }
+
+    fn set_topic(&mut self, chan: &[u8], topic: Option<&[u8]>) {
+        match self.channels.get_mut(&self.casemapping.lower(chan)) {
+            None => (),
+            Some(chan) => chan.topic = topic.map(|t| t.to_vec())
+        }
+    }
+
+    fn apply_modes(&mut self, chan: &[u8], modestr: &[u8], params: &[Vec<u8>]) {
+        let prefix = self.isupport.prefix();
+        let chanmodes = self.isupport.chanmodes();
+        let changes = modes::parse(&self.isupport, modestr, params);
+        let chan = match self.channels.get_mut(&self.casemapping.lower(chan)) {
+            None => return,
+            Some(chan) => chan
+        };
+        for change in changes.into_iter() {
+            if prefix.iter().any(|&(m, _)| m == change.mode) {
+                let nick = match change.param {
+                    None => continue,
+                    Some(ref nick) => self.casemapping.lower(nick.as_slice())
+                };
+                match chan.members.get_mut(&nick) {
+                    None => (),
+                    Some(member) => {
+                        if change.set {
+                            add_member_mode(&mut member.modes, change.mode, prefix.as_slice());
+                        } else {
+                            member.modes.retain(|&m| m != change.mode);
+                        }
+                    }
+                }
+            } else if !chanmodes.list.contains(&change.mode) {
+                chan.modes.retain(|&(m, _)| m != change.mode);
+                if change.set {
+                    chan.modes.push((change.mode, change.param));
+                }
+            }
+        }
+    }
+
+    fn add_names(&mut self, chan: &[u8], names: &[u8]) {
+        let prefix = self.isupport.prefix();
+        let mut joined = Vec::new();
+        {
+            let key = self.casemapping.lower(chan);
+            let chan = match self.channels.get_mut(&key) {
+                None => return,
+                Some(chan) => chan
+            };
+            if !self.names.contains_key(&key) {
+                self.names.insert(key.clone(), HashSet::new());
+            }
+            let listed = self.names.get_mut(&key).unwrap();
+            for name in names.split(|&b| b == ' ' as u8).filter(|n| !n.is_empty()) {
+                let mut modes = Vec::new();
+                let mut name = name;
+                loop {
+                    match prefix.iter().find(|&&(_, s)| !name.is_empty() && s as u8 == name[0]) {
+                        None => break,
+                        Some(&(m, _)) => {
+                            add_member_mode(&mut modes, m, prefix.as_slice());
+                            name = name.slice_from(1);
+                        }
+                    }
+                }
+                // userhost-in-names sends nick!user@host
+                let who = User::parse(name);
+                if who.nick().is_empty() {
+                    continue;
+                }
+                let member = Member {
+                    nick: who.nick().to_vec(),
+                    user: who.user().map(|v| v.to_vec()),
+                    host: who.host().map(|v| v.to_vec()),
+                    modes: modes
+                };
+                let nick = self.casemapping.lower(who.nick());
+                listed.insert(nick.clone());
+                chan.members.insert(nick, member);
+                joined.push(who);
+            }
+            chan.stats.peak_members = max(chan.stats.peak_members, chan.members.len());
+        }
+        for who in joined.iter() {
+            self.track_user(who);
+        }
+    }
+
+    /// Removes the members of `chan` that weren't in the NAMES reply that
+    /// just ended
+    fn end_names(&mut self, chan: &[u8]) {
+        let key = self.casemapping.lower(chan);
+        let listed = match self.names.remove(&key) {
+            None => return,
+            Some(listed) => listed
+        };
+        match self.channels.get_mut(&key) {
+            None => return,
+            Some(chan) => {
+                let gone = chan.members.keys().filter(|nick| !listed.contains(*nick))
+                                              .map(|nick| nick.clone()).collect::<Vec<_>>();
+                for nick in gone.iter() {
+                    chan.members.remove(nick);
+                }
+            }
+        }
+        self.forget_users();
+    }
+}
+
+/// Returns the account from an extended-join or ACCOUNT line, where `*` means none
+fn account_name(account: &[u8]) -> Option<Vec<u8>> {
+    if account == b"*" { None } else { Some(account.to_vec()) }
+}
+
+/// Adds a membership mode to a list of modes, keeping it sorted by rank
+fn add_member_mode(modes: &mut Vec<char>, mode: char, prefix: &[(char, char)]) {
+    if modes.contains(&mode) {
+        return;
+    }
+    fn rank(prefix: &[(char, char)], mode: char) -> uint {
+        prefix.iter().position(|&(p, _)| p == mode).unwrap_or(prefix.len())
+    }
+    modes.push(mode);
+    modes.sort_by(|&a, &b| rank(prefix, a).cmp(&rank(prefix, b)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{State, ChannelStats};
+    use conn::Line;
+    use isupport::ISupport;
+    use std::time::Duration;
+
+    fn feed(state: &mut State, lines: &[&[u8]]) {
+        for &line in lines.iter() {
+            state.handle_line(b"me", &Line::parse(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_predicates() {
+        let mut state = State::new();
+        feed(&mut state, [b":me!u@h JOIN #rust",
+                          b":server 353 me = #rust :me @op +voice",
+                          b":server 324 me #rust +mntlk 10 secret"]);
+        assert!(state.is_voiced(b"#rust", b"voice"));
+        assert!(state.is_voiced(b"#rust", b"op"));
+        assert!(!state.is_voiced(b"#rust", b"me"));
+        assert!(state.channel_mode(b"#rust", 'm'));
+        assert!(!state.channel_mode(b"#rust", 'i'));
+        assert_eq!(state.channel_mode_param(b"#rust", 'l'), Some(b"10"));
+        assert_eq!(state.channel_mode_param(b"#rust", 'k'), Some(b"secret"));
+        assert_eq!(state.channel_mode_param(b"#rust", 'm'), None);
+
+        feed(&mut state, [b":op!u@h MODE #rust -ml"]);
+        assert!(!state.channel_mode(b"#rust", 'm'));
+        assert_eq!(state.channel_mode_param(b"#rust", 'l'), None);
+        assert_eq!(state.member_count(b"#RUST"), 3);
+
+        feed(&mut state, [b":new!ident@example.com JOIN #rust",
+                          b":server 353 me = #rust :@nick!user@host"]);
+        let member = state.member(b"#rust", b"new").unwrap();
+        assert_eq!((member.user(), member.host()), (Some(b"ident"), Some(b"example.com")));
+        let member = state.member(b"#rust", b"nick").unwrap();
+        assert_eq!((member.user(), member.host()), (Some(b"user"), Some(b"host")));
+        assert!(state.member(b"#rust", b"voice").unwrap().host().is_none());
+    }
+
+    #[test]
+    fn test_membership() {
+        let mut state = State::new();
+        feed(&mut state, [b":me!u@h JOIN #Rust",
+                          b":server 353 me = #rust :me @Op +voice plain",
+                          b":server 366 me #rust :End of /NAMES list.",
+                          b":server 332 me #rust :the topic",
+                          b":new!u@h JOIN #rust"]);
+        {
+            let chan = state.channel(b"#RUST").expect("channel not tracked");
+            assert_eq!(chan.name(), b"#Rust");
+            assert_eq!(chan.topic(), Some(b"the topic"));
+            assert_eq!(chan.members().len(), 5);
+        }
+        assert!(state.is_op(b"#rust", b"op"));
+        assert!(!state.is_op(b"#rust", b"voice"));
+        assert!(state.has_rank(b"#rust", b"voice", 'v'));
+        assert!(state.has_rank(b"#rust", b"op", 'v'));
+        assert!(!state.has_rank(b"#rust", b"plain", 'v'));
+
+        feed(&mut state, [b":op!u@h MODE #rust +o-o+m plain op",
+                          b":plain!u@h NICK renamed",
+                          b":voice!u@h PART #rust :bye",
+                          b":op!u@h KICK #rust new :no"]);
+        assert!(state.is_op(b"#rust", b"renamed"));
+        assert!(!state.is_op(b"#rust", b"op"));
+        assert!(state.member(b"#rust", b"plain").is_none());
+        assert!(state.member(b"#rust", b"voice").is_none());
+        assert!(state.member(b"#rust", b"new").is_none());
+        assert_eq!(state.channel(b"#rust").unwrap().modes(), [('m', None)].as_slice());
+        assert_eq!(state.common_channels(b"renamed").len(), 1);
+        assert_eq!(state.member_count(b"#rust"), 2);
+        assert_eq!(state.member_count(b"#other"), 0);
+
+        feed(&mut state, [b":renamed!u@h QUIT :gone"]);
+        assert_eq!(state.common_channels(b"renamed").len(), 0);
+
+        feed(&mut state, [b":me!u@h PART #rust"]);
+        assert!(state.channel(b"#rust").is_none());
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut state = State::new();
+        feed(&mut state, [b":me!u@h JOIN #rust",
+                          b":server 353 me = #rust :me @XMPPwocky +xmpp_bot [away] other"]);
+        assert_eq!(state.complete(b"#RUST", b"xmpp"), vec![b"xmpp_bot".as_slice(), b"XMPPwocky".as_slice()]);
+        assert_eq!(state.complete(b"#rust", b"{AWAY"), vec![b"[away]".as_slice()]);
+        assert_eq!(state.complete(b"#rust", b"nobody"), Vec::<&[u8]>::new());
+        assert_eq!(state.complete(b"#rust", b"").len(), 5);
+        assert_eq!(state.complete(b"#other", b"x"), Vec::<&[u8]>::new());
+    }
+
+    #[test]
+    fn test_names_resync() {
+        let mut state = State::new();
+        feed(&mut state, [b":me!u@h JOIN #rust",
+                          b":server 353 me = #rust :me @op stale",
+                          b":server 366 me #rust :End of /NAMES list.",
+                          b":server 353 me = #rust :me op",
+                          b":server 353 me = #rust :+new"]);
+        // members aren't dropped until the reply is complete
+        assert!(state.member(b"#rust", b"stale").is_some());
+        assert!(!state.is_op(b"#rust", b"op"));
+        feed(&mut state, [b":server 366 me #rust :End of /NAMES list."]);
+        assert!(state.member(b"#rust", b"stale").is_none());
+        assert!(state.user(b"stale").is_none());
+        assert!(state.is_voiced(b"#rust", b"new"));
+        assert_eq!(state.member_count(b"#rust"), 3);
+    }
+
+    #[test]
+    fn test_tracked_users() {
+        let mut state = State::new();
+        feed(&mut state, [b":me!u@h JOIN #rust",
+                          b":server 353 me = #rust :me @op",
+                          b":new!ident@host JOIN #rust acct :Real Name"]);
+        {
+            let user = state.user(b"NEW").unwrap();
+            assert_eq!(user.account(), Some(Some(b"acct")));
+            assert_eq!(user.realname(), Some(b"Real Name"));
+            assert_eq!(user.host(), Some(b"host"));
+            assert_eq!(user.is_away(), None);
+            let op = state.user(b"op").unwrap();
+            assert_eq!(op.account(), None);
+            assert_eq!(op.host(), None);
+        }
+
+        feed(&mut state, [b":new!ident@host ACCOUNT *",
+                          b":new!ident@host AWAY :lunch",
+                          b":server 352 me #rust opuser ophost server op H* :0 Op Real",
+                          b":server 311 me op opuser ophost * :Op Real",
+                          b":server 330 me op opacct :is logged in as"]);
+        {
+            let user = state.user(b"new").unwrap();
+            assert_eq!(user.account(), Some(None));
+            assert_eq!(user.is_away(), Some(true));
+            let op = state.user(b"op").unwrap();
+            assert_eq!(op.host(), Some(b"ophost"));
+            assert_eq!(op.realname(), Some(b"Op Real"));
+            assert_eq!(op.account(), Some(Some(b"opacct")));
+            assert_eq!((op.is_away(), op.is_oper()), (Some(false), Some(true)));
+        }
+
+        feed(&mut state, [b":new!ident@host AWAY",
+                          b":new!ident@host NICK newer",
+                          b":newer!ident@host CHGHOST ident2 host2"]);
+        assert!(state.user(b"new").is_none());
+        {
+            let user = state.user(b"newer").unwrap();
+            assert_eq!(user.is_away(), Some(false));
+            assert_eq!(user.host(), Some(b"host2"));
+        }
+        assert_eq!(state.member(b"#rust", b"newer").unwrap().host(), Some(b"host2"));
+
+        feed(&mut state, [b":newer!ident2@host2 PART #rust"]);
+        assert!(state.user(b"newer").is_none());
+        // users that aren't in our channels aren't tracked
+        feed(&mut state, [b":server 311 me stranger u h * :Stranger"]);
+        assert!(state.user(b"stranger").is_none());
+        feed(&mut state, [b":me!u@h PART #rust"]);
+        assert_eq!(state.users().len(), 0);
+    }
+
+    #[test]
+    fn test_channel_stats() {
+        let mut state = State::new();
+        state.set_clock(0);
+        feed(&mut state, [b":me!u@h JOIN #rust",
+                          b":server 353 me = #rust :me a b",
+                          b":a!u@h PRIVMSG #rust :hi",
+                          b":b!u@h NOTICE #rust :hey",
+                          b":a!u@h PRIVMSG #rust :\x01ACTION waves\x01",
+                          b":a!u@h PRIVMSG me :private",
+                          b":c!u@h JOIN #rust",
+                          b":c!u@h PART #rust"]);
+        let stats = state.channel(b"#rust").unwrap().stats();
+        assert_eq!(stats.total_messages(), 3);
+        assert_eq!(stats.peak_members(), 4);
+        assert_eq!(stats.messages_within(Duration::minutes(1)), 3);
+        assert_eq!(stats.active_users_within(Duration::minutes(1)), 2);
+        state.set_clock(120 * 1_000_000_000);
+        let stats = state.channel(b"#rust").unwrap().stats();
+        assert_eq!(stats.messages_within(Duration::minutes(1)), 0);
+        assert_eq!(stats.messages_within(Duration::minutes(5)), 3);
+
+        let mut stats = ChannelStats::new(None);
+        let sec = 1_000_000_000;
+        stats.record(0, b"a".to_vec());
+        stats.record(10 * sec, b"b".to_vec());
+        stats.record(20 * sec, b"a".to_vec());
+        assert_eq!(stats.messages_since(20 * sec, 15 * sec), 2);
+        assert_eq!(stats.active_since(20 * sec, 5 * sec), 1);
+        // older than an hour is forgotten
+        stats.record(3605 * sec, b"c".to_vec());
+        assert_eq!(stats.messages_since(3605 * sec, 3600 * sec), 3);
+        assert_eq!(stats.total_messages(), 4);
+    }
+
+    #[test]
+    fn test_casemapping_change() {
+        let mut state = State::new();
+        feed(&mut state, [b":me!u@h JOIN #rust[",
+                          b":server 353 me = #rust[ :me @Nick[1]"]);
+        assert!(state.member(b"#RUST{", b"nick{1}").is_some());
+        let mut isupport = ISupport::new();
+        isupport.add_tokens([b"me".to_vec(), b"CASEMAPPING=ascii".to_vec(), b"are supported".to_vec()]);
+        state.set_isupport(&isupport);
+        assert!(state.channel(b"#RUST{").is_none());
+        assert!(state.member(b"#RUST[", b"nick[1]").is_some());
+        assert!(state.member(b"#rust[", b"NICK[1]").unwrap().nick() == b"Nick[1]");
+    }
+}
@@ -0,0 +1,71 @@
+//! Persistence of connection state across restarts
+
+use std::io;
+use std::io::{File, IoResult};
+use std::io::fs;
+
+/// Storage for blobs of data, keyed by name.
+///
+/// A store can be given to a connection with `Options.store`, in which case
+/// state such as the watch list and known channel keys is saved to it as it
+/// changes, and loaded from it when connecting.
+pub trait StateStore {
+    /// Returns the blob saved under `key`, or None if there isn't one
+    fn load(&self, key: &str) -> IoResult<Option<Vec<u8>>>;
+
+    /// Saves `data` under `key`, replacing whatever was saved before
+    fn save(&mut self, key: &str, data: &[u8]) -> IoResult<()>;
+}
+
+/// A StateStore that keeps each key in its own file inside a directory.
+///
+/// The directory is created when something is first saved. Keys are used as
+/// file names as they are.
+pub struct FileStore {
+    dir: Path
+}
+
+impl FileStore {
+    /// Returns a FileStore that uses the given directory
+    pub fn new(dir: Path) -> FileStore {
+        FileStore { dir: dir }
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self, key: &str) -> IoResult<Option<Vec<u8>>> {
+        match File::open(&self.dir.join(key)).and_then(|mut f| f.read_to_end()) {
+            Ok(data) => Ok(Some(data)),
+            Err(ref e) if e.kind == io::FileNotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn save(&mut self, key: &str, data: &[u8]) -> IoResult<()> {
+        try!(fs::mkdir_recursive(&self.dir, io::USER_RWX));
+        // write to a temporary file first, so a crash can't leave a truncated file
+        let path = self.dir.join(key);
+        let tmp = self.dir.join(format!("{}.tmp", key));
+        try!(File::create(&tmp).and_then(|mut f| f.write(data)));
+        fs::rename(&tmp, &path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StateStore, FileStore};
+    use std::io::TempDir;
+
+    #[test]
+    fn test_file_store() {
+        let dir = TempDir::new("irc-store").unwrap();
+        let mut store = FileStore::new(dir.path().join("state"));
+        assert_eq!(store.load("watch").unwrap(), None);
+        store.save("watch", b"nick1\nnick2").unwrap();
+        store.save("keys", b"#chan key").unwrap();
+        assert_eq!(store.load("watch").unwrap(), Some(b"nick1\nnick2".to_vec()));
+        store.save("watch", b"").unwrap();
+        assert_eq!(store.load("watch").unwrap(), Some(Vec::new()));
+        assert_eq!(store.load("keys").unwrap(), Some(b"#chan key".to_vec()));
+    }
+}
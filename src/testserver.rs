@@ -0,0 +1,575 @@
+//! A tiny IRC server for tests
+//!
+//! `TestServer` listens on a local port and speaks just enough IRC for a
+//! `Conn` to register and talk to other clients: NICK and USER, PING, JOIN,
+//! PART, PRIVMSG, NOTICE and QUIT. Messages are relayed between the clients
+//! connected to it, so tests and examples can run without a network.
+//!
+//! Users on other servers can be added with `add_remote_user()`. Splitting
+//! their server off with `split()` and joining it back with `rejoin()`
+//! scripts a netsplit. Anything else can be sent with `send_all()`.
+//!
+//! Channel names are lowercased, and nicks are compared ignoring ASCII case.
+//!
+//! For tests of the exact lines a client sends, `script()` plays a fixed
+//! conversation instead.
+//!
+//! This module is only built for the crate's own tests, or with the
+//! "testserver" feature.
+
+use std::ascii::StrAsciiExt;
+use std::collections::HashMap;
+use std::io::{Acceptor, BufferedReader, IoResult, Listener};
+use std::io::net::tcp::{TcpAcceptor, TcpListener, TcpStream};
+use std::task::TaskBuilder;
+use std::uint;
+
+/// The name of the server, which its replies come from
+pub static SERVER_NAME: &'static str = "irc.test";
+
+/// What the server task is told, by the connections and by `TestServer`
+enum Msg {
+    Accepted(uint, TcpStream),
+    Received(uint, String),
+    Closed(uint),
+    AddRemote(String, String, Vec<String>),
+    Split(String),
+    Rejoin(String),
+    SendAll(String),
+    Shutdown
+}
+
+/// A running test server. It stops, closing the connections, when this is
+/// dropped.
+pub struct TestServer {
+    port: u16,
+    tx: Sender<Msg>,
+    acceptor: TcpAcceptor
+}
+
+impl TestServer {
+    /// Starts a server on a free port of 127.0.0.1
+    pub fn start() -> IoResult<TestServer> {
+        let mut listener = try!(TcpListener::bind("127.0.0.1", 0));
+        let port = try!(listener.socket_name()).port;
+        let mut acceptor = try!(listener.listen());
+        let (tx, rx) = channel();
+        let server = TestServer { port: port, tx: tx.clone(), acceptor: acceptor.clone() };
+        TaskBuilder::new().named("testserver acceptor").spawn(proc() {
+            // this fails once close_accept() has been called
+            for (id, stream) in acceptor.incoming().enumerate() {
+                let stream = match stream {
+                    Err(_) => break,
+                    Ok(stream) => stream
+                };
+                let reader = stream.clone();
+                if tx.send_opt(Accepted(id, stream)).is_err() {
+                    break;
+                }
+                let tx = tx.clone();
+                TaskBuilder::new().named("testserver reader").spawn(proc() {
+                    let mut reader = BufferedReader::new(reader);
+                    loop {
+                        let line = match reader.read_line() {
+                            Err(_) => break,
+                            Ok(line) => line
+                        };
+                        if tx.send_opt(Received(id, line)).is_err() {
+                            return;
+                        }
+                    }
+                    let _ = tx.send_opt(Closed(id));
+                });
+            }
+        });
+        TaskBuilder::new().named("testserver").spawn(proc() {
+            let mut ircd = Ircd { clients: HashMap::new(), next_remote: 0 };
+            for msg in rx.iter() {
+                match msg {
+                    Shutdown => break,
+                    msg => ircd.handle(msg)
+                }
+            }
+            for client in ircd.clients.values_mut() {
+                match client.stream {
+                    None => (),
+                    Some(ref mut stream) => { let _ = stream.close_read(); }
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    /// Returns the port the server listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Adds a user on another server, e.g. `leaf.test`, in the given
+    /// channels. Their JOINs are sent to the clients in the channels.
+    pub fn add_remote_user(&self, nick: &str, server: &str, chans: &[&str]) {
+        let chans = chans.iter().map(|c| c.to_string()).collect();
+        let _ = self.tx.send_opt(AddRemote(nick.to_string(), server.to_string(), chans));
+    }
+
+    /// Splits `server` off the network: its users QUIT with the reason
+    /// `irc.test <server>`, as in a netsplit
+    pub fn split(&self, server: &str) {
+        let _ = self.tx.send_opt(Split(server.to_string()));
+    }
+
+    /// Joins `server` back after `split()`: its users join their channels again
+    pub fn rejoin(&self, server: &str) {
+        let _ = self.tx.send_opt(Rejoin(server.to_string()));
+    }
+
+    /// Sends a raw line, without the CRLF, to every registered client
+    pub fn send_all(&self, line: &str) {
+        let _ = self.tx.send_opt(SendAll(line.to_string()));
+    }
+}
+
+/// Plays the server's side of a single connection on a free port of
+/// 127.0.0.1, which is returned. Lines of `script` starting with `S: ` are
+/// sent to the client and those starting with `C: ` are what the client has
+/// to send next. The connection is closed when the script ends. The receiver
+/// gets the first line that didn't match, or None if the whole script played
+/// out.
+pub fn script(script: Vec<&'static str>) -> IoResult<(u16, Receiver<Option<String>>)> {
+    let mut listener = try!(TcpListener::bind("127.0.0.1", 0));
+    let port = try!(listener.socket_name()).port;
+    let mut acceptor = try!(listener.listen());
+    let (tx, rx) = channel();
+    TaskBuilder::new().named("testserver script").spawn(proc() {
+        let stream = match acceptor.accept() {
+            Err(e) => {
+                tx.send(Some(format!("accept failed: {}", e)));
+                return;
+            }
+            Ok(stream) => stream
+        };
+        let mut writer = stream.clone();
+        let mut reader = BufferedReader::new(stream);
+        for step in script.into_iter() {
+            if step.starts_with("S: ") {
+                let _ = writer.write_str(step.slice_from(3)).and_then(|_| writer.write_str("\r\n"));
+                continue;
+            }
+            let line = reader.read_line().unwrap_or(String::new());
+            if line.as_slice().trim_right() != step.slice_from(3) {
+                tx.send(Some(format!("expected {}, got {}", step, line)));
+                return;
+            }
+        }
+        tx.send(None);
+    });
+    Ok((port, rx))
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.acceptor.close_accept();
+        let _ = self.tx.send_opt(Shutdown);
+    }
+}
+
+/// A user: one of the connected clients, or a user on another server
+struct Client {
+    /// The connection, None for users on other servers
+    stream: Option<TcpStream>,
+    nick: String,
+    user: String,
+    server: String,
+    registered: bool,
+    /// The channels the user is in, lowercased
+    channels: Vec<String>,
+    /// Whether the user's server has been split off
+    split: bool
+}
+
+/// The state of the server. Clients are keyed by connection id, and users
+/// on other servers by ids counting down from `uint::MAX`.
+struct Ircd {
+    clients: HashMap<uint, Client>,
+    next_remote: uint
+}
+
+impl Ircd {
+    fn handle(&mut self, msg: Msg) {
+        match msg {
+            Accepted(id, stream) => {
+                self.clients.insert(id, Client {
+                    stream: Some(stream),
+                    nick: String::new(),
+                    user: String::new(),
+                    server: SERVER_NAME.to_string(),
+                    registered: false,
+                    channels: Vec::new(),
+                    split: false
+                });
+            }
+            Received(id, line) => {
+                let line = line.as_slice().trim_right_chars(['\r', '\n'].as_slice());
+                let (cmd, args) = parse(line);
+                self.command(id, cmd.as_slice(), args.as_slice());
+            }
+            Closed(id) => self.quit(id, "Connection closed"),
+            AddRemote(nick, server, chans) => {
+                self.next_remote += 1;
+                let id = uint::MAX - self.next_remote;
+                self.clients.insert(id, Client {
+                    stream: None,
+                    user: nick.clone(),
+                    nick: nick,
+                    server: server,
+                    registered: true,
+                    channels: Vec::new(),
+                    split: false
+                });
+                for chan in chans.iter() {
+                    self.join(id, lower(chan.as_slice()).as_slice());
+                }
+            }
+            Split(server) => {
+                let reason = format!("{} {}", SERVER_NAME, server);
+                for id in self.on_server(server.as_slice(), false).into_iter() {
+                    let line = format!(":{} QUIT :{}", self.mask(id), reason);
+                    self.send_peers(id, line.as_slice());
+                    self.clients.get_mut(&id).unwrap().split = true;
+                }
+            }
+            Rejoin(server) => {
+                for id in self.on_server(server.as_slice(), true).into_iter() {
+                    self.clients.get_mut(&id).unwrap().split = false;
+                    for chan in self.client(id).channels.clone().into_iter() {
+                        let line = format!(":{} JOIN {}", self.mask(id), chan);
+                        self.send_channel(chan.as_slice(), line.as_slice(), None);
+                    }
+                }
+            }
+            SendAll(line) => {
+                let ids = self.clients.iter().filter(|&(_, c)| c.registered).map(|(&id, _)| id).collect::<Vec<_>>();
+                for &id in ids.iter() {
+                    self.send(id, line.as_slice());
+                }
+            }
+            Shutdown => ()
+        }
+    }
+
+    fn command(&mut self, id: uint, cmd: &str, args: &[String]) {
+        let (nick, registered) = match self.clients.get(&id) {
+            None => return,
+            Some(c) => (if c.nick.is_empty() { "*".to_string() } else { c.nick.clone() }, c.registered)
+        };
+        let arg = |i: uint| args.get(i).map_or("", |a| a.as_slice());
+        match cmd {
+            "NICK" if args.is_empty() => self.reply(id, format!("431 {} :No nickname given", nick)),
+            "NICK" => {
+                let new = arg(0);
+                let taken = self.clients.iter().any(|(&other, c)| {
+                    other != id && !c.split && lower(c.nick.as_slice()) == lower(new)
+                });
+                if taken {
+                    self.reply(id, format!("433 {} {} :Nickname is already in use", nick, new));
+                } else if registered {
+                    let line = format!(":{} NICK {}", self.mask(id), new);
+                    self.send(id, line.as_slice());
+                    self.send_peers(id, line.as_slice());
+                    self.clients.get_mut(&id).unwrap().nick = new.to_string();
+                } else {
+                    self.clients.get_mut(&id).unwrap().nick = new.to_string();
+                    self.register(id);
+                }
+            }
+            "USER" if args.len() < 4 => self.reply(id, format!("461 {} USER :Not enough parameters", nick)),
+            "USER" if registered => self.reply(id, format!("462 {} :You may not reregister", nick)),
+            "USER" => {
+                self.clients.get_mut(&id).unwrap().user = arg(0).to_string();
+                self.register(id);
+            }
+            "PING" => self.reply(id, format!("PONG {} :{}", SERVER_NAME, arg(0))),
+            "PONG" => (),
+            "QUIT" => {
+                let reason = format!("Quit: {}", arg(0));
+                self.send(id, format!("ERROR :Closing Link: localhost ({})", reason).as_slice());
+                self.quit(id, reason.as_slice());
+            }
+            _ if !registered => self.reply(id, format!("451 {} :You have not registered", nick)),
+            "JOIN" if args.is_empty() => self.reply(id, format!("461 {} JOIN :Not enough parameters", nick)),
+            "JOIN" => {
+                for chan in arg(0).split(',') {
+                    if !chan.starts_with("#") {
+                        self.reply(id, format!("403 {} {} :No such channel", nick, chan));
+                    } else {
+                        self.join(id, lower(chan).as_slice());
+                    }
+                }
+            }
+            "PART" if args.is_empty() => self.reply(id, format!("461 {} PART :Not enough parameters", nick)),
+            "PART" => {
+                for chan in arg(0).split(',') {
+                    let chan = lower(chan);
+                    if !self.client(id).channels.contains(&chan) {
+                        self.reply(id, format!("442 {} {} :You're not on that channel", nick, chan));
+                        continue;
+                    }
+                    let line = if args.len() > 1 {
+                        format!(":{} PART {} :{}", self.mask(id), chan, arg(1))
+                    } else {
+                        format!(":{} PART {}", self.mask(id), chan)
+                    };
+                    self.send_channel(chan.as_slice(), line.as_slice(), None);
+                    self.clients.get_mut(&id).unwrap().channels.retain(|c| *c != chan);
+                }
+            }
+            "PRIVMSG" | "NOTICE" if args.len() < 2 => {
+                self.reply(id, format!("461 {} {} :Not enough parameters", nick, cmd));
+            }
+            "PRIVMSG" | "NOTICE" => {
+                let target = arg(0);
+                let line = format!(":{} {} {} :{}", self.mask(id), cmd, target, arg(1));
+                if target.starts_with("#") {
+                    let chan = lower(target);
+                    if self.client(id).channels.contains(&chan) {
+                        self.send_channel(chan.as_slice(), line.as_slice(), Some(id));
+                    } else if cmd == "PRIVMSG" {
+                        self.reply(id, format!("404 {} {} :Cannot send to channel", nick, target));
+                    }
+                } else {
+                    match self.find_nick(target) {
+                        Some(to) => self.send(to, line.as_slice()),
+                        None => self.reply(id, format!("401 {} {} :No such nick/channel", nick, target))
+                    }
+                }
+            }
+            _ => self.reply(id, format!("421 {} {} :Unknown command", nick, cmd))
+        }
+    }
+
+    /// Welcomes a client once it has sent both NICK and USER
+    fn register(&mut self, id: uint) {
+        let nick = {
+            let client = &self.client(id);
+            if client.registered || client.nick.is_empty() || client.user.is_empty() {
+                return;
+            }
+            client.nick.clone()
+        };
+        self.clients.get_mut(&id).unwrap().registered = true;
+        let welcome = format!("001 {} :Welcome to the test network {}", nick, self.mask(id));
+        self.reply(id, welcome);
+        self.reply(id, format!("005 {} CHANTYPES=# CASEMAPPING=ascii NICKLEN=30 :are supported by this server",
+                               nick));
+        self.reply(id, format!("422 {} :MOTD File is missing", nick));
+    }
+
+    fn join(&mut self, id: uint, chan: &str) {
+        if self.client(id).channels.iter().any(|c| c.as_slice() == chan) {
+            return;
+        }
+        self.clients.get_mut(&id).unwrap().channels.push(chan.to_string());
+        let line = format!(":{} JOIN {}", self.mask(id), chan);
+        self.send_channel(chan, line.as_slice(), None);
+        if self.client(id).stream.is_some() {
+            let nick = self.client(id).nick.clone();
+            let names = self.members(chan).iter().map(|&m| self.client(m).nick.clone()).collect::<Vec<_>>();
+            self.reply(id, format!("353 {} = {} :{}", nick, chan, names.connect(" ")));
+            self.reply(id, format!("366 {} {} :End of /NAMES list.", nick, chan));
+        }
+    }
+
+    /// Removes a client, telling the users in its channels
+    fn quit(&mut self, id: uint, reason: &str) {
+        if !self.clients.contains_key(&id) {
+            return;
+        }
+        if self.client(id).registered {
+            let line = format!(":{} QUIT :{}", self.mask(id), reason);
+            self.send_peers(id, line.as_slice());
+        }
+        match self.clients.remove(&id).and_then(|c| c.stream) {
+            None => (),
+            Some(mut stream) => { let _ = stream.close_read(); let _ = stream.close_write(); }
+        }
+    }
+
+    fn client<'a>(&'a self, id: uint) -> &'a Client {
+        self.clients.get(&id).unwrap()
+    }
+
+    /// Returns the users on `server`, either the split ones or the others
+    fn on_server(&self, server: &str, split: bool) -> Vec<uint> {
+        self.clients.iter().filter(|&(_, c)| c.stream.is_none() && c.split == split && c.server.as_slice() == server)
+                           .map(|(&id, _)| id).collect()
+    }
+
+    fn find_nick(&self, nick: &str) -> Option<uint> {
+        let nick = lower(nick);
+        self.clients.iter().find(|&(_, c)| c.registered && !c.split && lower(c.nick.as_slice()) == nick)
+                           .map(|(&id, _)| id)
+    }
+
+    /// Returns the users in `chan`
+    fn members(&self, chan: &str) -> Vec<uint> {
+        self.clients.iter().filter(|&(_, c)| !c.split && c.channels.iter().any(|ch| ch.as_slice() == chan))
+                           .map(|(&id, _)| id).collect()
+    }
+
+    fn mask(&self, id: uint) -> String {
+        let client = &self.client(id);
+        let host = if client.stream.is_some() { "localhost" } else { client.server.as_slice() };
+        format!("{}!{}@{}", client.nick, client.user, host)
+    }
+
+    /// Sends a reply from the server
+    fn reply(&mut self, id: uint, reply: String) {
+        self.send(id, format!(":{} {}", SERVER_NAME, reply).as_slice());
+    }
+
+    /// Sends `line` to the clients in `chan`, except `except`
+    fn send_channel(&mut self, chan: &str, line: &str, except: Option<uint>) {
+        for id in self.members(chan).into_iter() {
+            if Some(id) != except {
+                self.send(id, line);
+            }
+        }
+    }
+
+    /// Sends `line` to the clients that share a channel with `id`, once each
+    fn send_peers(&mut self, id: uint, line: &str) {
+        let channels = self.client(id).channels.clone();
+        let peers = self.clients.iter().filter(|&(&other, c)| {
+            other != id && !c.split && c.channels.iter().any(|ch| channels.contains(ch))
+        }).map(|(&other, _)| other).collect::<Vec<_>>();
+        for &peer in peers.iter() {
+            self.send(peer, line);
+        }
+    }
+
+    fn send(&mut self, id: uint, line: &str) {
+        match self.clients.get_mut(&id).and_then(|c| c.stream.as_mut()) {
+            None => (),
+            Some(stream) => {
+                let _ = stream.write_str(line);
+                let _ = stream.write_str("\r\n");
+            }
+        }
+    }
+}
+
+/// Splits a line into its command, uppercased, and its arguments
+fn parse(line: &str) -> (String, Vec<String>) {
+    let mut rest = line.trim_left();
+    if rest.starts_with(":") {
+        // clients don't need to send a prefix, skip it
+        rest = rest.find(' ').map_or("", |idx| rest.slice_from(idx + 1));
+    }
+    let mut args = Vec::new();
+    loop {
+        rest = rest.trim_left();
+        if rest.is_empty() {
+            break;
+        }
+        if rest.starts_with(":") {
+            args.push(rest.slice_from(1).to_string());
+            break;
+        }
+        let end = rest.find(' ').unwrap_or(rest.len());
+        args.push(rest.slice_to(end).to_string());
+        rest = rest.slice_from(end);
+    }
+    if args.is_empty() {
+        return (String::new(), args);
+    }
+    let cmd = args.remove(0).unwrap().as_slice().to_ascii_upper();
+    (cmd, args)
+}
+
+fn lower(s: &str) -> String {
+    s.to_ascii_lower()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TestServer, parse};
+    use std::io::{BufferedReader, TcpStream};
+
+    struct Client {
+        stream: TcpStream,
+        reader: BufferedReader<TcpStream>
+    }
+
+    impl Client {
+        fn connect(server: &TestServer, nick: &str) -> Client {
+            let stream = TcpStream::connect("127.0.0.1", server.port()).unwrap();
+            let mut client = Client { reader: BufferedReader::new(stream.clone()), stream: stream };
+            client.send(format!("NICK {}", nick).as_slice());
+            client.send("USER u 8 * :test user");
+            client.expect(format!(":irc.test 001 {}", nick).as_slice());
+            client.expect(":irc.test 005");
+            client.expect(":irc.test 422");
+            client
+        }
+
+        fn send(&mut self, line: &str) {
+            self.stream.write_str(line).unwrap();
+            self.stream.write_str("\r\n").unwrap();
+        }
+
+        fn expect(&mut self, start: &str) {
+            let line = self.reader.read_line().unwrap();
+            assert!(line.as_slice().starts_with(start), "expected {}, got {}", start, line);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("privmsg #chan :hi there"),
+                   ("PRIVMSG".to_string(), vec!["#chan".to_string(), "hi there".to_string()]));
+        assert_eq!(parse(":me JOIN #a,#b"), ("JOIN".to_string(), vec!["#a,#b".to_string()]));
+        assert_eq!(parse("QUIT"), ("QUIT".to_string(), vec![]));
+        assert_eq!(parse(""), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn relay_between_clients() {
+        let server = TestServer::start().unwrap();
+        let mut alice = Client::connect(&server, "alice");
+        let mut bob = Client::connect(&server, "bob");
+        alice.send("JOIN #Test");
+        alice.expect(":alice!u@localhost JOIN #test");
+        alice.expect(":irc.test 353 alice = #test :alice");
+        alice.expect(":irc.test 366");
+        bob.send("JOIN #test");
+        bob.expect(":bob!u@localhost JOIN #test");
+        alice.expect(":bob!u@localhost JOIN #test");
+        bob.expect(":irc.test 353");
+        bob.expect(":irc.test 366");
+        bob.send("PRIVMSG #test :hello");
+        alice.expect(":bob!u@localhost PRIVMSG #test :hello");
+        alice.send("PRIVMSG BOB :hi");
+        bob.expect(":alice!u@localhost PRIVMSG BOB :hi");
+        bob.send("PING :token");
+        bob.expect(":irc.test PONG irc.test :token");
+        bob.send("QUIT :bye");
+        bob.expect("ERROR :Closing Link");
+        alice.expect(":bob!u@localhost QUIT :Quit: bye");
+    }
+
+    #[test]
+    fn scripted_netsplit() {
+        let server = TestServer::start().unwrap();
+        let mut alice = Client::connect(&server, "alice");
+        alice.send("JOIN #test");
+        alice.expect(":alice!u@localhost JOIN #test");
+        alice.expect(":irc.test 353");
+        alice.expect(":irc.test 366");
+        server.add_remote_user("carol", "leaf.test", ["#test"]);
+        alice.expect(":carol!carol@leaf.test JOIN #test");
+        server.split("leaf.test");
+        alice.expect(":carol!carol@leaf.test QUIT :irc.test leaf.test");
+        server.rejoin("leaf.test");
+        alice.expect(":carol!carol@leaf.test JOIN #test");
+    }
+}
@@ -0,0 +1,142 @@
+//! Checking nicks and channel names before sending them
+//!
+//! The server rejects invalid names with an error reply, which only arrives
+//! after a round trip. These checks use the limits the server advertised in
+//! RPL_ISUPPORT and the rules of RFC 2812, so that an invalid name is caught
+//! before anything is sent.
+
+use isupport::ISupport;
+
+/// The RFC 2812 limit on channel names, for servers that don't send CHANNELLEN
+static DEFAULT_CHANNELLEN: uint = 50;
+
+/// Why a nick or channel name is invalid
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum InvalidName {
+    /// The name is empty
+    EmptyName,
+    /// The name is longer than the server allows. The value is the limit.
+    NameTooLong(uint),
+    /// The name contains a byte that isn't allowed, or isn't allowed where it is
+    BadNameChar(u8),
+    /// The channel name doesn't start with one of the server's CHANTYPES
+    NotAChannel
+}
+
+/// Checks a nick against the server's NICKLEN and the RFC 2812 rules: a
+/// letter or one of `[]\`_^{|}` first, followed by those, digits and `-`.
+///
+/// Bytes outside ASCII are allowed, since some networks accept Unicode
+/// nicks; the server still rejects them where they aren't. Before the server
+/// has sent NICKLEN, the length isn't checked.
+pub fn nick(nick: &[u8], isupport: &ISupport) -> Result<(), InvalidName> {
+    if nick.is_empty() {
+        return Err(EmptyName);
+    }
+    match isupport.get_uint("NICKLEN") {
+        Some(max) if nick.len() > max => return Err(NameTooLong(max)),
+        _ => ()
+    }
+    for (i, &b) in nick.iter().enumerate() {
+        let ok = match b as char {
+            'a'...'z' | 'A'...'Z' | '[' | ']' | '\\' | '`' | '_' | '^' | '{' | '|' | '}' => true,
+            '0'...'9' | '-' => i > 0,
+            _ => b >= 0x80
+        };
+        if !ok {
+            return Err(BadNameChar(b));
+        }
+    }
+    Ok(())
+}
+
+/// Returns `nick` cut down to at most `max` bytes. A UTF-8 character that
+/// doesn't fit is dropped whole, for networks that allow Unicode nicks.
+pub fn truncate_nick<'a>(nick: &'a [u8], max: uint) -> &'a [u8] {
+    if nick.len() <= max {
+        return nick;
+    }
+    let mut end = max;
+    // back up to the start of the character that crosses the limit
+    while end > 0 && nick[end] & 0xC0 == 0x80 {
+        end -= 1;
+    }
+    nick.slice_to(end)
+}
+
+/// Checks a channel name against the server's CHANTYPES and CHANNELLEN, and
+/// the RFC 2812 rules: no spaces, commas, colons, BELs, NULs or line breaks.
+pub fn channel(chan: &[u8], isupport: &ISupport) -> Result<(), InvalidName> {
+    if chan.is_empty() {
+        return Err(EmptyName);
+    }
+    if !isupport.is_channel(chan) {
+        return Err(NotAChannel);
+    }
+    let max = isupport.get_uint("CHANNELLEN").unwrap_or(DEFAULT_CHANNELLEN);
+    if chan.len() > max {
+        return Err(NameTooLong(max));
+    }
+    match chan.iter().find(|&&b| b == ' ' as u8 || b == ',' as u8 || b == ':' as u8 ||
+                                 b == 7 || b == 0 || b == '\r' as u8 || b == '\n' as u8) {
+        Some(&b) => Err(BadNameChar(b)),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nick, channel, truncate_nick, EmptyName, NameTooLong, BadNameChar, NotAChannel};
+    use isupport::ISupport;
+
+    fn isupport(tokens: &[&str]) -> ISupport {
+        let mut args = vec![b"me".to_vec()];
+        args.extend(tokens.iter().map(|t| t.as_bytes().to_vec()));
+        args.push(b"are supported by this server".to_vec());
+        let mut isupport = ISupport::new();
+        isupport.add_tokens(args.as_slice());
+        isupport
+    }
+
+    #[test]
+    fn test_nick() {
+        let none = isupport([]);
+        assert_eq!(nick(b"XMPPwocky", &none), Ok(()));
+        assert_eq!(nick(b"[away]|x-2", &none), Ok(()));
+        assert_eq!(nick(b"kl\xc3\xa4rchen", &none), Ok(()));
+        assert_eq!(nick(b"", &none), Err(EmptyName));
+        assert_eq!(nick(b"2fast", &none), Err(BadNameChar('2' as u8)));
+        assert_eq!(nick(b"-dash", &none), Err(BadNameChar('-' as u8)));
+        assert_eq!(nick(b"two words", &none), Err(BadNameChar(' ' as u8)));
+        assert_eq!(nick(b"a@b", &none), Err(BadNameChar('@' as u8)));
+        let short = isupport(["NICKLEN=9"]);
+        assert_eq!(nick(b"ninechars", &short), Ok(()));
+        assert_eq!(nick(b"tenchars__", &short), Err(NameTooLong(9)));
+    }
+
+    #[test]
+    fn test_truncate_nick() {
+        assert_eq!(truncate_nick(b"XMPPwocky", 9), b"XMPPwocky");
+        assert_eq!(truncate_nick(b"XMPPwocky", 4), b"XMPP");
+        // "kläre" with a two-byte ä at bytes 2 and 3
+        assert_eq!(truncate_nick(b"kl\xc3\xa4re", 4), b"kl\xc3\xa4");
+        assert_eq!(truncate_nick(b"kl\xc3\xa4re", 3), b"kl");
+        assert_eq!(truncate_nick(b"\xe2\x98\x83snow", 2), b"");
+    }
+
+    #[test]
+    fn test_channel() {
+        let none = isupport([]);
+        assert_eq!(channel(b"#rust", &none), Ok(()));
+        assert_eq!(channel(b"&local", &none), Ok(()));
+        assert_eq!(channel(b"", &none), Err(EmptyName));
+        assert_eq!(channel(b"rust", &none), Err(NotAChannel));
+        assert_eq!(channel(b"#a,#b", &none), Err(BadNameChar(',' as u8)));
+        assert_eq!(channel(b"#a b", &none), Err(BadNameChar(' ' as u8)));
+        assert_eq!(channel(b"#\x07ding", &none), Err(BadNameChar(7)));
+        let ts = isupport(["CHANTYPES=#", "CHANNELLEN=8"]);
+        assert_eq!(channel(b"&local", &ts), Err(NotAChannel));
+        assert_eq!(channel(b"#eight__", &ts), Ok(()));
+        assert_eq!(channel(b"#nine____", &ts), Err(NameTooLong(8)));
+    }
+}
@@ -0,0 +1,103 @@
+//! Parsing of WHO replies
+
+use conn::{Line, IRCCode};
+
+/// A single RPL_WHOREPLY (352) line
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct WhoReply {
+    /// The channel the reply is for, or None if the query didn't match
+    /// through a channel (the server sends `*`)
+    pub channel: Option<Vec<u8>>,
+    /// The user's username
+    pub user: Vec<u8>,
+    /// The user's hostname
+    pub host: Vec<u8>,
+    /// The server the user is on
+    pub server: Vec<u8>,
+    /// The user's nick
+    pub nick: Vec<u8>,
+    /// Whether the user is away (flag `G` rather than `H`)
+    pub away: bool,
+    /// Whether the user is an IRC operator (flag `*`)
+    pub oper: bool,
+    /// The membership prefixes the user has in the channel, e.g. `@` or `+`,
+    /// from the highest rank to the lowest
+    pub prefixes: Vec<char>,
+    /// The number of hops between our server and theirs
+    pub hopcount: uint,
+    /// The user's realname
+    pub realname: Vec<u8>
+}
+
+impl WhoReply {
+    /// Parses a RPL_WHOREPLY line. Returns None for other lines, or if the
+    /// line is malformed. `prefix_chars` are the membership prefixes
+    /// supported by the server, e.g. `@+`.
+    pub fn parse(line: &Line, prefix_chars: &[char]) -> Option<WhoReply> {
+        // :server 352 me #chan user host server nick flags :hops realname
+        match line.command {
+            IRCCode(352) if line.args.len() > 7 => (),
+            _ => return None
+        }
+        let args = line.args.as_slice();
+        let flags = args[6].as_slice();
+        let (hops, realname) = {
+            let last = args[7].as_slice();
+            match last.position_elem(&(' ' as u8)) {
+                None => (last, [].as_slice()),
+                Some(idx) => (last.slice_to(idx), last.slice_from(idx+1))
+            }
+        };
+        let hopcount = match ::std::str::from_utf8(hops).and_then(|h| from_str::<uint>(h)) {
+            None => return None,
+            Some(h) => h
+        };
+        let channel = args[1].as_slice();
+        Some(WhoReply {
+            channel: if channel == b"*" { None } else { Some(channel.to_vec()) },
+            user: args[2].clone(),
+            host: args[3].clone(),
+            server: args[4].clone(),
+            nick: args[5].clone(),
+            away: flags.starts_with(b"G"),
+            oper: flags.contains(&('*' as u8)),
+            prefixes: flags.iter().map(|&b| b as char).filter(|c| prefix_chars.contains(c)).collect(),
+            hopcount: hopcount,
+            realname: realname.to_vec()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WhoReply;
+    use conn::Line;
+
+    #[test]
+    fn test_parse() {
+        let line = Line::parse(b":srv 352 me #chan ident host.net irc.net nick G*@ :3 Real Name").unwrap();
+        assert_eq!(WhoReply::parse(&line, ['@', '+']), Some(WhoReply {
+            channel: Some(b"#chan".to_vec()),
+            user: b"ident".to_vec(),
+            host: b"host.net".to_vec(),
+            server: b"irc.net".to_vec(),
+            nick: b"nick".to_vec(),
+            away: true,
+            oper: true,
+            prefixes: vec!['@'],
+            hopcount: 3,
+            realname: b"Real Name".to_vec()
+        }));
+
+        let line = Line::parse(b":srv 352 me * ident host irc.net nick H :0 ").unwrap();
+        let reply = WhoReply::parse(&line, ['@', '+']).unwrap();
+        assert_eq!(reply.channel, None);
+        assert!(!reply.away && !reply.oper && reply.prefixes.is_empty());
+        assert_eq!(reply.realname, Vec::new());
+
+        let line = Line::parse(b":srv 352 me #chan ident host irc.net nick H :x y").unwrap();
+        assert_eq!(WhoReply::parse(&line, ['@']), None);
+        let line = Line::parse(b":srv 315 me #chan :End of /WHO list.").unwrap();
+        assert_eq!(WhoReply::parse(&line, ['@']), None);
+    }
+}